@@ -42,6 +42,11 @@ pub async fn run(
         seed: 0,
         repetition_penalty: repetition_penalty.unwrap_or(1.0),
         watermark,
+        logit_bias: vec![],
+        logit_bias_first_token_only: false,
+        sampler_order: vec![],
+        presence_penalty: 0.0,
+        frequency_penalty: 0.0,
     };
 
     // Initialize terminal properties
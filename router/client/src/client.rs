@@ -124,6 +124,11 @@ impl Client {
                     seed: 0,
                     repetition_penalty: 1.2,
                     watermark: true,
+                    logit_bias: vec![],
+                    logit_bias_first_token_only: false,
+                    sampler_order: vec![],
+                    presence_penalty: 0.1,
+                    frequency_penalty: 0.1,
                 }),
                 stopping_parameters: Some(StoppingCriteriaParameters {
                     max_new_tokens: 2,
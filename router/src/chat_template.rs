@@ -0,0 +1,259 @@
+//! Jinja chat templates shipped in the `tokenizer_config.json` of the models
+use crate::validation::ValidationError;
+use minijinja::{Environment, Error, ErrorKind};
+use serde::{Deserialize, Serialize, Serializer};
+
+/// Name of the template in the Jinja environment
+const TEMPLATE_NAME: &str = "chat_template";
+
+/// Jinja `chat_template` of a tokenizer, rendering whole conversations like the
+/// `apply_chat_template` of `transformers`
+#[derive(Debug)]
+pub struct ChatTemplate {
+    env: Environment<'static>,
+    template: String,
+    /// Where the template was loaded from, reported in the `Info` of the router
+    source: String,
+    bos_token: Option<String>,
+    eos_token: Option<String>,
+}
+
+/// Message of a conversation as seen by the templates
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct TemplateMessage {
+    pub role: String,
+    pub content: String,
+}
+
+#[derive(Deserialize)]
+struct TokenizerConfig {
+    #[serde(default)]
+    chat_template: Option<ChatTemplates>,
+    #[serde(default)]
+    bos_token: Option<TokenizerConfigToken>,
+    #[serde(default)]
+    eos_token: Option<TokenizerConfigToken>,
+}
+
+/// A single template, or named templates of which the `default` one renders the conversations
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ChatTemplates {
+    Single(String),
+    Named(Vec<NamedChatTemplate>),
+}
+
+#[derive(Deserialize)]
+struct NamedChatTemplate {
+    name: String,
+    template: String,
+}
+
+/// Special token, as a string or as an added token object
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TokenizerConfigToken {
+    Text(String),
+    AddedToken { content: String },
+}
+
+impl From<TokenizerConfigToken> for String {
+    fn from(token: TokenizerConfigToken) -> Self {
+        match token {
+            TokenizerConfigToken::Text(token) => token,
+            TokenizerConfigToken::AddedToken { content } => content,
+        }
+    }
+}
+
+/// `raise_exception` of the templates, e.g. when the roles of a conversation do not alternate
+fn raise_exception(message: String) -> Result<String, Error> {
+    Err(Error::new(ErrorKind::InvalidOperation, message))
+}
+
+impl ChatTemplate {
+    /// Template of the `content` of a `tokenizer_config.json` loaded from `source`. `Ok(None)`
+    /// when the tokenizer has no chat template
+    pub fn from_tokenizer_config(content: &str, source: String) -> Result<Option<Self>, String> {
+        let config: TokenizerConfig =
+            serde_json::from_str(content).map_err(|err| err.to_string())?;
+        let template = match config.chat_template {
+            None => return Ok(None),
+            Some(ChatTemplates::Single(template)) => template,
+            Some(ChatTemplates::Named(templates)) => {
+                match templates.into_iter().find(|t| t.name == "default") {
+                    Some(default) => default.template,
+                    None => return Err("no `default` chat template".to_string()),
+                }
+            }
+        };
+        Self::new(
+            template,
+            source,
+            config.bos_token.map(String::from),
+            config.eos_token.map(String::from),
+        )
+        .map(Some)
+    }
+
+    pub(crate) fn new(
+        template: String,
+        source: String,
+        bos_token: Option<String>,
+        eos_token: Option<String>,
+    ) -> Result<Self, String> {
+        let mut env = Environment::new();
+        // Same whitespace handling as `transformers`
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        // Python string methods, e.g. `message['content'].strip()`
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", raise_exception);
+        env.add_template_owned(TEMPLATE_NAME, template.clone())
+            .map_err(|err| err.to_string())?;
+        Ok(Self {
+            env,
+            template,
+            source,
+            bos_token,
+            eos_token,
+        })
+    }
+
+    /// Where the template was loaded from, e.g. the path of the `tokenizer_config.json`
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Prompt of a conversation. `add_generation_prompt` opens the assistant turn that the model
+    /// then generates
+    pub(crate) fn render(
+        &self,
+        messages: &[TemplateMessage],
+        add_generation_prompt: bool,
+    ) -> Result<String, ValidationError> {
+        let template = self
+            .env
+            .get_template(TEMPLATE_NAME)
+            .expect("chat template was added on creation");
+        template
+            .render(minijinja::context! {
+                messages => messages,
+                add_generation_prompt => add_generation_prompt,
+                bos_token => self.bos_token,
+                eos_token => self.eos_token,
+            })
+            .map_err(|err| {
+                // Errors of the engine, e.g. on undefined values, may come without a detail
+                let detail = err.detail().map_or_else(|| err.to_string(), str::to_string);
+                ValidationError::ChatTemplate(detail)
+            })
+    }
+}
+
+/// The text of the template identifies it, e.g. in the chat template hash
+impl Serialize for ChatTemplate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.template)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(messages: &[(&str, &str)]) -> Vec<TemplateMessage> {
+        messages
+            .iter()
+            .map(|(role, content)| TemplateMessage {
+                role: role.to_string(),
+                content: content.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chatml_template() {
+        let config = r#"{
+            "chat_template": "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}",
+            "eos_token": "<|im_end|>"
+        }"#;
+        let template = ChatTemplate::from_tokenizer_config(config, "chatml".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(template.source(), "chatml");
+        let conversation = messages(&[("system", "Be brief"), ("user", "Hi")]);
+        assert_eq!(
+            template.render(&conversation, true).unwrap(),
+            "<|im_start|>system\nBe brief<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            template.render(&conversation, false).unwrap(),
+            "<|im_start|>system\nBe brief<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n"
+        );
+    }
+
+    #[test]
+    fn test_llama_template() {
+        let config = r#"{
+            "chat_template": "{{ bos_token }}{% for message in messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if message['role'] == 'user' %}{{ '[INST] ' + message['content'].strip() + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ ' ' + message['content'].strip() + ' ' + eos_token }}{% endif %}{% endfor %}",
+            "bos_token": {"__type": "AddedToken", "content": "<s>", "lstrip": false},
+            "eos_token": {"__type": "AddedToken", "content": "</s>", "lstrip": false}
+        }"#;
+        let template = ChatTemplate::from_tokenizer_config(config, "llama".to_string())
+            .unwrap()
+            .unwrap();
+        let conversation = messages(&[("user", " Hi "), ("assistant", "Hello"), ("user", "Bye")]);
+        assert_eq!(
+            template.render(&conversation, true).unwrap(),
+            "<s>[INST] Hi [/INST] Hello </s>[INST] Bye [/INST]"
+        );
+
+        let err = template
+            .render(&messages(&[("user", "Hi"), ("user", "Hi")]), true)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "chat template error: Conversation roles must alternate user/assistant/user/assistant/..."
+        );
+    }
+
+    #[test]
+    fn test_template_error_without_detail() {
+        let config = r#"{"chat_template": "{{ messages.missing.content }}"}"#;
+        let template = ChatTemplate::from_tokenizer_config(config, String::new())
+            .unwrap()
+            .unwrap();
+        let err = template
+            .render(&messages(&[("user", "Hi")]), true)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "chat template error: undefined value (in chat_template:1)"
+        );
+    }
+
+    #[test]
+    fn test_tokenizer_config_templates() {
+        // Tokenizers without a template fall back to the `TGICHAT_*` templates
+        let config = r#"{"eos_token": "</s>"}"#;
+        assert!(ChatTemplate::from_tokenizer_config(config, String::new())
+            .unwrap()
+            .is_none());
+
+        let config = r#"{"chat_template": [
+            {"name": "tool_use", "template": "tools"},
+            {"name": "default", "template": "{{ messages[0]['content'] }}"}
+        ]}"#;
+        let template = ChatTemplate::from_tokenizer_config(config, String::new())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            template.render(&messages(&[("user", "Hi")]), true).unwrap(),
+            "Hi"
+        );
+
+        let config = r#"{"chat_template": "{% for message in messages %}"}"#;
+        assert!(ChatTemplate::from_tokenizer_config(config, String::new()).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//! Coalescing of the streamed tokens of the OpenAI compatible routes
+use crate::completion::TrailingWhitespace;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+/// Punctuation ending a sentence or a clause, used when no boundaries are configured
+pub(crate) const DEFAULT_SENTENCE_BOUNDARIES: &str = ".!?;:";
+
+/// Amount of text sent with each streamed chunk
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum StreamGranularity {
+    /// One chunk per generated token
+    #[default]
+    Token,
+    /// One chunk per sentence or clause
+    Sentence,
+}
+
+/// Holds back the streamed text until the end of a sentence or clause: a boundary punctuation
+/// followed by a whitespace, or a line break.
+/// Works on the text released by the `StopSequenceBuffer`, so that the stop sequences are
+/// already handled.
+#[derive(Debug)]
+pub(crate) struct SentenceBuffer {
+    granularity: StreamGranularity,
+    boundaries: Vec<char>,
+    buffer: String,
+}
+
+impl SentenceBuffer {
+    pub(crate) fn new(granularity: StreamGranularity, boundaries: &str) -> Self {
+        Self {
+            granularity,
+            boundaries: boundaries.chars().collect(),
+            buffer: String::new(),
+        }
+    }
+
+    /// Add streamed text and return the complete sentences that can be sent
+    pub(crate) fn push(&mut self, text: &str) -> String {
+        if self.granularity == StreamGranularity::Token {
+            return text.to_string();
+        }
+        self.buffer.push_str(text);
+
+        // End of the last boundary. Char indices keep the split on a UTF-8 boundary
+        let mut chars = self.buffer.char_indices().peekable();
+        let mut end = 0;
+        while let Some((i, c)) = chars.next() {
+            let boundary = match chars.peek() {
+                _ if c == '\n' => true,
+                Some((_, next)) => self.boundaries.contains(&c) && next.is_whitespace(),
+                // Wait for the next token to know whether the punctuation ends a sentence
+                None => false,
+            };
+            if boundary {
+                end = i + c.len_utf8();
+            }
+        }
+
+        let tail = self.buffer.split_off(end);
+        std::mem::replace(&mut self.buffer, tail)
+    }
+
+    /// Return the remaining text once the generation is over
+    pub(crate) fn finish(&mut self) -> String {
+        std::mem::take(&mut self.buffer)
+    }
+}
+
+/// `text` without the assistant `prefix` the model started it with, and the whitespace around it
+pub(crate) fn strip_assistant_prefix<'a>(text: &'a str, prefix: &str) -> &'a str {
+    match text.trim_start().strip_prefix(prefix) {
+        Some(rest) => rest.trim_start(),
+        None => text,
+    }
+}
+
+/// Holds back the start of a streamed chat completion until it is known whether the model
+/// started it with the assistant prefix, which is then stripped like from the complete `content`.
+/// Works on the generated tokens, before the `StopSequenceBuffer`
+#[derive(Debug)]
+pub(crate) struct AssistantPrefixBuffer {
+    /// Prefix to strip, cleared once the start of the text is sent
+    prefix: Option<String>,
+    buffer: String,
+}
+
+impl AssistantPrefixBuffer {
+    pub(crate) fn new(prefix: Option<String>) -> Self {
+        Self {
+            prefix,
+            buffer: String::new(),
+        }
+    }
+
+    /// Add streamed text and return the text that can be sent
+    pub(crate) fn push(&mut self, text: &str) -> String {
+        let prefix = match &self.prefix {
+            Some(prefix) => prefix,
+            None => return text.to_string(),
+        };
+        self.buffer.push_str(text);
+        let start = self.buffer.trim_start();
+        let pending = match start.strip_prefix(prefix.as_str()) {
+            // Wait for the text following the prefix, as the whitespace after it is stripped too
+            Some(rest) => rest.trim_start().is_empty(),
+            None => prefix.starts_with(start),
+        };
+        if pending {
+            return String::new();
+        }
+        let text = strip_assistant_prefix(&self.buffer, prefix).to_string();
+        self.prefix = None;
+        self.buffer.clear();
+        text
+    }
+
+    /// Return the remaining text once the generation is over
+    pub(crate) fn finish(&mut self) -> String {
+        let buffer = std::mem::take(&mut self.buffer);
+        match self.prefix.take() {
+            Some(prefix) => strip_assistant_prefix(&buffer, &prefix).to_string(),
+            None => buffer,
+        }
+    }
+}
+
+/// Holds back the whitespace ending the streamed text, which is only sent if more text follows
+/// it, so that the trailing whitespace of the output can be trimmed.
+/// Works on the text released by the `SentenceBuffer`
+#[derive(Debug)]
+pub(crate) struct TrailingWhitespaceBuffer {
+    trailing_whitespace: TrailingWhitespace,
+    buffer: String,
+}
+
+impl TrailingWhitespaceBuffer {
+    pub(crate) fn new(trailing_whitespace: TrailingWhitespace) -> Self {
+        Self {
+            trailing_whitespace,
+            buffer: String::new(),
+        }
+    }
+
+    /// Add streamed text and return the text that can be sent
+    pub(crate) fn push(&mut self, text: &str) -> String {
+        if self.trailing_whitespace == TrailingWhitespace::Keep {
+            return text.to_string();
+        }
+        self.buffer.push_str(text);
+        let tail = self.buffer.split_off(self.buffer.trim_end().len());
+        std::mem::replace(&mut self.buffer, tail)
+    }
+
+    /// Return the remaining text once the generation is over, without the trimmed whitespace
+    pub(crate) fn finish(&mut self) -> String {
+        let buffer = std::mem::take(&mut self.buffer);
+        self.trailing_whitespace.trim(&buffer).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(buffer: &mut SentenceBuffer, tokens: &[&str]) -> Vec<String> {
+        tokens.iter().map(|token| buffer.push(token)).collect()
+    }
+
+    fn stream_whitespace(buffer: &mut TrailingWhitespaceBuffer, tokens: &[&str]) -> Vec<String> {
+        tokens.iter().map(|token| buffer.push(token)).collect()
+    }
+
+    #[test]
+    fn test_token_granularity() {
+        let mut buffer = SentenceBuffer::new(StreamGranularity::Token, DEFAULT_SENTENCE_BOUNDARIES);
+        let sent = stream(&mut buffer, &["Hi", ".", " How"]);
+        assert_eq!(sent, vec!["Hi", ".", " How"]);
+        assert_eq!(buffer.finish(), "");
+    }
+
+    #[test]
+    fn test_sentence_granularity() {
+        let mut buffer =
+            SentenceBuffer::new(StreamGranularity::Sentence, DEFAULT_SENTENCE_BOUNDARIES);
+        let sent = stream(
+            &mut buffer,
+            &["Pi", " is", " 3", ".", "14", ".", " Isn't", " it", "?", "\n", "Yes"],
+        );
+        assert_eq!(
+            sent,
+            vec!["", "", "", "", "", "", "Pi is 3.14.", "", "", " Isn't it?\n", ""]
+        );
+        // The remainder is sent at the end of the generation
+        assert_eq!(buffer.finish(), "Yes");
+    }
+
+    #[test]
+    fn test_sentence_granularity_utf8() {
+        let mut buffer = SentenceBuffer::new(StreamGranularity::Sentence, "。");
+        let sent = stream(&mut buffer, &["你好", "。", " 再见"]);
+        assert_eq!(sent, vec!["", "", "你好。"]);
+        assert_eq!(buffer.finish(), " 再见");
+    }
+
+    #[test]
+    fn test_trailing_whitespace_buffer() {
+        let tokens = ["Hi", " ", "there", " \n", "\n"];
+        let mut buffer = TrailingWhitespaceBuffer::new(TrailingWhitespace::Keep);
+        assert_eq!(stream_whitespace(&mut buffer, &tokens), tokens);
+        assert_eq!(buffer.finish(), "");
+
+        // Whitespace is only sent once followed by more text
+        let mut buffer = TrailingWhitespaceBuffer::new(TrailingWhitespace::All);
+        let sent = stream_whitespace(&mut buffer, &tokens);
+        assert_eq!(sent, vec!["Hi", "", " there", "", ""]);
+        assert_eq!(buffer.finish(), "");
+
+        // The spaces ending the last line are kept
+        let mut buffer = TrailingWhitespaceBuffer::new(TrailingWhitespace::Lines);
+        stream_whitespace(&mut buffer, &tokens);
+        assert_eq!(buffer.finish(), " ");
+    }
+
+    #[test]
+    fn test_assistant_prefix_buffer() {
+        let prefix = "<|assistant|>";
+        let generations: [&[&str]; 6] = [
+            &["<|assistant|>", "\n", "Hi", " there"],
+            &[" <|", "assistant", "|>", " Hi"],
+            &["<|", "assist", "ant|>"],
+            &["<|", "user|>", " Hi"],
+            &[" ", "Hi", " <|assistant|>"],
+            &["<|assis"],
+        ];
+        for tokens in generations {
+            let mut buffer = AssistantPrefixBuffer::new(Some(prefix.to_string()));
+            let mut streamed: String = tokens.iter().map(|token| buffer.push(token)).collect();
+            streamed.push_str(&buffer.finish());
+            // The streamed content matches the complete content
+            let text = tokens.concat();
+            assert_eq!(
+                streamed,
+                strip_assistant_prefix(&text, prefix),
+                "{tokens:?}"
+            );
+        }
+
+        // The first tokens are held back until the prefix is ruled out
+        let mut buffer = AssistantPrefixBuffer::new(Some(prefix.to_string()));
+        assert_eq!(buffer.push("<|"), "");
+        assert_eq!(buffer.push("assistant|>"), "");
+        assert_eq!(buffer.push("\n"), "");
+        assert_eq!(buffer.push("Hi"), "Hi");
+        assert_eq!(buffer.push(" there"), " there");
+
+        let mut buffer = AssistantPrefixBuffer::new(None);
+        assert_eq!(buffer.push("<|assistant|>"), "<|assistant|>");
+        assert_eq!(buffer.finish(), "");
+    }
+}
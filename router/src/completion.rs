@@ -14,597 +14,7098 @@
 ///
 
 /// Converting generate to completions and chat/completions protocol
+use crate::chat_template::{ChatTemplate, TemplateMessage};
+use crate::coalesce::{strip_assistant_prefix, StreamGranularity};
+use crate::prompt_cache::PromptTokenCache;
+use crate::stop::response_stop_sequence;
+use crate::validation::ValidationError;
 use crate::{
-    default_max_new_tokens, FinishReason, GenerateParameters, GenerateRequest, GenerateResponse,
-    Info, OpenaiStreamType, StreamDetails, Token,
+    default_max_new_tokens, Details, ErrorResponse, FinishReason, GenerateParameters,
+    GenerateRequest, GenerateResponse, Info, OpenaiStreamType, PrefillToken, StreamDetails, Token,
+    TruncationSide,
 };
-use axum::extract::Extension;
+use axum::async_trait;
+use axum::body::{Body, Bytes};
+use axum::extract::rejection::JsonRejection;
+use axum::extract::FromRequest;
+use axum::http::{header, HeaderMap, Request, StatusCode};
 use axum::response::sse::Event;
+use axum::response::{IntoResponse, Response};
 use axum::Json;
-use serde::{Deserialize, Serialize};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use serde::de::{self, DeserializeOwned, IgnoredAny, MapAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::io::Read;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use text_generation_client::PrefillTokens;
+use thiserror::Error;
+use tokio::time::Instant;
 use utoipa::ToSchema;
-use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Clone, Debug, Deserialize, ToSchema)]
-pub(crate) struct CompatCompletionRequest {
-    #[schema(example = "My name is Michael and I")]
-    pub prompt: String,
+/// Server-side configuration of the OpenAI compatible routes
+#[derive(Clone, Debug, Default)]
+pub struct CompatConfig {
+    /// Hard ceiling on the wall-time of a stream. `None` means unlimited
+    pub max_stream_duration: Option<Duration>,
+    /// Return the normalized request hash in the `x-request-hash` header
+    pub request_hash_header: bool,
+    /// Identify the chat templates that rendered the prompt in the `x-chat-template-hash` and
+    /// `x-chat-template` headers of the chat completions
+    pub chat_template_header: bool,
+    /// Reject request bodies with duplicate top-level keys, instead of only the known fields
+    pub strict_json: bool,
+    /// Price of 1K prompt tokens, used for the `tgi_estimated_cost` of `Usage`
+    pub prompt_token_price: Option<f64>,
+    /// Price of 1K completion tokens, used for the `tgi_estimated_cost` of `Usage`
+    pub completion_token_price: Option<f64>,
+    /// Chat completions configuration
+    pub chat_config: ChatConfig,
+    /// Report generations stopped by a server policy in the `tgi_finish_reason` extension field
+    pub server_finish_reasons: bool,
+    /// Other names accepted for the served model. When set, requests naming another model are
+    /// rejected
+    pub model_aliases: Vec<String>,
+    /// Content of the terminal chunk closing a stream that failed after it started, instead of an
+    /// error event. `None` sends the error event
+    pub stream_error_fallback: Option<String>,
+    /// Punctuation ending the sentences streamed with the `sentence` granularity. `None` uses
+    /// the default boundaries
+    pub sentence_boundaries: Option<String>,
+    /// Maximum time `flex` requests wait for capacity before being rejected as overloaded
+    pub flex_timeout: Duration,
+    /// Maximum number of concurrent streams of each client, identified by its IP address, or by
+    /// its API key with `verified_api_keys`. `None` means unlimited
+    pub max_streams_per_client: Option<usize>,
+    /// The bearer tokens of the `Authorization` headers are verified in front of the router, e.g.
+    /// by a gateway, so that they identify the clients
+    pub verified_api_keys: bool,
+    /// Invisible characters stripped from the prompts and message contents
+    pub prompt_normalization: PromptNormalization,
+    /// Shape of the responses to batch requests
+    pub batch_response_format: BatchResponseFormat,
+    /// Maximum size of the `gzip` and `deflate` request bodies once decompressed. 0 rejects
+    /// compressed bodies
+    pub max_decompressed_body_size: usize,
+    /// Prompt completed at startup before the server reports as healthy, to warm the model up.
+    /// `None` reports healthy as soon as the server starts
+    pub warmup_prompt: Option<String>,
+    /// Number of tokens generated by the warmup completion
+    pub warmup_max_tokens: u32,
+    /// Name the Server-Sent Events of the streams, for the SSE clients that require named events.
+    /// OpenAI events are unnamed
+    pub sse_event_names: bool,
+    /// End every stream with a usage chunk, even when `include_usage` is not
+    /// requested. OpenAI only sends it when requested
+    pub always_stream_usage: bool,
+    /// Trailing whitespace trimmed from the generated text
+    pub trailing_whitespace: TrailingWhitespace,
+    /// Generate from prompts, or conversations, that are empty or only contain whitespace,
+    /// instead of rejecting them
+    pub allow_blank_prompts: bool,
+    /// Return the timings of the generations in the `__timings` extension field
+    pub response_timings: bool,
+    /// Maximum number of tokens whose logprobs are returned by the chat completions and the
+    /// completions, echoed prompt tokens included, 0 for no limit
+    pub max_logprobs_tokens: usize,
+    /// Token budget of the templated chat prompts, fit by evicting the oldest turns
+    pub max_conversation_tokens: Option<u32>,
+    /// Number of templated system prompts whose token count is cached, for the prompts counted
+    /// by the router. 0 disables the cache
+    pub prompt_token_cache_size: usize,
+    /// Maximum ratio of the requested `max_tokens` to the prompt tokens. `None` means unlimited
+    pub max_output_ratio: Option<u32>,
+    /// Handling of the requests exceeding `max_output_ratio`
+    pub output_ratio_policy: OutputRatioPolicy,
+    /// Handling of the empty strings of the `stop` of the requests
+    pub empty_stop: EmptyStop,
+    /// Answer the streamed requests whose `Accept` header does not allow Server-Sent Events with
+    /// a complete JSON response, rather than rejecting them
+    pub buffer_unaccepted_streams: bool,
+    /// Range of the `repetition_penalty` of the requests supported by the model sampler. `None`
+    /// means unbounded
+    pub min_repetition_penalty: Option<f32>,
+    pub max_repetition_penalty: Option<f32>,
+    /// Return the timings of the non-streamed generations in the `Server-Timing` header
+    pub server_timing_header: bool,
+    /// Serialize the `created` timestamps and the usage counts of the responses as strings
+    pub numbers_as_strings: bool,
+    /// Explain the empty generations in the `tgi_empty_note` of the choices
+    pub empty_generation_notes: bool,
+    /// Handling of the `top_p` and `typical_p` of the requests that are not strictly positive
+    pub zero_top_p: ZeroTopP,
+    /// Maximum `n` of the requests, and number of choices over all the prompts of a completions
+    /// request, as each choice is a copy of the whole request. 0 means unlimited
+    pub max_n: u32,
+    /// Maximum number of prompts of a completions request, and of conversations of a chat
+    /// `batch`. 0 means unlimited
+    pub max_prompts: usize,
+    /// Jinja chat template of the tokenizer, rendering the chat prompts instead of the
+    /// `TGICHAT_*` templates when set
+    pub chat_template: Option<Arc<ChatTemplate>>,
+    /// Templates of the chat prompts, built at startup by `build_chat_formatter`
+    pub chat_formatter: Arc<ChatFormatter>,
+    /// Hash of the chat templates in the `x-chat-template-hash` header, computed at startup
+    /// with `chat_template_header`
+    pub chat_template_hash: Option<String>,
+    /// Token counts of the templated system prompts, created at startup with
+    /// `prompt_token_cache_size` entries
+    pub prompt_token_cache: Arc<PromptTokenCache>,
+}
+
+/// Handling of the empty stop sequences, which would stop the generation before any output and
+/// usually are a client bug
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum EmptyStop {
+    /// Leave them out of the stop sequences, with a warning
+    #[default]
+    Filter,
+    /// Reject the request with a 400
+    Reject,
+}
+
+/// Handling of a `top_p` or `typical_p` that is not strictly positive, which would keep no token
+/// and usually is a client bug
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum ZeroTopP {
+    /// Reject the request with a 400
+    #[default]
+    Reject,
+    /// Raise it to `MIN_TOP_P`, which only keeps the most likely token
+    Clamp,
+}
+
+/// Lowest `top_p` and `typical_p` of the requests with `ZeroTopP::Clamp`
+pub(crate) const MIN_TOP_P: f32 = 1e-6;
+
+/// Lowest sampling temperature, below which the requests decode greedily. The logits divided by
+/// lower temperatures overflow in half precision
+const MIN_SAMPLING_TEMPERATURE: f32 = 1e-3;
+
+/// Handling of the requests whose `max_tokens` is too large for their prompt
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum OutputRatioPolicy {
+    /// Reject the request with a 400
+    #[default]
+    Reject,
+    /// Lower `max_tokens` to the limit, reported in the `x-max-tokens` response header
+    Cap,
+}
+
+/// Shape of the responses to batch requests
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum BatchResponseFormat {
+    /// A single response, with a choice per conversation
+    #[default]
+    Choices,
+    /// An `object: list` wrapping a response per conversation
+    List,
+}
+
+/// Invisible characters stripped from the prompts before tokenization, such as the ones of
+/// prompts pasted from documents
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum PromptNormalization {
+    /// Keep the prompts as sent
+    #[default]
+    None,
+    /// Strip the UTF-8 byte order marks
+    Bom,
+    /// Strip the byte order marks and the control characters, except tabs and line breaks
+    Control,
+}
+
+impl PromptNormalization {
+    /// Strip the characters from `text`. Whitespace is always kept as is
+    pub(crate) fn normalize(&self, text: &mut String) {
+        let stripped = |c: char| match self {
+            PromptNormalization::None => false,
+            PromptNormalization::Bom => c == '\u{feff}',
+            PromptNormalization::Control => {
+                c == '\u{feff}' || (c.is_control() && !matches!(c, '\t' | '\n' | '\r'))
+            }
+        };
+        if text.contains(stripped) {
+            text.retain(|c| !stripped(c));
+        }
+    }
+}
+
+/// Trailing whitespace trimmed from the generated text, that models sometimes end their
+/// output with
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum TrailingWhitespace {
+    /// Keep the output as generated
+    #[default]
+    Keep,
+    /// Trim the trailing line breaks and blank lines, but keep the spaces ending the last line
+    Lines,
+    /// Trim all the trailing whitespace
+    All,
+}
+
+impl TrailingWhitespace {
+    /// `text` without its trailing whitespace
+    pub(crate) fn trim<'a>(&self, text: &'a str) -> &'a str {
+        let trimmed = text.trim_end();
+        match self {
+            TrailingWhitespace::Keep => text,
+            TrailingWhitespace::Lines => {
+                let tail = &text[trimmed.len()..];
+                match tail.find(['\n', '\r']) {
+                    Some(line_break) => &text[..trimmed.len() + line_break],
+                    None => text,
+                }
+            }
+            TrailingWhitespace::All => trimmed,
+        }
+    }
+}
+
+/// Configuration of the chat completions route, loaded from the `--chat-config` JSON file
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChatConfig {
+    /// Sampling presets selectable with the `preset` request field.
+    /// They extend and override the built-in `creative`, `balanced` and `precise` presets
     #[serde(default)]
-    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 1)]
-    pub best_of: Option<usize>,
+    pub presets: HashMap<String, SamplingPreset>,
+    /// Handling of user messages with an empty `content`
     #[serde(default)]
-    #[schema(
-        exclusive_minimum = 0.0,
-        nullable = true,
-        default = "null",
-        example = 0.5
-    )]
-    pub temperature: Option<f32>,
+    pub empty_content: EmptyContent,
+    /// Sampling parameters recommended for the served model
     #[serde(default)]
-    #[schema(
-        exclusive_minimum = -2.0,
-        nullable = true,
-        default = "null",
-        example = 0.0
-    )]
-    pub presence_penalty: Option<f32>,
-    // #[serde(default)]
-    // #[schema(exclusive_minimum = 0, nullable = true, default = 1, example = 1)]
-    // pub n: Option<i32>,
+    pub defaults: SamplingDefaults,
+    /// Templates of the message roles other than `system`, `user`, `assistant` and `tool`, e.g.
+    /// the `ipython` role of tool-use chat formats
     #[serde(default)]
-    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 10)]
-    pub top_k: Option<i32>,
+    pub role_templates: HashMap<String, ChatFormatterPrePost>,
+    /// Template of the other roles without a template. Messages with such a role are rejected
+    /// with a 400 when unset
     #[serde(default)]
-    #[schema(
-        exclusive_minimum = 0.0,
-        maximum = 1.0,
-        nullable = true,
-        default = "null",
-        example = 0.95
-    )]
-    pub top_p: Option<f32>,
+    pub default_role_template: Option<ChatFormatterPrePost>,
+    /// Strip the assistant template, e.g. a role marker, from the start of the chat completions
+    /// of the models that generate it, in both the streamed and the complete `content`
     #[serde(default)]
-    #[schema(
-        exclusive_minimum = 0.0,
-        maximum = 1.0,
-        nullable = true,
-        default = "null",
-        example = 0.95
-    )]
-    pub typical_p: Option<f32>,
+    pub strip_assistant_prefix: bool,
+    /// Name and version of the chat templates, e.g. `llama-2-chat@2`, reported in the
+    /// `x-chat-template` header with `--chat-template-header`
     #[serde(default)]
-    #[schema(default = "false", example = true)]
-    pub do_sample: bool,
-    #[serde(default = "default_max_new_tokens")]
-    #[schema(exclusive_minimum = 0, exclusive_maximum = 512, default = "20")]
-    pub max_tokens: u32,
+    pub template_name: Option<String>,
+    /// Stop sequences added to those of every chat request, e.g. the end of turn marker of the
+    /// template, unless the request sets `disable_default_stops`
     #[serde(default)]
-    #[schema(nullable = true, default = "null", example = false)]
-    pub echo: Option<bool>,
+    pub default_stop: Vec<String>,
+    /// System message starting the conversations without one, unless the request sets
+    /// `disable_default_system`
     #[serde(default)]
-    #[schema(inline, max_items = 4, example = json ! (["photographer"]))]
-    pub stop: Vec<String>,
+    pub default_system: Option<String>,
+    /// Handling of consecutive user messages, without an assistant message between them
     #[serde(default)]
-    #[schema(nullable = true, default = "null", example = "null")]
-    pub truncate: Option<usize>,
+    pub consecutive_user_messages: ConsecutiveMessages,
+    /// Handling of consecutive assistant messages, passed through when unset
     #[serde(default)]
-    #[schema(default = "false", example = true)]
-    pub watermark: bool,
+    pub consecutive_assistant_messages: Option<ConsecutiveMessages>,
+    /// Separator joining the content of merged consecutive messages, `"\n\n"` by default
     #[serde(default)]
-    #[schema(default = "false")]
-    pub decoder_input_details: bool,
+    pub merge_separator: Option<String>,
+    /// Factor converting the OpenAI `temperature` of the requests of both routes to the
+    /// temperature expected by the model sampler, 1 by default
     #[serde(default)]
-    #[schema(
-        exclusive_minimum = 0,
-        nullable = true,
-        default = "null",
-        example = "null"
-    )]
-    pub seed: Option<u64>,
+    pub temperature_scale: Option<f32>,
+    /// Role of the generated messages, `assistant` by default. Messages of the conversation
+    /// with this role are templated as assistant messages, so that responses can be sent back
+    /// as is in the next request
     #[serde(default)]
-    #[schema(default = "false")]
-    pub stream: bool,
-}
-
-impl From<CompatCompletionRequest> for GenerateRequest {
-    fn from(req: CompatCompletionRequest) -> Self {
-        let presence_penalty = req.presence_penalty;
-        let presence_penalty = match presence_penalty {
-            Some(presence_penalty) => Some((presence_penalty + 2.0) / 2.0),
-            None => None,
-        };
-        Self {
-            inputs: req.prompt,
-            parameters: GenerateParameters {
-                best_of: req.best_of,
-                temperature: req.temperature,
-                repetition_penalty: presence_penalty,
-                top_k: req.top_k,
-                top_p: req.top_p,
-                typical_p: req.typical_p,
-                do_sample: req.do_sample,
-                max_new_tokens: req.max_tokens,
-                return_full_text: req.echo,
-                stop: req.stop,
-                truncate: req.truncate,
-                watermark: req.watermark,
-                details: true,
-                decoder_input_details: req.decoder_input_details,
-                seed: req.seed,
-            },
-        }
-    }
-}
-
-#[derive(Clone, Debug, ToSchema, Deserialize, Serialize)]
-pub(crate) enum ChatRole {
-    #[serde(rename = "user")]
-    User,
-    #[serde(rename = "assistant")]
-    Assistant,
-    #[serde(rename = "system")]
-    System,
+    pub assistant_role: Option<String>,
+    /// Template of each of the `context` documents of the completions requests, placed before
+    /// the `prompt`. Requests with a `context` are rejected when unset
+    #[serde(default)]
+    pub completion_context_template: Option<ChatFormatterPrePost>,
 }
 
-#[derive(Clone, Debug, Serialize, ToSchema)]
-pub(crate) struct ChatFormatterPrePost {
-    pre: String,
-    post: String,
+/// Sampling parameters applied to the requests of both routes that leave them unset.
+/// Presets and explicit request parameters take precedence
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SamplingDefaults {
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub top_k: Option<i32>,
+    #[serde(default)]
+    pub repetition_penalty: Option<f32>,
 }
 
-#[derive(Clone, Debug, Serialize, ToSchema)]
-pub(crate) struct ChatFormatter {
-    user_template: ChatFormatterPrePost,
-    assistant_template: ChatFormatterPrePost,
-    system_template: ChatFormatterPrePost,
+impl SamplingDefaults {
+    /// Fill the parameters the request left unset. The sampling defaults are left out when the
+    /// request explicitly disabled sampling with `"do_sample": false`
+    pub(crate) fn apply(&self, parameters: &mut GenerateParameters, do_sample: Option<bool>) {
+        if do_sample != Some(false) {
+            parameters.temperature = parameters.temperature.or(self.temperature);
+            parameters.top_p = parameters.top_p.or(self.top_p);
+            parameters.top_k = parameters.top_k.or(self.top_k);
+        }
+        parameters.repetition_penalty = parameters.repetition_penalty.or(self.repetition_penalty);
+    }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
-pub(crate) struct ChatMessage {
-    #[schema(example = "user")]
-    role: ChatRole,
-    #[schema(example = "What is the capital of Bavaria?")]
-    content: String,
-    // user: Option<String>,
+/// Handling of user messages with an empty `content`, which would only add delimiters to the prompt
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyContent {
+    /// Leave the message out of the prompt
+    #[default]
+    Skip,
+    /// Reject the request with a 400
+    Error,
+    /// Template the message like any other
+    PassThrough,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
-pub(crate) struct ChatDeltaStreamMessage {
-    #[schema(example = "user")]
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub role: Option<ChatRole>,
-    #[schema(example = "What is the capital of Bavaria?")]
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub content: Option<String>,
-    // user: Option<String>,
+/// Handling of consecutive messages with the same role, which models trained on alternating
+/// turns can misbehave on
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsecutiveMessages {
+    /// Join their contents into a single message
+    #[default]
+    Merge,
+    /// Reject the request with a 400
+    Error,
+    /// Template each message on its own
+    PassThrough,
 }
 
-#[derive(Clone, Debug, Deserialize, ToSchema)]
-pub(crate) struct CompatChatCompletionRequest {
-    pub messages: Vec<ChatMessage>,
-    #[serde(default)]
-    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 1)]
-    pub best_of: Option<usize>,
+/// Named combination of sampling parameters. Explicit request parameters take precedence
+#[derive(Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SamplingPreset {
     #[serde(default)]
-    #[schema(
-        exclusive_minimum = 0.0,
-        nullable = true,
-        default = "null",
-        example = 0.5
-    )]
     pub temperature: Option<f32>,
     #[serde(default)]
-    #[schema(
-        exclusive_minimum = -2.0,
-        nullable = true,
-        default = "null",
-        example = 0.0
-    )]
-    pub presence_penalty: Option<f32>,
-    // #[serde(default)]
-    // #[schema(exclusive_minimum = 0, nullable = true, default = 1, example = 1)]
-    // pub n: Option<u32>,
-    #[serde(default)]
-    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 10)]
     pub top_k: Option<i32>,
     #[serde(default)]
-    #[schema(
-        exclusive_minimum = 0.0,
-        maximum = 1.0,
-        nullable = true,
-        default = "null",
-        example = 0.95
-    )]
     pub top_p: Option<f32>,
     #[serde(default)]
-    #[schema(
-        exclusive_minimum = 0.0,
-        maximum = 1.0,
-        nullable = true,
-        default = "null",
-        example = 0.95
-    )]
     pub typical_p: Option<f32>,
     #[serde(default)]
-    #[schema(default = "false", example = true)]
-    pub do_sample: bool,
-    #[serde(default = "default_max_new_tokens")]
-    #[schema(exclusive_minimum = 0, exclusive_maximum = 512, default = "20")]
-    pub max_tokens: u32,
-    #[serde(default)]
-    #[schema(nullable = true, default = "null", example = false)]
-    pub echo: Option<bool>,
-    #[serde(default)]
-    #[schema(inline, max_items = 4, example = json ! (["photographer"]))]
-    pub stop: Vec<String>,
-    #[serde(default)]
-    #[schema(nullable = true, default = "null", example = "null")]
-    pub truncate: Option<usize>,
-    #[serde(default)]
-    #[schema(default = "false", example = true)]
-    pub watermark: bool,
-    #[serde(default)]
-    #[schema(default = "false")]
-    pub decoder_input_details: bool,
-    #[serde(default)]
-    #[schema(
-        exclusive_minimum = 0,
-        nullable = true,
-        default = "null",
-        example = "null"
-    )]
-    pub seed: Option<u64>,
-    #[serde(default)]
-    #[schema(default = "false")]
-    pub stream: bool,
-    // #[serde(default)]
-    // #[schema(nullable = true, default = "null", example = "null")]
-    // pub user: Option<String>,
+    pub presence_penalty: Option<f32>,
 }
 
-pub(crate) fn chat_to_generate_request(
-    req: CompatChatCompletionRequest,
-    formatter: ChatFormatter,
-) -> GenerateRequest {
-    let mut prompt = String::from("");
-    for m in req.messages {
-        // let role = m.role
-        let template = match m.role {
-            ChatRole::Assistant => &formatter.assistant_template,
-            ChatRole::System => &formatter.system_template,
-            ChatRole::User => &formatter.user_template,
+impl ChatConfig {
+    fn preset(&self, name: &str) -> Option<SamplingPreset> {
+        self.presets
+            .get(name)
+            .cloned()
+            .or_else(|| builtin_preset(name))
+    }
+
+    /// Handling of consecutive messages with `role`. System messages are always kept apart
+    fn consecutive_messages(&self, role: &ChatRole) -> ConsecutiveMessages {
+        match role {
+            ChatRole::User => self.consecutive_user_messages,
+            ChatRole::Assistant => self
+                .consecutive_assistant_messages
+                .unwrap_or(ConsecutiveMessages::PassThrough),
+            _ => ConsecutiveMessages::PassThrough,
+        }
+    }
+
+    /// Convert the OpenAI `temperature`, from 0 to 2, to the parameters of the backend sampler,
+    /// which divides the logits by the temperature like OpenAI does. Unlike OpenAI, the backend
+    /// rejects a temperature of 0 rather than decoding greedily, and does not sample at a
+    /// temperature of 1 without `do_sample`. Temperatures scaled below `MIN_SAMPLING_TEMPERATURE`
+    /// decode greedily too, and negative temperatures are left to the validation.
+    /// `do_sample` is the one of the request: sampling is only forced when it was left unset, and
+    /// requests explicitly disabling it decode greedily whatever their temperature
+    pub(crate) fn convert_temperature(
+        &self,
+        parameters: &mut GenerateParameters,
+        do_sample: Option<bool>,
+    ) {
+        let temperature = match parameters.temperature {
+            Some(temperature) if temperature >= 0.0 => {
+                temperature * self.temperature_scale.unwrap_or(1.0)
+            }
+            _ => return,
         };
-        prompt.push_str(&template.pre);
-        prompt.push_str(&m.content);
-        prompt.push_str(&template.post);
+        if temperature < MIN_SAMPLING_TEMPERATURE || do_sample == Some(false) {
+            parameters.temperature = None;
+            parameters.top_k = None;
+            parameters.top_p = None;
+            parameters.typical_p = None;
+            parameters.do_sample = false;
+        } else {
+            parameters.temperature = Some(temperature);
+            parameters.do_sample = true;
+        }
     }
-    let presence_penalty = req.presence_penalty;
-    let presence_penalty = match presence_penalty {
-        Some(presence_penalty) => Some((presence_penalty + 2.0) / 2.0),
-        None => None,
-    };
 
-    GenerateRequest {
-        inputs: prompt,
-        parameters: GenerateParameters {
-            best_of: req.best_of,
-            temperature: req.temperature,
-            repetition_penalty: presence_penalty,
-            top_k: req.top_k,
-            top_p: req.top_p,
-            typical_p: req.typical_p,
-            do_sample: req.do_sample,
-            max_new_tokens: req.max_tokens,
-            return_full_text: req.echo,
-            stop: req.stop,
-            truncate: req.truncate,
-            watermark: req.watermark,
-            details: true,
-            decoder_input_details: req.decoder_input_details,
-            seed: req.seed,
-        },
+    /// Role of the generated messages
+    pub(crate) fn assistant_role(&self) -> ChatRole {
+        match &self.assistant_role {
+            Some(role) => ChatRole::from(role.clone()),
+            None => ChatRole::Assistant,
+        }
     }
-}
 
-#[derive(Serialize, ToSchema)]
-pub(crate) struct Usage {
-    #[schema(example = 1)]
-    pub total_tokens: u32,
-    #[schema(example = 1)]
-    pub completion_tokens: u32,
-    #[schema(example = 1)]
-    pub prompt_tokens: u32,
-}
+    /// Template the messages with the configured assistant role as assistant messages
+    pub(crate) fn normalize_assistant_role(&self, messages: &mut [ChatMessage]) {
+        let role = self.assistant_role();
+        if role == ChatRole::Assistant {
+            return;
+        }
+        for message in messages.iter_mut().filter(|m| m.role == role) {
+            message.role = ChatRole::Assistant;
+        }
+    }
 
-#[derive(Serialize, ToSchema)]
-pub(crate) struct CompletionChoices {
-    #[schema(example = "test")]
-    pub text: String,
-    #[schema(example = "length")]
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub finish_reason: Option<FinishReason>,
-    // pub generated_tokens: u32,
-    // logprobs is not implemented, send None
-    pub logprobs: Option<Vec<u32>>,
-    #[schema(example = 0)]
-    pub index: u32,
-}
+    /// Place the templated `context` documents of a completions request before its `prompt`, so
+    /// that they are counted, echoed and truncated like the rest of the prompt
+    pub(crate) fn add_completion_context(
+        &self,
+        req: &mut CompatCompletionRequest,
+    ) -> Result<(), ValidationError> {
+        let context = match req.context.take() {
+            Some(context) => context,
+            None => return Ok(()),
+        };
+        let template = self
+            .completion_context_template
+            .as_ref()
+            .ok_or(ValidationError::CompletionContext)?;
+        if context.is_empty() {
+            return Ok(());
+        }
+        let context: String = context
+            .iter()
+            .map(|document| format!("{}{document}{}", template.pre, template.post))
+            .collect();
+        match &mut req.prompt {
+            Some(prompts) => {
+                for prompt in prompts {
+                    prompt.insert_str(0, &context);
+                }
+            }
+            None => req.prompt = Some(vec![context]),
+        }
+        Ok(())
+    }
 
-#[derive(Serialize, ToSchema)]
-pub(crate) struct CompletionsResponse {
-    #[schema(example = "cmpl-abcdefgehij1234")]
-    pub id: String,
-    #[schema(example = "text_completion")]
-    pub object: String,
-    #[schema(example = 1589478379)]
-    pub created: u64,
-    #[schema(example = "tgi")]
-    pub model: String,
-    pub choices: Vec<CompletionChoices>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub usage: Option<Usage>,
+    /// Start the conversation with the default system message, unless it has a system message
+    pub(crate) fn add_default_system(&self, messages: &mut Vec<ChatMessage>) {
+        let default_system = match &self.default_system {
+            Some(default_system) => default_system,
+            None => return,
+        };
+        if messages.iter().any(|m| m.role == ChatRole::System) {
+            return;
+        }
+        messages.insert(
+            0,
+            ChatMessage {
+                role: ChatRole::System,
+                content: ChatMessageContent::Text(default_system.clone()),
+            },
+        );
+    }
 }
 
-#[derive(Serialize, ToSchema)]
-pub(crate) struct ChatCompletionChoices {
-    #[schema(example = "test")]
-    pub message: ChatMessage,
-    #[schema(example = "length")]
-    pub finish_reason: Option<FinishReason>,
-    // pub generated_tokens: u32,
-    #[schema(example = 0)]
-    pub index: u32,
+fn builtin_preset(name: &str) -> Option<SamplingPreset> {
+    let preset = match name {
+        "creative" => SamplingPreset {
+            temperature: Some(1.0),
+            top_p: Some(0.95),
+            presence_penalty: Some(0.2),
+            ..Default::default()
+        },
+        "balanced" => SamplingPreset {
+            temperature: Some(0.7),
+            top_p: Some(0.9),
+            ..Default::default()
+        },
+        "precise" => SamplingPreset {
+            temperature: Some(0.2),
+            top_p: Some(0.5),
+            ..Default::default()
+        },
+        _ => return None,
+    };
+    Some(preset)
 }
 
-#[derive(Serialize, ToSchema)]
-pub(crate) struct ChatCompletionDeltaStreamChoices {
-    #[schema(example = "test")]
-    pub delta: ChatDeltaStreamMessage,
-    #[schema(example = "length")]
-    pub finish_reason: Option<FinishReason>,
-    // pub generated_tokens: u32,
-    #[schema(example = 0)]
-    pub index: u32,
-}
+impl CompatConfig {
+    /// Server policy responsible for a generation that finished with `finish_reason`, if reported
+    pub(crate) fn server_finish_reason(
+        &self,
+        finish_reason: &FinishReason,
+        options: &CompatRequestOptions,
+    ) -> Option<ServerFinishReason> {
+        if !self.server_finish_reasons {
+            return None;
+        }
+        match finish_reason {
+            FinishReason::Length if options.default_max_tokens || options.max_tokens_capped => {
+                Some(ServerFinishReason::ServerLengthLimit)
+            }
+            _ => None,
+        }
+    }
 
-#[derive(Serialize, ToSchema)]
-pub(crate) struct ChatCompletionsResponse {
-    #[schema(example = "chatcmpl-abcdefgehij1234")]
-    pub id: String,
-    #[schema(example = "chat.completion")]
-    pub object: String,
-    #[schema(example = 1589478380)]
-    pub created: u64,
-    #[schema(example = "tgi")]
-    pub model: String,
-    pub choices: Vec<ChatCompletionChoices>,
-    pub usage: Usage,
+    /// Number of choices of a request, 1 by default. Several choices cannot be streamed
+    pub(crate) fn validate_n(&self, n: Option<u32>, stream: bool) -> Result<u32, ValidationError> {
+        match n.unwrap_or(1) {
+            0 => Err(ValidationError::ZeroN),
+            1 => Ok(1),
+            n if self.max_n != 0 && n > self.max_n => Err(ValidationError::MaxN(self.max_n, n)),
+            _ if stream => Err(ValidationError::NStream),
+            n => Ok(n),
+        }
+    }
+
+    /// Reject the completions requests with more than `max_prompts` prompts, or more than `max_n`
+    /// choices over all their prompts, as each prompt is a copy of the whole request, generated
+    /// `n` times
+    pub(crate) fn validate_prompts(
+        &self,
+        prompts: usize,
+        n: Option<u32>,
+    ) -> Result<(), ValidationError> {
+        if self.max_prompts != 0 && prompts > self.max_prompts {
+            return Err(ValidationError::MaxPrompts(self.max_prompts, prompts));
+        }
+        let n = n.unwrap_or(1);
+        if self.max_n != 0 && (prompts as u64).saturating_mul(n as u64) > self.max_n as u64 {
+            return Err(ValidationError::PromptChoices(self.max_n, prompts, n));
+        }
+        Ok(())
+    }
+
+    /// Generate request of a completions request: prompt normalization, `context`, `defaults`
+    /// and the validation of its parameters. The prompt tokens are left to the caller
+    pub(crate) fn completion_request(
+        &self,
+        mut req: CompatCompletionRequest,
+    ) -> Result<GenerateRequest, ValidationError> {
+        req.validate_logprobs()?;
+        for prompt in req.prompt.iter_mut().flatten() {
+            self.prompt_normalization.normalize(prompt);
+        }
+        self.chat_config.add_completion_context(&mut req)?;
+        let do_sample = req.do_sample;
+        let mut req: GenerateRequest = req.into();
+        self.chat_config
+            .defaults
+            .apply(&mut req.parameters, do_sample);
+        self.chat_config
+            .convert_temperature(&mut req.parameters, do_sample);
+        self.validate_stop(&mut req.parameters)?;
+        self.validate_repetition_penalty(&req.parameters)?;
+        self.validate_top_p(&mut req.parameters)?;
+        Ok(req)
+    }
+
+    /// Reject the chat `batch` of more than `max_prompts` conversations
+    pub(crate) fn validate_batch(&self, conversations: usize) -> Result<(), ValidationError> {
+        if self.max_prompts != 0 && conversations > self.max_prompts {
+            return Err(ValidationError::MaxBatch(self.max_prompts, conversations));
+        }
+        Ok(())
+    }
+
+    /// Filter or reject the empty stop sequences of the request
+    pub(crate) fn validate_stop(
+        &self,
+        parameters: &mut GenerateParameters,
+    ) -> Result<(), ValidationError> {
+        let empty = match parameters.stop.iter().position(String::is_empty) {
+            Some(empty) => empty,
+            None => return Ok(()),
+        };
+        match self.empty_stop {
+            EmptyStop::Filter => {
+                tracing::warn!("Empty stop sequences left out of the `stop` of the request");
+                parameters.stop.retain(|stop| !stop.is_empty());
+                Ok(())
+            }
+            EmptyStop::Reject => Err(ValidationError::EmptyStop(empty)),
+        }
+    }
+
+    /// Note explaining a choice with an empty generated `text`, with `empty_generation_notes`, so
+    /// that clients can tell a model that immediately stopped from a bug
+    fn empty_generation_note(
+        &self,
+        text: &str,
+        finish_reason: Option<&FinishReason>,
+    ) -> Option<String> {
+        if !self.empty_generation_notes || !text.is_empty() {
+            return None;
+        }
+        let note = match finish_reason? {
+            FinishReason::EndOfSequenceToken => {
+                "the model generated its end of sequence token before any text"
+            }
+            FinishReason::StopSequence => "the generation reached a stop sequence before any text",
+            FinishReason::Length => {
+                "`max_tokens` was reached before any text, the generated tokens were only special tokens or whitespace"
+            }
+        };
+        Some(note.to_string())
+    }
+
+    /// Reject or clamp a `top_p` or `typical_p` that is not strictly positive. The sampler
+    /// validation rejects them with a 422 rather than a 400
+    pub(crate) fn validate_top_p(
+        &self,
+        parameters: &mut GenerateParameters,
+    ) -> Result<(), ValidationError> {
+        let zero_top_p = self.zero_top_p;
+        let clamp = |p: &mut Option<f32>, err: ValidationError| match p {
+            Some(value) if *value <= 0.0 || value.is_nan() => match zero_top_p {
+                ZeroTopP::Reject => Err(err),
+                ZeroTopP::Clamp => {
+                    *value = MIN_TOP_P;
+                    Ok(())
+                }
+            },
+            _ => Ok(()),
+        };
+        clamp(&mut parameters.top_p, ValidationError::TopP)?;
+        clamp(&mut parameters.typical_p, ValidationError::TypicalP)
+    }
+
+    /// Reject a `repetition_penalty` the sampler cannot handle: the penalty must be strictly
+    /// positive and within the configured range
+    pub(crate) fn validate_repetition_penalty(
+        &self,
+        parameters: &GenerateParameters,
+    ) -> Result<(), ValidationError> {
+        let repetition_penalty = match parameters.repetition_penalty {
+            Some(repetition_penalty) => repetition_penalty,
+            None => return Ok(()),
+        };
+        if repetition_penalty <= 0.0 || repetition_penalty.is_nan() {
+            return Err(ValidationError::RepetitionPenalty);
+        }
+        match (self.min_repetition_penalty, self.max_repetition_penalty) {
+            (Some(min), _) if repetition_penalty < min => Err(
+                ValidationError::RepetitionPenaltyMin(min, repetition_penalty),
+            ),
+            (_, Some(max)) if repetition_penalty > max => Err(
+                ValidationError::RepetitionPenaltyMax(max, repetition_penalty),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Guard against a `max_tokens` absurdly large for the prompt, e.g. 4096 tokens for a one
+    /// token prompt, which usually is a client bug: the requested tokens must be at most
+    /// `max_output_ratio` times the prompt tokens. Requests left to the default `max_tokens`, and
+    /// prompts that were not counted, are not guarded
+    pub(crate) fn guard_output_ratio(
+        &self,
+        parameters: &mut GenerateParameters,
+        options: &mut CompatRequestOptions,
+    ) -> Result<(), ValidationError> {
+        let (max_output_ratio, prompt_tokens) = match (self.max_output_ratio, options.prompt_tokens)
+        {
+            (Some(max_output_ratio), Some(prompt_tokens)) if !options.default_max_tokens => {
+                (max_output_ratio, prompt_tokens)
+            }
+            _ => return Ok(()),
+        };
+        let limit = max_output_ratio.saturating_mul(prompt_tokens.max(1));
+        if parameters.max_new_tokens <= limit {
+            return Ok(());
+        }
+        match self.output_ratio_policy {
+            OutputRatioPolicy::Reject => Err(ValidationError::OutputRatio(
+                limit,
+                prompt_tokens,
+                parameters.max_new_tokens,
+            )),
+            OutputRatioPolicy::Cap => {
+                parameters.max_new_tokens = limit;
+                options.max_tokens_capped = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether a stream ends with a usage chunk
+    pub(crate) fn stream_usage(&self, options: &CompatRequestOptions) -> bool {
+        options.include_usage || self.always_stream_usage
+    }
+
+    /// Assistant template stripped from the start of the chat completions, if enabled
+    pub(crate) fn assistant_prefix(&self) -> Option<String> {
+        if !self.chat_config.strip_assistant_prefix {
+            return None;
+        }
+        self.chat_formatter.assistant_prefix().map(str::to_string)
+    }
+
+    /// Templates of the chat prompts: the chat template of the tokenizer if any, otherwise the
+    /// `TGICHAT_*` templates
+    pub(crate) fn build_chat_formatter(&self) -> ChatFormatter {
+        ChatFormatter {
+            chat_template: self.chat_template.clone(),
+            ..get_chatformatter(&self.chat_config)
+        }
+    }
+
+    /// `[DONE]` sentinel ending the OpenAI compatible streams after their last chunk, which
+    /// the OpenAI clients wait for. Not sent by the native `/generate_stream`
+    pub(crate) fn done_event(&self) -> Event {
+        self.sse_event(Event::default().data("[DONE]"), DONE_EVENT)
+    }
+
+    /// `event` named `name` when `sse_event_names` is set
+    pub(crate) fn sse_event(&self, event: Event, name: &str) -> Event {
+        match self.sse_event_names {
+            true => event.event(name),
+            false => event,
+        }
+    }
+
+    /// Model name reported in the response to a request naming `model`, `None` if unknown.
+    /// The requested model is echoed back, and only checked when aliases are configured.
+    /// Requests without a model get the served `model_id`
+    pub(crate) fn served_model(&self, model_id: &str, model: Option<&str>) -> Option<String> {
+        match model {
+            Some(model) if model != model_id && !self.model_aliases.iter().any(|a| a == model) => {
+                self.model_aliases.is_empty().then(|| model.to_string())
+            }
+            Some(model) => Some(model.to_string()),
+            None => Some(model_id.to_string()),
+        }
+    }
+
+    /// `None` unless at least one token price is configured
+    fn estimated_cost(&self, prompt_tokens: u32, completion_tokens: u32) -> Option<f64> {
+        if self.prompt_token_price.is_none() && self.completion_token_price.is_none() {
+            return None;
+        }
+        let prompt_cost = self.prompt_token_price.unwrap_or(0.0) * prompt_tokens as f64;
+        let completion_cost = self.completion_token_price.unwrap_or(0.0) * completion_tokens as f64;
+        Some((prompt_cost + completion_cost) / 1000.0)
+    }
+
+    /// `created` timestamp or usage count of a response, serialized as configured
+    pub(crate) fn number<N>(&self, value: N) -> CompatNumber<N> {
+        CompatNumber {
+            value,
+            as_string: self.numbers_as_strings,
+        }
+    }
 }
 
-#[derive(Serialize, ToSchema)]
-pub(crate) struct ChatCompletionsStreamResponse {
-    #[schema(example = "chatcmpl-abcdefgehij1234")]
-    pub id: String,
-    #[schema(example = "chat.completion.chunk")]
-    pub object: String,
-    #[schema(example = 1589478380)]
-    pub created: u64,
-    #[schema(example = "tgi")]
-    pub model: String,
-    pub choices: Vec<ChatCompletionDeltaStreamChoices>,
+/// JSON body of the OpenAI compatible routes, honoring the `CompatConfig` parsing options
+pub(crate) struct CompatJson<T>(pub T);
+
+impl<T> Deref for CompatJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
 }
 
-pub(crate) fn get_chatformatter() -> ChatFormatter {
-    // TODO: improve reading this, e.g. at startup once from a chat_config.json
-    let chat_user_pre: String = match std::env::var_os("TGICHAT_USER_PRE") {
-        Some(v) => v.into_string().unwrap(),
-        None => String::from(""),
-    };
-    let chat_user_post: String = match std::env::var_os("TGICHAT_USER_POST") {
-        Some(v) => v.into_string().unwrap(),
-        None => String::from(""),
-    };
-    let chat_ass_pre: String = match std::env::var_os("TGICHAT_ASS_PRE") {
-        Some(v) => v.into_string().unwrap(),
-        None => String::from(""),
-    };
-    let chat_ass_post: String = match std::env::var_os("TGICHAT_ASS_POST") {
-        Some(v) => v.into_string().unwrap(),
-        None => String::from(""),
-    };
-    let chat_sys_pre: String = match std::env::var_os("TGICHAT_SYS_PRE") {
-        Some(v) => v.into_string().unwrap(),
-        None => String::from(""),
-    };
-    let chat_sys_post: String = match std::env::var_os("TGICHAT_SYS_POST") {
-        Some(v) => v.into_string().unwrap(),
-        None => String::from(""),
-    };
+#[async_trait]
+impl<T, S> FromRequest<S, Body> for CompatJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
 
-    ChatFormatter {
-        user_template: ChatFormatterPrePost {
-            pre: chat_user_pre,
-            post: chat_user_post,
-        },
-        assistant_template: ChatFormatterPrePost {
-            pre: chat_ass_pre,
-            post: chat_ass_post,
-        },
-        system_template: ChatFormatterPrePost {
-            pre: chat_sys_pre,
-            post: chat_sys_post,
-        },
+    async fn from_request(req: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        let (strict_json, max_decompressed_body_size) = req
+            .extensions()
+            .get::<CompatConfig>()
+            .map_or((false, 0), |compat_config| {
+                (
+                    compat_config.strict_json,
+                    compat_config.max_decompressed_body_size,
+                )
+            });
+        let content_encoding = req
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .map(|encoding| {
+                encoding
+                    .to_str()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_ascii_lowercase()
+            })
+            .filter(|encoding| encoding != "identity");
+
+        let req = if strict_json || content_encoding.is_some() {
+            let (mut parts, body) = req.into_parts();
+            let mut bytes = Bytes::from_request(Request::new(body), state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            if let Some(encoding) = content_encoding {
+                bytes = decompress_body(&encoding, &bytes, max_decompressed_body_size)
+                    .map_err(|err| {
+                        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+                        tracing::error!("{}", err.1.error);
+                        CompatError::from(err).into_response()
+                    })?
+                    .into();
+                parts.headers.remove(header::CONTENT_ENCODING);
+                parts.headers.remove(header::CONTENT_LENGTH);
+            }
+            if strict_json {
+                if let Some(key) = duplicate_top_level_key(&bytes) {
+                    metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+                    let err = ErrorResponse {
+                        error: format!("duplicate key `{key}` in request body"),
+                        error_type: "validation".to_string(),
+                        param: Some(key),
+                    };
+                    return Err(
+                        CompatError::from((StatusCode::BAD_REQUEST, Json(err))).into_response()
+                    );
+                }
+            }
+            Request::from_parts(parts, Body::from(bytes))
+        } else {
+            req
+        };
+
+        let Json(value) = Json::<T>::from_request(req, state)
+            .await
+            .map_err(|rejection| {
+                metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+                let err = ErrorResponse {
+                    error: rejection.body_text(),
+                    error_type: "validation".to_string(),
+                    param: None,
+                };
+                tracing::error!("{}", err.error);
+                // Parameters of the wrong type are invalid, as with OpenAI
+                let status_code = match rejection {
+                    JsonRejection::JsonDataError(_) => StatusCode::BAD_REQUEST,
+                    rejection => rejection.status(),
+                };
+                CompatError::from((status_code, Json(err))).into_response()
+            })?;
+        Ok(CompatJson(value))
     }
 }
 
-pub(crate) async fn generate_to_completions(
-    resp: Json<GenerateResponse>,
-    info: Extension<Info>,
-) -> Json<CompletionsResponse> {
-    // let details = resp.details.as_ref().ok_or("details missing"); //;
-    let details = resp.details.as_ref();
+/// Error of the OpenAI compatible routes, with the body of the OpenAI errors that the OpenAI
+/// clients parse, rather than the TGI one
+pub(crate) struct CompatError(pub StatusCode, pub Json<CompatErrorResponse>);
 
-    let gen_tokens = match details {
-        Some(details) => details.generated_tokens,
-        None => 0,
-    };
-    let finish_reason = match details {
-        Some(details) => Some(details.finish_reason.clone()),
-        None => None,
-    };
-    let prefill_len = match details {
-        Some(details) => details.prefill.len() as u32,
-        None => 0,
-    };
+#[derive(Serialize, ToSchema)]
+pub(crate) struct CompatErrorResponse {
+    pub error: CompatErrorDetails,
+}
 
-    let choices = CompletionChoices {
-        text: resp.generated_text.clone(),
-        finish_reason: finish_reason,
-        logprobs: None,
-        index: 0,
-    };
-    let usage = Some(Usage {
-        completion_tokens: gen_tokens,
-        total_tokens: gen_tokens + prefill_len,
-        prompt_tokens: prefill_len,
-    });
-    let created_time = create_timestamp();
-    let model = info.0.model_id;
-    let resp: CompletionsResponse = CompletionsResponse {
-        choices: vec![choices],
-        created: created_time,
-        id: String::from(format!("cmpl-{}", created_time)),
-        object: String::from("text_completion"),
-        model,
-        usage,
-    };
-    Json(resp.into())
+#[derive(Serialize, ToSchema)]
+pub(crate) struct CompatErrorDetails {
+    #[schema(example = "`temperature` must be strictly positive")]
+    pub message: String,
+    /// `invalid_request_error` for the errors of the client, `server_error` for the failed
+    /// generations and the overloaded server
+    #[serde(rename = "type")]
+    #[schema(example = "invalid_request_error")]
+    pub error_type: String,
+    /// Request parameter responsible for the error
+    #[schema(nullable = true, example = "temperature")]
+    pub param: Option<String>,
+    /// TGI type of the error
+    #[schema(nullable = true, example = "validation")]
+    pub code: Option<String>,
 }
 
-pub(crate) async fn generate_to_chatcompletions(
-    resp: Json<GenerateResponse>,
-    info: Extension<Info>,
-) -> Json<ChatCompletionsResponse> {
-    // let details = resp.details.as_ref().ok_or("details missing"); //;
-    let details = resp.details.as_ref();
+impl CompatErrorResponse {
+    pub(crate) fn new(status_code: StatusCode, err: ErrorResponse) -> Self {
+        let error_type = match status_code {
+            StatusCode::FAILED_DEPENDENCY | StatusCode::TOO_MANY_REQUESTS => "server_error",
+            status_code if status_code.is_client_error() => "invalid_request_error",
+            _ => "server_error",
+        };
+        Self {
+            error: CompatErrorDetails {
+                message: err.error,
+                error_type: error_type.to_string(),
+                param: err.param,
+                code: Some(err.error_type),
+            },
+        }
+    }
+}
 
-    let gen_tokens = match details {
-        Some(details) => details.generated_tokens,
-        None => 0,
-    };
-    let finish_reason = match details {
-        Some(details) => Some(details.finish_reason.clone()),
-        None => None,
-    };
-    let prefill_len = match details {
-        Some(details) => details.prefill.len() as u32,
-        None => 0,
-    };
+impl From<(StatusCode, Json<ErrorResponse>)> for CompatError {
+    fn from((status_code, Json(err)): (StatusCode, Json<ErrorResponse>)) -> Self {
+        Self(
+            status_code,
+            Json(CompatErrorResponse::new(status_code, err)),
+        )
+    }
+}
 
-    let choices = ChatCompletionChoices {
-        message: ChatMessage {
-            role: ChatRole::Assistant,
-            content: resp.generated_text.clone(),
-        },
-        finish_reason: finish_reason,
-        index: 0,
-    };
-    let usage = Usage {
-        completion_tokens: gen_tokens,
-        total_tokens: gen_tokens + prefill_len,
-        prompt_tokens: prefill_len,
+impl IntoResponse for CompatError {
+    fn into_response(self) -> Response {
+        (self.0, self.1).into_response()
+    }
+}
+
+/// Decompress a request body sent with the `gzip` or `deflate` content `encoding`.
+/// Bodies decompressing to more than `limit` bytes are rejected, so that a small compressed body
+/// cannot exhaust the memory of the router. A `limit` of 0 rejects compressed bodies
+fn decompress_body(
+    encoding: &str,
+    body: &[u8],
+    limit: usize,
+) -> Result<Vec<u8>, (StatusCode, Json<ErrorResponse>)> {
+    let error = |status, error: String| {
+        let err = ErrorResponse {
+            error,
+            error_type: "validation".to_string(),
+            param: None,
+        };
+        (status, Json(err))
     };
-    let created_time = create_timestamp();
-    let model = info.0.model_id;
-    let resp = ChatCompletionsResponse {
-        choices: vec![choices],
-        created: created_time,
-        id: String::from(format!("chatcmpl-{}", created_time)),
-        object: String::from("chat.completion"),
-        model,
-        usage,
+    let decoder: Box<dyn Read + '_> = match encoding {
+        "gzip" | "x-gzip" if limit > 0 => Box::new(GzDecoder::new(body)),
+        "deflate" if limit > 0 => Box::new(ZlibDecoder::new(body)),
+        _ => {
+            return Err(error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("unsupported content encoding `{encoding}`"),
+            ))
+        }
     };
-    Json(resp.into())
+    let mut decompressed = Vec::new();
+    decoder
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|err| {
+            error(
+                StatusCode::BAD_REQUEST,
+                format!("invalid {encoding} request body: {err}"),
+            )
+        })?;
+    if decompressed.len() > limit {
+        return Err(error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("decompressed request body is larger than {limit} bytes"),
+        ));
+    }
+    Ok(decompressed)
 }
 
-pub (crate) fn create_timestamp() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("time went backwards")
-        .as_secs() as u64
+/// Deserialize the `prompt` of a completions request, sent as a string, `null`, or a non-empty
+/// array of strings, each prompt generated independently. Token id prompts are rejected
+fn deserialize_prompt<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Prompt {
+        Text(String),
+        Texts(Vec<String>),
+        TokenIds(Vec<u32>),
+        TokenIdsBatch(Vec<Vec<u32>>),
+    }
+
+    match Option::<Prompt>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Prompt::Text(prompt)) => Ok(Some(vec![prompt])),
+        Some(Prompt::Texts(prompts)) if prompts.is_empty() => Err(de::Error::custom(
+            "`prompt` must contain at least one prompt",
+        )),
+        Some(Prompt::Texts(prompts)) => Ok(Some(prompts)),
+        Some(Prompt::TokenIds(_)) | Some(Prompt::TokenIdsBatch(_)) => Err(de::Error::custom(
+            "`prompt` must be text, token id prompts are not supported",
+        )),
+    }
 }
 
-pub(crate) fn chat_start_message(
-    created_time: u64,
-    model_name: &String,
-) -> ChatCompletionsStreamResponse {
-    let choices: ChatCompletionDeltaStreamChoices = ChatCompletionDeltaStreamChoices {
-        delta: ChatDeltaStreamMessage {
-            content: None,
-            role: Some(ChatRole::Assistant),
-        },
-        finish_reason: None,
-        index: 0,
-    };
-    ChatCompletionsStreamResponse {
-        choices: vec![choices],
-        created: created_time,
-        id: String::from(format!("chatcmpl-{}", created_time)),
-        object: String::from("chat.completion.chunk"),
-        model: model_name.to_owned(),
+/// Deserialize the `stop` of an OpenAI compatible request, sent as a single string, an array of
+/// strings, or `null` for no stop sequence
+fn deserialize_stop<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stop {
+        Text(String),
+        Texts(Vec<String>),
+    }
+
+    match Option::<Stop>::deserialize(deserializer)? {
+        None => Ok(vec![]),
+        Some(Stop::Text(stop)) => Ok(vec![stop]),
+        Some(Stop::Texts(stop)) => Ok(stop),
     }
 }
 
-pub(crate) fn create_streaming_event(
-    // st: StreamResponse,
-    stream_type: &OpenaiStreamType,
-    created_time: u64,
-    details: Option<StreamDetails>,
-    token: Token,
-    model_name: &String,
-) -> Event {
-    match stream_type {
-        &OpenaiStreamType::ChatCompletionsStreamResponse => {
-            let choices: ChatCompletionDeltaStreamChoices = ChatCompletionDeltaStreamChoices {
-                delta: ChatDeltaStreamMessage {
-                    content: Some(token.text),
-                    role: None,
-                },
-                finish_reason: match details {
-                    Some(i) => Some(i.finish_reason),
-                    None => None,
-                },
-                index: 0,
-            };
-            let response = ChatCompletionsStreamResponse {
-                choices: vec![choices],
-                created: created_time,
-                id: String::from(format!("chatcmpl-{}", created_time)),
-                object: String::from("chat.completion.chunk"),
-                model: model_name.to_owned(),
-            };
-            Event::default().json_data(response).expect("cannot parse ChatCompletionsStreamResponse")
+/// First top-level key appearing more than once in a JSON object body.
+/// Invalid JSON returns `None` and is reported by the regular JSON parsing
+fn duplicate_top_level_key(body: &[u8]) -> Option<String> {
+    struct DuplicateKeyVisitor;
+
+    impl<'de> Visitor<'de> for DuplicateKeyVisitor {
+        type Value = Option<String>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a JSON object")
         }
-        &OpenaiStreamType::CompletionsResponse => {
-            let choices = CompletionChoices {
-                text: token.text,
-                finish_reason: match details {
-                    Some(i) => Some(i.finish_reason),
-                    None => None,
-                },
-                logprobs: None,
-                index: 0,
-            };
 
-            let response = CompletionsResponse {
-                choices: vec![choices],
-                created: created_time,
-                id: String::from(format!("cmpl-{}", created_time)),
-                object: String::from("text_completion"),
-                model: model_name.to_owned(),
-                usage: None,
-            };
-            Event::default().json_data(response).expect("cannot parse streamed CompletionsResponse")
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut keys = HashSet::new();
+            let mut duplicate = None;
+            while let Some(key) = map.next_key::<String>()? {
+                map.next_value::<IgnoredAny>()?;
+                if !keys.insert(key.clone()) && duplicate.is_none() {
+                    duplicate = Some(key);
+                }
+            }
+            Ok(duplicate)
         }
     }
+
+    serde_json::Deserializer::from_slice(body)
+        .deserialize_map(DuplicateKeyVisitor)
+        .ok()
+        .flatten()
+}
+
+/// Options of an OpenAI compatible request that are not generation parameters
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct CompatRequestOptions {
+    /// Keep the stop sequence at the end of the output
+    pub include_stop_str_in_output: bool,
+    /// `max_tokens` was not set and the server default applies
+    pub default_max_tokens: bool,
+    /// `max_tokens` was lowered to `max_output_ratio` times the prompt tokens
+    pub max_tokens_capped: bool,
+    /// End the stream with a chunk carrying the usage
+    pub include_usage: bool,
+    /// Amount of text sent with each streamed chunk
+    pub stream_granularity: StreamGranularity,
+    /// Prompt length counted by the router, used when the prefill is not returned
+    pub prompt_tokens: Option<u32>,
+    /// Return the logprobs of the generated tokens
+    pub logprobs: bool,
+    /// Number of alternatives listed with each chat logprob
+    pub top_logprobs: u32,
+    /// The completion starts with the prompt
+    pub echo: bool,
+    /// Requested format of the chat completions
+    pub response_format: ResponseFormat,
+    /// Oldest turns of the conversation evicted to fit `max_conversation_tokens`
+    pub evicted_turns: u32,
+    /// Return the token-level details of why each choice stopped
+    pub finish_details: bool,
+    /// Return the stop sequences applied to the generation
+    pub return_stop_sequences: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct StreamOptions {
+    /// End the stream with a chunk carrying the usage, with empty `choices`, before `[DONE]`
+    #[serde(default)]
+    #[schema(default = "false", example = true)]
+    pub include_usage: bool,
+}
+
+/// TGI parameters sent in a nested `extra_body` object, by the clients that do not merge their
+/// `extra_body` into the request body like the OpenAI SDKs do. Flags set at either level are
+/// enabled, and top-level values take precedence
+#[derive(Clone, Debug, Default, Deserialize, ToSchema)]
+pub(crate) struct ExtraBody {
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = true)]
+    pub watermark: Option<bool>,
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.95
+    )]
+    pub typical_p: Option<f32>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = true)]
+    pub decoder_input_details: Option<bool>,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct CompatCompletionRequest {
+    /// Name of the served model, or one of its aliases
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "tgi")]
+    pub model: Option<String>,
+    /// Deprecated: legacy name of `model`, ignored when `model` is set
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub engine: Option<String>,
+    /// `null` or absent generates from the special tokens the tokenizer starts the inputs with,
+    /// e.g. BOS, like OpenAI does from `<|endoftext|>`. An array of prompts gets the choices of
+    /// each prompt in turn
+    #[serde(default, deserialize_with = "deserialize_prompt")]
+    #[schema(value_type = Option<Vec<String>>, nullable = true, example = "My name is Michael and I")]
+    pub prompt: Option<Vec<String>>,
+    /// TGI extension: documents retrieved for the prompt, each wrapped with the
+    /// `completion_context_template` of the chat config and placed before the `prompt`.
+    /// Rejected when no template is configured
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json!(["Munich is the capital of Bavaria."]))]
+    pub context: Option<Vec<String>>,
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 1)]
+    pub best_of: Option<usize>,
+    /// Sampling temperature from 0, for greedy decoding, to 2
+    #[serde(default)]
+    #[schema(
+        minimum = 0.0,
+        maximum = 2.0,
+        nullable = true,
+        default = "null",
+        example = 0.5
+    )]
+    pub temperature: Option<f32>,
+    /// Penalty subtracted once from the logits of the tokens already in the sequence, like
+    /// OpenAI does. Negative values encourage repetitions
+    #[serde(default)]
+    #[schema(
+        minimum = -2.0,
+        maximum = 2.0,
+        nullable = true,
+        default = "null",
+        example = 0.0
+    )]
+    pub presence_penalty: Option<f32>,
+    /// Penalty subtracted from the logits of the tokens already in the sequence for each of
+    /// their occurrences, like OpenAI does. Applied on top of the `presence_penalty`, so that a
+    /// token seen `n` times loses `presence_penalty + n * frequency_penalty`
+    #[serde(default)]
+    #[schema(
+        minimum = -2.0,
+        maximum = 2.0,
+        nullable = true,
+        default = "null",
+        example = 0.0
+    )]
+    pub frequency_penalty: Option<f32>,
+    /// TGI extension: multiplicative `repetition_penalty` passed to the model as is, e.g. through
+    /// the `extra_body` of the OpenAI clients. Applied along with the additive penalties
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        nullable = true,
+        default = "null",
+        example = 1.03
+    )]
+    pub repetition_penalty: Option<f32>,
+    /// Number of choices to generate, each with its own seed
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 1)]
+    pub n: Option<u32>,
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 10)]
+    pub top_k: Option<i32>,
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.95
+    )]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.95
+    )]
+    pub typical_p: Option<f32>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "false", example = true)]
+    pub do_sample: Option<bool>,
+    /// Bounded by `--max-total-tokens`, with the prompt tokens
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "20")]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = false)]
+    pub echo: Option<bool>,
+    /// Return the logprobs of the generated tokens, and of the prompt tokens with `echo`.
+    /// Only the logprobs of the chosen tokens are returned, without alternatives, so that
+    /// `logprobs` is at most 1
+    #[serde(default)]
+    #[schema(maximum = 1, nullable = true, default = "null", example = 1)]
+    pub logprobs: Option<u32>,
+    /// Stop sequences, as an array or a single string
+    #[serde(default, deserialize_with = "deserialize_stop")]
+    #[schema(inline, max_items = 4, example = json ! (["photographer"]))]
+    pub stop: Vec<String>,
+    /// Keep the matched stop sequence at the end of the generated text
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub include_stop_str_in_output: bool,
+    /// TGI extension: return the `tgi_finish_details` of each choice, with the token that
+    /// stopped the generation. Not returned by streams
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub finish_details: bool,
+    /// TGI extension: return the `tgi_stop_sequences` applied to the generation, the default stop
+    /// sequences of the server included. Not returned by streams
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub return_stop_sequences: bool,
+    /// TGI extension: stream a chunk per `token` or per `sentence`
+    #[serde(default)]
+    #[schema(default = "token", example = "sentence")]
+    pub stream_granularity: StreamGranularity,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub truncate: Option<usize>,
+    /// Side of the prompt dropped by `truncate`. `right` by default, to keep the beginning of
+    /// the prompt
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "left")]
+    pub truncation_side: Option<TruncationSide>,
+    #[serde(default)]
+    #[schema(default = "false", example = true)]
+    pub watermark: bool,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json ! ({"50256": -100.0}))]
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    /// TGI extension: only apply `logit_bias` to the first generated token
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub logit_bias_first_token_only: bool,
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub decoder_input_details: bool,
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        nullable = true,
+        default = "null",
+        example = "null"
+    )]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub stream: bool,
+    /// `flex` requests are scheduled after the other requests, and wait for capacity instead of
+    /// being rejected when the server is overloaded
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "flex")]
+    pub service_tier: Option<ServiceTier>,
+    /// TGI extension: LoRA adapter to generate with. The shards cannot load adapters yet, so
+    /// that requests naming one are rejected
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub adapter_id: Option<String>,
+    /// Key-value pairs attached to the request, echoed in the response but ignored for generation
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json!({"experiment": "a"}))]
+    pub metadata: Option<HashMap<String, String>>,
+    /// TGI parameters nested in an `extra_body` object
+    #[serde(default)]
+    #[schema(nullable = true, default = "null")]
+    pub extra_body: Option<ExtraBody>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null")]
+    pub stream_options: Option<StreamOptions>,
+}
+
+/// TGI `best_of` of an OpenAI compatible request. Many clients explicitly send a `best_of` of 1,
+/// which is generated like no `best_of` at all, without the best-of machinery
+fn best_of(best_of: Option<usize>) -> Option<usize> {
+    best_of.filter(|best_of| *best_of != 1)
+}
+
+impl CompatCompletionRequest {
+    /// Requested model, also accepted in the legacy `engine` field
+    pub(crate) fn model(&self) -> Option<&str> {
+        self.model.as_deref().or(self.engine.as_deref())
+    }
+
+    /// Requests of each prompt of a request with several prompts, in order. `None` for a single
+    /// prompt, or none
+    pub(crate) fn split_prompts(&mut self) -> Option<Vec<CompatCompletionRequest>> {
+        if self
+            .prompt
+            .as_ref()
+            .map_or(true, |prompts| prompts.len() < 2)
+        {
+            return None;
+        }
+        let prompts = self.prompt.take()?;
+        Some(
+            prompts
+                .into_iter()
+                .map(|prompt| CompatCompletionRequest {
+                    prompt: Some(vec![prompt]),
+                    ..self.clone()
+                })
+                .collect(),
+        )
+    }
+
+    /// Reject a `logprobs` over the maximum of OpenAI's legacy completions
+    pub(crate) fn validate_logprobs(&self) -> Result<(), ValidationError> {
+        match self.logprobs {
+            Some(logprobs) if logprobs > MAX_COMPLETION_LOGPROBS => Err(
+                ValidationError::MaxLogprobs(MAX_COMPLETION_LOGPROBS, logprobs),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn options(&self) -> CompatRequestOptions {
+        CompatRequestOptions {
+            include_stop_str_in_output: self.include_stop_str_in_output,
+            finish_details: self.finish_details,
+            return_stop_sequences: self.return_stop_sequences,
+            default_max_tokens: self.max_tokens.is_none(),
+            max_tokens_capped: false,
+            stream_granularity: self.stream_granularity,
+            prompt_tokens: None,
+            logprobs: self.logprobs.is_some(),
+            top_logprobs: 0,
+            echo: self.echo.unwrap_or(false),
+            response_format: ResponseFormat::Text,
+            evicted_turns: 0,
+            include_usage: self
+                .stream_options
+                .as_ref()
+                .map_or(false, |stream_options| stream_options.include_usage),
+        }
+    }
+}
+
+impl From<CompatCompletionRequest> for GenerateRequest {
+    fn from(req: CompatCompletionRequest) -> Self {
+        let extra_body = req.extra_body.unwrap_or_default();
+        // The logprobs of the echoed prompt come with the prefill details
+        let prompt_logprobs = req.echo == Some(true) && req.logprobs.is_some();
+        Self {
+            parameters: GenerateParameters {
+                allow_empty_inputs: req.prompt.is_none(),
+                best_of: best_of(req.best_of),
+                temperature: req.temperature,
+                repetition_penalty: req.repetition_penalty,
+                presence_penalty: req.presence_penalty,
+                frequency_penalty: req.frequency_penalty,
+                top_k: req.top_k,
+                top_p: req.top_p,
+                typical_p: req.typical_p.or(extra_body.typical_p),
+                do_sample: req.do_sample.unwrap_or(false),
+                max_new_tokens: req.max_tokens.unwrap_or_else(default_max_new_tokens),
+                return_full_text: req.echo,
+                stop: req.stop,
+                truncate: req.truncate,
+                truncation_side: req.truncation_side.unwrap_or(TruncationSide::Right),
+                watermark: req.watermark || extra_body.watermark == Some(true),
+                logit_bias: req.logit_bias,
+                logit_bias_first_token_only: req.logit_bias_first_token_only,
+                details: true,
+                decoder_input_details: req.decoder_input_details
+                    || extra_body.decoder_input_details == Some(true)
+                    || prompt_logprobs,
+                seed: req.seed,
+                low_priority: req.service_tier == Some(ServiceTier::Flex),
+            },
+            // Requests with several prompts are split by `split_prompts` first
+            inputs: req
+                .prompt
+                .map(|prompts| prompts.concat())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Sampling parameter sets to preview on a prompt, without generating
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct ParametersPreviewRequest {
+    #[schema(example = "My name is Olivier and I")]
+    pub prompt: String,
+    /// Completions request fields, e.g. `{"temperature": 0.7, "presence_penalty": 1.0}`
+    #[schema(value_type = Vec<Object>, example = json ! ([{"temperature": 0.7}, {"presence_penalty": 1.0}]))]
+    pub parameter_sets: Vec<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// TGI generation parameters of each parameter set, in order
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct ParametersPreviewResponse {
+    pub previews: Vec<ParametersPreview>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct ParametersPreview {
+    /// Parameters of the generate request the parameter set converts to
+    pub parameters: GenerateParameters,
+    /// Whether the tokens are sampled, which any sampling parameter implies even without
+    /// `do_sample`
+    #[schema(example = true)]
+    pub sampling: bool,
+}
+
+/// Invalid parameter set of a parameters preview
+#[derive(Debug, Error)]
+pub(crate) enum ParameterSetError {
+    /// Not the fields of a completions request
+    #[error(transparent)]
+    Deserialize(#[from] serde_json::Error),
+    /// Rejected like a completions request would be
+    #[error(transparent)]
+    Validation(#[from] ValidationError),
+}
+
+/// Convert each parameter set to the parameters of a generate request, as a completions request
+/// on the prompt would be, `defaults` and validation included. `Err` with the index of the first
+/// invalid parameter set
+pub(crate) fn preview_parameters(
+    req: ParametersPreviewRequest,
+    compat_config: &CompatConfig,
+) -> Result<ParametersPreviewResponse, (usize, ParameterSetError)> {
+    let previews = req
+        .parameter_sets
+        .into_iter()
+        .enumerate()
+        .map(|(i, mut parameter_set)| {
+            parameter_set.insert("prompt".to_string(), req.prompt.clone().into());
+            let parameters = serde_json::from_value(parameter_set.into())
+                .map_err(ParameterSetError::from)
+                .and_then(|req| Ok(compat_config.completion_request(req)?.parameters))
+                .map_err(|err| (i, err))?;
+            Ok(ParametersPreview {
+                sampling: parameters.sampling(),
+                parameters,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(ParametersPreviewResponse { previews })
+}
+
+/// Type of the response to an OpenAI compatible request
+#[derive(Debug, PartialEq)]
+pub(crate) enum CompatResponseType {
+    /// `application/json` response
+    Json,
+    /// `text/event-stream` response
+    EventStream,
+    /// Streamed request that does not accept Server-Sent Events
+    NotAcceptable,
+    /// Streamed request that does not accept Server-Sent Events, generated completely and
+    /// answered with an `application/json` response
+    Buffered,
+}
+
+/// Response type of a request with the `stream` body flag. The body flag always wins:
+/// `"stream": false` gets a JSON response even when the `Accept` header prefers
+/// `text/event-stream`, as some frameworks always send it.
+/// The `Accept` header only rejects streamed requests, or gets them a JSON response with
+/// `buffer_streams`
+pub(crate) fn compat_response_type(
+    headers: &HeaderMap,
+    stream: bool,
+    buffer_streams: bool,
+) -> CompatResponseType {
+    match stream {
+        false => CompatResponseType::Json,
+        true if accepts_event_stream(headers) => CompatResponseType::EventStream,
+        true if buffer_streams => CompatResponseType::Buffered,
+        true => CompatResponseType::NotAcceptable,
+    }
+}
+
+/// Whether the `Accept` header of a request allows a Server-Sent Events response.
+/// Requests without an `Accept` header accept any media type
+fn accepts_event_stream(headers: &HeaderMap) -> bool {
+    let mut accept = headers.get_all(header::ACCEPT).iter().peekable();
+    if accept.peek().is_none() {
+        return true;
+    }
+    accept
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_range| {
+            let mut params = media_range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+            // `q=0` explicitly refuses the media type
+            let refused = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .map_or(false, |q| q == 0.0)
+            });
+            !refused && matches!(media_type.as_str(), "*/*" | "text/*" | "text/event-stream")
+        })
+}
+
+/// Maximum `logprobs` of the completions requests: only the sampled token can be listed
+const MAX_COMPLETION_LOGPROBS: u32 = 1;
+
+/// Maximum `top_logprobs` of the chat requests: only the sampled token can be listed
+const MAX_CHAT_TOP_LOGPROBS: u32 = 1;
+
+/// Limits of the request `metadata`, the same as OpenAI's
+const MAX_METADATA_PAIRS: usize = 16;
+const MAX_METADATA_KEY_LENGTH: usize = 64;
+const MAX_METADATA_VALUE_LENGTH: usize = 512;
+
+/// Check the size of the request `metadata`, which is echoed in every response
+pub(crate) fn validate_metadata(metadata: &HashMap<String, String>) -> Result<(), String> {
+    if metadata.len() > MAX_METADATA_PAIRS {
+        return Err(format!(
+            "`metadata` must have at most {MAX_METADATA_PAIRS} key-value pairs. Given: {}",
+            metadata.len()
+        ));
+    }
+    for (key, value) in metadata {
+        if key.chars().count() > MAX_METADATA_KEY_LENGTH {
+            return Err(format!(
+                "`metadata` keys must be at most {MAX_METADATA_KEY_LENGTH} characters long. Given: `{key}`"
+            ));
+        }
+        if value.chars().count() > MAX_METADATA_VALUE_LENGTH {
+            return Err(format!(
+                "`metadata` values must be at most {MAX_METADATA_VALUE_LENGTH} characters long. Key: `{key}`"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Canonical hash of a request once defaults and the OpenAI parameter transforms are applied.
+/// Two requests the server considers equivalent share the same hash.
+///
+/// `serde_json::Value` objects keep their keys sorted, which makes the serialization
+/// deterministic, including for `HashMap` fields.
+pub(crate) fn request_hash(req: &GenerateRequest) -> String {
+    let normalized = serde_json::to_value(req).expect("cannot serialize GenerateRequest");
+    Sha256::digest(normalized.to_string().as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Role of a chat message. Roles other than the standard ones, e.g. the `ipython` role of
+/// tool-use chat formats, are templated with the `role_templates` of the chat config
+#[derive(Clone, Debug, PartialEq, ToSchema, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
+pub(crate) enum ChatRole {
+    User,
+    Assistant,
+    System,
+    /// Tool results, e.g. documents retrieved for RAG
+    Tool,
+    Other(String),
+}
+
+impl From<String> for ChatRole {
+    fn from(role: String) -> Self {
+        match role.as_str() {
+            "user" => ChatRole::User,
+            "assistant" => ChatRole::Assistant,
+            "system" => ChatRole::System,
+            "tool" => ChatRole::Tool,
+            _ => ChatRole::Other(role),
+        }
+    }
+}
+
+impl From<ChatRole> for String {
+    fn from(role: ChatRole) -> Self {
+        match role {
+            ChatRole::User => "user".to_string(),
+            ChatRole::Assistant => "assistant".to_string(),
+            ChatRole::System => "system".to_string(),
+            ChatRole::Tool => "tool".to_string(),
+            ChatRole::Other(role) => role,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ChatFormatterPrePost {
+    #[serde(default)]
+    pre: String,
+    #[serde(default)]
+    post: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, ToSchema)]
+pub struct ChatFormatter {
+    user_template: ChatFormatterPrePost,
+    assistant_template: ChatFormatterPrePost,
+    system_template: ChatFormatterPrePost,
+    /// Template of the `tool` messages, the system template when unset
+    context_template: Option<ChatFormatterPrePost>,
+    /// Templates of the other roles
+    role_templates: HashMap<String, ChatFormatterPrePost>,
+    /// Template of the other roles without a template, which are rejected when unset
+    default_role_template: Option<ChatFormatterPrePost>,
+    /// Jinja chat template of the tokenizer, which renders the whole conversation instead of the
+    /// templates of each role when set
+    #[serde(serialize_with = "serialize_chat_template")]
+    #[schema(value_type = Option<String>)]
+    chat_template: Option<Arc<ChatTemplate>>,
+}
+
+fn serialize_chat_template<S: Serializer>(
+    template: &Option<Arc<ChatTemplate>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    template.as_deref().serialize(serializer)
+}
+
+impl ChatFormatter {
+    fn template(&self, role: &ChatRole) -> Result<&ChatFormatterPrePost, ValidationError> {
+        match role {
+            ChatRole::Assistant => Ok(&self.assistant_template),
+            ChatRole::System => Ok(&self.system_template),
+            ChatRole::User => Ok(&self.user_template),
+            ChatRole::Tool => Ok(self
+                .context_template
+                .as_ref()
+                .unwrap_or(&self.system_template)),
+            ChatRole::Other(role) => self
+                .role_templates
+                .get(role)
+                .or(self.default_role_template.as_ref())
+                .ok_or_else(|| ValidationError::UnknownRole(role.clone())),
+        }
+    }
+
+    /// Hash of all the templates, which changes with any of them
+    pub(crate) fn hash(&self) -> String {
+        let templates = serde_json::to_value(self).expect("cannot serialize ChatFormatter");
+        Sha256::digest(templates.to_string().as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// Marker of the assistant template the model may start its response with, without the
+    /// surrounding whitespace
+    fn assistant_prefix(&self) -> Option<&str> {
+        if self.chat_template.is_some() {
+            return None;
+        }
+        let prefix = self.assistant_template.pre.trim();
+        (!prefix.is_empty()).then_some(prefix)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct ChatMessage {
+    #[schema(example = "user")]
+    pub role: ChatRole,
+    #[schema(example = "What is the capital of Bavaria?")]
+    pub content: ChatMessageContent,
+    // user: Option<String>,
+}
+
+/// Content of a chat message, either plain text or typed content parts
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub(crate) enum ChatMessageContent {
+    Text(String),
+    Parts(Vec<ChatContentPart>),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ChatContentPart {
+    Text {
+        text: String,
+    },
+    ImageUrl {
+        #[schema(value_type = Object)]
+        image_url: serde_json::Value,
+    },
+    InputAudio {
+        #[schema(value_type = Object)]
+        input_audio: serde_json::Value,
+    },
+}
+
+impl ChatMessageContent {
+    /// Type of the first content part that a text-only model cannot handle
+    pub(crate) fn unsupported_part(&self) -> Option<&'static str> {
+        match self {
+            ChatMessageContent::Text(_) => None,
+            ChatMessageContent::Parts(parts) => parts.iter().find_map(|part| match part {
+                ChatContentPart::Text { .. } => None,
+                ChatContentPart::ImageUrl { .. } => Some("image_url"),
+                ChatContentPart::InputAudio { .. } => Some("input_audio"),
+            }),
+        }
+    }
+
+    /// Strip the invisible characters of the text
+    pub(crate) fn normalize(&mut self, normalization: PromptNormalization) {
+        match self {
+            ChatMessageContent::Text(text) => normalization.normalize(text),
+            ChatMessageContent::Parts(parts) => {
+                for part in parts {
+                    if let ChatContentPart::Text { text } = part {
+                        normalization.normalize(text);
+                    }
+                }
+            }
+        }
+    }
+
+    /// No text nor other content part
+    fn is_empty(&self) -> bool {
+        match self {
+            ChatMessageContent::Text(text) => text.is_empty(),
+            ChatMessageContent::Parts(parts) => parts
+                .iter()
+                .all(|part| matches!(part, ChatContentPart::Text { text } if text.is_empty())),
+        }
+    }
+
+    /// Whether the text content is empty or only contains whitespace
+    pub(crate) fn is_blank(&self) -> bool {
+        match self {
+            ChatMessageContent::Text(text) => text.trim().is_empty(),
+            ChatMessageContent::Parts(parts) => parts.iter().all(
+                |part| matches!(part, ChatContentPart::Text { text } if text.trim().is_empty()),
+            ),
+        }
+    }
+
+    fn push_text(&self, prompt: &mut String) {
+        match self {
+            ChatMessageContent::Text(text) => prompt.push_str(text),
+            ChatMessageContent::Parts(parts) => {
+                for part in parts {
+                    if let ChatContentPart::Text { text } = part {
+                        prompt.push_str(text);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct ChatDeltaStreamMessage {
+    #[schema(example = "user")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<ChatRole>,
+    #[schema(example = "What is the capital of Bavaria?")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    // user: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct CompatChatCompletionRequest {
+    /// Name of the served model, or one of its aliases
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "tgi")]
+    pub model: Option<String>,
+    /// Deprecated: legacy name of `model`, ignored when `model` is set
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub engine: Option<String>,
+    /// Conversation to complete, empty when `batch` is set
+    #[serde(default)]
+    pub messages: Vec<ChatMessage>,
+    /// TGI extension: independent conversations to complete with a single request, each one
+    /// with its own choice. Cannot be streamed
+    #[serde(default)]
+    #[schema(nullable = true, default = "null")]
+    pub batch: Option<Vec<Vec<ChatMessage>>>,
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 1)]
+    pub best_of: Option<usize>,
+    /// Sampling temperature from 0, for greedy decoding, to 2
+    #[serde(default)]
+    #[schema(
+        minimum = 0.0,
+        maximum = 2.0,
+        nullable = true,
+        default = "null",
+        example = 0.5
+    )]
+    pub temperature: Option<f32>,
+    /// Penalty subtracted once from the logits of the tokens already in the sequence, like
+    /// OpenAI does. Negative values encourage repetitions
+    #[serde(default)]
+    #[schema(
+        minimum = -2.0,
+        maximum = 2.0,
+        nullable = true,
+        default = "null",
+        example = 0.0
+    )]
+    pub presence_penalty: Option<f32>,
+    /// Penalty subtracted from the logits of the tokens already in the sequence for each of
+    /// their occurrences, like OpenAI does. Applied on top of the `presence_penalty`, so that a
+    /// token seen `n` times loses `presence_penalty + n * frequency_penalty`
+    #[serde(default)]
+    #[schema(
+        minimum = -2.0,
+        maximum = 2.0,
+        nullable = true,
+        default = "null",
+        example = 0.0
+    )]
+    pub frequency_penalty: Option<f32>,
+    /// TGI extension: multiplicative `repetition_penalty` passed to the model as is, e.g. through
+    /// the `extra_body` of the OpenAI clients. Applied along with the additive penalties
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        nullable = true,
+        default = "null",
+        example = 1.03
+    )]
+    pub repetition_penalty: Option<f32>,
+    /// Number of choices to generate, each with its own seed
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 1)]
+    pub n: Option<u32>,
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 10)]
+    pub top_k: Option<i32>,
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.95
+    )]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.95
+    )]
+    pub typical_p: Option<f32>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "false", example = true)]
+    pub do_sample: Option<bool>,
+    /// Deprecated by OpenAI in favor of `max_completion_tokens`
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "20")]
+    pub max_tokens: Option<u32>,
+    /// Bounded by `--max-total-tokens`, with the prompt tokens. Takes precedence over
+    /// `max_tokens`
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "20")]
+    pub max_completion_tokens: Option<u32>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = false)]
+    pub echo: Option<bool>,
+    /// Stop sequences, as an array or a single string
+    #[serde(default, deserialize_with = "deserialize_stop")]
+    #[schema(inline, max_items = 4, example = json ! (["photographer"]))]
+    pub stop: Vec<String>,
+    /// Keep the matched stop sequence at the end of the generated text
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub include_stop_str_in_output: bool,
+    /// TGI extension: return the `tgi_finish_details` of each choice, with the token that
+    /// stopped the generation. Not returned by streams
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub finish_details: bool,
+    /// TGI extension: return the `tgi_stop_sequences` applied to the generation, the default stop
+    /// sequences of the server included. Not returned by streams
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub return_stop_sequences: bool,
+    /// TGI extension: stream a chunk per `token` or per `sentence`
+    #[serde(default)]
+    #[schema(default = "token", example = "sentence")]
+    pub stream_granularity: StreamGranularity,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub truncate: Option<usize>,
+    /// Side of the conversation dropped by `truncate`. `left` by default, to keep the most
+    /// recent messages
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "right")]
+    pub truncation_side: Option<TruncationSide>,
+    #[serde(default)]
+    #[schema(default = "false", example = true)]
+    pub watermark: bool,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json ! ({"50256": -100.0}))]
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    /// TGI extension: only apply `logit_bias` to the first generated token
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub logit_bias_first_token_only: bool,
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub decoder_input_details: bool,
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        nullable = true,
+        default = "null",
+        example = "null"
+    )]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub stream: bool,
+    /// `flex` requests are scheduled after the other requests, and wait for capacity instead of
+    /// being rejected when the server is overloaded
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "flex")]
+    pub service_tier: Option<ServiceTier>,
+    /// TGI extension: LoRA adapter to generate with. The shards cannot load adapters yet, so
+    /// that requests naming one are rejected
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub adapter_id: Option<String>,
+    /// Key-value pairs attached to the request, echoed in the response but ignored for generation
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json!({"experiment": "a"}))]
+    pub metadata: Option<HashMap<String, String>>,
+    /// TGI parameters nested in an `extra_body` object
+    #[serde(default)]
+    #[schema(nullable = true, default = "null")]
+    pub extra_body: Option<ExtraBody>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null")]
+    pub stream_options: Option<StreamOptions>,
+    /// Format of the output, unconstrained `text` by default
+    #[serde(default)]
+    #[schema(nullable = true, default = "null")]
+    pub response_format: Option<ResponseFormat>,
+    /// Return the logprobs of the generated tokens
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub logprobs: bool,
+    /// Number of alternatives listed with the logprob of each generated token, with `logprobs`.
+    /// The backend does not return alternatives, so at most the sampled token is listed
+    #[serde(default)]
+    #[schema(maximum = 1, nullable = true, default = "null", example = 1)]
+    pub top_logprobs: Option<u32>,
+    /// Require the conversation to end with an assistant message, which is continued. A final
+    /// assistant message is continued in any case
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub continue_final_message: bool,
+    /// TGI extension: named sampling preset, e.g. `creative`, `balanced` or `precise`
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "balanced")]
+    pub preset: Option<String>,
+    /// TGI extension: leave out the default stop sequences of the server, keeping the `stop` of
+    /// the request
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub disable_default_stops: bool,
+    /// TGI extension: do not start the conversation with the default system message of the server
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub disable_default_system: bool,
+    // #[serde(default)]
+    // #[schema(nullable = true, default = "null", example = "null")]
+    // pub user: Option<String>,
+}
+
+impl CompatChatCompletionRequest {
+    /// Add the system messages of the server: the default system message, unless the request
+    /// disabled it, then the instruction requesting the `response_format`
+    pub(crate) fn add_system_messages(
+        &mut self,
+        chat_config: &ChatConfig,
+        response_format: ResponseFormat,
+    ) {
+        if !self.disable_default_system {
+            chat_config.add_default_system(&mut self.messages);
+        }
+        response_format.add_instruction(&mut self.messages);
+    }
+
+    /// Requested model, also accepted in the legacy `engine` field
+    pub(crate) fn model(&self) -> Option<&str> {
+        self.model.as_deref().or(self.engine.as_deref())
+    }
+
+    /// Requested maximum number of generated tokens: `max_completion_tokens`, or the deprecated
+    /// `max_tokens` that older clients still send
+    pub(crate) fn max_tokens(&self) -> Option<u32> {
+        self.max_completion_tokens.or(self.max_tokens)
+    }
+
+    /// Reject a `top_logprobs` listing alternatives the backend does not return, or without
+    /// `logprobs`
+    pub(crate) fn validate_logprobs(&self) -> Result<(), ValidationError> {
+        match self.top_logprobs {
+            Some(top_logprobs) if top_logprobs > MAX_CHAT_TOP_LOGPROBS => Err(
+                ValidationError::MaxTopLogprobs(MAX_CHAT_TOP_LOGPROBS, top_logprobs),
+            ),
+            Some(_) if !self.logprobs => Err(ValidationError::TopLogprobsWithoutLogprobs),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn options(&self) -> CompatRequestOptions {
+        CompatRequestOptions {
+            include_stop_str_in_output: self.include_stop_str_in_output,
+            finish_details: self.finish_details,
+            return_stop_sequences: self.return_stop_sequences,
+            default_max_tokens: self.max_tokens().is_none(),
+            stream_granularity: self.stream_granularity,
+            logprobs: self.logprobs,
+            top_logprobs: self.top_logprobs.unwrap_or(0),
+            response_format: self.response_format.unwrap_or_default(),
+            include_usage: self
+                .stream_options
+                .as_ref()
+                .map_or(false, |stream_options| stream_options.include_usage),
+            ..Default::default()
+        }
+    }
+}
+
+pub(crate) fn chat_to_generate_request(
+    req: CompatChatCompletionRequest,
+    formatter: &ChatFormatter,
+    chat_config: &ChatConfig,
+) -> Result<GenerateRequest, ValidationError> {
+    let preset = match &req.preset {
+        Some(name) => chat_config
+            .preset(name)
+            .ok_or_else(|| ValidationError::UnknownPreset(name.clone()))?,
+        None => SamplingPreset::default(),
+    };
+
+    let ends_with_assistant = matches!(
+        req.messages.last().map(|m| &m.role),
+        Some(ChatRole::Assistant)
+    );
+    if req.continue_final_message && !ends_with_assistant {
+        return Err(ValidationError::ContinueFinalMessage);
+    }
+
+    let prompt = chat_prompt(&req.messages, formatter, chat_config)?;
+    let max_tokens = req.max_tokens();
+    let extra_body = req.extra_body.unwrap_or_default();
+    let mut stop = req.stop;
+    if !req.disable_default_stops {
+        for default_stop in &chat_config.default_stop {
+            if !stop.contains(default_stop) {
+                stop.push(default_stop.clone());
+            }
+        }
+    }
+
+    let mut parameters = GenerateParameters {
+        best_of: best_of(req.best_of),
+        temperature: req.temperature.or(preset.temperature),
+        repetition_penalty: req.repetition_penalty,
+        presence_penalty: req.presence_penalty.or(preset.presence_penalty),
+        frequency_penalty: req.frequency_penalty,
+        top_k: req.top_k.or(preset.top_k),
+        top_p: req.top_p.or(preset.top_p),
+        typical_p: req.typical_p.or(extra_body.typical_p).or(preset.typical_p),
+        do_sample: req.do_sample.unwrap_or(false),
+        max_new_tokens: max_tokens.unwrap_or_else(default_max_new_tokens),
+        return_full_text: req.echo,
+        stop,
+        truncate: req.truncate,
+        truncation_side: req.truncation_side.unwrap_or(TruncationSide::Left),
+        watermark: req.watermark || extra_body.watermark == Some(true),
+        logit_bias: req.logit_bias,
+        logit_bias_first_token_only: req.logit_bias_first_token_only,
+        details: true,
+        decoder_input_details: req.decoder_input_details
+            || extra_body.decoder_input_details == Some(true),
+        seed: req.seed,
+        low_priority: req.service_tier == Some(ServiceTier::Flex),
+        allow_empty_inputs: false,
+    };
+    chat_config.defaults.apply(&mut parameters, req.do_sample);
+    chat_config.convert_temperature(&mut parameters, req.do_sample);
+
+    Ok(GenerateRequest {
+        inputs: prompt,
+        parameters,
+    })
+}
+
+/// Templated prompt of a conversation. A final assistant message is left open as the prefix
+/// of the generated reply
+fn chat_prompt(
+    messages: &[ChatMessage],
+    formatter: &ChatFormatter,
+    chat_config: &ChatConfig,
+) -> Result<String, ValidationError> {
+    if let Some(template) = &formatter.chat_template {
+        return template_chat_prompt(messages, template, chat_config);
+    }
+    let mut prompt = String::from("");
+    // Last templated message, closed once the next message is not merged into it
+    let mut open: Option<(&ChatRole, &ChatFormatterPrePost)> = None;
+    for (i, m) in messages.iter().enumerate() {
+        // A conversation left empty is rejected as an empty input
+        if matches!(m.role, ChatRole::User) && m.content.is_empty() {
+            match chat_config.empty_content {
+                EmptyContent::Skip => continue,
+                EmptyContent::Error => return Err(ValidationError::EmptyContent(i)),
+                EmptyContent::PassThrough => {}
+            }
+        }
+        let template = formatter.template(&m.role)?;
+        match open {
+            Some((role, _)) if *role == m.role => match chat_config.consecutive_messages(role) {
+                ConsecutiveMessages::Merge => {
+                    let separator = chat_config.merge_separator.as_deref();
+                    prompt.push_str(separator.unwrap_or("\n\n"));
+                    m.content.push_text(&mut prompt);
+                    continue;
+                }
+                ConsecutiveMessages::Error => {
+                    return Err(ValidationError::ConsecutiveMessages(i, role.clone().into()))
+                }
+                ConsecutiveMessages::PassThrough => prompt.push_str(&template.post),
+            },
+            Some((_, previous)) => prompt.push_str(&previous.post),
+            None => {}
+        }
+        prompt.push_str(&template.pre);
+        m.content.push_text(&mut prompt);
+        open = Some((&m.role, template));
+    }
+    // Leave the final assistant message open so that the model continues it
+    if let Some((role, template)) = open {
+        if *role != ChatRole::Assistant {
+            prompt.push_str(&template.post);
+        }
+    }
+    Ok(prompt)
+}
+
+/// Appended to the final assistant message continued by the model, to find where its content ends
+/// in the rendered prompt, even when the template transforms it, e.g. with `trim`
+const CONTINUE_SENTINEL: &str = "<|tgi_continue_final_message|>";
+
+/// Prompt of a conversation rendered by the Jinja chat template of the tokenizer. Empty and
+/// consecutive messages are handled as with the `TGICHAT_*` templates first
+fn template_chat_prompt(
+    messages: &[ChatMessage],
+    template: &ChatTemplate,
+    chat_config: &ChatConfig,
+) -> Result<String, ValidationError> {
+    let mut template_messages: Vec<TemplateMessage> = Vec::with_capacity(messages.len());
+    let mut last_role = None;
+    for (i, m) in messages.iter().enumerate() {
+        if matches!(m.role, ChatRole::User) && m.content.is_empty() {
+            match chat_config.empty_content {
+                EmptyContent::Skip => continue,
+                EmptyContent::Error => return Err(ValidationError::EmptyContent(i)),
+                EmptyContent::PassThrough => {}
+            }
+        }
+        let mut content = String::new();
+        m.content.push_text(&mut content);
+        if last_role == Some(&m.role) {
+            match chat_config.consecutive_messages(&m.role) {
+                ConsecutiveMessages::Merge => {
+                    let last = template_messages.last_mut().expect("last message was kept");
+                    let separator = chat_config.merge_separator.as_deref();
+                    last.content.push_str(separator.unwrap_or("\n\n"));
+                    last.content.push_str(&content);
+                    continue;
+                }
+                ConsecutiveMessages::Error => {
+                    return Err(ValidationError::ConsecutiveMessages(
+                        i,
+                        m.role.clone().into(),
+                    ))
+                }
+                ConsecutiveMessages::PassThrough => {}
+            }
+        }
+        template_messages.push(TemplateMessage {
+            role: m.role.clone().into(),
+            content,
+        });
+        last_role = Some(&m.role);
+    }
+
+    let continue_final_message = last_role == Some(&ChatRole::Assistant);
+    // Leave the final assistant message open by cutting the end of turn the template added, at
+    // the sentinel ending its content
+    if continue_final_message {
+        let last = template_messages.last_mut().expect("last message was kept");
+        last.content.push_str(CONTINUE_SENTINEL);
+        let mut prompt = template.render(&template_messages, false)?;
+        if let Some(end) = prompt.rfind(CONTINUE_SENTINEL) {
+            prompt.truncate(end);
+            return Ok(prompt);
+        }
+        // The template left the final message out
+        let last = template_messages.last_mut().expect("last message was kept");
+        last.content
+            .truncate(last.content.len() - CONTINUE_SENTINEL.len());
+    }
+    template.render(&template_messages, !continue_final_message)
+}
+
+/// Evict the oldest turns of the conversation, a user message with the replies following it,
+/// until its templated prompt has at most `max_tokens` tokens as counted by `count_tokens`.
+/// The system messages and the last user turn are always kept, and the conversation is rejected
+/// when they do not fit. Returns the number of evicted turns.
+/// Each evicted turn is tokenized once, templated on its own, and its tokens are subtracted from
+/// the excess. The whole conversation is only counted again once the excess is covered
+pub(crate) async fn evict_oldest_turns<F: Future<Output = Option<u32>>>(
+    req: &mut CompatChatCompletionRequest,
+    formatter: &ChatFormatter,
+    chat_config: &ChatConfig,
+    max_tokens: u32,
+    count_tokens: impl Fn(String) -> F,
+) -> Result<u32, ValidationError> {
+    let mut evicted_turns = 0;
+    loop {
+        let prompt = chat_prompt(&req.messages, formatter, chat_config)?;
+        // Conversations that cannot be tokenized are left to the validation of the inputs
+        let tokens = match count_tokens(prompt).await {
+            Some(tokens) => tokens,
+            None => return Ok(evicted_turns),
+        };
+        if tokens <= max_tokens {
+            return Ok(evicted_turns);
+        }
+
+        let mut excess = tokens - max_tokens;
+        if oldest_turn(&req.messages).is_none() {
+            return Err(ValidationError::ConversationTokens(max_tokens, tokens));
+        }
+        while let Some(turn) = oldest_turn(&req.messages).filter(|_| excess > 0) {
+            let turn: Vec<ChatMessage> = req.messages.drain(turn).collect();
+            evicted_turns += 1;
+            // A turn templated on its own usually has more tokens than in the conversation,
+            // which is the safe side, and the next count of the conversation catches the rest
+            let turn_tokens = match chat_prompt(&turn, formatter, chat_config) {
+                Ok(prompt) => count_tokens(prompt).await.unwrap_or(0),
+                Err(_) => 0,
+            };
+            excess = excess.saturating_sub(turn_tokens.max(1));
+        }
+    }
+}
+
+/// Templated system and tool messages starting the conversation, usually shared by many requests.
+/// Empty with a Jinja chat template, which renders the conversation as a whole rather than each
+/// message in turn
+pub(crate) fn chat_prompt_prefix(
+    messages: &[ChatMessage],
+    formatter: &ChatFormatter,
+    chat_config: &ChatConfig,
+) -> String {
+    if formatter.chat_template.is_some() {
+        return String::new();
+    }
+    let end = messages
+        .iter()
+        .take_while(|m| matches!(m.role, ChatRole::System | ChatRole::Tool))
+        .count();
+    chat_prompt(&messages[..end], formatter, chat_config).unwrap_or_default()
+}
+
+/// Range of the oldest turn of the conversation, a user message with the replies following it,
+/// unless it is the last user turn
+fn oldest_turn(messages: &[ChatMessage]) -> Option<Range<usize>> {
+    let last_user = messages.iter().rposition(|m| m.role == ChatRole::User)?;
+    let start = messages.iter().position(|m| m.role != ChatRole::System)?;
+    if start >= last_user {
+        return None;
+    }
+    // The turn ends at the next user or system message
+    let end = messages[start + 1..]
+        .iter()
+        .position(|m| matches!(m.role, ChatRole::User | ChatRole::System))
+        .map_or(messages.len(), |end| start + 1 + end);
+    Some(start..end)
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Usage {
+    #[schema(value_type = u32, example = 1)]
+    pub total_tokens: CompatNumber<u32>,
+    #[schema(value_type = u32, example = 1)]
+    pub completion_tokens: CompatNumber<u32>,
+    #[schema(value_type = u32, example = 1)]
+    pub prompt_tokens: CompatNumber<u32>,
+    /// TGI extension: cost of the request given the configured token prices
+    #[schema(value_type = Option<f64>, nullable = true, example = 0.002)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tgi_estimated_cost: Option<CompatNumber<f64>>,
+    /// Set when the prompt was truncated
+    #[schema(nullable = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+}
+
+/// Breakdown of the prompt tokens
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct PromptTokensDetails {
+    /// TGI extension: number of tokens dropped from the prompt by `truncate`, not counted in
+    /// `prompt_tokens`
+    #[schema(value_type = u32, example = 12)]
+    pub truncated_tokens: CompatNumber<u32>,
+    /// TGI extension: number of turns evicted from the start of the conversation to fit the
+    /// conversation token budget of the server
+    #[schema(value_type = Option<u32>, nullable = true, example = 2)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub evicted_turns: Option<CompatNumber<u32>>,
+}
+
+/// `created` timestamp or usage count of a response, serialized as a string with
+/// `numbers_as_strings`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct CompatNumber<N> {
+    pub value: N,
+    pub as_string: bool,
+}
+
+impl<N> From<N> for CompatNumber<N> {
+    fn from(value: N) -> Self {
+        Self {
+            value,
+            as_string: false,
+        }
+    }
+}
+
+impl<N: Serialize + fmt::Display> Serialize for CompatNumber<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_number_as(&self.value, self.as_string, serializer)
+    }
+}
+
+fn serialize_number_as<S: Serializer, N: Serialize + fmt::Display>(
+    number: &N,
+    as_string: bool,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match as_string {
+        true => serializer.collect_str(number),
+        false => number.serialize(serializer),
+    }
+}
+
+impl Usage {
+    pub(crate) fn new(
+        prompt_tokens: u32,
+        completion_tokens: u32,
+        compat_config: &CompatConfig,
+    ) -> Self {
+        Self {
+            total_tokens: compat_config.number(prompt_tokens + completion_tokens),
+            completion_tokens: compat_config.number(completion_tokens),
+            prompt_tokens: compat_config.number(prompt_tokens),
+            tgi_estimated_cost: compat_config
+                .estimated_cost(prompt_tokens, completion_tokens)
+                .map(|cost| compat_config.number(cost)),
+            prompt_tokens_details: None,
+        }
+    }
+
+    /// Report the `truncated_tokens` dropped from the prompt, if any
+    pub(crate) fn with_truncated_tokens(mut self, truncated_tokens: u32) -> Self {
+        self.prompt_tokens_details = (truncated_tokens > 0).then_some(PromptTokensDetails {
+            truncated_tokens: self.number(truncated_tokens),
+            evicted_turns: None,
+        });
+        self
+    }
+
+    /// Report the `evicted_turns` of the conversation, if any. Set after the truncated tokens
+    pub(crate) fn with_evicted_turns(mut self, evicted_turns: u32) -> Self {
+        if evicted_turns > 0 {
+            let evicted_turns = self.number(evicted_turns);
+            let truncated_tokens = self.number(0);
+            let details = self
+                .prompt_tokens_details
+                .get_or_insert(PromptTokensDetails {
+                    truncated_tokens,
+                    evicted_turns: None,
+                });
+            details.evicted_turns = Some(evicted_turns);
+        }
+        self
+    }
+
+    /// Count serialized like the other counts of the usage
+    fn number(&self, value: u32) -> CompatNumber<u32> {
+        CompatNumber {
+            value,
+            as_string: self.total_tokens.as_string,
+        }
+    }
+
+    /// Number of turns evicted from the conversation
+    fn evicted_turns(&self) -> u32 {
+        self.prompt_tokens_details
+            .as_ref()
+            .and_then(|details| details.evicted_turns)
+            .map_or(0, |evicted_turns| evicted_turns.value)
+    }
+
+    /// Number of tokens dropped from the prompt
+    fn truncated_tokens(&self) -> u32 {
+        self.prompt_tokens_details
+            .as_ref()
+            .map_or(0, |details| details.truncated_tokens.value)
+    }
+}
+
+/// Server-side timings of a generation, in milliseconds, to tell whether a slow request waited
+/// in the queue or was slow to prefill or decode
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct Timings {
+    /// Tokenization and validation of the request
+    #[schema(example = 0.4)]
+    pub validation_ms: f64,
+    /// Wait in the queue until the request was added to a batch
+    #[schema(example = 12.5)]
+    pub queue_ms: f64,
+    /// Prefill of the prompt, until the first token
+    #[schema(example = 35.2)]
+    pub prefill_ms: f64,
+    /// Decoding of the other tokens
+    #[schema(example = 410.7)]
+    pub decode_ms: f64,
+    /// Decoded tokens per second, `null` for single token generations
+    #[schema(nullable = true, example = 46.2)]
+    pub decode_tokens_per_second: Option<f64>,
+}
+
+impl Timings {
+    pub(crate) fn new(
+        start_time: Instant,
+        queued: Instant,
+        start: Instant,
+        first_token: Instant,
+        end: Instant,
+        generated_tokens: u32,
+    ) -> Self {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let decode = end - first_token;
+        let decode_tokens_per_second = (generated_tokens > 1 && !decode.is_zero())
+            .then_some(generated_tokens.saturating_sub(1) as f64 / decode.as_secs_f64());
+        Self {
+            validation_ms: ms(queued - start_time),
+            queue_ms: ms(start - queued),
+            prefill_ms: ms(first_token - start),
+            decode_ms: ms(decode),
+            decode_tokens_per_second,
+        }
+    }
+
+    /// Value of the `Server-Timing` header, shown by the network panel of the browsers and by
+    /// APM tools
+    pub(crate) fn server_timing(&self) -> String {
+        format!(
+            "queue;dur={:.1}, prefill;dur={:.1}, decode;dur={:.1}",
+            self.queue_ms, self.prefill_ms, self.decode_ms
+        )
+    }
+}
+
+/// How strictly a `response_format` was enforced for a response.
+/// Reported so that clients do not assume strict JSON they did not get.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResponseFormatEnforcement {
+    /// No output constraint was applied
+    None,
+    /// The format was only requested through the prompt, compliance is not guaranteed
+    PromptOnly,
+    /// Generation was constrained with a grammar, output complies with the format
+    GrammarConstrained,
+}
+
+/// Format of the output of a chat completion, as requested with `response_format`
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub(crate) enum ResponseFormat {
+    /// Unconstrained text
+    #[default]
+    Text,
+    /// Any JSON object
+    JsonObject,
+    /// JSON complying with the `json_schema` of the request
+    JsonSchema,
+}
+
+/// Instruction of the system message of the conversations requesting a JSON object
+const JSON_OBJECT_INSTRUCTION: &str = "Respond only with a valid JSON object.";
+
+impl ResponseFormat {
+    /// How strictly the format is enforced. JSON objects are requested through the prompt, and
+    /// JSON schemas are accepted but not enforced yet
+    pub(crate) fn enforcement(&self) -> ResponseFormatEnforcement {
+        match self {
+            ResponseFormat::JsonObject => ResponseFormatEnforcement::PromptOnly,
+            ResponseFormat::Text | ResponseFormat::JsonSchema => ResponseFormatEnforcement::None,
+        }
+    }
+
+    /// Request the format through the prompt: the instruction ends the first system message of
+    /// the conversation, or starts the conversation in a new system message
+    pub(crate) fn add_instruction(&self, messages: &mut Vec<ChatMessage>) {
+        let instruction = match self {
+            ResponseFormat::JsonObject => JSON_OBJECT_INSTRUCTION,
+            ResponseFormat::Text | ResponseFormat::JsonSchema => return,
+        };
+        let system = messages.iter_mut().find(|m| m.role == ChatRole::System);
+        match system.map(|system| &mut system.content) {
+            Some(ChatMessageContent::Text(text)) if text.trim().is_empty() => {
+                *text = instruction.to_string()
+            }
+            Some(ChatMessageContent::Text(text)) => {
+                text.push_str("\n\n");
+                text.push_str(instruction);
+            }
+            Some(ChatMessageContent::Parts(parts)) => parts.push(ChatContentPart::Text {
+                text: format!("\n\n{instruction}"),
+            }),
+            None => messages.insert(
+                0,
+                ChatMessage {
+                    role: ChatRole::System,
+                    content: ChatMessageContent::Text(instruction.to_string()),
+                },
+            ),
+        }
+    }
+
+    /// Check the output of a finished choice against the format. JSON objects in a Markdown code
+    /// block are unwrapped, and outputs that do not comply report `tgi_format_complete: false`,
+    /// like the outputs cut at `max_tokens`, rather than failing the whole generation
+    pub(crate) fn check_output(&self, choice: &mut ChatCompletionChoices) {
+        if *self != ResponseFormat::JsonObject || choice.tgi_format_complete != Some(true) {
+            return;
+        }
+        let text = match &mut choice.message.content {
+            ChatMessageContent::Text(text) => text,
+            ChatMessageContent::Parts(_) => return,
+        };
+        if is_json_object(text) {
+            return;
+        }
+        match fenced_code(text) {
+            Some(code) if is_json_object(code) => *text = code.to_string(),
+            _ => {
+                metrics::increment_counter!("tgi_request_format_incomplete");
+                tracing::warn!("The model output is not a valid JSON object");
+                choice.tgi_format_complete = Some(false);
+            }
+        }
+    }
+}
+
+fn is_json_object(text: &str) -> bool {
+    serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(text).is_ok()
+}
+
+/// Code of a Markdown code block without language tag or tagged `json`, e.g. ```` ```json ````
+fn fenced_code(text: &str) -> Option<&str> {
+    let code = text.trim().strip_prefix("```")?.strip_suffix("```")?;
+    let (tag, code) = code.split_once('\n')?;
+    let tag = tag.trim();
+    (tag.is_empty() || tag.eq_ignore_ascii_case("json")).then_some(code)
+}
+
+impl ResponseFormatEnforcement {
+    /// Whether the output of a generation that finished with `finish_reason` is complete with
+    /// respect to the format, `None` when no format constrained it
+    pub(crate) fn format_complete(&self, finish_reason: Option<&FinishReason>) -> Option<bool> {
+        match self {
+            ResponseFormatEnforcement::None => None,
+            _ => Some(!matches!(finish_reason, Some(FinishReason::Length))),
+        }
+    }
+}
+
+/// OpenAI processing tier of a request
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ServiceTier {
+    /// Same as `default`
+    Auto,
+    Default,
+    /// Lower priority, for cost-sensitive batch workloads
+    Flex,
+}
+
+/// `finish_reason` of the OpenAI compatible choices. OpenAI clients branch on the standard
+/// values, so that the end of sequence token and the stop sequences both report `stop`. TGI
+/// does not filter the outputs and never reports `content_filter`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OpenaiFinishReason {
+    Stop,
+    Length,
+}
+
+impl From<FinishReason> for OpenaiFinishReason {
+    fn from(finish_reason: FinishReason) -> Self {
+        match finish_reason {
+            FinishReason::EndOfSequenceToken | FinishReason::StopSequence => Self::Stop,
+            FinishReason::Length => Self::Length,
+        }
+    }
+}
+
+/// Serialize the `finish_reason` of a choice as its OpenAI value. The choices keep the TGI
+/// reason, which tells the end of sequence token from the stop sequences
+fn serialize_finish_reason<S: Serializer>(
+    finish_reason: &Option<FinishReason>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    finish_reason
+        .clone()
+        .map(OpenaiFinishReason::from)
+        .serialize(serializer)
+}
+
+/// TGI extension: server policy that stopped a generation before the model did.
+/// `finish_reason` keeps the nearest standard reason, `length`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ServerFinishReason {
+    /// `max_tokens` was not set, or lowered by the server, and the server limit was reached
+    ServerLengthLimit,
+    /// The stream exceeded the maximum stream duration
+    Timeout,
+}
+
+/// Token that stopped a generation, richer than its `finish_reason`
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct FinishDetails {
+    /// Id of the last generated token
+    #[schema(example = 2)]
+    pub token_id: u32,
+    /// Position of the last token among the generated tokens, from 0
+    #[schema(example = 19)]
+    pub position: u32,
+    /// Stop sequence the output is cut at, when the generation stopped on one
+    #[schema(nullable = true, example = "\nUser:")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequence: Option<String>,
+}
+
+impl FinishDetails {
+    /// Finish details of `response`, before its stop sequence is stripped. `None` without
+    /// generated tokens
+    pub(crate) fn new(response: &GenerateResponse, stop: &[String]) -> Option<Self> {
+        let details = response.details.as_ref()?;
+        let token = details.tokens.last()?;
+        Some(Self {
+            token_id: token.id,
+            position: details.tokens.len() as u32 - 1,
+            stop_sequence: response_stop_sequence(response, stop).map(|(_, stop)| stop.to_string()),
+        })
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct CompletionChoices {
+    #[schema(example = "test")]
+    pub text: String,
+    #[schema(value_type = Option<OpenaiFinishReason>, example = "length")]
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_finish_reason"
+    )]
+    pub finish_reason: Option<FinishReason>,
+    /// TGI extension: set when a server policy stopped the generation
+    #[schema(nullable = true, example = "timeout")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tgi_finish_reason: Option<ServerFinishReason>,
+    /// TGI extension: set when a `response_format` constrained the output. `false` when the
+    /// generation stopped at `max_tokens`, so that the output may be cut before complying with
+    /// the format, e.g. with unclosed JSON
+    #[schema(nullable = true, example = false)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tgi_format_complete: Option<bool>,
+    /// TGI extension: why the generation stopped at the token level, set when
+    /// `finish_details` was requested
+    #[schema(nullable = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tgi_finish_details: Option<FinishDetails>,
+    /// TGI extension: why the generated text is empty, e.g. when the model immediately generated
+    /// its end of sequence token, set with `--empty-generation-notes`
+    #[schema(
+        nullable = true,
+        example = "the generation reached a stop sequence before any text"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tgi_empty_note: Option<String>,
+    // pub generated_tokens: u32,
+    // logprobs are only streamed for the echoed prompt tokens
+    #[schema(nullable = true)]
+    pub logprobs: Option<CompletionLogprobs>,
+    #[schema(example = 0)]
+    pub index: u32,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct CompletionLogprobs {
+    #[schema(example = json ! (["test"]))]
+    pub tokens: Vec<String>,
+    /// `null` for tokens without a log probability, like the first prompt token
+    #[schema(example = json ! ([- 0.34]))]
+    pub token_logprobs: Vec<Option<f32>>,
+    /// `null` for tokens without a log probability, like the first prompt token
+    #[schema(value_type = Vec<Object>, example = json ! ([{"test": - 0.34}]))]
+    pub top_logprobs: Vec<Option<TopLogprobs>>,
+    /// Character offset of each token in the `text` of the choice
+    #[schema(example = json ! ([0]))]
+    pub text_offset: Vec<usize>,
+    /// TGI extension: set when the logprobs were capped by the server, with the number of
+    /// tokens left out at the end of the lists
+    #[schema(nullable = true, example = 12)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tgi_omitted_tokens: Option<usize>,
+}
+
+impl CompletionLogprobs {
+    /// Logprobs of the `prompt` tokens, when echoed, followed by the generated `tokens`. Only the
+    /// first `max_tokens` of them, of all of them when 0
+    fn new(prompt: &[PrefillToken], tokens: &[Token], max_tokens: usize) -> Self {
+        let total = prompt.len() + tokens.len();
+        let kept = match max_tokens {
+            0 => total,
+            max_tokens => total.min(max_tokens),
+        };
+        let (tokens, token_logprobs) = prompt
+            .iter()
+            // The first prompt token has no logprob
+            .map(|token| {
+                (
+                    &token.text,
+                    (!token.logprob.is_nan()).then_some(token.logprob),
+                )
+            })
+            .chain(
+                tokens
+                    .iter()
+                    .map(|token| (&token.text, Some(token.logprob))),
+            )
+            .take(kept)
+            .map(|(text, logprob)| (text.clone(), logprob))
+            .unzip::<_, _, Vec<_>, Vec<_>>();
+        let text_offset = text_offsets(&tokens, 0);
+        let top_logprobs = tokens
+            .iter()
+            .zip(&token_logprobs)
+            .map(|(text, logprob)| logprob.map(|logprob| TopLogprobs::new(text.clone(), logprob)))
+            .collect();
+        Self {
+            tokens,
+            token_logprobs,
+            top_logprobs,
+            text_offset,
+            tgi_omitted_tokens: (total > kept).then_some(total - kept),
+        }
+    }
+}
+
+/// Character offsets of consecutive `tokens`, the first one starting at `start`
+fn text_offsets(tokens: &[String], start: usize) -> Vec<usize> {
+    tokens
+        .iter()
+        .scan(start, |offset, token| {
+            let token_offset = *offset;
+            *offset += token.chars().count();
+            Some(token_offset)
+        })
+        .collect()
+}
+
+/// Most likely tokens at a position, serialized as an OpenAI `{token: logprob}` object.
+/// The backend does not return alternatives, so that only the sampled token is listed
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct TopLogprobs(Vec<(String, f32)>);
+
+impl TopLogprobs {
+    pub(crate) fn new(token: String, logprob: f32) -> Self {
+        Self(vec![(token, logprob)])
+    }
+}
+
+impl Serialize for TopLogprobs {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (token, logprob) in &self.0 {
+            map.serialize_entry(token, logprob)?;
+        }
+        map.end()
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct CompletionsResponse {
+    #[schema(example = "cmpl-abcdefgehij1234")]
+    pub id: String,
+    #[schema(example = "text_completion")]
+    pub object: String,
+    #[schema(value_type = u64, example = 1589478379)]
+    pub created: CompatNumber<u64>,
+    #[schema(example = "tgi")]
+    pub model: String,
+    pub choices: Vec<CompletionChoices>,
+    /// Always set on the responses. `null` on the chunks of the streams, but for their usage
+    /// chunk
+    #[schema(nullable = true)]
+    pub usage: Option<Usage>,
+    #[schema(example = "none")]
+    pub response_format_enforcement: ResponseFormatEnforcement,
+    /// `metadata` of the request
+    #[schema(nullable = true, example = json!({"experiment": "a"}))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<HashMap<String, String>>,
+    /// TGI extension: stop sequences applied to the generation, the default stop sequences of
+    /// the server included, set when `return_stop_sequences` was requested
+    #[schema(nullable = true, example = json!(["\nUser:"]))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tgi_stop_sequences: Option<Vec<String>>,
+    /// TGI extension: timings of the generation, with `--response-timings`
+    #[schema(nullable = true)]
+    #[serde(rename = "__timings", skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatCompletionChoices {
+    #[schema(example = "test")]
+    pub message: ChatMessage,
+    #[schema(value_type = Option<OpenaiFinishReason>, example = "length")]
+    #[serde(serialize_with = "serialize_finish_reason")]
+    pub finish_reason: Option<FinishReason>,
+    /// TGI extension: set when a server policy stopped the generation
+    #[schema(nullable = true, example = "timeout")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tgi_finish_reason: Option<ServerFinishReason>,
+    /// TGI extension: set when a `response_format` constrained the output. `false` when the
+    /// generation stopped at `max_tokens`, so that the output may be cut before complying with
+    /// the format, e.g. with unclosed JSON, or when the finished output does not comply
+    #[schema(nullable = true, example = false)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tgi_format_complete: Option<bool>,
+    /// TGI extension: why the generation stopped at the token level, set when
+    /// `finish_details` was requested
+    #[schema(nullable = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tgi_finish_details: Option<FinishDetails>,
+    /// TGI extension: why the generated text is empty, e.g. when the model immediately generated
+    /// its end of sequence token, set with `--empty-generation-notes`
+    #[schema(
+        nullable = true,
+        example = "the generation reached a stop sequence before any text"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tgi_empty_note: Option<String>,
+    // pub generated_tokens: u32,
+    /// Always present, `null` unless `logprobs` was requested
+    #[schema(nullable = true)]
+    pub logprobs: Option<ChatCompletionLogprobs>,
+    #[schema(example = 0)]
+    pub index: u32,
+}
+
+/// Logprobs of the generated tokens of a chat completion
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatCompletionLogprobs {
+    pub content: Vec<ChatCompletionLogprob>,
+    /// TGI extension: set when the logprobs were capped by the server, with the number of
+    /// generated tokens left out at the end of `content`
+    #[schema(nullable = true, example = 12)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tgi_omitted_tokens: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatCompletionLogprob {
+    #[schema(example = "test")]
+    pub token: String,
+    #[schema(example = -0.34)]
+    pub logprob: f32,
+    /// UTF-8 bytes of the token
+    pub bytes: Vec<u8>,
+    /// The backend does not return alternatives to the generated tokens, so only the sampled
+    /// token is listed, with a `top_logprobs` of 1
+    pub top_logprobs: Vec<ChatCompletionTopLogprob>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatCompletionTopLogprob {
+    #[schema(example = "test")]
+    pub token: String,
+    #[schema(example = -0.34)]
+    pub logprob: f32,
+    pub bytes: Vec<u8>,
+}
+
+impl ChatCompletionLogprobs {
+    /// Logprobs of the first `max_tokens` tokens, of all of them when 0, listing at most
+    /// `top_logprobs` alternatives
+    fn new(tokens: &[Token], max_tokens: usize, top_logprobs: u32) -> Self {
+        let kept = match max_tokens {
+            0 => tokens.len(),
+            max_tokens => tokens.len().min(max_tokens),
+        };
+        let omitted = tokens.len() - kept;
+        let content = tokens[..kept]
+            .iter()
+            .map(|token| ChatCompletionLogprob {
+                token: token.text.clone(),
+                logprob: token.logprob,
+                bytes: token.text.as_bytes().to_vec(),
+                top_logprobs: (top_logprobs > 0)
+                    .then(|| ChatCompletionTopLogprob {
+                        token: token.text.clone(),
+                        logprob: token.logprob,
+                        bytes: token.text.as_bytes().to_vec(),
+                    })
+                    .into_iter()
+                    .collect(),
+            })
+            .collect();
+        Self {
+            content,
+            tgi_omitted_tokens: (omitted > 0).then_some(omitted),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatCompletionDeltaStreamChoices {
+    #[schema(example = "test")]
+    pub delta: ChatDeltaStreamMessage,
+    #[schema(value_type = Option<OpenaiFinishReason>, example = "length")]
+    #[serde(serialize_with = "serialize_finish_reason")]
+    pub finish_reason: Option<FinishReason>,
+    /// TGI extension: set when a server policy stopped the generation
+    #[schema(nullable = true, example = "timeout")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tgi_finish_reason: Option<ServerFinishReason>,
+    // pub generated_tokens: u32,
+    #[schema(example = 0)]
+    pub index: u32,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatCompletionsResponse {
+    #[schema(example = "chatcmpl-abcdefgehij1234")]
+    pub id: String,
+    #[schema(example = "chat.completion")]
+    pub object: String,
+    #[schema(value_type = u64, example = 1589478380)]
+    pub created: CompatNumber<u64>,
+    #[schema(example = "tgi")]
+    pub model: String,
+    pub choices: Vec<ChatCompletionChoices>,
+    pub usage: Usage,
+    #[schema(example = "none")]
+    pub response_format_enforcement: ResponseFormatEnforcement,
+    /// `metadata` of the request
+    #[schema(nullable = true, example = json!({"experiment": "a"}))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<HashMap<String, String>>,
+    /// TGI extension: stop sequences applied to the generation, the default stop sequences of
+    /// the server included, set when `return_stop_sequences` was requested
+    #[schema(nullable = true, example = json!(["\nUser:"]))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tgi_stop_sequences: Option<Vec<String>>,
+    /// TGI extension: timings of the generation, with `--response-timings`
+    #[schema(nullable = true)]
+    #[serde(rename = "__timings", skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+}
+
+/// Responses to the conversations of a batch, as separate completion objects
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatCompletionsListResponse {
+    #[schema(example = "list")]
+    pub object: String,
+    pub data: Vec<ChatCompletionsResponse>,
+}
+
+impl ChatCompletionsListResponse {
+    pub(crate) fn new(data: Vec<ChatCompletionsResponse>) -> Self {
+        Self {
+            object: String::from("list"),
+            data,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatCompletionsStreamResponse {
+    #[schema(example = "chatcmpl-abcdefgehij1234")]
+    pub id: String,
+    #[schema(example = "chat.completion.chunk")]
+    pub object: String,
+    #[schema(value_type = u64, example = 1589478380)]
+    pub created: CompatNumber<u64>,
+    #[schema(example = "tgi")]
+    pub model: String,
+    pub choices: Vec<ChatCompletionDeltaStreamChoices>,
+    /// `null` but for the usage chunk ending the streams that requested it
+    #[schema(nullable = true)]
+    pub usage: Option<Usage>,
+}
+
+/// `TGICHAT_*` templates of the environment, read once at startup
+pub(crate) fn get_chatformatter(chat_config: &ChatConfig) -> ChatFormatter {
+    let env = |name: &str| {
+        std::env::var_os(name).map(|v| {
+            v.into_string()
+                .unwrap_or_else(|_| panic!("{name} is not valid unicode"))
+        })
+    };
+    let chat_user_pre = env("TGICHAT_USER_PRE").unwrap_or_default();
+    let chat_user_post = env("TGICHAT_USER_POST").unwrap_or_default();
+    let chat_ass_pre = env("TGICHAT_ASS_PRE").unwrap_or_default();
+    let chat_ass_post = env("TGICHAT_ASS_POST").unwrap_or_default();
+    let chat_sys_pre = env("TGICHAT_SYS_PRE").unwrap_or_default();
+    let chat_sys_post = env("TGICHAT_SYS_POST").unwrap_or_default();
+    // Tool messages are wrapped like system messages unless a context template is set
+    let chat_context_pre = env("TGICHAT_CONTEXT_PRE");
+    let chat_context_post = env("TGICHAT_CONTEXT_POST");
+    let context_template = match (chat_context_pre, chat_context_post) {
+        (None, None) => None,
+        (pre, post) => Some(ChatFormatterPrePost {
+            pre: pre.unwrap_or_default(),
+            post: post.unwrap_or_default(),
+        }),
+    };
+
+    ChatFormatter {
+        user_template: ChatFormatterPrePost {
+            pre: chat_user_pre,
+            post: chat_user_post,
+        },
+        assistant_template: ChatFormatterPrePost {
+            pre: chat_ass_pre,
+            post: chat_ass_post,
+        },
+        system_template: ChatFormatterPrePost {
+            pre: chat_sys_pre,
+            post: chat_sys_post,
+        },
+        context_template,
+        role_templates: chat_config.role_templates.clone(),
+        default_role_template: chat_config.default_role_template.clone(),
+        chat_template: None,
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ModelsResponse {
+    #[schema(example = "list")]
+    pub object: String,
+    pub data: Vec<ModelCard>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ModelCard {
+    #[schema(example = "bigscience/blomm-560m")]
+    pub id: String,
+    #[schema(example = "model")]
+    pub object: String,
+    #[schema(example = 1589478379)]
+    pub created: u64,
+    #[schema(example = "tgi")]
+    pub owned_by: String,
+    /// Served model of an alias
+    #[schema(nullable = true, example = "bigscience/blomm-560m")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<String>,
+    /// TGI extension: features supported by this deployment
+    pub tgi_capabilities: ModelCapabilities,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub(crate) struct ModelCapabilities {
+    #[schema(example = true)]
+    pub streaming: bool,
+    #[schema(example = true)]
+    pub echo: bool,
+    #[schema(example = true)]
+    pub logprobs: bool,
+    #[schema(example = true)]
+    pub logit_bias: bool,
+    #[schema(example = false)]
+    pub tools: bool,
+    #[schema(example = true)]
+    pub json_mode: bool,
+    #[schema(example = "prompt-only")]
+    pub response_format_enforcement: ResponseFormatEnforcement,
+    #[schema(example = 2)]
+    pub max_best_of: usize,
+    #[schema(example = 4)]
+    pub max_stop_sequences: usize,
+    #[schema(example = 1024)]
+    pub max_input_length: usize,
+    #[schema(example = 2048)]
+    pub max_total_tokens: usize,
+    #[schema(nullable = true, example = 600)]
+    pub max_stream_duration: Option<u64>,
+    #[schema(example = "0.5.0")]
+    pub version: &'static str,
+}
+
+pub(crate) fn models_response(info: &Info, compat_config: &CompatConfig) -> ModelsResponse {
+    // JSON mode is requested through the prompt, as with `response_format`
+    let response_format_enforcement = ResponseFormat::JsonObject.enforcement();
+    let capabilities = ModelCapabilities {
+        streaming: true,
+        echo: true,
+        // The logprobs of the chosen tokens are returned on both routes
+        logprobs: true,
+        logit_bias: true,
+        tools: false,
+        json_mode: response_format_enforcement != ResponseFormatEnforcement::None,
+        response_format_enforcement,
+        max_best_of: info.max_best_of,
+        max_stop_sequences: info.max_stop_sequences,
+        max_input_length: info.max_input_length,
+        max_total_tokens: info.max_total_tokens,
+        max_stream_duration: compat_config
+            .max_stream_duration
+            .map(|max_stream_duration| max_stream_duration.as_secs()),
+        version: info.version,
+    };
+    let created = create_timestamp();
+    let model = ModelCard {
+        id: info.model_id.clone(),
+        object: String::from("model"),
+        created,
+        owned_by: String::from("tgi"),
+        root: None,
+        tgi_capabilities: capabilities.clone(),
+    };
+    let aliases = compat_config.model_aliases.iter().map(|alias| ModelCard {
+        id: alias.clone(),
+        object: String::from("model"),
+        created,
+        owned_by: String::from("tgi"),
+        root: Some(info.model_id.clone()),
+        tgi_capabilities: capabilities.clone(),
+    });
+    ModelsResponse {
+        object: String::from("list"),
+        data: std::iter::once(model).chain(aliases).collect(),
+    }
+}
+
+/// Prompt tokens of a generation: the count of the validation, or else the prefill when it was
+/// returned, with `decoder_input_details`, or else the count of the router
+fn prompt_tokens(details: Option<&Details>, options: &CompatRequestOptions) -> u32 {
+    match details {
+        Some(Details {
+            prompt_tokens: Some(prompt_tokens),
+            ..
+        }) => *prompt_tokens,
+        Some(details) if !details.prefill.is_empty() => details.prefill.len() as u32,
+        _ => options.prompt_tokens.unwrap_or(0),
+    }
+}
+
+pub(crate) async fn generate_to_completions(
+    resp: Json<GenerateResponse>,
+    model: String,
+    compat_config: &CompatConfig,
+    options: &CompatRequestOptions,
+) -> Json<CompletionsResponse> {
+    // let details = resp.details.as_ref().ok_or("details missing"); //;
+    let details = resp.details.as_ref();
+
+    let gen_tokens = match details {
+        Some(details) => details.generated_tokens,
+        None => 0,
+    };
+    let finish_reason = details.map(|details| details.finish_reason.clone());
+    let tgi_finish_reason = finish_reason
+        .as_ref()
+        .and_then(|finish_reason| compat_config.server_finish_reason(finish_reason, options));
+    let prefill_len = prompt_tokens(details, options);
+    let truncated_tokens = details.map_or(0, |details| details.truncated_tokens);
+
+    let response_format_enforcement = ResponseFormatEnforcement::None;
+    let text = compat_config
+        .trailing_whitespace
+        .trim(&resp.generated_text)
+        .to_string();
+    let tgi_empty_note = compat_config.empty_generation_note(&text, finish_reason.as_ref());
+    let choices = CompletionChoices {
+        text,
+        tgi_format_complete: response_format_enforcement.format_complete(finish_reason.as_ref()),
+        finish_reason,
+        tgi_finish_reason,
+        tgi_finish_details: None,
+        tgi_empty_note,
+        logprobs: details.filter(|_| options.logprobs).map(|details| {
+            let prompt = if options.echo {
+                &details.prefill[..]
+            } else {
+                &[]
+            };
+            CompletionLogprobs::new(prompt, &details.tokens, compat_config.max_logprobs_tokens)
+        }),
+        index: 0,
+    };
+    let usage = Some(
+        Usage::new(prefill_len, gen_tokens, compat_config).with_truncated_tokens(truncated_tokens),
+    );
+    let created_time = compat_config.number(create_timestamp());
+    let resp: CompletionsResponse = CompletionsResponse {
+        choices: vec![choices],
+        created: created_time,
+        id: create_id(&OpenaiStreamType::CompletionsResponse),
+        object: String::from("text_completion"),
+        model,
+        usage,
+        response_format_enforcement,
+        metadata: None,
+        tgi_stop_sequences: None,
+        timings: compat_config
+            .response_timings
+            .then(|| details.and_then(|details| details.timings.clone()))
+            .flatten(),
+    };
+    Json(resp)
+}
+
+pub(crate) async fn generate_to_chatcompletions(
+    resp: Json<GenerateResponse>,
+    model: String,
+    compat_config: &CompatConfig,
+    options: &CompatRequestOptions,
+) -> Json<ChatCompletionsResponse> {
+    // let details = resp.details.as_ref().ok_or("details missing"); //;
+    let details = resp.details.as_ref();
+
+    let gen_tokens = match details {
+        Some(details) => details.generated_tokens,
+        None => 0,
+    };
+    let finish_reason = details.map(|details| details.finish_reason.clone());
+    let tgi_finish_reason = finish_reason
+        .as_ref()
+        .and_then(|finish_reason| compat_config.server_finish_reason(finish_reason, options));
+    let prefill_len = prompt_tokens(details, options);
+    let truncated_tokens = details.map_or(0, |details| details.truncated_tokens);
+
+    let response_format_enforcement = options.response_format.enforcement();
+    let content = match compat_config.assistant_prefix() {
+        Some(prefix) => strip_assistant_prefix(&resp.generated_text, &prefix),
+        None => &resp.generated_text,
+    };
+    let content = compat_config.trailing_whitespace.trim(content).to_string();
+    let tgi_empty_note = compat_config.empty_generation_note(&content, finish_reason.as_ref());
+    let mut choices = ChatCompletionChoices {
+        message: ChatMessage {
+            role: compat_config.chat_config.assistant_role(),
+            content: ChatMessageContent::Text(content),
+        },
+        tgi_format_complete: response_format_enforcement.format_complete(finish_reason.as_ref()),
+        finish_reason,
+        tgi_finish_reason,
+        tgi_finish_details: None,
+        tgi_empty_note,
+        logprobs: details.filter(|_| options.logprobs).map(|details| {
+            ChatCompletionLogprobs::new(
+                &details.tokens,
+                compat_config.max_logprobs_tokens,
+                options.top_logprobs,
+            )
+        }),
+        index: 0,
+    };
+    options.response_format.check_output(&mut choices);
+    let usage = Usage::new(prefill_len, gen_tokens, compat_config)
+        .with_truncated_tokens(truncated_tokens)
+        .with_evicted_turns(options.evicted_turns);
+    let created_time = compat_config.number(create_timestamp());
+    let resp = ChatCompletionsResponse {
+        choices: vec![choices],
+        created: created_time,
+        id: create_id(&OpenaiStreamType::ChatCompletionsStreamResponse),
+        object: String::from("chat.completion"),
+        model,
+        usage,
+        response_format_enforcement,
+        metadata: None,
+        tgi_stop_sequences: None,
+        timings: compat_config
+            .response_timings
+            .then(|| details.and_then(|details| details.timings.clone()))
+            .flatten(),
+    };
+    Json(resp)
+}
+
+/// Response to a batch of conversations: the choices of the responses to each conversation, in
+/// order, with the total usage
+pub(crate) fn batch_chatcompletions(
+    responses: Vec<ChatCompletionsResponse>,
+    compat_config: &CompatConfig,
+) -> Option<ChatCompletionsResponse> {
+    let usage = batch_usage(&responses, compat_config);
+    let mut responses = responses.into_iter();
+    let mut batch = responses.next()?;
+    for response in responses {
+        batch.choices.extend(response.choices);
+    }
+    for (index, choice) in batch.choices.iter_mut().enumerate() {
+        choice.index = index as u32;
+    }
+    batch.usage = usage;
+    Some(batch)
+}
+
+/// Seeds of the `n` choices of a request. The choices of a request with a `seed` get consecutive
+/// seeds, so that they differ from each other but are reproducible. Without a `seed`, each choice
+/// gets an independent random seed
+pub(crate) fn choice_seeds(seed: Option<u64>, n: u32) -> Vec<Option<u64>> {
+    (0..n)
+        .map(|index| seed.map(|seed| seed.wrapping_add(index as u64)))
+        .collect()
+}
+
+/// Response to a completions request with several prompts: the choices of the responses to
+/// each prompt, in order, with the total usage
+pub(crate) fn batch_completions(
+    responses: Vec<CompletionsResponse>,
+    compat_config: &CompatConfig,
+) -> Option<CompletionsResponse> {
+    let usages = || responses.iter().filter_map(|r| r.usage.as_ref());
+    let prompt_tokens = usages().map(|usage| usage.prompt_tokens.value).sum();
+    let completion_tokens = usages().map(|usage| usage.completion_tokens.value).sum();
+    let truncated_tokens = usages().map(|usage| usage.truncated_tokens()).sum();
+    let usage = Usage::new(prompt_tokens, completion_tokens, compat_config)
+        .with_truncated_tokens(truncated_tokens);
+    let mut responses = responses.into_iter();
+    let mut batch = responses.next()?;
+    for response in responses {
+        batch.choices.extend(response.choices);
+    }
+    for (index, choice) in batch.choices.iter_mut().enumerate() {
+        choice.index = index as u32;
+    }
+    batch.usage = Some(usage);
+    Some(batch)
+}
+
+/// Response with the choices generated for a request with `n` > 1, in order. The prompt is
+/// shared by the choices, and only counted once in the usage
+pub(crate) fn merge_completions(
+    responses: Vec<CompletionsResponse>,
+    compat_config: &CompatConfig,
+) -> Option<CompletionsResponse> {
+    let completion_tokens = responses
+        .iter()
+        .filter_map(|r| r.usage.as_ref())
+        .map(|usage| usage.completion_tokens.value)
+        .sum();
+    let mut responses = responses.into_iter();
+    let mut merged = responses.next()?;
+    for response in responses {
+        merged.choices.extend(response.choices);
+    }
+    for (index, choice) in merged.choices.iter_mut().enumerate() {
+        choice.index = index as u32;
+    }
+    if let Some(usage) = &mut merged.usage {
+        *usage = Usage::new(usage.prompt_tokens.value, completion_tokens, compat_config)
+            .with_truncated_tokens(usage.truncated_tokens());
+    }
+    Some(merged)
+}
+
+/// Chat response with the choices generated for a request with `n` > 1, in order. The prompt is
+/// shared by the choices, and only counted once in the usage
+pub(crate) fn merge_chatcompletions(
+    responses: Vec<ChatCompletionsResponse>,
+    compat_config: &CompatConfig,
+) -> Option<ChatCompletionsResponse> {
+    let prompt_tokens = responses.first()?.usage.prompt_tokens.value;
+    let truncated_tokens = responses.first()?.usage.truncated_tokens();
+    let evicted_turns = responses.first()?.usage.evicted_turns();
+    let completion_tokens = responses
+        .iter()
+        .map(|r| r.usage.completion_tokens.value)
+        .sum();
+    let mut merged = batch_chatcompletions(responses, compat_config)?;
+    merged.usage = Usage::new(prompt_tokens, completion_tokens, compat_config)
+        .with_truncated_tokens(truncated_tokens)
+        .with_evicted_turns(evicted_turns);
+    Some(merged)
+}
+
+/// Total usage of the responses to a batch of conversations
+pub(crate) fn batch_usage(
+    responses: &[ChatCompletionsResponse],
+    compat_config: &CompatConfig,
+) -> Usage {
+    let prompt_tokens = responses.iter().map(|r| r.usage.prompt_tokens.value).sum();
+    let completion_tokens = responses
+        .iter()
+        .map(|r| r.usage.completion_tokens.value)
+        .sum();
+    let truncated_tokens = responses.iter().map(|r| r.usage.truncated_tokens()).sum();
+    let evicted_turns = responses.iter().map(|r| r.usage.evicted_turns()).sum();
+    Usage::new(prompt_tokens, completion_tokens, compat_config)
+        .with_truncated_tokens(truncated_tokens)
+        .with_evicted_turns(evicted_turns)
+}
+
+pub(crate) fn create_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_secs()
+}
+
+/// Unique `id` of a response, e.g. `cmpl-` followed by 32 random hex digits. Unlike the
+/// `created` timestamp, the ids of the requests of the same second never collide. All the chunks
+/// of a stream share the id of the stream
+pub(crate) fn create_id(stream_type: &OpenaiStreamType) -> String {
+    let prefix = match stream_type {
+        OpenaiStreamType::ChatCompletionsStreamResponse => "chatcmpl",
+        OpenaiStreamType::CompletionsResponse => "cmpl",
+    };
+    format!("{prefix}-{:032x}", rand::random::<u128>())
+}
+
+pub(crate) fn chat_start_message(
+    created_time: CompatNumber<u64>,
+    id: &str,
+    model_name: &String,
+    role: ChatRole,
+) -> ChatCompletionsStreamResponse {
+    let choices: ChatCompletionDeltaStreamChoices = ChatCompletionDeltaStreamChoices {
+        delta: ChatDeltaStreamMessage {
+            content: None,
+            role: Some(role),
+        },
+        finish_reason: None,
+        tgi_finish_reason: None,
+        index: 0,
+    };
+    ChatCompletionsStreamResponse {
+        choices: vec![choices],
+        created: created_time,
+        id: id.to_string(),
+        object: String::from("chat.completion.chunk"),
+        model: model_name.to_owned(),
+        usage: None,
+    }
+}
+
+/// Names of the Server-Sent Events of the OpenAI compatible streams, with `sse_event_names`
+pub(crate) const CHUNK_EVENT: &str = "chunk";
+pub(crate) const DONE_EVENT: &str = "done";
+pub(crate) const ERROR_EVENT: &str = "error";
+
+/// Server-Sent Event carrying a streamed chunk. The JSON encoding escapes line breaks, so a
+/// chunk is always a single `data` line, whatever the size of its content
+pub(crate) fn stream_event<T: Serialize>(chunk: T) -> Event {
+    Event::default().json_data(chunk).unwrap_or_else(|err| {
+        // Do not abort the stream on a chunk that cannot be serialized
+        tracing::error!("Cannot serialize stream chunk: {err}");
+        Event::default()
+            .json_data(ErrorResponse {
+                error: err.to_string(),
+                error_type: "serialization".to_string(),
+                param: None,
+            })
+            .unwrap()
+    })
+}
+
+pub(crate) fn create_streaming_event(
+    // st: StreamResponse,
+    stream_type: &OpenaiStreamType,
+    created_time: CompatNumber<u64>,
+    id: &str,
+    details: Option<StreamDetails>,
+    tgi_finish_reason: Option<ServerFinishReason>,
+    token: Token,
+    model_name: &String,
+) -> Event {
+    match *stream_type {
+        OpenaiStreamType::ChatCompletionsStreamResponse => {
+            let choices: ChatCompletionDeltaStreamChoices = ChatCompletionDeltaStreamChoices {
+                delta: ChatDeltaStreamMessage {
+                    content: Some(token.text),
+                    role: None,
+                },
+                finish_reason: details.map(|i| i.finish_reason),
+                tgi_finish_reason,
+                index: 0,
+            };
+            let response = ChatCompletionsStreamResponse {
+                choices: vec![choices],
+                created: created_time,
+                id: id.to_string(),
+                object: String::from("chat.completion.chunk"),
+                model: model_name.to_owned(),
+                usage: None,
+            };
+            stream_event(response)
+        }
+        OpenaiStreamType::CompletionsResponse => {
+            let choices = CompletionChoices {
+                text: token.text,
+                finish_reason: details.map(|i| i.finish_reason),
+                tgi_finish_reason,
+                tgi_format_complete: None,
+                tgi_finish_details: None,
+                tgi_empty_note: None,
+                logprobs: None,
+                index: 0,
+            };
+
+            let response = CompletionsResponse {
+                choices: vec![choices],
+                created: created_time,
+                id: id.to_string(),
+                object: String::from("text_completion"),
+                model: model_name.to_owned(),
+                usage: None,
+                response_format_enforcement: ResponseFormatEnforcement::None,
+                metadata: None,
+                tgi_stop_sequences: None,
+                timings: None,
+            };
+            stream_event(response)
+        }
+    }
+}
+
+/// Completions chunk echoing the whole prompt, sent before the generated tokens
+pub(crate) fn echo_prompt_chunk(
+    created_time: CompatNumber<u64>,
+    id: &str,
+    prompt: String,
+    model_name: &String,
+) -> CompletionsResponse {
+    completions_chunk(created_time, id, prompt, None, model_name)
+}
+
+/// Completions chunks echoing the prompt one token at a time, with the prompt tokens logprobs
+pub(crate) fn echo_prompt_token_chunks(
+    created_time: CompatNumber<u64>,
+    id: &str,
+    prefill: PrefillTokens,
+    model_name: &String,
+) -> Vec<CompletionsResponse> {
+    let text_offset = text_offsets(&prefill.texts, 0);
+    prefill
+        .texts
+        .into_iter()
+        .zip(prefill.logprobs)
+        .zip(text_offset)
+        .map(|((text, logprob), text_offset)| {
+            // The first prompt token has no logprob
+            let logprob = (!logprob.is_nan()).then_some(logprob);
+            // The backend does not return alternatives to the prompt tokens
+            let top_logprobs = logprob.map(|logprob| TopLogprobs::new(text.clone(), logprob));
+            let logprobs = CompletionLogprobs {
+                tokens: vec![text.clone()],
+                token_logprobs: vec![logprob],
+                top_logprobs: vec![top_logprobs],
+                text_offset: vec![text_offset],
+                tgi_omitted_tokens: None,
+            };
+            completions_chunk(created_time, id, text, Some(logprobs), model_name)
+        })
+        .collect()
+}
+
+fn completions_chunk(
+    created_time: CompatNumber<u64>,
+    id: &str,
+    text: String,
+    logprobs: Option<CompletionLogprobs>,
+    model_name: &String,
+) -> CompletionsResponse {
+    let choices = CompletionChoices {
+        text,
+        finish_reason: None,
+        tgi_finish_reason: None,
+        tgi_format_complete: None,
+        tgi_finish_details: None,
+        tgi_empty_note: None,
+        logprobs,
+        index: 0,
+    };
+    CompletionsResponse {
+        choices: vec![choices],
+        created: created_time,
+        id: id.to_string(),
+        object: String::from("text_completion"),
+        model: model_name.to_owned(),
+        usage: None,
+        response_format_enforcement: ResponseFormatEnforcement::None,
+        metadata: None,
+        tgi_stop_sequences: None,
+        timings: None,
+    }
+}
+
+/// Terminal chunk, used when the server stops a stream on its own
+pub(crate) fn create_stream_end_event(
+    stream_type: &OpenaiStreamType,
+    created_time: CompatNumber<u64>,
+    id: &str,
+    finish_reason: FinishReason,
+    tgi_finish_reason: Option<ServerFinishReason>,
+    text: Option<String>,
+    model_name: &String,
+) -> Event {
+    match *stream_type {
+        OpenaiStreamType::ChatCompletionsStreamResponse => {
+            let choices = ChatCompletionDeltaStreamChoices {
+                delta: ChatDeltaStreamMessage {
+                    content: text,
+                    role: None,
+                },
+                finish_reason: Some(finish_reason),
+                tgi_finish_reason,
+                index: 0,
+            };
+            let response = ChatCompletionsStreamResponse {
+                choices: vec![choices],
+                created: created_time,
+                id: id.to_string(),
+                object: String::from("chat.completion.chunk"),
+                model: model_name.to_owned(),
+                usage: None,
+            };
+            stream_event(response)
+        }
+        OpenaiStreamType::CompletionsResponse => {
+            let choices = CompletionChoices {
+                text: text.unwrap_or_default(),
+                finish_reason: Some(finish_reason),
+                tgi_finish_reason,
+                tgi_format_complete: None,
+                tgi_finish_details: None,
+                tgi_empty_note: None,
+                logprobs: None,
+                index: 0,
+            };
+            let response = CompletionsResponse {
+                choices: vec![choices],
+                created: created_time,
+                id: id.to_string(),
+                object: String::from("text_completion"),
+                model: model_name.to_owned(),
+                usage: None,
+                response_format_enforcement: ResponseFormatEnforcement::None,
+                metadata: None,
+                tgi_stop_sequences: None,
+                timings: None,
+            };
+            stream_event(response)
+        }
+    }
+}
+
+/// Events closing a stream: the usage chunk, when requested, then `[DONE]`. Every path ending a
+/// stream with `[DONE]` sends them, so that the usage is never missing
+pub(crate) fn stream_done_events(
+    stream_type: &OpenaiStreamType,
+    created_time: CompatNumber<u64>,
+    id: &str,
+    usage: Option<Usage>,
+    model_name: &String,
+    compat_config: &CompatConfig,
+) -> Vec<Event> {
+    let usage = usage.map(|usage| {
+        let event = create_usage_event(stream_type, created_time, id, usage, model_name);
+        compat_config.sse_event(event, CHUNK_EVENT)
+    });
+    usage
+        .into_iter()
+        .chain(std::iter::once(compat_config.done_event()))
+        .collect()
+}
+
+/// Chunk ending the streams that requested `stream_options.include_usage`, after the last token
+/// and before `[DONE]`. Like OpenAI's, it has no choices
+pub(crate) fn create_usage_event(
+    stream_type: &OpenaiStreamType,
+    created_time: CompatNumber<u64>,
+    id: &str,
+    usage: Usage,
+    model_name: &String,
+) -> Event {
+    match *stream_type {
+        OpenaiStreamType::ChatCompletionsStreamResponse => {
+            stream_event(ChatCompletionsStreamResponse {
+                choices: vec![],
+                created: created_time,
+                id: id.to_string(),
+                object: String::from("chat.completion.chunk"),
+                model: model_name.to_owned(),
+                usage: Some(usage),
+            })
+        }
+        OpenaiStreamType::CompletionsResponse => stream_event(CompletionsResponse {
+            choices: vec![],
+            created: created_time,
+            id: id.to_string(),
+            object: String::from("text_completion"),
+            model: model_name.to_owned(),
+            usage: Some(usage),
+            response_format_enforcement: ResponseFormatEnforcement::None,
+            metadata: None,
+            tgi_stop_sequences: None,
+            timings: None,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completion_request(body: &str) -> GenerateRequest {
+        serde_json::from_str::<CompatCompletionRequest>(body)
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn test_request_hash() {
+        let hash = request_hash(&completion_request(r#"{"prompt": "Hello"}"#));
+        assert_eq!(hash.len(), 64);
+        assert_eq!(
+            hash,
+            request_hash(&completion_request(r#"{"prompt": "Hello"}"#))
+        );
+
+        // Defaults and field order do not change the normalized request
+        let explicit = completion_request(
+            r#"{"max_tokens": 20, "prompt": "Hello", "logit_bias": {"2": 1.0, "1": -1.0}}"#,
+        );
+        let reordered =
+            completion_request(r#"{"logit_bias": {"1": -1.0, "2": 1.0}, "prompt": "Hello"}"#);
+        assert_eq!(request_hash(&explicit), request_hash(&reordered));
+
+        assert_ne!(
+            hash,
+            request_hash(&completion_request(r#"{"prompt": "Hello", "seed": 1}"#))
+        );
+    }
+
+    fn chat_formatter() -> ChatFormatter {
+        let template = |pre: &str, post: &str| ChatFormatterPrePost {
+            pre: pre.to_string(),
+            post: post.to_string(),
+        };
+        ChatFormatter {
+            user_template: template("[INST] ", " [/INST]"),
+            assistant_template: template(" ", "</s>"),
+            system_template: template("<<SYS>> ", " <</SYS>>"),
+            context_template: None,
+            role_templates: HashMap::new(),
+            default_role_template: None,
+            chat_template: None,
+        }
+    }
+
+    #[test]
+    fn test_chat_formatter_hash() {
+        let hash = chat_formatter().hash();
+        assert_eq!(hash.len(), 64);
+        assert_eq!(chat_formatter().hash(), hash);
+
+        let mut formatter = chat_formatter();
+        formatter.role_templates.insert(
+            "ipython".to_string(),
+            ChatFormatterPrePost {
+                pre: "<ipython> ".to_string(),
+                post: String::new(),
+            },
+        );
+        assert_ne!(formatter.hash(), hash);
+        formatter.role_templates.clear();
+        formatter.assistant_template.post = "<|eot|>".to_string();
+        assert_ne!(formatter.hash(), hash);
+    }
+
+    #[test]
+    fn test_assistant_prefix() {
+        let mut compat_config = CompatConfig {
+            chat_formatter: Arc::new(chat_formatter()),
+            ..Default::default()
+        };
+        assert_eq!(compat_config.assistant_prefix(), None);
+        // The prefix comes from the formatter built at startup
+        compat_config.chat_config.strip_assistant_prefix = true;
+        assert_eq!(compat_config.assistant_prefix(), None);
+        compat_config.chat_formatter = Arc::new(ChatFormatter {
+            assistant_template: ChatFormatterPrePost {
+                pre: "<|assistant|> ".to_string(),
+                post: String::new(),
+            },
+            ..chat_formatter()
+        });
+        assert_eq!(
+            compat_config.assistant_prefix(),
+            Some("<|assistant|>".to_string())
+        );
+    }
+
+    #[test]
+    fn test_jinja_chat_template() {
+        let template = ChatTemplate::new(
+            "{% for message in messages %}{{ '<|' + message['role'] + '|>' + message['content'] + eos_token }}{% endfor %}{% if add_generation_prompt %}{{ '<|assistant|>' }}{% endif %}".to_string(),
+            "tokenizer_config.json".to_string(),
+            None,
+            Some("</s>".to_string()),
+        )
+        .unwrap();
+        let compat_config = CompatConfig {
+            chat_template: Some(Arc::new(template)),
+            ..Default::default()
+        };
+        let formatter = compat_config.build_chat_formatter();
+        assert_ne!(formatter.hash(), chat_formatter().hash());
+        assert_eq!(formatter.assistant_prefix(), None);
+
+        let prompt = |body: &str, chat_config: &ChatConfig| {
+            let req: CompatChatCompletionRequest = serde_json::from_str(body).unwrap();
+            // The rendered conversation has no prefix known in advance
+            assert_eq!(
+                chat_prompt_prefix(&req.messages, &formatter, chat_config),
+                ""
+            );
+            chat_to_generate_request(req, &formatter, chat_config).map(|req| req.inputs)
+        };
+        // The template replaces the `TGICHAT_*` templates, and opens the assistant turn
+        let body = r#"{"messages": [{"role": "system", "content": "Be brief"}, {"role": "user", "content": "Hi"}]}"#;
+        assert_eq!(
+            prompt(body, &ChatConfig::default()).unwrap(),
+            "<|system|>Be brief</s><|user|>Hi</s><|assistant|>"
+        );
+
+        // Consecutive messages are merged before rendering
+        let body = r#"{"messages": [{"role": "user", "content": "Hi"}, {"role": "user", "content": "there"}]}"#;
+        let chat_config = ChatConfig {
+            consecutive_user_messages: ConsecutiveMessages::Merge,
+            ..Default::default()
+        };
+        assert_eq!(
+            prompt(body, &chat_config).unwrap(),
+            "<|user|>Hi\n\nthere</s><|assistant|>"
+        );
+
+        // The final assistant message is left open
+        let body = r#"{"messages": [{"role": "user", "content": "Hi"}, {"role": "assistant", "content": "Hello"}], "continue_final_message": true}"#;
+        assert_eq!(
+            prompt(body, &ChatConfig::default()).unwrap(),
+            "<|user|>Hi</s><|assistant|>Hello"
+        );
+        // Even when it is empty
+        let body = r#"{"messages": [{"role": "user", "content": "Hi"}, {"role": "assistant", "content": ""}], "continue_final_message": true}"#;
+        assert_eq!(
+            prompt(body, &ChatConfig::default()).unwrap(),
+            "<|user|>Hi</s><|assistant|>"
+        );
+
+        // Or transformed by the template
+        let template = ChatTemplate::new(
+            "{% for message in messages %}{{ '<|' + message['role'] + '|>' + message['content'] | trim + eos_token }}{% endfor %}".to_string(),
+            "tokenizer_config.json".to_string(),
+            None,
+            Some("</s>".to_string()),
+        )
+        .unwrap();
+        let compat_config = CompatConfig {
+            chat_template: Some(Arc::new(template)),
+            ..Default::default()
+        };
+        let body = r#"{"messages": [{"role": "user", "content": "Hi"}, {"role": "assistant", "content": " Hello"}], "continue_final_message": true}"#;
+        let req: CompatChatCompletionRequest = serde_json::from_str(body).unwrap();
+        let req = chat_to_generate_request(
+            req,
+            &compat_config.build_chat_formatter(),
+            &ChatConfig::default(),
+        );
+        assert_eq!(req.unwrap().inputs, "<|user|>Hi</s><|assistant|>Hello");
+    }
+
+    #[test]
+    fn test_blank_content() {
+        let content = |content: &str| serde_json::from_str::<ChatMessageContent>(content).unwrap();
+        assert!(content(r#"" \n\t""#).is_blank());
+        assert!(
+            content(r#"[{"type": "text", "text": " "}, {"type": "text", "text": ""}]"#).is_blank()
+        );
+        assert!(!content(r#"" Hi ""#).is_blank());
+        assert!(
+            !content(r#"[{"type": "text", "text": " "}, {"type": "text", "text": "Hi"}]"#)
+                .is_blank()
+        );
+    }
+
+    #[test]
+    fn test_empty_content() {
+        let prompt = |body: &str, empty_content: EmptyContent| {
+            let req: CompatChatCompletionRequest = serde_json::from_str(body).unwrap();
+            let chat_config = ChatConfig {
+                empty_content,
+                consecutive_user_messages: ConsecutiveMessages::PassThrough,
+                ..Default::default()
+            };
+            chat_to_generate_request(req, &chat_formatter(), &chat_config).map(|req| req.inputs)
+        };
+        let body = r#"{"messages": [
+            {"role": "user", "content": "Hi"},
+            {"role": "user", "content": ""}
+        ]}"#;
+
+        assert_eq!(
+            prompt(body, EmptyContent::Skip).unwrap(),
+            "[INST] Hi [/INST]"
+        );
+        assert!(matches!(
+            prompt(body, EmptyContent::Error),
+            Err(ValidationError::EmptyContent(1))
+        ));
+        assert_eq!(
+            prompt(body, EmptyContent::PassThrough).unwrap(),
+            "[INST] Hi [/INST][INST]  [/INST]"
+        );
+
+        // Left for the empty input validation, without delimiters
+        let body = r#"{"messages": [{"role": "user", "content": [{"type": "text", "text": ""}]}]}"#;
+        assert_eq!(prompt(body, EmptyContent::Skip).unwrap(), "");
+
+        let chat_config: ChatConfig =
+            serde_json::from_str(r#"{"empty_content": "error"}"#).unwrap();
+        assert_eq!(chat_config.empty_content, EmptyContent::Error);
+        assert_eq!(ChatConfig::default().empty_content, EmptyContent::Skip);
+    }
+
+    #[test]
+    fn test_consecutive_user_messages() {
+        let prompt = |body: &str, chat_config: &ChatConfig| {
+            let req: CompatChatCompletionRequest = serde_json::from_str(body).unwrap();
+            chat_to_generate_request(req, &chat_formatter(), chat_config).map(|req| req.inputs)
+        };
+        let body = r#"{"messages": [
+            {"role": "user", "content": "Hi"},
+            {"role": "user", "content": "Are you there?"}
+        ]}"#;
+
+        assert_eq!(
+            prompt(body, &ChatConfig::default()).unwrap(),
+            "[INST] Hi\n\nAre you there? [/INST]"
+        );
+        let chat_config = ChatConfig {
+            merge_separator: Some(" ".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            prompt(body, &chat_config).unwrap(),
+            "[INST] Hi Are you there? [/INST]"
+        );
+        let chat_config = ChatConfig {
+            consecutive_user_messages: ConsecutiveMessages::Error,
+            ..Default::default()
+        };
+        let err = prompt(body, &chat_config).unwrap_err();
+        assert_eq!(err.to_string(), "message 1 follows another `user` message");
+        let chat_config = ChatConfig {
+            consecutive_user_messages: ConsecutiveMessages::PassThrough,
+            ..Default::default()
+        };
+        assert_eq!(
+            prompt(body, &chat_config).unwrap(),
+            "[INST] Hi [/INST][INST] Are you there? [/INST]"
+        );
+    }
+
+    #[test]
+    fn test_consecutive_messages_before_assistant() {
+        let prompt = |body: &str, chat_config: &ChatConfig| {
+            let req: CompatChatCompletionRequest = serde_json::from_str(body).unwrap();
+            chat_to_generate_request(req, &chat_formatter(), chat_config).map(|req| req.inputs)
+        };
+        let body = r#"{"messages": [
+            {"role": "user", "content": "Hi"},
+            {"role": "user", "content": "Are you there?"},
+            {"role": "assistant", "content": "Yes"},
+            {"role": "assistant", "content": "How can I help?"},
+            {"role": "user", "content": "Bye"}
+        ]}"#;
+
+        // Only the user messages are merged by default
+        assert_eq!(
+            prompt(body, &ChatConfig::default()).unwrap(),
+            "[INST] Hi\n\nAre you there? [/INST] Yes</s> How can I help?</s>[INST] Bye [/INST]"
+        );
+        let chat_config: ChatConfig =
+            serde_json::from_str(r#"{"consecutive_assistant_messages": "merge"}"#).unwrap();
+        assert_eq!(
+            prompt(body, &chat_config).unwrap(),
+            "[INST] Hi\n\nAre you there? [/INST] Yes\n\nHow can I help?</s>[INST] Bye [/INST]"
+        );
+
+        // The merged final assistant message is continued
+        let body = r#"{"continue_final_message": true, "messages": [
+            {"role": "user", "content": "Hi"},
+            {"role": "assistant", "content": "Hello"},
+            {"role": "assistant", "content": "I am"}
+        ]}"#;
+        assert_eq!(
+            prompt(body, &chat_config).unwrap(),
+            "[INST] Hi [/INST] Hello\n\nI am"
+        );
+    }
+
+    #[test]
+    fn test_batch_chatcompletions() {
+        let req: CompatChatCompletionRequest = serde_json::from_str(
+            r#"{"batch": [
+                [{"role": "user", "content": "Hi"}],
+                [{"role": "user", "content": "Hello"}]
+            ]}"#,
+        )
+        .unwrap();
+        assert!(req.messages.is_empty());
+        assert_eq!(req.batch.unwrap().len(), 2);
+
+        let compat_config = CompatConfig::default();
+        let response = |text: &str, prompt_tokens, completion_tokens| ChatCompletionsResponse {
+            id: "chatcmpl-0".to_string(),
+            object: "chat.completion".to_string(),
+            created: 0.into(),
+            model: "tgi".to_string(),
+            choices: vec![ChatCompletionChoices {
+                message: ChatMessage {
+                    role: ChatRole::Assistant,
+                    content: ChatMessageContent::Text(text.to_string()),
+                },
+                finish_reason: Some(FinishReason::EndOfSequenceToken),
+                tgi_finish_reason: None,
+                tgi_format_complete: None,
+                tgi_finish_details: None,
+                tgi_empty_note: None,
+                logprobs: None,
+                index: 0,
+            }],
+            usage: Usage::new(prompt_tokens, completion_tokens, &compat_config),
+            response_format_enforcement: ResponseFormatEnforcement::None,
+            metadata: None,
+            tgi_stop_sequences: None,
+            timings: None,
+        };
+
+        let batch = batch_chatcompletions(
+            vec![response("Hi!", 3, 2), response("Hello!", 4, 5)],
+            &compat_config,
+        )
+        .unwrap();
+        let batch = serde_json::to_value(batch).unwrap();
+        assert_eq!(batch["choices"][0]["index"], 0);
+        assert_eq!(batch["choices"][0]["message"]["content"], "Hi!");
+        assert_eq!(batch["choices"][1]["index"], 1);
+        assert_eq!(batch["choices"][1]["message"]["content"], "Hello!");
+        assert_eq!(batch["usage"]["prompt_tokens"], 7);
+        assert_eq!(batch["usage"]["completion_tokens"], 7);
+        assert_eq!(batch["usage"]["total_tokens"], 14);
+
+        assert!(batch_chatcompletions(vec![], &compat_config).is_none());
+
+        // List of separate responses
+        let list =
+            ChatCompletionsListResponse::new(vec![response("Hi!", 3, 2), response("Hello!", 4, 5)]);
+        let list = serde_json::to_value(list).unwrap();
+        assert_eq!(list["object"], "list");
+        assert_eq!(list["data"][1]["object"], "chat.completion");
+        assert_eq!(list["data"][1]["choices"][0]["index"], 0);
+        assert_eq!(
+            list["data"][1]["choices"][0]["message"]["content"],
+            "Hello!"
+        );
+        assert_eq!(list["data"][1]["usage"]["prompt_tokens"], 4);
+    }
+
+    #[test]
+    fn test_choice_seeds() {
+        // The choices of a seeded request differ from each other, but are the same across runs
+        let seeds = choice_seeds(Some(42), 3);
+        assert_eq!(seeds, vec![Some(42), Some(43), Some(44)]);
+        assert_eq!(choice_seeds(Some(42), 3), seeds);
+        // The first choice keeps the seed of the request
+        assert_eq!(choice_seeds(Some(42), 1), vec![Some(42)]);
+        assert_eq!(
+            choice_seeds(Some(u64::MAX), 2),
+            vec![Some(u64::MAX), Some(0)]
+        );
+        // Unseeded choices get independent random seeds
+        assert_eq!(choice_seeds(None, 2), vec![None, None]);
+    }
+
+    #[test]
+    fn test_validate_n() {
+        let compat_config = CompatConfig {
+            max_n: 4,
+            ..Default::default()
+        };
+        assert_eq!(compat_config.validate_n(None, true).unwrap(), 1);
+        assert_eq!(compat_config.validate_n(Some(4), false).unwrap(), 4);
+        assert!(matches!(
+            compat_config.validate_n(Some(0), false),
+            Err(ValidationError::ZeroN)
+        ));
+        assert!(matches!(
+            compat_config.validate_n(Some(10_000_000), false),
+            Err(ValidationError::MaxN(4, 10_000_000))
+        ));
+        assert!(matches!(
+            compat_config.validate_n(Some(2), true),
+            Err(ValidationError::NStream)
+        ));
+        let unlimited = CompatConfig::default();
+        assert_eq!(unlimited.validate_n(Some(64), false).unwrap(), 64);
+    }
+
+    #[test]
+    fn test_validate_logprobs() {
+        let req = |logprobs: &str| {
+            let body = format!(r#"{{"prompt": "Hi", "logprobs": {logprobs}}}"#);
+            serde_json::from_str::<CompatCompletionRequest>(&body).unwrap()
+        };
+        assert!(req("null").validate_logprobs().is_ok());
+        assert!(req("0").validate_logprobs().is_ok());
+        assert!(req("1").validate_logprobs().is_ok());
+        // Alternatives to the sampled token cannot be returned
+        let err = req("5").validate_logprobs().unwrap_err();
+        assert!(matches!(err, ValidationError::MaxLogprobs(1, 5)));
+        assert_eq!(err.param(), Some("logprobs"));
+
+        let chat = |body: &str| {
+            let body = format!(r#"{{"messages": [], {body}}}"#);
+            serde_json::from_str::<CompatChatCompletionRequest>(&body).unwrap()
+        };
+        assert!(chat(r#""logprobs": true, "top_logprobs": 1"#)
+            .validate_logprobs()
+            .is_ok());
+        assert!(chat(r#""logprobs": true, "top_logprobs": 0"#)
+            .validate_logprobs()
+            .is_ok());
+        let err = chat(r#""logprobs": true, "top_logprobs": 5"#)
+            .validate_logprobs()
+            .unwrap_err();
+        assert!(matches!(err, ValidationError::MaxTopLogprobs(1, 5)));
+        assert_eq!(err.param(), Some("top_logprobs"));
+        let err = chat(r#""top_logprobs": 1"#)
+            .validate_logprobs()
+            .unwrap_err();
+        assert!(matches!(err, ValidationError::TopLogprobsWithoutLogprobs));
+    }
+
+    #[test]
+    fn test_validate_prompts() {
+        let compat_config = CompatConfig {
+            max_n: 8,
+            max_prompts: 4,
+            ..Default::default()
+        };
+        assert!(compat_config.validate_prompts(4, None).is_ok());
+        assert!(compat_config.validate_prompts(4, Some(2)).is_ok());
+        assert!(matches!(
+            compat_config.validate_prompts(5, None),
+            Err(ValidationError::MaxPrompts(4, 5))
+        ));
+        // Each prompt fans out `n` ways
+        assert!(matches!(
+            compat_config.validate_prompts(3, Some(4)),
+            Err(ValidationError::PromptChoices(8, 3, 4))
+        ));
+        assert!(matches!(
+            compat_config.validate_prompts(2, Some(u32::MAX)),
+            Err(ValidationError::PromptChoices(8, 2, u32::MAX))
+        ));
+
+        assert!(compat_config.validate_batch(4).is_ok());
+        assert!(matches!(
+            compat_config.validate_batch(5),
+            Err(ValidationError::MaxBatch(4, 5))
+        ));
+        assert!(CompatConfig::default().validate_batch(1000).is_ok());
+    }
+
+    #[test]
+    fn test_merge_completions() {
+        let compat_config = CompatConfig::default();
+        let response = |text: &str, completion_tokens| CompletionsResponse {
+            usage: Some(Usage::new(3, completion_tokens, &compat_config).with_truncated_tokens(1)),
+            ..completions_chunk(
+                0.into(),
+                "cmpl-0",
+                text.to_string(),
+                None,
+                &"tgi".to_string(),
+            )
+        };
+
+        let merged = merge_completions(
+            vec![
+                response("Hi!", 2),
+                response("Hello!", 5),
+                response("Hey", 1),
+            ],
+            &compat_config,
+        )
+        .unwrap();
+        let merged = serde_json::to_value(merged).unwrap();
+        let indices: Vec<_> = merged["choices"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|choice| choice["index"].as_u64().unwrap())
+            .collect();
+        assert_eq!(indices, vec![0, 1, 2]);
+        assert_eq!(merged["choices"][1]["text"], "Hello!");
+        // The shared prompt is counted once, and the completion tokens of the choices are summed
+        assert_eq!(merged["usage"]["prompt_tokens"], 3);
+        assert_eq!(merged["usage"]["completion_tokens"], 8);
+        assert_eq!(merged["usage"]["total_tokens"], 11);
+        assert_eq!(
+            merged["usage"]["prompt_tokens_details"]["truncated_tokens"],
+            1
+        );
+
+        // A single choice is returned as is
+        let single = merge_completions(vec![response("Hi!", 2)], &compat_config).unwrap();
+        assert_eq!(single.choices.len(), 1);
+        assert_eq!(single.usage.unwrap().completion_tokens.value, 2);
+
+        assert!(merge_completions(vec![], &compat_config).is_none());
+    }
+
+    #[test]
+    fn test_multiple_prompts() {
+        let mut req: CompatCompletionRequest =
+            serde_json::from_str(r#"{"prompt": ["One", "Two", "Three"], "max_tokens": 4}"#)
+                .unwrap();
+        let requests = req.split_prompts().unwrap();
+        let inputs: Vec<_> = requests
+            .into_iter()
+            .map(|req| {
+                assert_eq!(req.max_tokens, Some(4));
+                GenerateRequest::from(req).inputs
+            })
+            .collect();
+        assert_eq!(inputs, vec!["One", "Two", "Three"]);
+
+        // A single prompt is generated as is, whether sent as a string or an array
+        for body in [r#"{"prompt": "One"}"#, r#"{"prompt": ["One"]}"#, r#"{}"#] {
+            let mut req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+            assert!(req.split_prompts().is_none(), "{body}");
+        }
+
+        // The choices of each prompt follow each other, in the order of the prompts
+        let compat_config = CompatConfig::default();
+        let response = |texts: &[&str], prompt_tokens, completion_tokens| CompletionsResponse {
+            choices: texts
+                .iter()
+                .enumerate()
+                .map(|(index, text)| CompletionChoices {
+                    index: index as u32,
+                    ..completions_chunk(
+                        0.into(),
+                        "cmpl-0",
+                        text.to_string(),
+                        None,
+                        &"tgi".to_string(),
+                    )
+                    .choices
+                    .remove(0)
+                })
+                .collect(),
+            usage: Some(Usage::new(prompt_tokens, completion_tokens, &compat_config)),
+            ..completions_chunk(0.into(), "cmpl-0", String::new(), None, &"tgi".to_string())
+        };
+        let batch = batch_completions(
+            vec![
+                response(&["One!", "One?"], 1, 4),
+                response(&["Two!", "Two?"], 2, 3),
+                response(&["Three!", "Three?"], 3, 6),
+            ],
+            &compat_config,
+        )
+        .unwrap();
+        let batch = serde_json::to_value(batch).unwrap();
+        let choices: Vec<_> = batch["choices"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|choice| {
+                (
+                    choice["index"].as_u64().unwrap(),
+                    choice["text"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            choices,
+            vec![
+                (0, "One!"),
+                (1, "One?"),
+                (2, "Two!"),
+                (3, "Two?"),
+                (4, "Three!"),
+                (5, "Three?")
+            ]
+        );
+        // Unlike the choices of a single prompt, the prompts are all counted
+        assert_eq!(batch["usage"]["prompt_tokens"], 6);
+        assert_eq!(batch["usage"]["completion_tokens"], 13);
+        assert_eq!(batch["usage"]["total_tokens"], 19);
+
+        assert!(batch_completions(vec![], &compat_config).is_none());
+    }
+
+    #[test]
+    fn test_merge_chatcompletions() {
+        let compat_config = CompatConfig::default();
+        let response = |text: &str, completion_tokens| ChatCompletionsResponse {
+            id: "chatcmpl-0".to_string(),
+            object: "chat.completion".to_string(),
+            created: 0.into(),
+            model: "tgi".to_string(),
+            choices: vec![ChatCompletionChoices {
+                message: ChatMessage {
+                    role: ChatRole::Assistant,
+                    content: ChatMessageContent::Text(text.to_string()),
+                },
+                finish_reason: Some(FinishReason::EndOfSequenceToken),
+                tgi_finish_reason: None,
+                tgi_format_complete: None,
+                tgi_finish_details: None,
+                tgi_empty_note: None,
+                logprobs: None,
+                index: 0,
+            }],
+            usage: Usage::new(3, completion_tokens, &compat_config),
+            response_format_enforcement: ResponseFormatEnforcement::None,
+            metadata: None,
+            tgi_stop_sequences: None,
+            timings: None,
+        };
+
+        let merged = merge_chatcompletions(
+            vec![response("Hi!", 2), response("Hello!", 5)],
+            &compat_config,
+        )
+        .unwrap();
+        let merged = serde_json::to_value(merged).unwrap();
+        assert_eq!(merged["choices"][0]["message"]["content"], "Hi!");
+        assert_eq!(merged["choices"][1]["index"], 1);
+        assert_eq!(merged["choices"][1]["message"]["content"], "Hello!");
+        // The shared prompt is counted once
+        assert_eq!(merged["usage"]["prompt_tokens"], 3);
+        assert_eq!(merged["usage"]["completion_tokens"], 7);
+        assert_eq!(merged["usage"]["total_tokens"], 10);
+
+        assert!(merge_chatcompletions(vec![], &compat_config).is_none());
+    }
+
+    #[test]
+    fn test_format_complete() {
+        let length = Some(&FinishReason::Length);
+        let eos = Some(&FinishReason::EndOfSequenceToken);
+        assert_eq!(
+            ResponseFormatEnforcement::None.format_complete(length),
+            None
+        );
+        assert_eq!(
+            ResponseFormatEnforcement::GrammarConstrained.format_complete(length),
+            Some(false)
+        );
+        assert_eq!(
+            ResponseFormatEnforcement::GrammarConstrained.format_complete(eos),
+            Some(true)
+        );
+        assert_eq!(
+            ResponseFormatEnforcement::PromptOnly.format_complete(length),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_accepts_event_stream() {
+        let accepts = |accept: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(accept) = accept {
+                headers.insert(header::ACCEPT, accept.parse().unwrap());
+            }
+            accepts_event_stream(&headers)
+        };
+        assert!(accepts(None));
+        assert!(accepts(Some("text/event-stream")));
+        assert!(accepts(Some(
+            "application/json, Text/Event-Stream; charset=utf-8"
+        )));
+        assert!(accepts(Some("*/*")));
+        assert!(accepts(Some("text/*;q=0.5")));
+        assert!(!accepts(Some("application/json")));
+        assert!(!accepts(Some("text/event-stream;q=0, application/json")));
+    }
+
+    #[test]
+    fn test_compat_response_type() {
+        let response_type = |accept: Option<&str>, stream: bool| {
+            let mut headers = HeaderMap::new();
+            if let Some(accept) = accept {
+                headers.insert(header::ACCEPT, accept.parse().unwrap());
+            }
+            compat_response_type(&headers, stream, false)
+        };
+        // The body flag wins over the `Accept` header
+        assert_eq!(
+            response_type(Some("text/event-stream"), false),
+            CompatResponseType::Json
+        );
+        assert_eq!(
+            response_type(Some("application/json"), false),
+            CompatResponseType::Json
+        );
+        assert_eq!(
+            response_type(Some("text/event-stream"), true),
+            CompatResponseType::EventStream
+        );
+        assert_eq!(
+            response_type(Some("application/json"), true),
+            CompatResponseType::NotAcceptable
+        );
+        assert_eq!(response_type(None, false), CompatResponseType::Json);
+        assert_eq!(response_type(None, true), CompatResponseType::EventStream);
+
+        // Streamed requests of the clients that do not accept Server-Sent Events can be buffered
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, "application/json".parse().unwrap());
+        assert_eq!(
+            compat_response_type(&headers, true, true),
+            CompatResponseType::Buffered
+        );
+        assert_eq!(
+            compat_response_type(&headers, false, true),
+            CompatResponseType::Json
+        );
+        headers.insert(header::ACCEPT, "text/event-stream".parse().unwrap());
+        assert_eq!(
+            compat_response_type(&headers, true, true),
+            CompatResponseType::EventStream
+        );
+    }
+
+    #[test]
+    fn test_tool_context_template() {
+        let prompt = |formatter: ChatFormatter| -> String {
+            let req: CompatChatCompletionRequest = serde_json::from_str(
+                r#"{"messages": [
+                    {"role": "user", "content": "Capital of Bavaria?"},
+                    {"role": "tool", "content": "Munich is the capital of Bavaria."}
+                ]}"#,
+            )
+            .unwrap();
+            chat_to_generate_request(req, &formatter, &ChatConfig::default())
+                .unwrap()
+                .inputs
+        };
+
+        // Wrapped like system messages by default
+        assert_eq!(
+            prompt(chat_formatter()),
+            "[INST] Capital of Bavaria? [/INST]<<SYS>> Munich is the capital of Bavaria. <</SYS>>"
+        );
+
+        let formatter = ChatFormatter {
+            context_template: Some(ChatFormatterPrePost {
+                pre: "<context>".to_string(),
+                post: "</context>".to_string(),
+            }),
+            ..chat_formatter()
+        };
+        assert_eq!(
+            prompt(formatter),
+            "[INST] Capital of Bavaria? [/INST]<context>Munich is the capital of Bavaria.</context>"
+        );
+    }
+
+    #[test]
+    fn test_custom_role_templates() {
+        let req: CompatChatCompletionRequest = serde_json::from_str(
+            r#"{"messages": [
+                {"role": "user", "content": "Weather in Munich?"},
+                {"role": "ipython", "content": "sunny"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(req.messages[1].role, ChatRole::Other("ipython".to_string()));
+        let prompt = |chat_config: &str| {
+            let chat_config: ChatConfig = serde_json::from_str(chat_config).unwrap();
+            let formatter = ChatFormatter {
+                role_templates: chat_config.role_templates.clone(),
+                default_role_template: chat_config.default_role_template.clone(),
+                ..chat_formatter()
+            };
+            chat_to_generate_request(req.clone(), &formatter, &chat_config).map(|req| req.inputs)
+        };
+
+        assert_eq!(
+            prompt(
+                r#"{"role_templates": {"ipython": {"pre": "<ipython>", "post": "</ipython>"}}}"#
+            )
+            .unwrap(),
+            "[INST] Weather in Munich? [/INST]<ipython>sunny</ipython>"
+        );
+        assert_eq!(
+            prompt(r#"{"default_role_template": {"pre": "<other>"}}"#).unwrap(),
+            "[INST] Weather in Munich? [/INST]<other>sunny"
+        );
+        // Rejected without a template
+        assert!(matches!(
+            prompt("{}"),
+            Err(ValidationError::UnknownRole(role)) if role == "ipython"
+        ));
+
+        // Roles are serialized back as sent
+        assert_eq!(
+            serde_json::to_string(&req.messages[1].role).unwrap(),
+            r#""ipython""#
+        );
+    }
+
+    #[test]
+    fn test_continue_final_message() {
+        let prompt = |body: &str| {
+            let req: CompatChatCompletionRequest = serde_json::from_str(body).unwrap();
+            chat_to_generate_request(req, &chat_formatter(), &ChatConfig::default())
+                .unwrap()
+                .inputs
+        };
+        assert_eq!(
+            prompt(r#"{"messages": [{"role": "user", "content": "Hi"}]}"#),
+            "[INST] Hi [/INST]"
+        );
+
+        // The trailing assistant message is the prefix of the reply, left without its `post`
+        let body = r#"{"messages": [
+            {"role": "user", "content": "Hi"},
+            {"role": "assistant", "content": "Hello, how"}
+        ]}"#;
+        assert_eq!(prompt(body), "[INST] Hi [/INST] Hello, how");
+        let body = r#"{"continue_final_message": true, "messages": [
+            {"role": "user", "content": "Hi"},
+            {"role": "assistant", "content": "Hello, how"}
+        ]}"#;
+        assert_eq!(prompt(body), "[INST] Hi [/INST] Hello, how");
+
+        // Only the last message is left open
+        let body = r#"{"messages": [
+            {"role": "user", "content": "Hi"},
+            {"role": "assistant", "content": "Hello"},
+            {"role": "user", "content": "Bye"}
+        ]}"#;
+        assert_eq!(
+            prompt(body),
+            "[INST] Hi [/INST] Hello</s>[INST] Bye [/INST]"
+        );
+    }
+
+    #[test]
+    fn test_continue_final_message_requires_assistant() {
+        let req: CompatChatCompletionRequest = serde_json::from_str(
+            r#"{"messages": [{"role": "user", "content": "Hi"}], "continue_final_message": true}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            chat_to_generate_request(req, &chat_formatter(), &ChatConfig::default()),
+            Err(ValidationError::ContinueFinalMessage)
+        ));
+    }
+
+    #[test]
+    fn test_usage_estimated_cost() {
+        let usage = Usage::new(1000, 500, &CompatConfig::default());
+        assert_eq!(usage.total_tokens.value, 1500);
+        assert_eq!(usage.tgi_estimated_cost, None);
+        assert!(serde_json::to_value(&usage)
+            .unwrap()
+            .get("tgi_estimated_cost")
+            .is_none());
+
+        let compat_config = CompatConfig {
+            prompt_token_price: Some(0.01),
+            completion_token_price: Some(0.03),
+            ..Default::default()
+        };
+        let usage = Usage::new(1000, 500, &compat_config);
+        assert!((usage.tgi_estimated_cost.unwrap().value - 0.025).abs() < 1e-9);
+
+        let compat_config = CompatConfig {
+            completion_token_price: Some(0.03),
+            ..Default::default()
+        };
+        let usage = Usage::new(1000, 500, &compat_config);
+        assert!((usage.tgi_estimated_cost.unwrap().value - 0.015).abs() < 1e-9);
+    }
+
+    async fn sse_body(event: Event) -> String {
+        use axum::body::HttpBody;
+        use axum::response::sse::Sse;
+        use std::convert::Infallible;
+
+        let stream = futures::stream::once(async { Ok::<_, Infallible>(event) });
+        let mut body = Sse::new(stream).into_response().into_body();
+        let mut sse = Vec::new();
+        while let Some(data) = body.data().await {
+            sse.extend_from_slice(&data.unwrap());
+        }
+        String::from_utf8(sse).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sse_event_names() {
+        // OpenAI events are unnamed
+        let sse = sse_body(CompatConfig::default().done_event()).await;
+        assert_eq!(sse, "data:[DONE]\n\n");
+
+        let compat_config = CompatConfig {
+            sse_event_names: true,
+            ..Default::default()
+        };
+        let sse = sse_body(compat_config.done_event()).await;
+        assert_eq!(sse, "data:[DONE]\nevent:done\n\n");
+    }
+
+    #[test]
+    fn test_numbers_as_strings() {
+        let serializer = || serde_json::value::Serializer;
+        assert_eq!(
+            serialize_number_as(&1700000000u64, true, serializer()).unwrap(),
+            "1700000000"
+        );
+        assert_eq!(serialize_number_as(&8u32, false, serializer()).unwrap(), 8);
+        assert_eq!(
+            serialize_number_as(&0.002f64, true, serializer()).unwrap(),
+            "0.002"
+        );
+
+        // Numbers are left as is by default
+        let compat_config = CompatConfig::default();
+        let response = serde_json::to_value(CompletionsResponse {
+            usage: Some(Usage::new(3, 5, &compat_config).with_truncated_tokens(2)),
+            ..completions_chunk(
+                1700000000.into(),
+                "cmpl-0",
+                "Hi".to_string(),
+                None,
+                &"tgi".to_string(),
+            )
+        })
+        .unwrap();
+        assert_eq!(response["created"], 1700000000);
+        assert_eq!(response["usage"]["total_tokens"], 8);
+        assert_eq!(
+            response["usage"]["prompt_tokens_details"]["truncated_tokens"],
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_numbers_as_strings_responses() {
+        let response = || {
+            Json(GenerateResponse {
+                generated_text: "Hi".to_string(),
+                details: Some(crate::Details {
+                    finish_reason: FinishReason::EndOfSequenceToken,
+                    generated_tokens: 2,
+                    seed: None,
+                    prefill: vec![],
+                    tokens: vec![],
+                    best_of_sequences: None,
+                    prompt_tokens: Some(3),
+                    truncated_tokens: 1,
+                    timings: None,
+                }),
+            })
+        };
+        let compat_config = CompatConfig {
+            numbers_as_strings: true,
+            prompt_token_price: Some(1.0),
+            ..Default::default()
+        };
+        let options = CompatRequestOptions {
+            evicted_turns: 2,
+            ..Default::default()
+        };
+
+        let Json(resp) =
+            generate_to_completions(response(), "tgi".to_string(), &compat_config, &options).await;
+        let resp = serde_json::to_value(resp).unwrap();
+        assert!(resp["created"].is_string());
+        assert_eq!(resp["usage"]["prompt_tokens"], "3");
+        assert_eq!(resp["usage"]["completion_tokens"], "2");
+        assert_eq!(resp["usage"]["total_tokens"], "5");
+        assert_eq!(resp["usage"]["tgi_estimated_cost"], "0.003");
+        assert_eq!(
+            resp["usage"]["prompt_tokens_details"]["truncated_tokens"],
+            "1"
+        );
+        // Other numbers are left as is
+        assert_eq!(resp["choices"][0]["index"], 0);
+
+        let Json(resp) =
+            generate_to_chatcompletions(response(), "tgi".to_string(), &compat_config, &options)
+                .await;
+        let resp = serde_json::to_value(resp).unwrap();
+        assert!(resp["created"].is_string());
+        assert_eq!(resp["usage"]["total_tokens"], "5");
+        assert_eq!(resp["usage"]["prompt_tokens_details"]["evicted_turns"], "2");
+        assert_eq!(
+            resp["usage"]["prompt_tokens_details"]["truncated_tokens"],
+            "1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streaming_event_large_token() {
+        let text = "lorem \"ipsum\"\n\r\tdolor ü 🦀 ".repeat(4096);
+        assert!(text.len() > 64 * 1024);
+        for stream_type in [
+            OpenaiStreamType::CompletionsResponse,
+            OpenaiStreamType::ChatCompletionsStreamResponse,
+        ] {
+            let token = Token {
+                id: 0,
+                text: text.clone(),
+                logprob: -0.1,
+                special: false,
+            };
+            let event = create_streaming_event(
+                &stream_type,
+                0.into(),
+                "cmpl-0",
+                None,
+                None,
+                token,
+                &"tgi".to_string(),
+            );
+            let sse = sse_body(event).await;
+
+            // A single event with a single data line
+            let data = sse
+                .strip_prefix("data:")
+                .unwrap()
+                .strip_suffix("\n\n")
+                .unwrap();
+            assert!(!data.contains('\n'));
+            let chunk: serde_json::Value = serde_json::from_str(data).unwrap();
+            let content = match stream_type {
+                OpenaiStreamType::CompletionsResponse => &chunk["choices"][0]["text"],
+                OpenaiStreamType::ChatCompletionsStreamResponse => {
+                    &chunk["choices"][0]["delta"]["content"]
+                }
+            };
+            assert_eq!(content.as_str().unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn test_repetition_penalty() {
+        let parameters = |body: &str| -> GenerateParameters {
+            let req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+            GenerateRequest::from(req).parameters
+        };
+        // The OpenAI penalty is additive, and passed as is rather than mapped onto the
+        // multiplicative repetition penalty
+        for presence_penalty in [-2.0, 0.0, 1.0, 2.0] {
+            let penalty = parameters(&format!(
+                r#"{{"prompt": "Hello", "presence_penalty": {presence_penalty}}}"#
+            ));
+            assert_eq!(penalty.presence_penalty, Some(presence_penalty));
+            assert_eq!(penalty.repetition_penalty, None);
+        }
+        let penalty = parameters(r#"{"prompt": "Hello", "repetition_penalty": 1.03}"#);
+        assert_eq!(penalty.repetition_penalty, Some(1.03));
+        assert_eq!(penalty.presence_penalty, None);
+        let penalty = parameters(
+            r#"{"prompt": "Hello", "presence_penalty": 1.0, "repetition_penalty": 1.03}"#,
+        );
+        assert_eq!(penalty.repetition_penalty, Some(1.03));
+        assert_eq!(penalty.presence_penalty, Some(1.0));
+        let penalty = parameters(r#"{"prompt": "Hello"}"#);
+        assert_eq!(penalty.repetition_penalty, None);
+        assert_eq!(penalty.presence_penalty, None);
+
+        // Both penalties apply along with the presence penalty of a preset
+        let req: CompatChatCompletionRequest = serde_json::from_str(
+            r#"{"messages": [], "preset": "creative", "repetition_penalty": 1.03}"#,
+        )
+        .unwrap();
+        let req = chat_to_generate_request(req, &chat_formatter(), &ChatConfig::default()).unwrap();
+        assert_eq!(req.parameters.repetition_penalty, Some(1.03));
+        assert_eq!(req.parameters.presence_penalty, Some(0.2));
+    }
+
+    #[test]
+    fn test_frequency_penalty() {
+        let parameters = |body: &str| -> GenerateParameters {
+            let req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+            GenerateRequest::from(req).parameters
+        };
+        let penalty = parameters(r#"{"prompt": "Hello", "presence_penalty": 0.5}"#);
+        assert_eq!(penalty.presence_penalty, Some(0.5));
+        assert_eq!(penalty.frequency_penalty, None);
+        let penalty = parameters(r#"{"prompt": "Hello", "frequency_penalty": -1.0}"#);
+        assert_eq!(penalty.presence_penalty, None);
+        assert_eq!(penalty.frequency_penalty, Some(-1.0));
+        // Both are passed as is, and add up in the sampler
+        let penalty =
+            parameters(r#"{"prompt": "Hello", "presence_penalty": 0.5, "frequency_penalty": 1.0}"#);
+        assert_eq!(penalty.presence_penalty, Some(0.5));
+        assert_eq!(penalty.frequency_penalty, Some(1.0));
+
+        let chat = |body: &str| -> GenerateParameters {
+            let req: CompatChatCompletionRequest = serde_json::from_str(body).unwrap();
+            chat_to_generate_request(req, &chat_formatter(), &ChatConfig::default())
+                .unwrap()
+                .parameters
+        };
+        let penalty = chat(r#"{"messages": [], "frequency_penalty": 1.0}"#);
+        assert_eq!(penalty.presence_penalty, None);
+        assert_eq!(penalty.frequency_penalty, Some(1.0));
+        let penalty =
+            chat(r#"{"messages": [], "presence_penalty": 0.5, "frequency_penalty": 1.0}"#);
+        assert_eq!(penalty.presence_penalty, Some(0.5));
+        assert_eq!(penalty.frequency_penalty, Some(1.0));
+        // Along with the presence penalty of a preset
+        let penalty = chat(r#"{"messages": [], "preset": "creative", "frequency_penalty": 1.0}"#);
+        assert_eq!(penalty.presence_penalty, Some(0.2));
+        assert_eq!(penalty.frequency_penalty, Some(1.0));
+    }
+
+    #[test]
+    fn test_best_of() {
+        let best_of = |body: &str| completion_request(body).parameters.best_of;
+        assert_eq!(best_of(r#"{"prompt": "Hello", "best_of": 1}"#), None);
+        assert_eq!(best_of(r#"{"prompt": "Hello"}"#), None);
+        assert_eq!(best_of(r#"{"prompt": "Hello", "best_of": 2}"#), Some(2));
+        // Left to the validation
+        assert_eq!(best_of(r#"{"prompt": "Hello", "best_of": 0}"#), Some(0));
+
+        let req: CompatChatCompletionRequest =
+            serde_json::from_str(r#"{"messages": [], "best_of": 1}"#).unwrap();
+        let req = chat_to_generate_request(req, &chat_formatter(), &ChatConfig::default()).unwrap();
+        assert_eq!(req.parameters.best_of, None);
+    }
+
+    #[test]
+    fn test_repetition_penalty_range() {
+        let parameters = |body: &str| -> GenerateParameters {
+            let req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+            GenerateRequest::from(req).parameters
+        };
+        let compat_config = CompatConfig::default();
+
+        // The lowest presence penalty no longer maps to a repetition penalty of 0
+        let lowest = parameters(r#"{"prompt": "Hello", "presence_penalty": -2.0}"#);
+        assert!(compat_config.validate_repetition_penalty(&lowest).is_ok());
+        let zero = parameters(r#"{"prompt": "Hello", "repetition_penalty": 0.0}"#);
+        let err = compat_config
+            .validate_repetition_penalty(&zero)
+            .unwrap_err();
+        assert!(matches!(err, ValidationError::RepetitionPenalty));
+        assert_eq!(err.param(), Some("repetition_penalty"));
+        let negative = parameters(r#"{"prompt": "Hello", "repetition_penalty": -1.0}"#);
+        assert!(compat_config
+            .validate_repetition_penalty(&negative)
+            .is_err());
+        let low = parameters(r#"{"prompt": "Hello", "repetition_penalty": 0.5}"#);
+        assert!(compat_config.validate_repetition_penalty(&low).is_ok());
+        assert!(compat_config
+            .validate_repetition_penalty(&parameters(r#"{"prompt": "Hello"}"#))
+            .is_ok());
+
+        let compat_config = CompatConfig {
+            min_repetition_penalty: Some(1.0),
+            max_repetition_penalty: Some(2.0),
+            ..Default::default()
+        };
+        let err = compat_config.validate_repetition_penalty(&low).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`repetition_penalty` must be >= 1. Given: 0.5"
+        );
+        let high = parameters(r#"{"prompt": "Hello", "repetition_penalty": 2.5}"#);
+        let err = compat_config
+            .validate_repetition_penalty(&high)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`repetition_penalty` must be <= 2. Given: 2.5"
+        );
+        let highest = parameters(r#"{"prompt": "Hello", "repetition_penalty": 2.0}"#);
+        assert!(compat_config.validate_repetition_penalty(&highest).is_ok());
+    }
+
+    #[test]
+    fn test_zero_top_p() {
+        let parameters = |body: &str| -> GenerateParameters {
+            let req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+            GenerateRequest::from(req).parameters
+        };
+        let compat_config = CompatConfig::default();
+
+        let mut zero = parameters(r#"{"prompt": "Hi", "top_p": 0.0}"#);
+        let err = compat_config.validate_top_p(&mut zero).unwrap_err();
+        assert_eq!(err.to_string(), "`top_p` must be > 0.0 and < 1.0");
+        assert_eq!(err.param(), Some("top_p"));
+        let mut zero = parameters(r#"{"prompt": "Hi", "typical_p": 0.0}"#);
+        let err = compat_config.validate_top_p(&mut zero).unwrap_err();
+        assert_eq!(err.param(), Some("typical_p"));
+        let mut negative = parameters(r#"{"prompt": "Hi", "top_p": -0.5}"#);
+        assert!(compat_config.validate_top_p(&mut negative).is_err());
+
+        // Tiny positive values are left to the sampler
+        let mut tiny = parameters(r#"{"prompt": "Hi", "top_p": 1e-9, "typical_p": 1e-9}"#);
+        compat_config.validate_top_p(&mut tiny).unwrap();
+        assert_eq!(tiny.top_p, Some(1e-9));
+        assert_eq!(tiny.typical_p, Some(1e-9));
+
+        let compat_config = CompatConfig {
+            zero_top_p: ZeroTopP::Clamp,
+            ..Default::default()
+        };
+        let mut zero = parameters(r#"{"prompt": "Hi", "top_p": 0.0, "typical_p": 0.0}"#);
+        compat_config.validate_top_p(&mut zero).unwrap();
+        assert_eq!(zero.top_p, Some(MIN_TOP_P));
+        assert_eq!(zero.typical_p, Some(MIN_TOP_P));
+        let mut tiny = parameters(r#"{"prompt": "Hi", "top_p": 1e-9}"#);
+        compat_config.validate_top_p(&mut tiny).unwrap();
+        assert_eq!(tiny.top_p, Some(1e-9));
+
+        // Greedy requests have no `top_p` left to validate
+        let mut greedy = parameters(r#"{"prompt": "Hi", "temperature": 0.0, "top_p": 0.0}"#);
+        ChatConfig::default().convert_temperature(&mut greedy, None);
+        assert!(CompatConfig::default().validate_top_p(&mut greedy).is_ok());
+    }
+
+    #[test]
+    fn test_empty_stop() {
+        let body = r#"{"prompt": "Hi", "stop": ["\n", "", "END", ""]}"#;
+        let parameters = || {
+            let req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+            GenerateRequest::from(req).parameters
+        };
+
+        let mut filtered = parameters();
+        CompatConfig::default()
+            .validate_stop(&mut filtered)
+            .unwrap();
+        assert_eq!(filtered.stop, vec!["\n", "END"]);
+
+        let compat_config = CompatConfig {
+            empty_stop: EmptyStop::Reject,
+            ..Default::default()
+        };
+        let err = compat_config.validate_stop(&mut parameters()).unwrap_err();
+        assert_eq!(err.to_string(), "stop sequence 1 is empty");
+        assert_eq!(err.param(), Some("stop"));
+
+        // The chat requests are validated the same way
+        let req: CompatChatCompletionRequest =
+            serde_json::from_str(r#"{"messages": [], "stop": ["", "</s>"]}"#).unwrap();
+        let mut req =
+            chat_to_generate_request(req, &chat_formatter(), &ChatConfig::default()).unwrap();
+        CompatConfig::default()
+            .validate_stop(&mut req.parameters)
+            .unwrap();
+        assert_eq!(req.parameters.stop, vec!["</s>"]);
+    }
+
+    #[test]
+    fn test_stop_string_or_array() {
+        let stop = |body: &str| -> Vec<String> {
+            let req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+            req.stop
+        };
+        assert_eq!(stop(r#"{"prompt": "Hi", "stop": "x"}"#), vec!["x"]);
+        assert_eq!(
+            stop(r#"{"prompt": "Hi", "stop": ["x", "y"]}"#),
+            vec!["x", "y"]
+        );
+        assert!(stop(r#"{"prompt": "Hi", "stop": null}"#).is_empty());
+        assert!(stop(r#"{"prompt": "Hi"}"#).is_empty());
+        assert!(
+            serde_json::from_str::<CompatCompletionRequest>(r#"{"prompt": "Hi", "stop": 1}"#)
+                .is_err()
+        );
+
+        let chat_stop = |body: &str| -> Vec<String> {
+            let req: CompatChatCompletionRequest = serde_json::from_str(body).unwrap();
+            req.stop
+        };
+        assert_eq!(chat_stop(r#"{"messages": [], "stop": "x"}"#), vec!["x"]);
+        assert_eq!(
+            chat_stop(r#"{"messages": [], "stop": ["x", "y"]}"#),
+            vec!["x", "y"]
+        );
+        assert!(chat_stop(r#"{"messages": [], "stop": null}"#).is_empty());
+    }
+
+    #[test]
+    fn test_output_ratio() {
+        let guard = |body: &str, prompt_tokens: Option<u32>, compat_config: &CompatConfig| {
+            let req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+            let mut options = CompatRequestOptions {
+                prompt_tokens,
+                ..req.options()
+            };
+            let mut parameters = GenerateRequest::from(req).parameters;
+            compat_config
+                .guard_output_ratio(&mut parameters, &mut options)
+                .map(|_| (parameters.max_new_tokens, options))
+        };
+        let body = r#"{"prompt": "Hi", "max_tokens": 100}"#;
+        let compat_config = CompatConfig {
+            max_output_ratio: Some(50),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            guard(body, Some(1), &compat_config),
+            Err(ValidationError::OutputRatio(50, 1, 100))
+        ));
+        // Empty prompts are guarded as one token prompts
+        assert!(guard(body, Some(0), &compat_config).is_err());
+        assert_eq!(guard(body, Some(2), &compat_config).unwrap().0, 100);
+        // Not guarded without a prompt count, nor with the default `max_tokens`
+        assert_eq!(guard(body, None, &compat_config).unwrap().0, 100);
+        let (max_tokens, options) = guard(r#"{"prompt": "Hi"}"#, Some(0), &compat_config).unwrap();
+        assert_eq!(max_tokens, default_max_new_tokens());
+        assert!(!options.max_tokens_capped);
+        // Off by default
+        assert!(guard(body, Some(1), &CompatConfig::default()).is_ok());
+
+        let compat_config = CompatConfig {
+            max_output_ratio: Some(50),
+            output_ratio_policy: OutputRatioPolicy::Cap,
+            server_finish_reasons: true,
+            ..Default::default()
+        };
+        let (max_tokens, options) = guard(body, Some(1), &compat_config).unwrap();
+        assert_eq!(max_tokens, 50);
+        assert!(options.max_tokens_capped);
+        assert_eq!(
+            compat_config.server_finish_reason(&FinishReason::Length, &options),
+            Some(ServerFinishReason::ServerLengthLimit)
+        );
+    }
+
+    #[test]
+    fn test_always_stream_usage() {
+        let requested = CompatRequestOptions {
+            include_usage: true,
+            ..Default::default()
+        };
+        let unrequested = CompatRequestOptions::default();
+        assert!(CompatConfig::default().stream_usage(&requested));
+        assert!(!CompatConfig::default().stream_usage(&unrequested));
+
+        let compat_config = CompatConfig {
+            always_stream_usage: true,
+            ..Default::default()
+        };
+        assert!(compat_config.stream_usage(&requested));
+        assert!(compat_config.stream_usage(&unrequested));
+    }
+
+    #[test]
+    fn test_stream_usage_option() {
+        let req: CompatCompletionRequest = serde_json::from_str(
+            r#"{"prompt": "Hello", "stream": true, "stream_options": {"include_usage": true}}"#,
+        )
+        .unwrap();
+        assert!(req.options().include_usage);
+        let req: CompatChatCompletionRequest = serde_json::from_str(
+            r#"{"messages": [{"role": "user", "content": "Hello"}], "stream": true, "stream_options": {"include_usage": true}}"#,
+        )
+        .unwrap();
+        assert!(req.options().include_usage);
+    }
+
+    #[tokio::test]
+    async fn test_openai_finish_reason() {
+        for (finish_reason, native, openai) in [
+            (FinishReason::EndOfSequenceToken, "eos_token", "stop"),
+            (FinishReason::StopSequence, "stop_sequence", "stop"),
+            (FinishReason::Length, "length", "length"),
+        ] {
+            // `/generate` keeps the TGI reasons
+            assert_eq!(serde_json::to_value(&finish_reason).unwrap(), native);
+            assert_eq!(
+                serde_json::to_value(OpenaiFinishReason::from(finish_reason.clone())).unwrap(),
+                openai
+            );
+
+            for stream_type in [
+                OpenaiStreamType::CompletionsResponse,
+                OpenaiStreamType::ChatCompletionsStreamResponse,
+            ] {
+                let event = create_stream_end_event(
+                    &stream_type,
+                    0.into(),
+                    "cmpl-0",
+                    finish_reason.clone(),
+                    None,
+                    None,
+                    &"tgi".to_string(),
+                );
+                let sse = sse_body(event).await;
+                let chunk: serde_json::Value =
+                    serde_json::from_str(sse.strip_prefix("data:").unwrap().trim_end()).unwrap();
+                assert_eq!(chunk["choices"][0]["finish_reason"], openai);
+            }
+        }
+
+        let choice = ChatCompletionDeltaStreamChoices {
+            delta: ChatDeltaStreamMessage {
+                content: Some("Hello".to_string()),
+                role: None,
+            },
+            finish_reason: None,
+            tgi_finish_reason: None,
+            index: 0,
+        };
+        assert!(serde_json::to_value(choice).unwrap()["finish_reason"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_stream_error_fallback_chunk() {
+        for stream_type in [
+            OpenaiStreamType::CompletionsResponse,
+            OpenaiStreamType::ChatCompletionsStreamResponse,
+        ] {
+            let event = create_stream_end_event(
+                &stream_type,
+                0.into(),
+                "cmpl-0",
+                FinishReason::EndOfSequenceToken,
+                None,
+                Some("Hello [generation error]".to_string()),
+                &"tgi".to_string(),
+            );
+            let sse = sse_body(event).await;
+            let chunk: serde_json::Value =
+                serde_json::from_str(sse.strip_prefix("data:").unwrap().trim_end()).unwrap();
+            let choice = &chunk["choices"][0];
+            let content = match stream_type {
+                OpenaiStreamType::CompletionsResponse => &choice["text"],
+                OpenaiStreamType::ChatCompletionsStreamResponse => &choice["delta"]["content"],
+            };
+            assert_eq!(content, "Hello [generation error]");
+            assert_eq!(choice["finish_reason"], "stop");
+            assert!(chunk.get("error").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_finish_reason() {
+        let options = |body: &str| -> CompatRequestOptions {
+            let req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+            req.options()
+        };
+        let default_max_tokens = options(r#"{"prompt": "Hello"}"#);
+        let max_tokens = options(r#"{"prompt": "Hello", "max_tokens": 20}"#);
+
+        // Not reported by default
+        let compat_config = CompatConfig::default();
+        let reason = compat_config.server_finish_reason(&FinishReason::Length, &default_max_tokens);
+        assert_eq!(reason, None);
+
+        let compat_config = CompatConfig {
+            server_finish_reasons: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            compat_config.server_finish_reason(&FinishReason::Length, &default_max_tokens),
+            Some(ServerFinishReason::ServerLengthLimit)
+        );
+        assert_eq!(
+            compat_config.server_finish_reason(&FinishReason::Length, &max_tokens),
+            None
+        );
+        assert_eq!(
+            compat_config
+                .server_finish_reason(&FinishReason::EndOfSequenceToken, &default_max_tokens),
+            None
+        );
+
+        // Stream stopped on timeout
+        let event = create_stream_end_event(
+            &OpenaiStreamType::ChatCompletionsStreamResponse,
+            0.into(),
+            "cmpl-0",
+            FinishReason::Length,
+            Some(ServerFinishReason::Timeout),
+            None,
+            &"tgi".to_string(),
+        );
+        let sse = sse_body(event).await;
+        let chunk: serde_json::Value =
+            serde_json::from_str(sse.strip_prefix("data:").unwrap().trim_end()).unwrap();
+        assert_eq!(chunk["choices"][0]["finish_reason"], "length");
+        assert_eq!(chunk["choices"][0]["tgi_finish_reason"], "timeout");
+    }
+
+    #[test]
+    fn test_sampling_presets() {
+        let chat_config: ChatConfig = serde_json::from_str(
+            r#"{"presets": {"precise": {"temperature": 0.1}, "code": {"top_k": 5}}}"#,
+        )
+        .unwrap();
+        let request = |body: &str| -> Result<GenerateParameters, ValidationError> {
+            let req: CompatChatCompletionRequest = serde_json::from_str(body).unwrap();
+            chat_to_generate_request(req, &chat_formatter(), &chat_config).map(|req| req.parameters)
+        };
+
+        // Built-in preset
+        let parameters = request(r#"{"messages": [], "preset": "balanced"}"#).unwrap();
+        assert_eq!(parameters.temperature, Some(0.7));
+        assert_eq!(parameters.top_p, Some(0.9));
+
+        // Explicit parameters override the preset
+        let parameters =
+            request(r#"{"messages": [], "preset": "balanced", "temperature": 1.2}"#).unwrap();
+        assert_eq!(parameters.temperature, Some(1.2));
+        assert_eq!(parameters.top_p, Some(0.9));
+
+        // Configured presets override the built-in ones
+        let parameters = request(r#"{"messages": [], "preset": "precise"}"#).unwrap();
+        assert_eq!(parameters.temperature, Some(0.1));
+        assert_eq!(parameters.top_p, None);
+        let parameters = request(r#"{"messages": [], "preset": "code"}"#).unwrap();
+        assert_eq!(parameters.top_k, Some(5));
+
+        assert!(matches!(
+            request(r#"{"messages": [], "preset": "unknown"}"#),
+            Err(ValidationError::UnknownPreset(_))
+        ));
+    }
+
+    #[test]
+    fn test_extra_body() {
+        let parameters = completion_request(
+            r#"{"prompt": "Hi", "extra_body": {"watermark": true, "typical_p": 0.9, "decoder_input_details": true}}"#,
+        )
+        .parameters;
+        assert!(parameters.watermark);
+        assert_eq!(parameters.typical_p, Some(0.9));
+        assert!(parameters.decoder_input_details);
+
+        // Top-level values take precedence
+        let parameters = completion_request(
+            r#"{"prompt": "Hi", "typical_p": 0.5, "extra_body": {"typical_p": 0.9}}"#,
+        )
+        .parameters;
+        assert_eq!(parameters.typical_p, Some(0.5));
+        assert!(!parameters.watermark);
+
+        let req: CompatChatCompletionRequest = serde_json::from_str(
+            r#"{"messages": [], "watermark": false, "extra_body": {"watermark": true}}"#,
+        )
+        .unwrap();
+        let parameters = chat_to_generate_request(req, &chat_formatter(), &ChatConfig::default())
+            .unwrap()
+            .parameters;
+        assert!(parameters.watermark);
+    }
+
+    #[test]
+    fn test_truncation_side_defaults() {
+        // Completions keep the beginning of the prompt, chats the most recent messages
+        let parameters = completion_request(r#"{"prompt": "Hi", "truncate": 2}"#).parameters;
+        assert_eq!(parameters.truncation_side, TruncationSide::Right);
+        let parameters =
+            completion_request(r#"{"prompt": "Hi", "truncation_side": "left"}"#).parameters;
+        assert_eq!(parameters.truncation_side, TruncationSide::Left);
+
+        let req: CompatChatCompletionRequest =
+            serde_json::from_str(r#"{"messages": [], "truncate": 2}"#).unwrap();
+        let parameters = chat_to_generate_request(req, &chat_formatter(), &ChatConfig::default())
+            .unwrap()
+            .parameters;
+        assert_eq!(parameters.truncation_side, TruncationSide::Left);
+    }
+
+    #[test]
+    fn test_sampling_defaults() {
+        let chat_config: ChatConfig = serde_json::from_str(
+            r#"{"defaults": {"temperature": 0.3, "top_p": 0.8, "repetition_penalty": 1.1}}"#,
+        )
+        .unwrap();
+        let request = |body: &str| -> GenerateParameters {
+            let req: CompatChatCompletionRequest = serde_json::from_str(body).unwrap();
+            chat_to_generate_request(req, &chat_formatter(), &chat_config)
+                .unwrap()
+                .parameters
+        };
+
+        let parameters = request(r#"{"messages": []}"#);
+        assert_eq!(parameters.temperature, Some(0.3));
+        assert_eq!(parameters.top_p, Some(0.8));
+        assert_eq!(parameters.top_k, None);
+        assert_eq!(parameters.repetition_penalty, Some(1.1));
+
+        // Presets and explicit parameters take precedence
+        let parameters =
+            request(r#"{"messages": [], "preset": "precise", "repetition_penalty": 2.0}"#);
+        assert_eq!(parameters.temperature, Some(0.2));
+        assert_eq!(parameters.top_p, Some(0.5));
+        assert_eq!(parameters.repetition_penalty, Some(2.0));
+
+        // The defaults also apply to the completions route
+        let req: CompatCompletionRequest =
+            serde_json::from_str(r#"{"prompt": "Hi", "temperature": 0.9}"#).unwrap();
+        let mut parameters = GenerateRequest::from(req).parameters;
+        chat_config.defaults.apply(&mut parameters, None);
+        assert_eq!(parameters.temperature, Some(0.9));
+        assert_eq!(parameters.top_p, Some(0.8));
+
+        // Requests disabling sampling decode greedily, with the other defaults
+        let parameters = request(r#"{"messages": [], "do_sample": false}"#);
+        assert!(!parameters.sampling());
+        assert_eq!(parameters.repetition_penalty, Some(1.1));
+        let parameters = request(r#"{"messages": [], "do_sample": true}"#);
+        assert_eq!(parameters.temperature, Some(0.3));
+        assert!(parameters.do_sample);
+    }
+
+    #[test]
+    fn test_openai_temperature() {
+        let parameters = |body: &str, chat_config: &ChatConfig| {
+            let req: CompatChatCompletionRequest = serde_json::from_str(body).unwrap();
+            chat_to_generate_request(req, &chat_formatter(), chat_config)
+                .unwrap()
+                .parameters
+        };
+        let chat_config = ChatConfig::default();
+
+        // Greedy decoding rather than an invalid temperature
+        let greedy = parameters(
+            r#"{"messages": [], "temperature": 0, "top_p": 0.9}"#,
+            &chat_config,
+        );
+        assert_eq!(greedy.temperature, None);
+        assert_eq!(greedy.top_p, None);
+        assert!(!greedy.sampling());
+        let near_zero = parameters(
+            r#"{"messages": [], "temperature": 1e-5, "do_sample": true}"#,
+            &chat_config,
+        );
+        assert_eq!(near_zero.temperature, None);
+        assert!(!near_zero.sampling());
+
+        // Small temperatures sample, nearly greedily
+        let small = parameters(
+            r#"{"messages": [], "temperature": 0.1, "top_p": 0.9}"#,
+            &chat_config,
+        );
+        assert_eq!(small.temperature, Some(0.1));
+        assert_eq!(small.top_p, Some(0.9));
+        assert!(small.do_sample);
+
+        // The backend would decode greedily at a temperature of 1 without `do_sample`
+        let neutral = parameters(r#"{"messages": [], "temperature": 1.0}"#, &chat_config);
+        assert_eq!(neutral.temperature, Some(1.0));
+        assert!(neutral.do_sample);
+
+        let flat = parameters(r#"{"messages": [], "temperature": 2.0}"#, &chat_config);
+        assert_eq!(flat.temperature, Some(2.0));
+        assert!(flat.do_sample);
+
+        let chat_config: ChatConfig =
+            serde_json::from_str(r#"{"temperature_scale": 0.5}"#).unwrap();
+        let scaled = parameters(r#"{"messages": [], "temperature": 2.0}"#, &chat_config);
+        assert_eq!(scaled.temperature, Some(1.0));
+        // The minimum sampling temperature applies to the scaled temperature
+        let scaled_greedy = parameters(r#"{"messages": [], "temperature": 1.5e-3}"#, &chat_config);
+        assert_eq!(scaled_greedy.temperature, None);
+        assert!(!scaled_greedy.sampling());
+        let chat_config: ChatConfig =
+            serde_json::from_str(r#"{"temperature_scale": 100.0}"#).unwrap();
+        let scaled_sampling = parameters(r#"{"messages": [], "temperature": 1e-4}"#, &chat_config);
+        assert_eq!(scaled_sampling.temperature, Some(1e-2));
+        assert!(scaled_sampling.do_sample);
+
+        // Unset and negative temperatures are left as is
+        let unset = parameters(r#"{"messages": []}"#, &chat_config);
+        assert_eq!(unset.temperature, None);
+        assert!(!unset.do_sample);
+        let negative = parameters(r#"{"messages": [], "temperature": -1.0}"#, &chat_config);
+        assert_eq!(negative.temperature, Some(-1.0));
+
+        // Same conversion on the completions route
+        let req: CompatCompletionRequest =
+            serde_json::from_str(r#"{"prompt": "Hi", "temperature": 0, "top_k": 5}"#).unwrap();
+        let mut greedy = GenerateRequest::from(req).parameters;
+        ChatConfig::default().convert_temperature(&mut greedy, None);
+        assert_eq!(greedy.top_k, None);
+        assert!(!greedy.sampling());
+
+        // An explicit `do_sample: false` is kept, and decodes greedily
+        let body = r#"{"prompt": "Hi", "temperature": 0.7, "do_sample": false}"#;
+        let req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+        let do_sample = req.do_sample;
+        let mut greedy = GenerateRequest::from(req).parameters;
+        ChatConfig::default().convert_temperature(&mut greedy, do_sample);
+        assert_eq!(greedy.temperature, None);
+        assert!(!greedy.do_sample);
+        assert!(!greedy.sampling());
+        let explicit = parameters(
+            r#"{"messages": [], "temperature": 0.7, "do_sample": false}"#,
+            &ChatConfig::default(),
+        );
+        assert!(!explicit.sampling());
+        let sampled = parameters(
+            r#"{"messages": [], "temperature": 0.7, "do_sample": true}"#,
+            &ChatConfig::default(),
+        );
+        assert_eq!(sampled.temperature, Some(0.7));
+        assert!(sampled.do_sample);
+    }
+
+    #[tokio::test]
+    async fn test_evict_oldest_turns() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let chat_config = &ChatConfig::default();
+        // One token per word
+        let counts = &AtomicUsize::new(0);
+        let count_tokens = |prompt: String| async move {
+            counts.fetch_add(1, Ordering::Relaxed);
+            Some(prompt.split_whitespace().count() as u32)
+        };
+        let body = r#"{"messages": [
+            {"role": "system", "content": "Be brief."},
+            {"role": "user", "content": "Hi"},
+            {"role": "assistant", "content": "Hello there"},
+            {"role": "user", "content": "How are you"},
+            {"role": "assistant", "content": "Fine"},
+            {"role": "user", "content": "Bye"}
+        ]}"#;
+        let evict = |max_tokens: u32| async move {
+            let mut req: CompatChatCompletionRequest = serde_json::from_str(body).unwrap();
+            let formatter = chat_formatter();
+            evict_oldest_turns(&mut req, &formatter, chat_config, max_tokens, count_tokens)
+                .await
+                .map(|evicted_turns| {
+                    let prompt = chat_prompt(&req.messages, &formatter, chat_config);
+                    (evicted_turns, prompt.unwrap())
+                })
+        };
+
+        let (evicted_turns, prompt) = evict(100).await.unwrap();
+        assert_eq!(evicted_turns, 0);
+        assert_eq!(count_tokens(prompt).await, Some(15));
+
+        // The user message and the replies following it are evicted together
+        let (evicted_turns, prompt) = evict(12).await.unwrap();
+        assert_eq!(evicted_turns, 1);
+        assert_eq!(
+            prompt,
+            "<<SYS>> Be brief. <</SYS>>[INST] How are you [/INST] Fine</s>[INST] Bye [/INST]"
+        );
+        counts.store(0, Ordering::Relaxed);
+        let (evicted_turns, prompt) = evict(10).await.unwrap();
+        assert_eq!(evicted_turns, 2);
+        assert_eq!(prompt, "<<SYS>> Be brief. <</SYS>>[INST] Bye [/INST]");
+        // Each evicted turn is counted once, and the conversation once per round of evictions
+        assert_eq!(counts.load(Ordering::Relaxed), 5);
+
+        // The system message is never evicted, and starts the prompts counted with the cache
+        let req: CompatChatCompletionRequest = serde_json::from_str(body).unwrap();
+        let prefix = chat_prompt_prefix(&req.messages, &chat_formatter(), chat_config);
+        assert_eq!(prefix, "<<SYS>> Be brief. <</SYS>>");
+        assert!(prompt.starts_with(&prefix));
+
+        // The system message and the last user turn are always kept
+        assert!(matches!(
+            evict(5).await,
+            Err(ValidationError::ConversationTokens(5, 6))
+        ));
+
+        let usage = Usage::new(10, 2, &CompatConfig::default())
+            .with_truncated_tokens(3)
+            .with_evicted_turns(2);
+        assert_eq!(
+            serde_json::to_value(usage.prompt_tokens_details).unwrap(),
+            serde_json::json!({"truncated_tokens": 3, "evicted_turns": 2})
+        );
+    }
+
+    #[test]
+    fn test_max_completion_tokens() {
+        let hi = r#""messages": [{"role": "user", "content": "Hi"}]"#;
+        for (tokens, expected) in [
+            ("", None),
+            (r#", "max_tokens": 100"#, Some(100)),
+            (r#", "max_completion_tokens": 200"#, Some(200)),
+            // The newer field wins
+            (
+                r#", "max_tokens": 100, "max_completion_tokens": 200"#,
+                Some(200),
+            ),
+            (
+                r#", "max_completion_tokens": 200, "max_tokens": 100"#,
+                Some(200),
+            ),
+            (
+                r#", "max_tokens": 100, "max_completion_tokens": null"#,
+                Some(100),
+            ),
+            // Beyond the former bound of the documentation
+            (r#", "max_completion_tokens": 4096"#, Some(4096)),
+        ] {
+            let req: CompatChatCompletionRequest =
+                serde_json::from_str(&format!("{{{hi}{tokens}}}")).unwrap();
+            assert_eq!(req.max_tokens(), expected, "{tokens}");
+            assert_eq!(req.options().default_max_tokens, expected.is_none());
+            let req =
+                chat_to_generate_request(req, &chat_formatter(), &ChatConfig::default()).unwrap();
+            assert_eq!(
+                req.parameters.max_new_tokens,
+                expected.unwrap_or_else(default_max_new_tokens),
+                "{tokens}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_default_stop_and_system() {
+        let chat_config: ChatConfig = serde_json::from_str(
+            r#"{"default_stop": ["</s>", "[INST]"], "default_system": "Be brief."}"#,
+        )
+        .unwrap();
+        let request = |body: &str| {
+            let mut req: CompatChatCompletionRequest = serde_json::from_str(body).unwrap();
+            req.add_system_messages(&chat_config, ResponseFormat::Text);
+            chat_to_generate_request(req, &chat_formatter(), &chat_config).unwrap()
+        };
+        let hi = r#"{"role": "user", "content": "Hi"}"#;
+
+        let req = request(&format!(
+            r#"{{"messages": [{hi}], "stop": ["\n", "</s>"]}}"#
+        ));
+        assert_eq!(req.inputs, "<<SYS>> Be brief. <</SYS>>[INST] Hi [/INST]");
+        assert_eq!(req.parameters.stop, vec!["\n", "</s>", "[INST]"]);
+
+        // A system message of the request replaces the default one
+        let system = r#"{"role": "system", "content": "Be verbose."}"#;
+        let req = request(&format!(r#"{{"messages": [{system}, {hi}]}}"#));
+        assert_eq!(req.inputs, "<<SYS>> Be verbose. <</SYS>>[INST] Hi [/INST]");
+
+        let req = request(&format!(
+            r#"{{"messages": [{hi}], "stop": ["\n"], "disable_default_stops": true, "disable_default_system": true}}"#
+        ));
+        assert_eq!(req.inputs, "[INST] Hi [/INST]");
+        assert_eq!(req.parameters.stop, vec!["\n"]);
+    }
+
+    #[tokio::test]
+    async fn test_empty_generation_notes() {
+        let response = |text: &str, finish_reason: FinishReason| {
+            Json(GenerateResponse {
+                generated_text: text.to_string(),
+                details: Some(crate::Details {
+                    finish_reason,
+                    generated_tokens: 1,
+                    seed: None,
+                    prefill: vec![],
+                    tokens: vec![],
+                    best_of_sequences: None,
+                    prompt_tokens: None,
+                    truncated_tokens: 0,
+                    timings: None,
+                }),
+            })
+        };
+        let options = CompatRequestOptions::default();
+        let note = |compat_config: &CompatConfig, text: &str, finish_reason: FinishReason| {
+            let compat_config = compat_config.clone();
+            let response = response(text, finish_reason);
+            async move {
+                let completion =
+                    generate_to_completions(response, "tgi".to_string(), &compat_config, &options)
+                        .await;
+                completion.0.choices[0].tgi_empty_note.clone()
+            }
+        };
+
+        // Empty generations are left as is by default
+        let compat_config = CompatConfig::default();
+        assert_eq!(
+            note(&compat_config, "", FinishReason::EndOfSequenceToken).await,
+            None
+        );
+
+        let compat_config = CompatConfig {
+            empty_generation_notes: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            note(&compat_config, "", FinishReason::EndOfSequenceToken)
+                .await
+                .unwrap(),
+            "the model generated its end of sequence token before any text"
+        );
+        assert!(note(&compat_config, "", FinishReason::StopSequence)
+            .await
+            .is_some());
+        assert_eq!(
+            note(&compat_config, "Hi", FinishReason::EndOfSequenceToken).await,
+            None
+        );
+
+        // Whitespace trimmed from the chat completions also leaves an empty content
+        let chat = generate_to_chatcompletions(
+            response("\n", FinishReason::EndOfSequenceToken),
+            "tgi".to_string(),
+            &CompatConfig {
+                trailing_whitespace: TrailingWhitespace::All,
+                ..compat_config
+            },
+            &options,
+        )
+        .await;
+        let chat = serde_json::to_value(chat.0).unwrap();
+        assert_eq!(
+            chat["choices"][0]["tgi_empty_note"],
+            "the model generated its end of sequence token before any text"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_assistant_role() {
+        let chat_config: ChatConfig =
+            serde_json::from_str(r#"{"assistant_role": "model"}"#).unwrap();
+        let prompt = |chat_config: &ChatConfig, role: &str| {
+            let mut req: CompatChatCompletionRequest = serde_json::from_str(&format!(
+                r#"{{"messages": [{{"role": "user", "content": "Hi"}}, {{"role": "{role}", "content": "Hello"}}, {{"role": "user", "content": "Bye"}}]}}"#
+            ))
+            .unwrap();
+            chat_config.normalize_assistant_role(&mut req.messages);
+            chat_to_generate_request(req, &chat_formatter(), chat_config)
+                .unwrap()
+                .inputs
+        };
+        // Messages with the configured role are templated as assistant messages
+        assert_eq!(
+            prompt(&chat_config, "model"),
+            prompt(&ChatConfig::default(), "assistant")
+        );
+        assert_eq!(
+            chat_start_message(
+                0.into(),
+                "cmpl-0",
+                &"tgi".to_string(),
+                chat_config.assistant_role()
+            )
+            .choices[0]
+                .delta
+                .role,
+            Some(ChatRole::Other("model".to_string()))
+        );
+
+        let response = Json(GenerateResponse {
+            generated_text: "Hi".to_string(),
+            details: None,
+        });
+        let compat_config = CompatConfig {
+            chat_config,
+            ..Default::default()
+        };
+        let chat = generate_to_chatcompletions(
+            response,
+            "tgi".to_string(),
+            &compat_config,
+            &CompatRequestOptions::default(),
+        )
+        .await;
+        assert_eq!(
+            serde_json::to_value(chat.0).unwrap()["choices"][0]["message"]["role"],
+            "model"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_return_stop_sequences() {
+        let req: CompatChatCompletionRequest =
+            serde_json::from_str(r#"{"messages": [], "return_stop_sequences": true}"#).unwrap();
+        assert!(req.options().return_stop_sequences);
+        let req: CompatChatCompletionRequest = serde_json::from_str(r#"{"messages": []}"#).unwrap();
+        assert!(!req.options().return_stop_sequences);
+
+        let response = Json(GenerateResponse {
+            generated_text: "Hi".to_string(),
+            details: None,
+        });
+        let compat_config = CompatConfig::default();
+        let mut chat = generate_to_chatcompletions(
+            response,
+            "tgi".to_string(),
+            &compat_config,
+            &CompatRequestOptions::default(),
+        )
+        .await
+        .0;
+        // Strict responses are left as is
+        let value = serde_json::to_value(&chat).unwrap();
+        assert!(value.get("tgi_stop_sequences").is_none());
+
+        chat.tgi_stop_sequences = Some(vec!["\n".to_string(), "</s>".to_string()]);
+        let batch = batch_chatcompletions(vec![chat], &compat_config).unwrap();
+        let value = serde_json::to_value(batch).unwrap();
+        assert_eq!(
+            value["tgi_stop_sequences"],
+            serde_json::json!(["\n", "</s>"])
+        );
+    }
+
+    #[test]
+    fn test_unsupported_content_parts() {
+        let req: CompatChatCompletionRequest = serde_json::from_str(
+            r#"{"messages": [
+                {"role": "user", "content": "Describe"},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "this"},
+                    {"type": "image_url", "image_url": {"url": "https://example.com/a.png"}}
+                ]}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(req.messages[0].content.unsupported_part(), None);
+        assert_eq!(
+            req.messages[1].content.unsupported_part(),
+            Some("image_url")
+        );
+
+        let content: ChatMessageContent = serde_json::from_str(
+            r#"[{"type": "input_audio", "input_audio": {"data": "", "format": "wav"}}]"#,
+        )
+        .unwrap();
+        assert_eq!(content.unsupported_part(), Some("input_audio"));
+
+        // Responses keep a plain string content
+        let message = ChatMessage {
+            role: ChatRole::Assistant,
+            content: ChatMessageContent::Text("Munich".to_string()),
+        };
+        assert_eq!(
+            serde_json::to_value(message).unwrap(),
+            serde_json::json!({"role": "assistant", "content": "Munich"})
+        );
+    }
+
+    #[test]
+    fn test_content_parts_prompt() {
+        let template = ChatTemplate::new(
+            "{% for message in messages %}{{ '<|' + message['role'] + '|>' + message['content'] }}{% endfor %}".to_string(),
+            String::new(),
+            None,
+            None,
+        )
+        .unwrap();
+        let compat_config = CompatConfig {
+            chat_template: Some(Arc::new(template)),
+            ..Default::default()
+        };
+        let prompt = |content: &str, formatter: ChatFormatter| {
+            let req: CompatChatCompletionRequest = serde_json::from_str(&format!(
+                r#"{{"messages": [{{"role": "user", "content": {content}}}]}}"#
+            ))
+            .unwrap();
+            chat_to_generate_request(req, &formatter, &ChatConfig::default())
+                .unwrap()
+                .inputs
+        };
+
+        // The text parts are concatenated, as if sent as a single string
+        let text = r#""Hello world""#;
+        let parts = r#"[{"type": "text", "text": "Hello"}, {"type": "text", "text": " world"}]"#;
+        assert_eq!(prompt(text, chat_formatter()), "[INST] Hello world [/INST]");
+        assert_eq!(
+            prompt(parts, chat_formatter()),
+            "[INST] Hello world [/INST]"
+        );
+        assert_eq!(
+            prompt(text, compat_config.build_chat_formatter()),
+            "<|user|>Hello world"
+        );
+        assert_eq!(
+            prompt(parts, compat_config.build_chat_formatter()),
+            "<|user|>Hello world"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_top_level_key() {
+        assert_eq!(
+            duplicate_top_level_key(br#"{"prompt": "a", "seed": 1}"#),
+            None
+        );
+        assert_eq!(
+            duplicate_top_level_key(br#"{"prompt": "a", "foo": 1, "foo": 2, "prompt": "b"}"#),
+            Some("foo".to_string())
+        );
+        // Only top-level keys are checked
+        assert_eq!(
+            duplicate_top_level_key(br#"{"logit_bias": {"1": 1.0}, "metadata": {"1": 2.0}}"#),
+            None
+        );
+        assert_eq!(duplicate_top_level_key(b"not json"), None);
+    }
+
+    #[test]
+    fn test_prompt_shapes() {
+        let prompt = |body: &str| {
+            serde_json::from_str::<CompatCompletionRequest>(body).map(|req| req.prompt)
+        };
+        assert_eq!(
+            prompt(r#"{"prompt": "Hi"}"#).unwrap(),
+            Some(vec!["Hi".to_string()])
+        );
+        assert_eq!(
+            prompt(r#"{"prompt": ["Hi"]}"#).unwrap(),
+            Some(vec!["Hi".to_string()])
+        );
+        assert_eq!(
+            prompt(r#"{"prompt": ["Hi", "Bye"]}"#).unwrap(),
+            Some(vec!["Hi".to_string(), "Bye".to_string()])
+        );
+        assert_eq!(prompt(r#"{"prompt": null}"#).unwrap(), None);
+        assert_eq!(prompt(r#"{}"#).unwrap(), None);
+        for body in [
+            r#"{"prompt": []}"#,
+            r#"{"prompt": [1, 2]}"#,
+            r#"{"prompt": [[1, 2]]}"#,
+            r#"{"prompt": 1}"#,
+        ] {
+            assert!(prompt(body).is_err(), "{body}");
+        }
+
+        // Only a null prompt may generate from empty inputs
+        let req: GenerateRequest = serde_json::from_str::<CompatCompletionRequest>("{}")
+            .unwrap()
+            .into();
+        assert_eq!(req.inputs, "");
+        assert!(req.parameters.allow_empty_inputs);
+        let req: GenerateRequest =
+            serde_json::from_str::<CompatCompletionRequest>(r#"{"prompt": ""}"#)
+                .unwrap()
+                .into();
+        assert!(!req.parameters.allow_empty_inputs);
+    }
+
+    #[test]
+    fn test_decompress_body() {
+        use flate2::write::{GzEncoder, ZlibEncoder};
+        use flate2::Compression;
+        use std::io::Write;
+
+        let body = br#"{"prompt": "Hello"}"#;
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(body).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let mut deflate = ZlibEncoder::new(Vec::new(), Compression::default());
+        deflate.write_all(body).unwrap();
+        let deflate = deflate.finish().unwrap();
+
+        assert_eq!(
+            decompress_body("gzip", &gzip, 1024).ok(),
+            Some(body.to_vec())
+        );
+        assert_eq!(
+            decompress_body("deflate", &deflate, 1024).ok(),
+            Some(body.to_vec())
+        );
+        // The limit applies to the decompressed size
+        assert_eq!(
+            decompress_body("gzip", &gzip, body.len()).ok(),
+            Some(body.to_vec())
+        );
+        assert_eq!(
+            decompress_body("gzip", &gzip, body.len() - 1)
+                .unwrap_err()
+                .0,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            decompress_body("gzip", &deflate, 1024).unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            decompress_body("br", &gzip, 1024).unwrap_err().0,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            decompress_body("gzip", &gzip, 0).unwrap_err().0,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+
+        // Compressed bodies are decompressed before the JSON parsing
+        let compat_config = CompatConfig {
+            max_decompressed_body_size: 1024,
+            ..Default::default()
+        };
+        let req = Request::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_ENCODING, "GZIP")
+            .extension(compat_config)
+            .body(Body::from(gzip))
+            .unwrap();
+        let req = futures::executor::block_on(CompatJson::<CompatCompletionRequest>::from_request(
+            req,
+            &(),
+        ))
+        .unwrap();
+        assert_eq!(req.prompt, Some(vec!["Hello".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn test_compat_error_body() {
+        use axum::body::HttpBody;
+
+        async fn error(response: Response) -> (StatusCode, serde_json::Value) {
+            let status = response.status();
+            let mut body = response.into_body();
+            let mut bytes = Vec::new();
+            while let Some(data) = body.data().await {
+                bytes.extend_from_slice(&data.unwrap());
+            }
+            (status, serde_json::from_slice(&bytes).unwrap())
+        }
+        let request = |body: &'static str| {
+            Request::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .extension(CompatConfig {
+                    strict_json: true,
+                    ..Default::default()
+                })
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        // Parameters of the wrong type
+        let rejection =
+            CompatJson::<CompatCompletionRequest>::from_request(request(r#"{"stop": 1}"#), &())
+                .await
+                .err()
+                .unwrap();
+        let (status, body) = error(rejection).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let details = body["error"].as_object().unwrap();
+        assert_eq!(
+            details.keys().collect::<Vec<_>>(),
+            vec!["code", "message", "param", "type"]
+        );
+        assert!(details["message"].as_str().unwrap().contains("stop"));
+        assert_eq!(details["type"], "invalid_request_error");
+        assert_eq!(details["code"], "validation");
+        assert!(details["param"].is_null());
+
+        // Duplicate parameters
+        let rejection = CompatJson::<CompatCompletionRequest>::from_request(
+            request(r#"{"temperature": 0.5, "temperature": 2}"#),
+            &(),
+        )
+        .await
+        .err()
+        .unwrap();
+        let (status, body) = error(rejection).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body,
+            serde_json::json!({"error": {
+                "message": "duplicate key `temperature` in request body",
+                "type": "invalid_request_error",
+                "param": "temperature",
+                "code": "validation"
+            }})
+        );
+
+        // Generation failures are server errors
+        let err: (StatusCode, Json<ErrorResponse>) =
+            crate::infer::InferError::IncompleteGeneration.into();
+        let (status, body) = error(CompatError::from(err).into_response()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["error"]["type"], "server_error");
+        assert_eq!(body["error"]["code"], "incomplete_generation");
+    }
+
+    #[test]
+    fn test_models_response() {
+        let info = Info {
+            model_id: "bigscience/blomm-560m".to_string(),
+            model_sha: None,
+            model_dtype: "torch.float16".to_string(),
+            model_device_type: "cuda".to_string(),
+            model_pipeline_tag: None,
+            max_concurrent_requests: 128,
+            max_best_of: 2,
+            max_stop_sequences: 4,
+            max_input_length: 1024,
+            max_total_tokens: 2048,
+            waiting_served_ratio: 1.2,
+            max_batch_total_tokens: 32000,
+            max_waiting_tokens: 20,
+            validation_workers: 2,
+            version: "0.5.0",
+            sha: None,
+            docker_label: None,
+            chat_template: None,
+        };
+        let compat_config = CompatConfig {
+            max_stream_duration: Some(Duration::from_secs(60)),
+            model_aliases: vec!["gpt-3.5-turbo".to_string()],
+            ..Default::default()
+        };
+        let models = serde_json::to_value(models_response(&info, &compat_config)).unwrap();
+
+        assert_eq!(models["object"], "list");
+        let model = &models["data"][0];
+        assert_eq!(model["id"], "bigscience/blomm-560m");
+        assert_eq!(model["object"], "model");
+        assert_eq!(model["owned_by"], "tgi");
+        assert_eq!(model["tgi_capabilities"]["streaming"], true);
+        assert_eq!(model["tgi_capabilities"]["logprobs"], true);
+        assert_eq!(model["tgi_capabilities"]["json_mode"], true);
+        assert_eq!(
+            model["tgi_capabilities"]["response_format_enforcement"],
+            "prompt-only"
+        );
+        assert_eq!(model["tgi_capabilities"]["max_total_tokens"], 2048);
+        assert_eq!(model["tgi_capabilities"]["max_stream_duration"], 60);
+        assert!(model.get("root").is_none());
+
+        // Aliases are listed after the served model
+        let alias = &models["data"][1];
+        assert_eq!(alias["id"], "gpt-3.5-turbo");
+        assert_eq!(alias["root"], "bigscience/blomm-560m");
+        assert_eq!(alias["tgi_capabilities"]["max_total_tokens"], 2048);
+    }
+
+    #[test]
+    fn test_legacy_engine() {
+        let model = |body: &str| -> Option<String> {
+            let req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+            req.model().map(String::from)
+        };
+        assert_eq!(
+            model(r#"{"prompt": "Hello", "engine": "tgi"}"#).unwrap(),
+            "tgi"
+        );
+        assert_eq!(
+            model(r#"{"prompt": "Hello", "engine": "tgi", "model": "gpt-3.5-turbo"}"#).unwrap(),
+            "gpt-3.5-turbo"
+        );
+        assert_eq!(model(r#"{"prompt": "Hello"}"#), None);
+
+        let req: CompatChatCompletionRequest =
+            serde_json::from_str(r#"{"messages": [], "engine": "tgi"}"#).unwrap();
+        assert_eq!(req.model(), Some("tgi"));
+    }
+
+    #[test]
+    fn test_served_model() {
+        let model_id = "bigscience/blomm-560m";
+
+        // Any model is accepted and echoed back without aliases
+        let compat_config = CompatConfig::default();
+        assert_eq!(
+            compat_config.served_model(model_id, Some("gpt-3.5-turbo")),
+            Some("gpt-3.5-turbo".to_string())
+        );
+        assert_eq!(
+            compat_config.served_model(model_id, Some(model_id)),
+            Some(model_id.to_string())
+        );
+        assert_eq!(
+            compat_config.served_model(model_id, None),
+            Some(model_id.to_string())
+        );
+
+        let compat_config = CompatConfig {
+            model_aliases: vec!["gpt-3.5-turbo".to_string(), "tgi".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            compat_config.served_model(model_id, Some("tgi")),
+            Some("tgi".to_string())
+        );
+        assert_eq!(
+            compat_config.served_model(model_id, Some(model_id)),
+            Some(model_id.to_string())
+        );
+        assert_eq!(
+            compat_config.served_model(model_id, None),
+            Some(model_id.to_string())
+        );
+        assert_eq!(compat_config.served_model(model_id, Some("gpt-4")), None);
+    }
+
+    #[tokio::test]
+    async fn test_response_model() {
+        let model_id = "bigscience/bloom-560m";
+        let compat_config = CompatConfig::default();
+        let options = CompatRequestOptions::default();
+        let response_model = |body: &str| {
+            let req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+            let model = compat_config.served_model(model_id, req.model()).unwrap();
+            let response = Json(GenerateResponse {
+                generated_text: "Hi".to_string(),
+                details: None,
+            });
+            generate_to_completions(response, model, &compat_config, &options)
+        };
+
+        // The requested model is echoed back
+        let resp = response_model(r#"{"prompt": "Hi", "model": "gpt-3.5-turbo"}"#).await;
+        assert_eq!(resp.model, "gpt-3.5-turbo");
+        // Requests without a model get the served model
+        let resp = response_model(r#"{"prompt": "Hi"}"#).await;
+        assert_eq!(resp.model, model_id);
+
+        let req: CompatChatCompletionRequest =
+            serde_json::from_str(r#"{"messages": [], "model": "gpt-4o"}"#).unwrap();
+        let model = compat_config.served_model(model_id, req.model()).unwrap();
+        let response = Json(GenerateResponse {
+            generated_text: "Hi".to_string(),
+            details: None,
+        });
+        let resp = generate_to_chatcompletions(response, model, &compat_config, &options).await;
+        assert_eq!(resp.model, "gpt-4o");
+    }
+
+    #[test]
+    fn test_echo_prompt_token_chunks() {
+        let prefill = PrefillTokens {
+            ids: vec![1, 2, 3],
+            logprobs: vec![f32::NAN, -0.5, -1.5],
+            texts: vec!["Hello".to_string(), ",".to_string(), " world".to_string()],
+        };
+        let chunks = echo_prompt_token_chunks(0.into(), "cmpl-0", prefill, &"tgi".to_string());
+        assert_eq!(chunks.len(), 3);
+
+        let texts: Vec<&str> = chunks.iter().map(|c| c.choices[0].text.as_str()).collect();
+        assert_eq!(texts, vec!["Hello", ",", " world"]);
+
+        let logprobs: Vec<Option<f32>> = chunks
+            .iter()
+            .map(|c| c.choices[0].logprobs.as_ref().unwrap().token_logprobs[0])
+            .collect();
+        assert_eq!(logprobs, vec![None, Some(-0.5), Some(-1.5)]);
+
+        let first = serde_json::to_value(&chunks[0]).unwrap();
+        assert_eq!(
+            first["choices"][0]["logprobs"],
+            serde_json::json!({
+                "tokens": ["Hello"],
+                "token_logprobs": [null],
+                "top_logprobs": [null],
+                "text_offset": [0]
+            })
+        );
+        assert!(first["choices"][0].get("finish_reason").is_none());
+        let second = serde_json::to_value(&chunks[1]).unwrap();
+        assert_eq!(
+            second["choices"][0]["logprobs"]["top_logprobs"],
+            serde_json::json!([{",": -0.5}])
+        );
+        // The offsets run across the chunks of the echoed prompt
+        let offsets: Vec<usize> = chunks
+            .iter()
+            .map(|c| c.choices[0].logprobs.as_ref().unwrap().text_offset[0])
+            .collect();
+        assert_eq!(offsets, vec![0, 5, 6]);
+    }
+
+    #[test]
+    fn test_completion_context() {
+        let chat_config: ChatConfig = serde_json::from_str(
+            r#"{"completion_context_template": {"pre": "<doc>", "post": "</doc>\n"}}"#,
+        )
+        .unwrap();
+        let request = |chat_config: &ChatConfig, body: &str| {
+            let mut req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+            chat_config
+                .add_completion_context(&mut req)
+                .map(|_| GenerateRequest::from(req))
+        };
+
+        let req = request(
+            &chat_config,
+            r#"{"prompt": "Q: capital?", "context": ["Munich", "Bavaria"], "echo": true}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            req.inputs,
+            "<doc>Munich</doc>\n<doc>Bavaria</doc>\nQ: capital?"
+        );
+        // The echoed prompt includes the context
+        assert_eq!(req.parameters.return_full_text, Some(true));
+
+        // Without a prompt, the generation continues the context
+        let req = request(&chat_config, r#"{"context": ["Munich"]}"#).unwrap();
+        assert_eq!(req.inputs, "<doc>Munich</doc>\n");
+        assert!(!req.parameters.allow_empty_inputs);
+
+        // Requests without a context are unchanged
+        let req = request(&chat_config, r#"{"prompt": "Hi", "context": []}"#).unwrap();
+        assert_eq!(req.inputs, "Hi");
+        let req = request(&ChatConfig::default(), r#"{"prompt": "Hi"}"#).unwrap();
+        assert_eq!(req.inputs, "Hi");
+
+        let err = request(
+            &ChatConfig::default(),
+            r#"{"prompt": "Hi", "context": ["Munich"]}"#,
+        )
+        .unwrap_err();
+        assert_eq!(err.param(), Some("context"));
+    }
+
+    #[tokio::test]
+    async fn test_echo_logprobs() {
+        let parameters = |body: &str| {
+            let req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+            (req.options(), GenerateRequest::from(req).parameters)
+        };
+        // The prefill details are computed for the logprobs of the echoed prompt
+        let (options, echo) = parameters(r#"{"prompt": "Hi", "echo": true, "logprobs": 0}"#);
+        assert!(echo.decoder_input_details);
+        assert!(options.logprobs && options.echo);
+        let (options, no_echo) = parameters(r#"{"prompt": "Hi", "logprobs": 1}"#);
+        assert!(!no_echo.decoder_input_details);
+        assert!(options.logprobs && !options.echo);
+        let (options, no_logprobs) = parameters(r#"{"prompt": "Hi", "echo": true}"#);
+        assert!(!no_logprobs.decoder_input_details);
+        assert!(!options.logprobs);
+
+        let response = Json(GenerateResponse {
+            generated_text: "Hi there".to_string(),
+            details: Some(crate::Details {
+                finish_reason: FinishReason::Length,
+                generated_tokens: 1,
+                seed: None,
+                prefill: vec![PrefillToken {
+                    id: 1,
+                    text: "Hi".to_string(),
+                    logprob: f32::NAN,
+                }],
+                tokens: vec![Token {
+                    id: 2,
+                    text: " there".to_string(),
+                    logprob: -0.5,
+                    special: false,
+                }],
+                best_of_sequences: None,
+                prompt_tokens: None,
+                truncated_tokens: 0,
+                timings: None,
+            }),
+        });
+        let options = parameters(r#"{"prompt": "Hi", "echo": true, "logprobs": 0}"#).0;
+        let completion = generate_to_completions(
+            response,
+            "tgi".to_string(),
+            &CompatConfig::default(),
+            &options,
+        )
+        .await;
+        let completion = serde_json::to_value(completion.0).unwrap();
+        assert_eq!(
+            completion["choices"][0]["logprobs"],
+            serde_json::json!({
+                "tokens": ["Hi", " there"],
+                "token_logprobs": [null, -0.5],
+                "top_logprobs": [null, {" there": -0.5}],
+                "text_offset": [0, 2]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_completion_logprobs_alignment() {
+        let response = |logprobs: &[(&str, f32)]| {
+            Json(GenerateResponse {
+                generated_text: logprobs.iter().map(|(text, _)| *text).collect(),
+                details: Some(crate::Details {
+                    finish_reason: FinishReason::Length,
+                    generated_tokens: logprobs.len() as u32,
+                    seed: None,
+                    prefill: vec![],
+                    tokens: logprobs
+                        .iter()
+                        .enumerate()
+                        .map(|(id, (text, logprob))| Token {
+                            id: id as u32,
+                            text: text.to_string(),
+                            logprob: *logprob,
+                            special: false,
+                        })
+                        .collect(),
+                    best_of_sequences: None,
+                    prompt_tokens: None,
+                    truncated_tokens: 0,
+                    timings: None,
+                }),
+            })
+        };
+        let completion = |body: &str, logprobs: &[(&str, f32)]| {
+            let req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+            let options = req.options();
+            let response = response(logprobs);
+            async move {
+                generate_to_completions(
+                    response,
+                    "tgi".to_string(),
+                    &CompatConfig::default(),
+                    &options,
+                )
+                .await
+                .0
+            }
+        };
+
+        let tokens = [("Grüß", -0.1), (" Gott", -1.5), ("!", -0.25)];
+        let resp = completion(r#"{"prompt": "Hi", "logprobs": 1}"#, &tokens).await;
+        let choice = &resp.choices[0];
+        let logprobs = choice.logprobs.as_ref().unwrap();
+        // One entry per generated token, in order, with the offsets in the returned text
+        assert_eq!(logprobs.tokens, vec!["Grüß", " Gott", "!"]);
+        assert_eq!(
+            logprobs.token_logprobs,
+            vec![Some(-0.1), Some(-1.5), Some(-0.25)]
+        );
+        assert_eq!(logprobs.text_offset, vec![0, 4, 9]);
+        assert_eq!(logprobs.top_logprobs.len(), 3);
+        for (i, offset) in logprobs.text_offset.iter().enumerate() {
+            let token: String = choice
+                .text
+                .chars()
+                .skip(*offset)
+                .take(logprobs.tokens[i].chars().count())
+                .collect();
+            assert_eq!(token, logprobs.tokens[i]);
+        }
+        assert_eq!(
+            logprobs.top_logprobs[1],
+            Some(TopLogprobs::new(" Gott".to_string(), -1.5))
+        );
+
+        // Not returned unless requested
+        let resp = completion(r#"{"prompt": "Hi"}"#, &tokens).await;
+        assert!(resp.choices[0].logprobs.is_none());
+    }
+
+    #[test]
+    fn test_top_logprobs() {
+        // Only the sampled token is listed
+        let top_logprobs = TopLogprobs::new(" Gott".to_string(), -1.5);
+        assert_eq!(
+            serde_json::to_string(&top_logprobs).unwrap(),
+            r#"{" Gott":-1.5}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_response_timings() {
+        let start_time = Instant::now();
+        let at = |ms| start_time + Duration::from_millis(ms);
+        let timings = Timings::new(start_time, at(1), at(3), at(10), at(110), 11);
+        assert_eq!(timings.queue_ms, 2.0);
+        assert_eq!(timings.prefill_ms, 7.0);
+        assert_eq!(timings.decode_ms, 100.0);
+        assert_eq!(timings.decode_tokens_per_second, Some(100.0));
+        assert_eq!(
+            timings.server_timing(),
+            "queue;dur=2.0, prefill;dur=7.0, decode;dur=100.0"
+        );
+        let single_token = Timings::new(start_time, at(1), at(3), at(10), at(10), 1);
+        assert_eq!(single_token.decode_tokens_per_second, None);
+
+        let response = || {
+            Json(GenerateResponse {
+                generated_text: "Hi".to_string(),
+                details: Some(crate::Details {
+                    finish_reason: FinishReason::EndOfSequenceToken,
+                    generated_tokens: 11,
+                    seed: None,
+                    prefill: vec![],
+                    tokens: vec![],
+                    best_of_sequences: None,
+                    prompt_tokens: None,
+                    truncated_tokens: 0,
+                    timings: Some(timings.clone()),
+                }),
+            })
+        };
+        let options = CompatRequestOptions::default();
+
+        // Only returned when enabled
+        let compat_config = CompatConfig::default();
+        let Json(resp) =
+            generate_to_completions(response(), "tgi".to_string(), &compat_config, &options).await;
+        assert!(resp.timings.is_none());
+        let compat_config = CompatConfig {
+            response_timings: true,
+            ..Default::default()
+        };
+        let Json(resp) =
+            generate_to_chatcompletions(response(), "tgi".to_string(), &compat_config, &options)
+                .await;
+        let resp = serde_json::to_value(resp).unwrap();
+        assert_eq!(resp["__timings"]["decode_ms"], 100.0);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_tokens_without_prefill() {
+        let response = |prefill: Vec<PrefillToken>| {
+            Json(GenerateResponse {
+                generated_text: "Hi".to_string(),
+                details: Some(crate::Details {
+                    finish_reason: FinishReason::EndOfSequenceToken,
+                    generated_tokens: 2,
+                    seed: None,
+                    prefill,
+                    tokens: vec![],
+                    best_of_sequences: None,
+                    prompt_tokens: None,
+                    truncated_tokens: 0,
+                    timings: None,
+                }),
+            })
+        };
+        let compat_config = CompatConfig::default();
+        // Prompt counted by the router, without `decoder_input_details`
+        let options = CompatRequestOptions {
+            prompt_tokens: Some(7),
+            ..Default::default()
+        };
+
+        let Json(resp) = generate_to_completions(
+            response(vec![]),
+            "tgi".to_string(),
+            &compat_config,
+            &options,
+        )
+        .await;
+        let usage = resp.usage.unwrap();
+        assert_eq!(usage.prompt_tokens.value, 7);
+        assert_eq!(usage.total_tokens.value, 9);
+        let Json(resp) = generate_to_chatcompletions(
+            response(vec![]),
+            "tgi".to_string(),
+            &compat_config,
+            &options,
+        )
+        .await;
+        assert_eq!(resp.usage.prompt_tokens.value, 7);
+        assert_eq!(resp.usage.total_tokens.value, 9);
+
+        // The returned prefill is counted as is
+        let prefill = (0..3)
+            .map(|id| PrefillToken {
+                id,
+                text: "<s>".to_string(),
+                logprob: f32::NAN,
+            })
+            .collect();
+        let Json(resp) = generate_to_completions(
+            response(prefill),
+            "tgi".to_string(),
+            &compat_config,
+            &options,
+        )
+        .await;
+        assert_eq!(resp.usage.unwrap().prompt_tokens.value, 3);
+
+        // The count of the validation is used first, as it does not depend on the router count
+        let mut validated = response(vec![]);
+        validated.details.as_mut().unwrap().prompt_tokens = Some(5);
+        let Json(resp) = generate_to_chatcompletions(
+            validated,
+            "tgi".to_string(),
+            &compat_config,
+            &CompatRequestOptions::default(),
+        )
+        .await;
+        assert_eq!(resp.usage.prompt_tokens.value, 5);
+    }
+
+    #[tokio::test]
+    async fn test_unique_ids() {
+        let response = || {
+            Json(GenerateResponse {
+                generated_text: "Hi".to_string(),
+                details: None,
+            })
+        };
+        let compat_config = CompatConfig::default();
+        let options = CompatRequestOptions::default();
+
+        // Requests of the same second get different ids
+        let Json(first) =
+            generate_to_completions(response(), "tgi".to_string(), &compat_config, &options).await;
+        let Json(second) =
+            generate_to_completions(response(), "tgi".to_string(), &compat_config, &options).await;
+        assert!(first.id.starts_with("cmpl-"));
+        assert_eq!(first.id.len(), "cmpl-".len() + 32);
+        assert_ne!(first.id, second.id);
+
+        let Json(first) =
+            generate_to_chatcompletions(response(), "tgi".to_string(), &compat_config, &options)
+                .await;
+        let Json(second) =
+            generate_to_chatcompletions(response(), "tgi".to_string(), &compat_config, &options)
+                .await;
+        assert!(first.id.starts_with("chatcmpl-"));
+        assert_ne!(first.id, second.id);
+
+        // The chunks of a stream share its id
+        let stream_type = OpenaiStreamType::ChatCompletionsStreamResponse;
+        let id = create_id(&stream_type);
+        assert_ne!(id, create_id(&stream_type));
+        let start = chat_start_message(0.into(), &id, &"tgi".to_string(), ChatRole::Assistant);
+        assert_eq!(start.id, id);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_tokens_usage() {
+        let response = |truncated_tokens| {
+            Json(GenerateResponse {
+                generated_text: "Hi".to_string(),
+                details: Some(crate::Details {
+                    finish_reason: FinishReason::EndOfSequenceToken,
+                    generated_tokens: 1,
+                    seed: None,
+                    prefill: vec![],
+                    tokens: vec![],
+                    best_of_sequences: None,
+                    prompt_tokens: None,
+                    truncated_tokens,
+                    timings: None,
+                }),
+            })
+        };
+        let compat_config = CompatConfig::default();
+        let options = CompatRequestOptions::default();
+
+        let Json(resp) =
+            generate_to_completions(response(3), "tgi".to_string(), &compat_config, &options).await;
+        let usage = serde_json::to_value(resp.usage).unwrap();
+        assert_eq!(usage["prompt_tokens_details"]["truncated_tokens"], 3);
+
+        // Only reported when the prompt was truncated
+        let Json(resp) =
+            generate_to_chatcompletions(response(0), "tgi".to_string(), &compat_config, &options)
+                .await;
+        let usage = serde_json::to_value(resp.usage).unwrap();
+        assert!(usage.get("prompt_tokens_details").is_none());
+
+        // The prompt of `n` choices is truncated once
+        let choices = vec![
+            generate_to_chatcompletions(response(3), "tgi".to_string(), &compat_config, &options)
+                .await
+                .0,
+            generate_to_chatcompletions(response(3), "tgi".to_string(), &compat_config, &options)
+                .await
+                .0,
+        ];
+        let merged = merge_chatcompletions(choices, &compat_config).unwrap();
+        assert_eq!(merged.usage.truncated_tokens(), 3);
+    }
+
+    #[test]
+    fn test_max_logprobs_tokens() {
+        let tokens: Vec<Token> = ["a", "b", "c"]
+            .into_iter()
+            .map(|text| Token {
+                id: 0,
+                text: text.to_string(),
+                logprob: -0.5,
+                special: false,
+            })
+            .collect();
+        let prompt = vec![PrefillToken {
+            id: 0,
+            text: "p".to_string(),
+            logprob: f32::NAN,
+        }];
+        let logprobs = CompletionLogprobs::new(&prompt, &tokens, 2);
+        assert_eq!(logprobs.tokens, vec!["p", "a"]);
+        assert_eq!(logprobs.text_offset.len(), 2);
+        assert_eq!(logprobs.tgi_omitted_tokens, Some(2));
+        assert!(CompletionLogprobs::new(&prompt, &tokens, 0)
+            .tgi_omitted_tokens
+            .is_none());
+
+        let logprobs = ChatCompletionLogprobs::new(&tokens, 2, 1);
+        assert_eq!(logprobs.content.len(), 2);
+        assert_eq!(logprobs.tgi_omitted_tokens, Some(1));
+
+        // Not reported when under the cap, nor without cap
+        for max_tokens in [3, 0] {
+            let logprobs = ChatCompletionLogprobs::new(&tokens, max_tokens, 1);
+            assert_eq!(logprobs.content.len(), 3);
+            let logprobs = serde_json::to_value(logprobs).unwrap();
+            assert!(logprobs.get("tgi_omitted_tokens").is_none());
+        }
+    }
+
+    #[test]
+    fn test_finish_details() {
+        let response = |text: &str, finish_reason: FinishReason| GenerateResponse {
+            generated_text: text.to_string(),
+            details: Some(crate::Details {
+                finish_reason,
+                generated_tokens: 2,
+                seed: None,
+                prefill: vec![],
+                tokens: [(5, "Hi"), (7, "##")]
+                    .into_iter()
+                    .map(|(id, text)| Token {
+                        id,
+                        text: text.to_string(),
+                        logprob: -0.5,
+                        special: false,
+                    })
+                    .collect(),
+                best_of_sequences: None,
+                prompt_tokens: None,
+                truncated_tokens: 0,
+                timings: None,
+            }),
+        };
+        let stop = vec!["##".to_string()];
+
+        let details = FinishDetails::new(&response("Hi##", FinishReason::StopSequence), &stop);
+        let expected = FinishDetails {
+            token_id: 7,
+            position: 1,
+            stop_sequence: Some("##".to_string()),
+        };
+        assert_eq!(details, Some(expected));
+
+        // The last token completed both stop sequences, and the output is cut at the first one
+        let stop = vec!["##".to_string(), "\n\n".to_string()];
+        let details = FinishDetails::new(&response("42##\n\n", FinishReason::StopSequence), &stop);
+        assert_eq!(details.unwrap().stop_sequence.as_deref(), Some("##"));
+
+        // The stop sequence is only reported when it stopped the generation
+        let details = FinishDetails::new(&response("Hi##", FinishReason::Length), &stop).unwrap();
+        assert_eq!(
+            serde_json::to_value(details).unwrap(),
+            serde_json::json!({"token_id": 7, "position": 1})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_logprobs_null() {
+        let response = || {
+            Json(GenerateResponse {
+                generated_text: "Hi".to_string(),
+                details: Some(crate::Details {
+                    finish_reason: FinishReason::EndOfSequenceToken,
+                    generated_tokens: 1,
+                    seed: None,
+                    prefill: vec![],
+                    tokens: vec![Token {
+                        id: 0,
+                        text: "Hi".to_string(),
+                        logprob: -0.5,
+                        special: false,
+                    }],
+                    best_of_sequences: None,
+                    prompt_tokens: None,
+                    truncated_tokens: 0,
+                    timings: None,
+                }),
+            })
+        };
+        let compat_config = CompatConfig::default();
+        let options = CompatRequestOptions::default();
+
+        // The key is present when logprobs are not requested
+        let chat =
+            generate_to_chatcompletions(response(), "tgi".to_string(), &compat_config, &options)
+                .await;
+        let chat = serde_json::to_value(chat.0).unwrap();
+        assert!(chat["choices"][0]
+            .as_object()
+            .unwrap()
+            .contains_key("logprobs"));
+        assert!(chat["choices"][0]["logprobs"].is_null());
+
+        let completion =
+            generate_to_completions(response(), "tgi".to_string(), &compat_config, &options).await;
+        let completion = serde_json::to_value(completion.0).unwrap();
+        assert!(completion["choices"][0]
+            .as_object()
+            .unwrap()
+            .contains_key("logprobs"));
+        assert!(completion["choices"][0]["logprobs"].is_null());
+
+        let options = CompatRequestOptions {
+            logprobs: true,
+            top_logprobs: 1,
+            ..Default::default()
+        };
+        let chat =
+            generate_to_chatcompletions(response(), "tgi".to_string(), &compat_config, &options)
+                .await;
+        let chat = serde_json::to_value(chat.0).unwrap();
+        assert_eq!(
+            chat["choices"][0]["logprobs"]["content"][0],
+            serde_json::json!({
+                "token": "Hi",
+                "logprob": -0.5,
+                "bytes": [72, 105],
+                "top_logprobs": [{"token": "Hi", "logprob": -0.5, "bytes": [72, 105]}]
+            })
+        );
+
+        // No alternatives are listed unless requested
+        let options = CompatRequestOptions {
+            logprobs: true,
+            ..Default::default()
+        };
+        let chat =
+            generate_to_chatcompletions(response(), "tgi".to_string(), &compat_config, &options)
+                .await;
+        let chat = serde_json::to_value(chat.0).unwrap();
+        assert_eq!(
+            chat["choices"][0]["logprobs"]["content"][0]["top_logprobs"],
+            serde_json::json!([])
+        );
+    }
+
+    #[test]
+    fn test_response_format() {
+        let request = |response_format: &str| {
+            serde_json::from_str::<CompatChatCompletionRequest>(&format!(
+                r#"{{"messages": [], "response_format": {response_format}}}"#
+            ))
+            .map(|req| req.options().response_format)
+        };
+        assert_eq!(request("null").unwrap(), ResponseFormat::Text);
+        assert_eq!(
+            request(r#"{"type": "text"}"#).unwrap(),
+            ResponseFormat::Text
+        );
+        assert_eq!(
+            request(r#"{"type": "json_object"}"#).unwrap(),
+            ResponseFormat::JsonObject
+        );
+        assert_eq!(
+            request(r#"{"type": "json_schema", "json_schema": {"name": "answer"}}"#).unwrap(),
+            ResponseFormat::JsonSchema
+        );
+        assert!(request(r#"{"type": "yaml"}"#).is_err());
+        assert_eq!(
+            ResponseFormat::Text.enforcement(),
+            ResponseFormatEnforcement::None
+        );
+        assert_eq!(
+            ResponseFormat::JsonObject.enforcement(),
+            ResponseFormatEnforcement::PromptOnly
+        );
+    }
+
+    #[test]
+    fn test_json_mode_instruction() {
+        let chat_config: ChatConfig =
+            serde_json::from_str(r#"{"default_system": "Be brief."}"#).unwrap();
+        let prompt = |body: &str| {
+            let mut req: CompatChatCompletionRequest = serde_json::from_str(body).unwrap();
+            let response_format = req.options().response_format;
+            req.add_system_messages(&chat_config, response_format);
+            chat_to_generate_request(req, &chat_formatter(), &chat_config)
+                .unwrap()
+                .inputs
+        };
+        let hi = r#"{"role": "user", "content": "Hi"}"#;
+        let json_object = r#""response_format": {"type": "json_object"}"#;
+
+        // The instruction ends the system message, the default one included
+        assert_eq!(
+            prompt(&format!(r#"{{"messages": [{hi}], {json_object}}}"#)),
+            "<<SYS>> Be brief.\n\nRespond only with a valid JSON object. <</SYS>>[INST] Hi [/INST]"
+        );
+        let system = r#"{"role": "system", "content": "Be verbose."}"#;
+        assert_eq!(
+            prompt(&format!(r#"{{"messages": [{system}, {hi}], {json_object}}}"#)),
+            "<<SYS>> Be verbose.\n\nRespond only with a valid JSON object. <</SYS>>[INST] Hi [/INST]"
+        );
+        // Or starts the conversation
+        assert_eq!(
+            prompt(&format!(
+                r#"{{"messages": [{hi}], {json_object}, "disable_default_system": true}}"#
+            )),
+            "<<SYS>> Respond only with a valid JSON object. <</SYS>>[INST] Hi [/INST]"
+        );
+
+        // Other formats leave the prompt unchanged
+        for response_format in ["", r#", "response_format": {"type": "text"}"#] {
+            assert_eq!(
+                prompt(&format!(r#"{{"messages": [{hi}]{response_format}}}"#)),
+                "<<SYS>> Be brief. <</SYS>>[INST] Hi [/INST]"
+            );
+        }
+    }
+
+    #[test]
+    fn test_json_mode_output() {
+        let choice = |content: &str, finish_reason: FinishReason| {
+            let enforcement = ResponseFormat::JsonObject.enforcement();
+            ChatCompletionChoices {
+                message: ChatMessage {
+                    role: ChatRole::Assistant,
+                    content: ChatMessageContent::Text(content.to_string()),
+                },
+                tgi_format_complete: enforcement.format_complete(Some(&finish_reason)),
+                finish_reason: Some(finish_reason),
+                tgi_finish_reason: None,
+                tgi_finish_details: None,
+                tgi_empty_note: None,
+                logprobs: None,
+                index: 0,
+            }
+        };
+        let check = |response_format: ResponseFormat, content: &str, finish_reason| {
+            let mut choice = choice(content, finish_reason);
+            response_format.check_output(&mut choice);
+            let content = match choice.message.content {
+                ChatMessageContent::Text(text) => text,
+                ChatMessageContent::Parts(_) => unreachable!(),
+            };
+            (content, choice.tgi_format_complete)
+        };
+        let json = ResponseFormat::JsonObject;
+        let output = " {\"answer\": 42}\n";
+        assert_eq!(
+            check(json, output, FinishReason::EndOfSequenceToken),
+            (output.to_string(), Some(true))
+        );
+        // JSON objects in a code block are unwrapped
+        for output in [
+            "```json\n{\"answer\": 42}\n```",
+            "```\n{\"answer\": 42}```\n",
+        ] {
+            let (content, format_complete) = check(json, output, FinishReason::StopSequence);
+            assert_eq!(content.trim(), "{\"answer\": 42}");
+            assert_eq!(format_complete, Some(true));
+        }
+        // Other outputs are returned as incomplete, rather than failing the request
+        for output in [
+            "The answer is 42",
+            "[42]",
+            "{\"answer\": ",
+            "```python\n{\"answer\": 42}\n```",
+        ] {
+            for finish_reason in [FinishReason::EndOfSequenceToken, FinishReason::StopSequence] {
+                assert_eq!(
+                    check(json, output, finish_reason),
+                    (output.to_string(), Some(false))
+                );
+            }
+        }
+        // Like the outputs cut at `max_tokens`
+        assert_eq!(
+            check(json, "{\"answer\": ", FinishReason::Length).1,
+            Some(false)
+        );
+        // Without JSON mode, any output is left as is
+        assert_eq!(
+            check(ResponseFormat::Text, "42", FinishReason::EndOfSequenceToken),
+            ("42".to_string(), Some(true))
+        );
+    }
+
+    #[test]
+    fn test_trailing_whitespace() {
+        let text = "Hello \n\n";
+        assert_eq!(TrailingWhitespace::Keep.trim(text), text);
+        assert_eq!(TrailingWhitespace::Lines.trim(text), "Hello ");
+        assert_eq!(TrailingWhitespace::All.trim(text), "Hello");
+
+        // A single trailing space is kept unless all the whitespace is trimmed
+        assert_eq!(TrailingWhitespace::Lines.trim("Hello "), "Hello ");
+        assert_eq!(TrailingWhitespace::Lines.trim("Hello\r\n \t"), "Hello");
+    }
+
+    #[test]
+    fn test_prompt_normalization() {
+        let normalize = |normalization: PromptNormalization, text: &str| {
+            let mut text = text.to_string();
+            normalization.normalize(&mut text);
+            text
+        };
+        let text = "\u{feff}Hello\u{0}\tworld\r\n\u{7}  ";
+        assert_eq!(normalize(PromptNormalization::None, text), text);
+        assert_eq!(
+            normalize(PromptNormalization::Bom, text),
+            "Hello\u{0}\tworld\r\n\u{7}  "
+        );
+        // Tabs, line breaks and spaces are kept
+        assert_eq!(
+            normalize(PromptNormalization::Control, text),
+            "Hello\tworld\r\n  "
+        );
+
+        let mut content = ChatMessageContent::Parts(vec![ChatContentPart::Text {
+            text: "\u{feff}Hi".to_string(),
+        }]);
+        content.normalize(PromptNormalization::Bom);
+        let mut prompt = String::new();
+        content.push_text(&mut prompt);
+        assert_eq!(prompt, "Hi");
+    }
+
+    #[test]
+    fn test_preview_parameters() {
+        let req: ParametersPreviewRequest = serde_json::from_str(
+            r#"{"prompt": "Hi", "parameter_sets": [
+                {},
+                {"presence_penalty": 1.0, "max_tokens": 5},
+                {"temperature": 0.7, "stop": ["\n"]}
+            ]}"#,
+        )
+        .unwrap();
+        let previews = preview_parameters(req, &CompatConfig::default())
+            .unwrap()
+            .previews;
+        assert_eq!(previews.len(), 3);
+
+        assert!(!previews[0].sampling);
+        assert_eq!(
+            previews[0].parameters.max_new_tokens,
+            default_max_new_tokens()
+        );
+
+        // The OpenAI penalty is passed as is
+        assert_eq!(previews[1].parameters.presence_penalty, Some(1.0));
+        assert_eq!(previews[1].parameters.repetition_penalty, None);
+        assert_eq!(previews[1].parameters.max_new_tokens, 5);
+
+        // Sampling is implied by the temperature, like with OpenAI
+        assert!(previews[2].parameters.do_sample);
+        assert!(previews[2].sampling);
+        assert_eq!(previews[2].parameters.stop, vec!["\n".to_string()]);
+
+        let req: ParametersPreviewRequest = serde_json::from_str(
+            r#"{"prompt": "Hi", "parameter_sets": [{}, {"temperature": "hot"}]}"#,
+        )
+        .unwrap();
+        let err = preview_parameters(req, &CompatConfig::default()).unwrap_err();
+        assert_eq!(err.0, 1);
+        assert!(matches!(err.1, ParameterSetError::Deserialize(_)));
+
+        // The parameter sets are validated like the completions requests
+        let req: ParametersPreviewRequest = serde_json::from_str(
+            r#"{"prompt": "Hi", "parameter_sets": [{"top_p": 0}, {"stop": [""]}]}"#,
+        )
+        .unwrap();
+        let err = preview_parameters(req.clone(), &CompatConfig::default()).unwrap_err();
+        assert_eq!(err.0, 0);
+        assert!(matches!(
+            err.1,
+            ParameterSetError::Validation(ValidationError::TopP)
+        ));
+        let compat_config = CompatConfig {
+            zero_top_p: ZeroTopP::Clamp,
+            empty_stop: EmptyStop::Reject,
+            ..Default::default()
+        };
+        let err = preview_parameters(req, &compat_config).unwrap_err();
+        assert_eq!(err.0, 1);
+        assert!(matches!(
+            err.1,
+            ParameterSetError::Validation(ValidationError::EmptyStop(0))
+        ));
+        let req: ParametersPreviewRequest =
+            serde_json::from_str(r#"{"prompt": "Hi", "parameter_sets": [{"top_p": 0}]}"#).unwrap();
+        let previews = preview_parameters(req, &compat_config).unwrap().previews;
+        assert_eq!(previews[0].parameters.top_p, Some(MIN_TOP_P));
+    }
+
+    #[test]
+    fn test_validate_metadata() {
+        let req: CompatChatCompletionRequest = serde_json::from_str(
+            r#"{"messages": [{"role": "user", "content": "Hi"}], "metadata": {"experiment": "a"}}"#,
+        )
+        .unwrap();
+        let metadata = req.metadata.unwrap();
+        assert!(validate_metadata(&metadata).is_ok());
+
+        let too_many: HashMap<String, String> = (0..=MAX_METADATA_PAIRS)
+            .map(|i| (i.to_string(), String::new()))
+            .collect();
+        assert!(validate_metadata(&too_many).is_err());
+
+        let long_key = HashMap::from([("k".repeat(MAX_METADATA_KEY_LENGTH + 1), String::new())]);
+        assert!(validate_metadata(&long_key).is_err());
+
+        // Lengths are counted in characters rather than bytes
+        let value = "é".repeat(MAX_METADATA_VALUE_LENGTH);
+        let long_value = HashMap::from([("key".to_string(), value)]);
+        assert!(validate_metadata(&long_value).is_ok());
+        let value = "é".repeat(MAX_METADATA_VALUE_LENGTH + 1);
+        let long_value = HashMap::from([("key".to_string(), value)]);
+        assert!(validate_metadata(&long_value).is_err());
+    }
 }
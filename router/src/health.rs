@@ -12,17 +12,28 @@ const BATCH_ID: u64 = u64::MAX;
 pub(crate) struct Health {
     client: ShardedClient,
     generation_health: Arc<AtomicBool>,
+    /// Set once the warmup generation completed
+    ready: Arc<AtomicBool>,
 }
 
 impl Health {
-    pub(crate) fn new(client: ShardedClient, generation_health: Arc<AtomicBool>) -> Self {
+    pub(crate) fn new(
+        client: ShardedClient,
+        generation_health: Arc<AtomicBool>,
+        ready: Arc<AtomicBool>,
+    ) -> Self {
         Self {
             client,
             generation_health,
+            ready,
         }
     }
 
     pub(crate) async fn check(&mut self) -> bool {
+        if !self.ready.load(Ordering::SeqCst) {
+            // Still warming up
+            return false;
+        }
         if self.generation_health.load(Ordering::SeqCst) {
             // Generation is healthy, we only check that the shards are answering gRPC calls
             self.client.health().await.is_ok()
@@ -44,6 +55,11 @@ impl Health {
                     seed: 0,
                     repetition_penalty: 1.0,
                     watermark: false,
+                    logit_bias: vec![],
+                    logit_bias_first_token_only: false,
+                    sampler_order: vec![],
+                    presence_penalty: 0.0,
+                    frequency_penalty: 0.0,
                 }),
                 stopping_parameters: Some(StoppingCriteriaParameters {
                     max_new_tokens: 1,
@@ -20,6 +20,9 @@ use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore, TryAcquireError};
 use tokio::time::Instant;
 use tracing::{info_span, instrument, Instrument, Span};
 
+/// Responses of a request added to the queue
+pub(crate) type InferStream = RecvStream<'static, Result<InferStreamResponse, InferError>>;
+
 /// Inference struct
 #[derive(Clone)]
 pub struct Infer {
@@ -30,7 +33,78 @@ pub struct Infer {
     /// Shared state
     shared: Arc<Shared>,
     /// Inference limit
-    limit_concurrent_requests: Arc<Semaphore>,
+    limit_concurrent_requests: ConcurrencyLimit,
+}
+
+/// Limit of the concurrent requests
+#[derive(Clone)]
+struct ConcurrencyLimit {
+    permits: Arc<Semaphore>,
+    /// Notified when a permit is released
+    released: Arc<Notify>,
+    /// Bound of the low priority requests waiting for a permit
+    low_priority_waiters: Arc<Semaphore>,
+    /// Maximum time low priority requests wait for a permit
+    low_priority_timeout: Duration,
+}
+
+impl ConcurrencyLimit {
+    fn new(max_concurrent_requests: usize, low_priority_timeout: Duration) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrent_requests)),
+            released: Arc::new(Notify::new()),
+            low_priority_waiters: Arc::new(Semaphore::new(max_concurrent_requests)),
+            low_priority_timeout,
+        }
+    }
+
+    /// Acquire a permit, failing when none is free. Low priority requests wait for other requests
+    /// to complete rather than failing, unless too many of them are waiting already. Permits are
+    /// only taken when free, so that waiting low priority requests never reject the other requests
+    async fn acquire(&self, low_priority: bool) -> Result<InferPermit, TryAcquireError> {
+        if !low_priority {
+            return self.try_acquire();
+        }
+        let _waiter = self.low_priority_waiters.try_acquire()?;
+        let deadline = Instant::now() + self.low_priority_timeout;
+        loop {
+            match self.try_acquire() {
+                Err(TryAcquireError::NoPermits) => {}
+                result => return result,
+            }
+            // A permit released since the attempt is not missed: without waiting requests,
+            // `notify_one` completes the next wait
+            if tokio::time::timeout_at(deadline, self.released.notified())
+                .await
+                .is_err()
+            {
+                return Err(TryAcquireError::NoPermits);
+            }
+        }
+    }
+
+    fn try_acquire(&self) -> Result<InferPermit, TryAcquireError> {
+        let permit = self.permits.clone().try_acquire_owned()?;
+        Ok(InferPermit {
+            permit: Some(permit),
+            released: self.released.clone(),
+        })
+    }
+}
+
+/// Inference permit of a request, waking up a waiting low priority request once released
+#[derive(Debug)]
+pub(crate) struct InferPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    released: Arc<Notify>,
+}
+
+impl Drop for InferPermit {
+    fn drop(&mut self) {
+        // Release the permit before waking up a waiting request
+        self.permit.take();
+        self.released.notify_one();
+    }
 }
 
 /// Infer shared state
@@ -51,6 +125,7 @@ impl Infer {
         max_concurrent_requests: usize,
         requires_padding: bool,
         generation_health: Arc<AtomicBool>,
+        low_priority_timeout: Duration,
     ) -> Self {
         // Infer shared state
         let queue = Queue::new(requires_padding, 16);
@@ -71,33 +146,45 @@ impl Infer {
         ));
 
         // Inference limit with a semaphore
-        let semaphore = Arc::new(Semaphore::new(max_concurrent_requests));
+        let limit_concurrent_requests =
+            ConcurrencyLimit::new(max_concurrent_requests, low_priority_timeout);
 
         Self {
             validation,
             queue,
             shared,
-            limit_concurrent_requests: semaphore,
+            limit_concurrent_requests,
         }
     }
 
-    /// Add a new request to the queue and return a stream of InferStreamResponse
+    /// Number of requests that can still be served concurrently
+    pub(crate) fn available_permits(&self) -> usize {
+        self.limit_concurrent_requests.permits.available_permits()
+    }
+
+    /// Number of tokens of `inputs`, if they can be counted by the validation
+    pub(crate) async fn count_tokens(
+        &self,
+        inputs: String,
+        add_special_tokens: bool,
+    ) -> Option<u32> {
+        self.validation
+            .count_tokens(inputs, add_special_tokens)
+            .await
+    }
+
+    /// Add a new request to the queue and return a stream of InferStreamResponse, with the
+    /// number of prompt tokens counted by the validation, if any
     #[instrument(skip(self))]
     pub(crate) async fn generate_stream(
         &self,
         request: GenerateRequest,
-    ) -> Result<
-        (
-            OwnedSemaphorePermit,
-            RecvStream<Result<InferStreamResponse, InferError>>,
-        ),
-        InferError,
-    > {
+    ) -> Result<(InferPermit, Option<u32>, InferStream), InferError> {
         // Limit concurrent requests by acquiring a permit from the semaphore
         let permit = self
-            .clone()
             .limit_concurrent_requests
-            .try_acquire_owned()
+            .acquire(request.parameters.low_priority)
+            .await
             .map_err(|err| {
                 metrics::increment_counter!("tgi_request_failure", "err" => "overloaded");
                 tracing::error!("{err}");
@@ -111,6 +198,8 @@ impl Infer {
             err
         })?;
 
+        let prompt_tokens = valid_request.prompt_tokens;
+
         // MPSC channel to communicate with the background batching task
         let (response_tx, response_rx) = flume::unbounded();
 
@@ -129,7 +218,7 @@ impl Infer {
         self.shared.batching_task.notify_one();
 
         // Return stream
-        Ok((permit, response_rx.into_stream()))
+        Ok((permit, prompt_tokens, response_rx.into_stream()))
     }
 
     /// Add a new request to the queue and return a InferResponse
@@ -139,7 +228,7 @@ impl Infer {
         request: GenerateRequest,
     ) -> Result<InferResponse, InferError> {
         // Create stream and keep semaphore permit as long as generate lives
-        let (_permit, mut stream) = self.generate_stream(request).await?;
+        let (_permit, prompt_tokens, mut stream) = self.generate_stream(request).await?;
 
         // Return values
         let mut result_prefill = Vec::new();
@@ -147,6 +236,8 @@ impl Infer {
         let mut result_generated_text = None;
         let mut result_start = None;
         let mut result_queued = None;
+        let mut result_truncated_tokens = 0;
+        let mut result_first_token = None;
 
         // Iterate on stream
         while let Some(response) = stream.next().await {
@@ -164,7 +255,10 @@ impl Infer {
                         .collect();
                 }
                 // Push last token
-                InferStreamResponse::Token(token) => result_tokens.push(token),
+                InferStreamResponse::Token(token) => {
+                    result_first_token.get_or_insert_with(Instant::now);
+                    result_tokens.push(token)
+                }
                 // Final message
                 // Set return values
                 InferStreamResponse::End {
@@ -172,25 +266,34 @@ impl Infer {
                     generated_text,
                     start,
                     queued,
+                    truncated_tokens,
                 } => {
+                    result_first_token.get_or_insert_with(Instant::now);
                     result_tokens.push(token);
                     result_generated_text = Some(generated_text);
                     result_start = Some(start);
-                    result_queued = Some(queued)
+                    result_queued = Some(queued);
+                    result_truncated_tokens = truncated_tokens;
                 }
             }
         }
 
         // Check that we received a `InferStreamResponse::End` message
-        if let (Some(generated_text), Some(queued), Some(start)) =
-            (result_generated_text, result_queued, result_start)
-        {
+        if let (Some(generated_text), Some(queued), Some(start), Some(first_token)) = (
+            result_generated_text,
+            result_queued,
+            result_start,
+            result_first_token,
+        ) {
             Ok(InferResponse {
                 prefill: result_prefill,
                 tokens: result_tokens,
                 generated_text,
                 queued,
                 start,
+                first_token,
+                prompt_tokens,
+                truncated_tokens: result_truncated_tokens,
             })
         } else {
             let err = InferError::IncompleteGeneration;
@@ -530,6 +633,7 @@ fn send_responses(
                 generated_text,
                 queued: entry.queue_time,
                 start: entry.batch_time.unwrap(),
+                truncated_tokens: entry.request.truncated_tokens,
             }),
             Duration::from_millis(10),
         )?;
@@ -565,7 +669,8 @@ fn send_errors(error: ClientError, entries: &mut IntMap<u64, Entry>) {
 pub(crate) enum InferStreamResponse {
     // Optional first message
     Prefill(PrefillTokens),
-    // Intermediate messages
+    // Intermediate messages, with a generated token. The prompt tokens are only sent back
+    // with the prefill
     Token(Token),
     // Last message
     End {
@@ -573,6 +678,8 @@ pub(crate) enum InferStreamResponse {
         generated_text: GeneratedText,
         start: Instant,
         queued: Instant,
+        /// Number of prompt tokens dropped by the router truncation
+        truncated_tokens: u32,
     },
 }
 
@@ -583,6 +690,11 @@ pub(crate) struct InferResponse {
     pub(crate) generated_text: GeneratedText,
     pub(crate) queued: Instant,
     pub(crate) start: Instant,
+    /// Instant when the first token was received, at the end of the prefill
+    pub(crate) first_token: Instant,
+    /// Number of prompt tokens counted by the validation, if any
+    pub(crate) prompt_tokens: Option<u32>,
+    pub(crate) truncated_tokens: u32,
 }
 
 #[derive(Debug, Error)]
@@ -606,4 +718,44 @@ impl InferError {
             InferError::IncompleteGeneration => "incomplete_generation",
         }
     }
+
+    /// Request parameter responsible for the error
+    pub(crate) fn param(&self) -> Option<&'static str> {
+        match self {
+            InferError::ValidationError(err) => err.param(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_low_priority_permits() {
+        let limit = ConcurrencyLimit::new(1, Duration::from_secs(10));
+        let permit = limit.acquire(false).await.unwrap();
+        assert!(limit.acquire(false).await.is_err());
+
+        // The low priority request waits for the permit, and further ones are rejected
+        let waiting = tokio::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire(true).await.map(|_| ()) }
+        });
+        while limit.low_priority_waiters.available_permits() > 0 {
+            tokio::task::yield_now().await;
+        }
+        assert!(limit.acquire(true).await.is_err());
+
+        let start = Instant::now();
+        drop(permit);
+        assert!(waiting.await.unwrap().is_ok());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Low priority requests stop waiting at the timeout
+        let limit = ConcurrencyLimit::new(1, Duration::from_millis(50));
+        let _permit = limit.acquire(false).await.unwrap();
+        assert!(limit.acquire(true).await.is_err());
+    }
 }
@@ -15,15 +15,21 @@
 /// Text Generation Inference Webserver
 mod health;
 
+pub mod chat_template;
+mod coalesce;
 pub mod completion;
 mod infer;
+mod prompt_cache;
 mod queue;
 pub mod server;
+mod stop;
+mod stream_limit;
 mod validation;
 
 use infer::Infer;
 use queue::{Entry, Queue};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use utoipa::ToSchema;
 use validation::Validation;
 
@@ -75,9 +81,16 @@ pub struct Info {
     pub sha: Option<&'static str>,
     #[schema(nullable = true, example = "null")]
     pub docker_label: Option<&'static str>,
+    /// Source of the Jinja chat template of the tokenizer rendering the chat prompts, `null`
+    /// when they are rendered with the `TGICHAT_*` templates
+    #[schema(
+        nullable = true,
+        example = "bigscience/blomm-560m/tokenizer_config.json"
+    )]
+    pub chat_template: Option<String>,
 }
 
-#[derive(Clone, Debug, Deserialize, ToSchema)]
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
 pub(crate) struct GenerateParameters {
     #[serde(default)]
     #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 1)]
@@ -98,6 +111,28 @@ pub(crate) struct GenerateParameters {
         example = 1.03
     )]
     pub repetition_penalty: Option<f32>,
+    /// Penalty subtracted once from the logits of the tokens already in the sequence, like the
+    /// OpenAI `presence_penalty`
+    #[serde(default)]
+    #[schema(
+        minimum = -2.0,
+        maximum = 2.0,
+        nullable = true,
+        default = "null",
+        example = 0.5
+    )]
+    pub presence_penalty: Option<f32>,
+    /// Penalty subtracted from the logits of the tokens already in the sequence for each of
+    /// their occurrences, like the OpenAI `frequency_penalty`
+    #[serde(default)]
+    #[schema(
+        minimum = -2.0,
+        maximum = 2.0,
+        nullable = true,
+        default = "null",
+        example = 0.5
+    )]
+    pub frequency_penalty: Option<f32>,
     #[serde(default)]
     #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 10)]
     pub top_k: Option<i32>,
@@ -135,9 +170,18 @@ pub(crate) struct GenerateParameters {
     #[schema(nullable = true, default = "null", example = "null")]
     pub truncate: Option<usize>,
     #[serde(default)]
+    #[schema(default = "left", example = "right")]
+    pub truncation_side: TruncationSide,
+    #[serde(default)]
     #[schema(default = "false", example = true)]
     pub watermark: bool,
     #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json ! ({"50256": -100.0}))]
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub logit_bias_first_token_only: bool,
+    #[serde(default)]
     #[schema(default = "true")]
     pub details: bool,
     #[serde(default)]
@@ -151,6 +195,64 @@ pub(crate) struct GenerateParameters {
         example = "null"
     )]
     pub seed: Option<u64>,
+    /// Scheduled after the other requests, and waits for capacity instead of being rejected
+    #[serde(skip)]
+    pub low_priority: bool,
+    /// Empty inputs generate from the special tokens the tokenizer starts the inputs with, e.g.
+    /// BOS, instead of being rejected
+    #[serde(skip)]
+    pub allow_empty_inputs: bool,
+}
+
+/// End of the prompt dropped when it is longer than `truncate` tokens
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TruncationSide {
+    /// Keep the end of the prompt, e.g. the most recent messages of a conversation
+    #[default]
+    Left,
+    /// Keep the beginning of the prompt
+    Right,
+}
+
+/// Truncation sampler, applied after the temperature
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum SamplerFilter {
+    TopK,
+    TopP,
+    TypicalP,
+}
+
+impl SamplerFilter {
+    /// Order of the shards when unset
+    pub const DEFAULT_ORDER: [SamplerFilter; 3] = [
+        SamplerFilter::TopK,
+        SamplerFilter::TopP,
+        SamplerFilter::TypicalP,
+    ];
+
+    /// Complete order starting with `order`, without duplicates. The filters left out apply
+    /// after, in the default order
+    pub(crate) fn order(order: &[SamplerFilter]) -> Vec<SamplerFilter> {
+        let mut complete = Vec::with_capacity(Self::DEFAULT_ORDER.len());
+        for filter in order.iter().chain(Self::DEFAULT_ORDER.iter()) {
+            if !complete.contains(filter) {
+                complete.push(*filter);
+            }
+        }
+        complete
+    }
+}
+
+impl GenerateParameters {
+    /// Whether the tokens are sampled rather than greedily chosen
+    pub(crate) fn sampling(&self) -> bool {
+        self.do_sample
+            || self.temperature.is_some()
+            || self.top_k.is_some()
+            || self.top_p.is_some()
+            || self.typical_p.is_some()
+    }
 }
 
 fn default_max_new_tokens() -> u32 {
@@ -162,6 +264,8 @@ fn default_parameters() -> GenerateParameters {
         best_of: None,
         temperature: None,
         repetition_penalty: None,
+        presence_penalty: None,
+        frequency_penalty: None,
         top_k: None,
         top_p: None,
         typical_p: None,
@@ -170,14 +274,19 @@ fn default_parameters() -> GenerateParameters {
         return_full_text: None,
         stop: Vec::new(),
         truncate: None,
+        truncation_side: TruncationSide::Left,
         watermark: false,
+        logit_bias: None,
+        logit_bias_first_token_only: false,
         details: false,
         decoder_input_details: false,
         seed: None,
+        low_priority: false,
+        allow_empty_inputs: false,
     }
 }
 
-#[derive(Clone, Debug, Deserialize, ToSchema)]
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
 pub(crate) struct GenerateRequest {
     #[schema(example = "My name is Olivier and I")]
     pub inputs: String,
@@ -227,7 +336,7 @@ pub struct Token {
     special: bool,
 }
 
-#[derive(Serialize, ToSchema, Clone)]
+#[derive(Serialize, ToSchema, Clone, Debug)]
 #[serde(rename_all(serialize = "snake_case"))]
 pub(crate) enum FinishReason {
     #[schema(rename = "length")]
@@ -265,6 +374,17 @@ pub(crate) struct Details {
     pub tokens: Vec<Token>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub best_of_sequences: Option<Vec<BestOfSequence>>,
+    /// Number of prompt tokens counted by the validation, reported by the OpenAI compatible
+    /// routes. `None` without a fast tokenizer
+    #[serde(skip)]
+    pub prompt_tokens: Option<u32>,
+    /// Number of prompt tokens dropped by the router truncation, reported by the OpenAI
+    /// compatible routes
+    #[serde(skip)]
+    pub truncated_tokens: u32,
+    /// Server-side timings, reported by the OpenAI compatible routes
+    #[serde(skip)]
+    pub timings: Option<completion::Timings>,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -303,10 +423,15 @@ pub enum OpenaiStreamType {
 pub(crate) struct ErrorResponse {
     pub error: String,
     pub error_type: String,
+    /// Request parameter responsible for the error
+    #[schema(nullable = true, example = "best_of")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub param: Option<String>,
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use std::io::Write;
     use tokenizers::Tokenizer;
 
@@ -323,4 +448,36 @@ mod tests {
         }
         Tokenizer::from_file("tokenizer.json").unwrap()
     }
+
+    /// Tokenizer of the whitespace separated `words`, with the ids following `[UNK]`, 0, in
+    /// order, for the tests that do not need a real tokenizer
+    pub(crate) fn word_level_tokenizer(words: &[&str]) -> Tokenizer {
+        use tokenizers::models::wordlevel::WordLevel;
+        use tokenizers::pre_tokenizers::whitespace::WhitespaceSplit;
+
+        let vocab = std::iter::once("[UNK]")
+            .chain(words.iter().copied())
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(WhitespaceSplit);
+        tokenizer
+    }
+
+    #[test]
+    fn test_sampler_order() {
+        use SamplerFilter::*;
+        assert_eq!(SamplerFilter::order(&[]), vec![TopK, TopP, TypicalP]);
+        assert_eq!(SamplerFilter::order(&[TopP]), vec![TopP, TopK, TypicalP]);
+        assert_eq!(
+            SamplerFilter::order(&[TypicalP, TopP, TypicalP, TopK]),
+            vec![TypicalP, TopP, TopK]
+        );
+    }
 }
@@ -9,9 +9,15 @@ use opentelemetry::{global, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 use text_generation_client::{ClientError, ShardedClient};
-use text_generation_router::{server, HubModelInfo};
+use text_generation_router::chat_template::ChatTemplate;
+use text_generation_router::completion::{
+    BatchResponseFormat, ChatConfig, CompatConfig, EmptyStop, OutputRatioPolicy,
+    PromptNormalization, TrailingWhitespace, ZeroTopP,
+};
+use text_generation_router::{server, HubModelInfo, SamplerFilter};
 use thiserror::Error;
 use tokenizers::{FromPretrainedParameters, Tokenizer};
 use tower_http::cors::AllowOrigin;
@@ -53,6 +59,10 @@ struct Args {
     revision: Option<String>,
     #[clap(default_value = "2", long, env)]
     validation_workers: usize,
+    /// Comma separated order the truncation samplers apply in, e.g. `top-p,top-k`. The samplers
+    /// left out apply after, in the default `top-k,top-p,typical-p` order of the shards
+    #[clap(long, env, value_enum, value_delimiter = ',')]
+    sampler_order: Vec<SamplerFilter>,
     #[clap(long, env)]
     json_output: bool,
     #[clap(long, env)]
@@ -65,6 +75,179 @@ struct Args {
     ngrok_authtoken: Option<String>,
     #[clap(long, env)]
     ngrok_edge: Option<String>,
+    /// Maximum wall-time of an OpenAI compatible stream, in seconds
+    #[clap(long, env)]
+    max_stream_duration: Option<u64>,
+    /// Return the hash of the normalized OpenAI compatible request in the `x-request-hash` header
+    #[clap(long, env)]
+    request_hash_header: bool,
+    /// Identify the chat templates that rendered the prompt of the chat completions in the
+    /// `x-chat-template-hash` header, and the `x-chat-template` header with the `template_name` of
+    /// the chat config
+    #[clap(long, env)]
+    chat_template_header: bool,
+    /// Reject OpenAI compatible requests containing duplicate top-level JSON keys with a 400
+    #[clap(long, env)]
+    strict_json: bool,
+    /// Price of 1K prompt tokens. When a price is set, OpenAI compatible usages report a `tgi_estimated_cost`
+    #[clap(long, env)]
+    prompt_token_price: Option<f64>,
+    /// Price of 1K completion tokens. When a price is set, OpenAI compatible usages report a `tgi_estimated_cost`
+    #[clap(long, env)]
+    completion_token_price: Option<f64>,
+    /// Path to a JSON file configuring the chat completions route, e.g. the sampling presets
+    #[clap(long, env)]
+    chat_config: Option<String>,
+    /// Report OpenAI compatible generations stopped by the server, e.g. on `--max-stream-duration`,
+    /// in a `tgi_finish_reason` extension field. `finish_reason` keeps the nearest standard reason
+    #[clap(long, env)]
+    server_finish_reasons: bool,
+    /// Comma separated names accepted for the served model by the OpenAI compatible routes, and
+    /// listed by `/models`. When set, requests naming any other model are rejected with a 400
+    #[clap(long, env, value_delimiter = ',')]
+    model_aliases: Vec<String>,
+    /// Close OpenAI compatible streams failing after they started with a last chunk containing
+    /// this message, instead of an error event
+    #[clap(long, env)]
+    stream_error_fallback: Option<String>,
+    /// Punctuation ending the sentences of OpenAI compatible streams with the `sentence`
+    /// granularity, when followed by a whitespace. Line breaks always end sentences
+    #[clap(long, env)]
+    sentence_boundaries: Option<String>,
+    /// Maximum time in seconds OpenAI compatible requests with `service_tier: flex` wait for
+    /// capacity, instead of being rejected when the server is overloaded
+    #[clap(default_value = "600", long, env)]
+    flex_timeout: u64,
+    /// Maximum number of concurrent OpenAI compatible streams of each client, identified by its
+    /// IP address, or by the bearer token of its `Authorization` header with
+    /// `--verified-api-keys`
+    #[clap(long, env)]
+    max_streams_per_client: Option<usize>,
+    /// The bearer tokens of the `Authorization` headers are verified in front of the router, e.g.
+    /// by a gateway, so that `--max-streams-per-client` identifies the clients by their token.
+    /// Unverified tokens are ignored, as a client could send a new one with each request
+    #[clap(long, env)]
+    verified_api_keys: bool,
+    /// Invisible characters stripped from the OpenAI compatible prompts and message contents:
+    /// `bom` strips the UTF-8 byte order marks, and `control` also strips the control characters
+    /// except tabs and line breaks
+    #[clap(default_value = "none", long, env, value_enum)]
+    prompt_normalization: PromptNormalization,
+    /// Shape of the responses to batched chat requests: `choices` returns a single response with
+    /// a choice per conversation, and `list` an `object: list` wrapping a response per conversation
+    #[clap(default_value = "choices", long, env, value_enum)]
+    batch_response_format: BatchResponseFormat,
+    /// Maximum size in bytes of the `gzip` and `deflate` OpenAI compatible request bodies once
+    /// decompressed. Larger bodies are rejected with a 413, and 0 rejects compressed bodies
+    #[clap(default_value = "8388608", long, env)]
+    max_decompressed_body_size: usize,
+    /// Prompt completed through the OpenAI compatible completions route at startup. `/health`
+    /// only reports the server as healthy once this warmup generation succeeded, so that the first
+    /// requests do not pay the cold start
+    #[clap(long, env)]
+    warmup_prompt: Option<String>,
+    /// Number of tokens generated by the warmup completion
+    #[clap(default_value = "16", long, env)]
+    warmup_max_tokens: u32,
+    /// Name the Server-Sent Events of the OpenAI compatible streams, for the SSE client libraries
+    /// that require named events: `event: chunk` for the chunks, `event: done` for the `[DONE]`
+    /// sentinel and `event: error` for the errors. OpenAI events are unnamed
+    #[clap(long, env)]
+    sse_event_names: bool,
+    /// End every OpenAI compatible stream with a usage chunk, for the clients
+    /// that cannot set `stream_options.include_usage`. OpenAI only sends it when requested
+    #[clap(long, env)]
+    always_stream_usage: bool,
+    /// Trailing whitespace trimmed from the OpenAI compatible outputs: `lines` trims the trailing
+    /// line breaks and blank lines but keeps the spaces ending the last line, and `all` trims all
+    /// the trailing whitespace. Outputs are kept as generated by default, as trailing whitespace
+    /// can be meaningful, e.g. for completions continued by the client
+    #[clap(default_value = "keep", long, env, value_enum)]
+    trailing_whitespace: TrailingWhitespace,
+    /// Generate from OpenAI compatible prompts, or conversations, that are empty or only contain
+    /// whitespace, instead of rejecting them with a 400
+    #[clap(long, env)]
+    allow_blank_prompts: bool,
+    /// Return the server-side timings of the non-streamed OpenAI compatible generations in a
+    /// `__timings` extension field: validation, queue, prefill and decode times, and decode
+    /// throughput, e.g. for capacity planning. Strict OpenAI clients may reject the field
+    #[clap(long, env)]
+    response_timings: bool,
+    /// Maximum number of tokens whose logprobs are returned by the chat completions and
+    /// completions routes, so that long generations do not produce huge responses. The
+    /// completions count the echoed prompt tokens. The logprobs of the following tokens are left
+    /// out, and their number is reported in `tgi_omitted_tokens`. 0 returns the logprobs of all
+    /// the tokens
+    #[clap(default_value = "4096", long, env)]
+    max_logprobs_tokens: usize,
+    /// Token budget of the templated chat prompts. The oldest turns of longer conversations are
+    /// evicted until they fit, always keeping the system messages and the last user turn.
+    /// Requires the tokenizer and the prompt token count of the router
+    #[clap(long, env)]
+    max_conversation_tokens: Option<u32>,
+    /// Number of chat system prompts whose token count is cached, so that the prompts counted by
+    /// the router for `--max-conversation-tokens` and `--max-output-ratio` only tokenize a shared
+    /// system prompt once. 0 disables the cache
+    #[clap(default_value = "128", long, env)]
+    prompt_token_cache_size: usize,
+    /// Maximum ratio of the `max_tokens` of the OpenAI compatible requests to their prompt
+    /// tokens, e.g. 50 rejects 100 tokens for a one token prompt, as a guard against runaway
+    /// requests. Requires the tokenizer and the prompt token count of the router
+    #[clap(long, env)]
+    max_output_ratio: Option<u32>,
+    /// Handling of the requests exceeding `--max-output-ratio`: `reject` answers with a 400, and
+    /// `cap` lowers `max_tokens` to the limit, reported in the `x-max-tokens` response header
+    #[clap(default_value = "reject", long, env, value_enum)]
+    output_ratio_policy: OutputRatioPolicy,
+    /// Handling of the empty strings of the OpenAI `stop`, which would stop the generation before
+    /// any output: `filter` leaves them out with a warning, and `reject` answers with a 400
+    #[clap(default_value = "filter", long, env, value_enum)]
+    empty_stop: EmptyStop,
+    /// Answer the OpenAI compatible requests setting `stream` whose `Accept` header does not allow
+    /// `text/event-stream`, e.g. `application/json`, with a complete JSON response, as if they
+    /// did not stream. Such requests are rejected with a 400 by default
+    #[clap(long, env)]
+    buffer_unaccepted_streams: bool,
+    /// Lowest `repetition_penalty` accepted by the OpenAI compatible routes, e.g. 1.0 for the
+    /// samplers that cannot reward repetitions. Penalties that are not strictly positive are
+    /// always rejected with a 400
+    #[clap(long, env)]
+    min_repetition_penalty: Option<f32>,
+    /// Highest `repetition_penalty` accepted by the OpenAI compatible routes, e.g. 2.0
+    #[clap(long, env)]
+    max_repetition_penalty: Option<f32>,
+    /// Return the queue, prefill and decode durations of the non-streamed OpenAI compatible
+    /// responses in the `Server-Timing` header, shown by the network panel of the browsers
+    #[clap(long, env)]
+    server_timing_header: bool,
+    /// Serialize the `created` timestamps and the `usage` token counts of the OpenAI compatible
+    /// responses, streamed or not, as strings, for the gateways and strictly typed clients that
+    /// require it. OpenAI serializes them as numbers
+    #[clap(long, env)]
+    numbers_as_strings: bool,
+    /// Explain the empty `text` or `content` of the non-streamed OpenAI compatible choices in a
+    /// `tgi_empty_note` extension field, e.g. when the model immediately generated its end of
+    /// sequence token, to tell an empty response from a bug
+    #[clap(long, env)]
+    empty_generation_notes: bool,
+    /// Handling of the OpenAI compatible requests whose `top_p` or `typical_p` is 0 or negative,
+    /// which would keep no token: `reject` answers with a 400, and `clamp` raises it to 1e-6,
+    /// which only keeps the most likely token
+    #[clap(default_value = "reject", long, env, value_enum)]
+    zero_top_p: ZeroTopP,
+    /// Maximum `n` of the OpenAI compatible requests, rejecting more choices with a 400. Each
+    /// choice is a copy of the request generated concurrently. 0 means unlimited
+    #[clap(default_value = "16", long, env)]
+    max_n: u32,
+    /// Maximum number of prompts of the completions requests, and of conversations of the chat
+    /// `batch`, rejecting more with a 400. The choices of all the prompts of a request are also
+    /// capped at `--max-n`. 0 means unlimited
+    #[clap(default_value = "16", long, env)]
+    max_prompts: usize,
+    /// Render the chat prompts with the `TGICHAT_*` templates even when the
+    /// `tokenizer_config.json` of the model ships a Jinja `chat_template`
+    #[clap(long, env)]
+    disable_tokenizer_chat_template: bool,
 }
 
 fn main() -> Result<(), RouterError> {
@@ -87,12 +270,53 @@ fn main() -> Result<(), RouterError> {
         tokenizer_name,
         revision,
         validation_workers,
+        sampler_order,
         json_output,
         otlp_endpoint,
         cors_allow_origin,
         ngrok,
         ngrok_authtoken,
         ngrok_edge,
+        max_stream_duration,
+        request_hash_header,
+        chat_template_header,
+        strict_json,
+        prompt_token_price,
+        completion_token_price,
+        chat_config,
+        server_finish_reasons,
+        model_aliases,
+        stream_error_fallback,
+        sentence_boundaries,
+        flex_timeout,
+        max_streams_per_client,
+        verified_api_keys,
+        prompt_normalization,
+        batch_response_format,
+        max_decompressed_body_size,
+        warmup_prompt,
+        warmup_max_tokens,
+        sse_event_names,
+        always_stream_usage,
+        trailing_whitespace,
+        allow_blank_prompts,
+        response_timings,
+        max_logprobs_tokens,
+        max_conversation_tokens,
+        prompt_token_cache_size,
+        max_output_ratio,
+        output_ratio_policy,
+        empty_stop,
+        buffer_unaccepted_streams,
+        min_repetition_penalty,
+        max_repetition_penalty,
+        server_timing_header,
+        numbers_as_strings,
+        empty_generation_notes,
+        zero_top_p,
+        max_n,
+        max_prompts,
+        disable_tokenizer_chat_template,
     } = args;
 
     // Validate args
@@ -131,6 +355,62 @@ fn main() -> Result<(), RouterError> {
         )
     });
 
+    // OpenAI compatible routes configuration
+    let chat_config = match chat_config {
+        Some(chat_config) => {
+            let content = std::fs::read_to_string(&chat_config)
+                .map_err(|err| RouterError::ChatConfig(format!("{chat_config}: {err}")))?;
+            serde_json::from_str(&content)
+                .map_err(|err| RouterError::ChatConfig(format!("{chat_config}: {err}")))?
+        }
+        None => ChatConfig::default(),
+    };
+    let mut compat_config = CompatConfig {
+        max_stream_duration: max_stream_duration.map(Duration::from_secs),
+        request_hash_header,
+        chat_template_header,
+        strict_json,
+        prompt_token_price,
+        completion_token_price,
+        chat_config,
+        server_finish_reasons,
+        model_aliases,
+        stream_error_fallback,
+        sentence_boundaries,
+        flex_timeout: Duration::from_secs(flex_timeout),
+        max_streams_per_client,
+        verified_api_keys,
+        prompt_normalization,
+        batch_response_format,
+        max_decompressed_body_size,
+        warmup_prompt,
+        warmup_max_tokens,
+        sse_event_names,
+        always_stream_usage,
+        trailing_whitespace,
+        allow_blank_prompts,
+        response_timings,
+        max_logprobs_tokens,
+        max_conversation_tokens,
+        prompt_token_cache_size,
+        max_output_ratio,
+        output_ratio_policy,
+        empty_stop,
+        buffer_unaccepted_streams,
+        min_repetition_penalty,
+        max_repetition_penalty,
+        server_timing_header,
+        numbers_as_strings,
+        empty_generation_notes,
+        zero_top_p,
+        max_n,
+        max_prompts,
+        chat_template: None,
+        chat_formatter: Arc::default(),
+        chat_template_hash: None,
+        prompt_token_cache: Arc::default(),
+    };
+
     // Parse Huggingface hub token
     let authorization_token = std::env::var("HUGGING_FACE_HUB_TOKEN").ok();
 
@@ -166,6 +446,18 @@ fn main() -> Result<(), RouterError> {
                 tracing::warn!("Rust input length validation and truncation is disabled");
             }
 
+            // The chat template of the tokenizer, if any, renders the chat prompts
+            if !disable_tokenizer_chat_template {
+                compat_config.chat_template = get_chat_template(
+                    &tokenizer_name,
+                    local_model,
+                    revision.clone(),
+                    authorization_token.clone(),
+                )
+                .await
+                .map(Arc::new);
+            }
+
             // Get Model info
             let model_info = match local_model {
                 true => HubModelInfo {
@@ -268,11 +560,13 @@ fn main() -> Result<(), RouterError> {
                 sharded_client,
                 tokenizer,
                 validation_workers,
+                sampler_order,
                 addr,
                 cors_allow_origin,
                 ngrok,
                 ngrok_authtoken,
                 ngrok_edge,
+                compat_config,
             )
                 .await?;
             Ok(())
@@ -375,10 +669,64 @@ pub async fn get_model_info(
     }
 }
 
+/// Jinja chat template of the `tokenizer_config.json` of the model, from its local directory or
+/// the Huggingface Hub. `None` falls back to the `TGICHAT_*` templates
+async fn get_chat_template(
+    tokenizer_name: &str,
+    local_model: bool,
+    revision: Option<String>,
+    token: Option<String>,
+) -> Option<ChatTemplate> {
+    let (content, source) = if local_model {
+        let path = Path::new(tokenizer_name).join("tokenizer_config.json");
+        let content = std::fs::read_to_string(&path).ok();
+        (content, path.display().to_string())
+    } else {
+        // Poor man's urlencode
+        let revision = revision.unwrap_or("main".to_string()).replace('/', "%2F");
+        let url = format!(
+            "https://huggingface.co/{tokenizer_name}/resolve/{revision}/tokenizer_config.json"
+        );
+        let mut builder = reqwest::Client::new()
+            .get(&url)
+            .timeout(Duration::from_secs(5));
+        if let Some(token) = token {
+            builder = builder.bearer_auth(token);
+        }
+        let content = match builder.send().await {
+            Ok(response) if response.status().is_success() => response.text().await.ok(),
+            _ => None,
+        };
+        (content, url)
+    };
+
+    let template = match content {
+        Some(content) => ChatTemplate::from_tokenizer_config(&content, source.clone()),
+        None => Ok(None),
+    };
+    match template {
+        Ok(Some(template)) => {
+            tracing::info!("Rendering the chat prompts with the chat template of {source}");
+            Some(template)
+        }
+        Ok(None) => {
+            tracing::info!("No chat template found, using the `TGICHAT_*` templates");
+            None
+        }
+        Err(err) => {
+            tracing::warn!("Invalid chat template in {source}: {err}");
+            tracing::warn!("Using the `TGICHAT_*` templates");
+            None
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 enum RouterError {
     #[error("Argument validation error: {0}")]
     ArgumentValidation(String),
+    #[error("Unable to load the chat config: {0}")]
+    ChatConfig(String),
     #[error("Unable to connect to the Python model shards: {0}")]
     Connection(ClientError),
     #[error("Unable to clear the Python model shards cache: {0}")]
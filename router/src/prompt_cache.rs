@@ -0,0 +1,126 @@
+//! Prompt length of the chat requests, counted by the router for the token budgets of the
+//! OpenAI compatible routes
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+
+/// Token counts of the templated system and tool messages starting the chat conversations.
+/// Chat workloads usually share a long system prompt, which is then only tokenized the first
+/// time it is seen rather than for every request and every turn evicted from a conversation.
+/// Entries are keyed on the exact templated text, so that a changed template never hits a
+/// stale entry
+#[derive(Debug, Default)]
+pub struct PromptTokenCache {
+    capacity: usize,
+    prefixes: Mutex<HashMap<String, u32>>,
+}
+
+impl PromptTokenCache {
+    /// Cache of at most `capacity` prefixes. 0 disables the cache
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            prefixes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Number of tokens of `prompt`, which starts with the templated `prefix`, as counted by
+    /// `count_tokens` with or without the special tokens added to a prompt.
+    /// The prefix and the rest of the prompt are tokenized separately, the prefix only once.
+    /// `None` if the prompt cannot be tokenized
+    pub(crate) async fn count_tokens<F: Future<Output = Option<u32>>>(
+        &self,
+        prompt: String,
+        prefix: &str,
+        count_tokens: impl Fn(String, bool) -> F,
+    ) -> Option<u32> {
+        let rest = match prompt.strip_prefix(prefix) {
+            Some(rest) if self.capacity > 0 && !prefix.is_empty() => rest.to_string(),
+            _ => return count_tokens(prompt, true).await,
+        };
+
+        let cached = self.prefixes.lock().unwrap().get(prefix).copied();
+        let prefix_tokens = match cached {
+            Some(prefix_tokens) => prefix_tokens,
+            None => {
+                let prefix_tokens = count_tokens(prefix.to_string(), true).await?;
+                let mut prefixes = self.prefixes.lock().unwrap();
+                // Start over rather than tracking the least recently used prefixes
+                if prefixes.len() >= self.capacity {
+                    prefixes.clear();
+                }
+                prefixes.insert(prefix.to_string(), prefix_tokens);
+                prefix_tokens
+            }
+        };
+        let rest_tokens = match rest.is_empty() {
+            true => 0,
+            false => count_tokens(rest, false).await?,
+        };
+        Some(prefix_tokens + rest_tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the words of the texts, with a leading special token, and the tokenized texts
+    fn count_words(
+        calls: &AtomicUsize,
+    ) -> impl Fn(String, bool) -> std::future::Ready<Option<u32>> + '_ {
+        move |text: String, add_special_tokens: bool| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            let words = text.split_whitespace().count() as u32;
+            std::future::ready(Some(words + add_special_tokens as u32))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_count_tokens() {
+        let cache = PromptTokenCache::new(2);
+        let calls = AtomicUsize::new(0);
+        let prefix = "<sys> be brief ";
+        let count = |prompt: &str, prefix: &'static str| {
+            cache.count_tokens(prompt.to_string(), prefix, count_words(&calls))
+        };
+        assert_eq!(count("<sys> be brief <user> hi", prefix).await, Some(6));
+        assert_eq!(cache.prefixes.lock().unwrap().get(prefix), Some(&4));
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        // The cached prefix is reused by the next conversations
+        assert_eq!(count("<sys> be brief <user>", prefix).await, Some(5));
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+        assert_eq!(count("<sys> be brief ", prefix).await, Some(4));
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+
+        // Conversations without a prefix, or not starting with it, are counted as a whole
+        assert_eq!(count("<user> hi", "").await, Some(3));
+        assert_eq!(count("<user> hi", prefix).await, Some(3));
+        assert_eq!(cache.prefixes.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_token_cache_capacity() {
+        let cache = PromptTokenCache::new(2);
+        let calls = AtomicUsize::new(0);
+        for prefix in ["<sys> a ", "<sys> b ", "<sys> c "] {
+            let prompt = format!("{prefix}<user> hi");
+            let tokens = cache.count_tokens(prompt, prefix, count_words(&calls));
+            assert_eq!(tokens.await, Some(5));
+        }
+        let prefixes: Vec<String> = cache.prefixes.lock().unwrap().keys().cloned().collect();
+        assert_eq!(prefixes, ["<sys> c "]);
+
+        // A disabled cache counts the whole prompts
+        let cache = PromptTokenCache::new(0);
+        let tokens = cache.count_tokens(
+            "<sys> a <user> hi".to_string(),
+            "<sys> a ",
+            count_words(&calls),
+        );
+        assert_eq!(tokens.await, Some(5));
+        assert!(cache.prefixes.lock().unwrap().is_empty());
+    }
+}
@@ -145,8 +145,16 @@ impl State {
         let queue_span = info_span!(parent: &entry.span, "queued");
         entry.temp_span = Some(queue_span);
 
-        // Push entry in the queue
-        self.entries.push_back((self.next_id, entry));
+        // Push entry in the queue, ahead of the low priority entries
+        let position = match entry.request.low_priority {
+            true => self.entries.len(),
+            false => self
+                .entries
+                .iter()
+                .position(|(_, entry)| entry.request.low_priority)
+                .unwrap_or(self.entries.len()),
+        };
+        self.entries.insert(position, (self.next_id, entry));
         self.next_id += 1;
     }
 
@@ -311,8 +319,11 @@ mod tests {
             request: ValidGenerateRequest {
                 inputs: "".to_string(),
                 input_length: 0,
+                prompt_tokens: None,
+                truncated_tokens: 0,
                 truncate: 0,
                 decoder_input_details: false,
+                low_priority: false,
                 parameters: NextTokenChooserParameters {
                     temperature: 0.0,
                     top_k: 0,
@@ -322,6 +333,11 @@ mod tests {
                     seed: 0,
                     repetition_penalty: 0.0,
                     watermark: false,
+                    logit_bias: vec![],
+                    logit_bias_first_token_only: false,
+                    sampler_order: vec![],
+                    presence_penalty: 0.0,
+                    frequency_penalty: 0.0,
                 },
                 stopping_parameters: StoppingCriteriaParameters {
                     ignore_eos_token: false,
@@ -354,6 +370,29 @@ mod tests {
         assert_eq!(id, 0);
     }
 
+    #[test]
+    fn test_append_low_priority() {
+        let mut state = State::new(false, 1);
+        let (mut entry1, _guard1) = default_entry();
+        let (entry2, _guard2) = default_entry();
+        let (mut entry3, _guard3) = default_entry();
+        let (entry4, _guard4) = default_entry();
+        entry1.request.low_priority = true;
+        entry3.request.low_priority = true;
+
+        for entry in [entry1, entry2, entry3, entry4] {
+            state.append(entry);
+        }
+
+        // Low priority entries are batched last, in order
+        let ids: Vec<u64> = state.entries.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![1, 3, 0, 2]);
+
+        let (entries, _, _) = state.next_batch(None, 2, 2).unwrap();
+        assert!(entries.contains_key(&1));
+        assert!(entries.contains_key(&3));
+    }
+
     #[test]
     fn test_next_batch_empty() {
         let mut state = State::new(false, 1);
@@ -14,35 +14,59 @@
 ///
 
 /// HTTP Server logic
+use crate::coalesce::{
+    AssistantPrefixBuffer, SentenceBuffer, StreamGranularity, TrailingWhitespaceBuffer,
+    DEFAULT_SENTENCE_BOUNDARIES,
+};
 use crate::completion::{
-    chat_start_message, chat_to_generate_request, create_streaming_event,
-    generate_to_chatcompletions, generate_to_completions, get_chatformatter, create_timestamp, ChatCompletionChoices,
-    ChatCompletionDeltaStreamChoices, ChatCompletionsResponse, ChatCompletionsStreamResponse,
-    ChatDeltaStreamMessage, ChatMessage, ChatRole, CompatChatCompletionRequest,
-    CompatCompletionRequest, CompletionChoices, CompletionsResponse, Usage,
+    batch_chatcompletions, batch_completions, batch_usage, chat_prompt_prefix, chat_start_message,
+    chat_to_generate_request, choice_seeds, compat_response_type, create_id,
+    create_stream_end_event, create_streaming_event, create_timestamp, echo_prompt_chunk,
+    echo_prompt_token_chunks, evict_oldest_turns, generate_to_chatcompletions,
+    generate_to_completions, merge_chatcompletions, merge_completions, models_response,
+    preview_parameters, request_hash, stream_done_events, stream_event, validate_metadata,
+    BatchResponseFormat, ChatCompletionChoices, ChatCompletionDeltaStreamChoices,
+    ChatCompletionLogprob, ChatCompletionLogprobs, ChatCompletionTopLogprob,
+    ChatCompletionsListResponse, ChatCompletionsResponse, ChatCompletionsStreamResponse,
+    ChatContentPart, ChatDeltaStreamMessage, ChatMessage, ChatMessageContent, ChatRole,
+    CompatChatCompletionRequest, CompatCompletionRequest, CompatConfig, CompatError,
+    CompatErrorDetails, CompatErrorResponse, CompatJson, CompatNumber, CompatRequestOptions,
+    CompatResponseType, CompletionChoices, CompletionLogprobs, CompletionsResponse, ExtraBody,
+    FinishDetails, ModelCapabilities, ModelCard, ModelsResponse, OpenaiFinishReason,
+    ParameterSetError, ParametersPreview, ParametersPreviewRequest, ParametersPreviewResponse,
+    PromptTokensDetails, ResponseFormat, ResponseFormatEnforcement, ServerFinishReason,
+    ServiceTier, Timings, Usage, CHUNK_EVENT, ERROR_EVENT,
 };
 use crate::health::Health;
 use crate::infer::{InferError, InferResponse, InferStreamResponse};
+use crate::prompt_cache::PromptTokenCache;
+use crate::stop::{strip_response_stop_sequence, StopSequenceBuffer};
+use crate::stream_limit::{StreamGuard, StreamLimiter};
 use crate::validation::ValidationError;
 use crate::{
     BestOfSequence, CompatGenerateRequest, Details, ErrorResponse, FinishReason,
     GenerateParameters, GenerateRequest, GenerateResponse, HubModelInfo, Infer, Info,
-    OpenaiStreamType, PrefillToken, StreamDetails, StreamResponse, Token, Validation,
+    OpenaiStreamType, PrefillToken, SamplerFilter, StreamDetails, StreamResponse, Token,
+    TruncationSide, Validation,
 };
-use axum::extract::Extension;
-use axum::http::{HeaderMap, Method, StatusCode};
+use axum::extract::{ConnectInfo, Extension};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::map_response;
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{http, Json, Router};
 use axum_tracing_opentelemetry::opentelemetry_tracing_layer;
+use futures::future::try_join_all;
 use futures::stream::StreamExt;
 use futures::Stream;
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::net::SocketAddr;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use text_generation_client::{ShardInfo, ShardedClient};
 use tokenizers::Tokenizer;
 use tokio::signal;
@@ -111,40 +135,117 @@ async fn compat_generate(
     ("application/json" = CompletionsResponse),
     ("text/event-stream" = CompletionsResponse),
     )),
-    (status = 424, description = "Generation Error", body = ErrorResponse,
-    example = json ! ({"error": "Request failed during generation"})),
-    (status = 429, description = "Model is overloaded", body = ErrorResponse,
-    example = json ! ({"error": "Model is overloaded"})),
-    (status = 422, description = "Input validation error", body = ErrorResponse,
-    example = json ! ({"error": "Input validation error"})),
-    (status = 500, description = "Incomplete generation", body = ErrorResponse,
-    example = json ! ({"error": "Incomplete generation"})),
+    (status = 424, description = "Generation Error", body = CompatErrorResponse,
+    example = json ! ({"error": {"message": "Request failed during generation", "type": "server_error", "param": null, "code": "generation"}})),
+    (status = 429, description = "Model is overloaded", body = CompatErrorResponse,
+    example = json ! ({"error": {"message": "Model is overloaded", "type": "server_error", "param": null, "code": "overloaded"}})),
+    (status = 400, description = "Invalid parameter", body = CompatErrorResponse,
+    example = json ! ({"error": {"message": "`top_p` must be > 0.0 and < 1.0", "type": "invalid_request_error", "param": "top_p", "code": "validation"}})),
+    (status = 422, description = "Input validation error", body = CompatErrorResponse,
+    example = json ! ({"error": {"message": "Input validation error", "type": "invalid_request_error", "param": null, "code": "validation"}})),
+    (status = 500, description = "Incomplete generation", body = CompatErrorResponse,
+    example = json ! ({"error": {"message": "Incomplete generation", "type": "server_error", "param": null, "code": "incomplete_generation"}})),
     )
     )]
-#[instrument(skip(infer, req))]
+#[instrument(
+skip_all,
+fields(
+id,
+stream = req.stream,
+prompt_tokens,
+completion_tokens,
+finish_reason,
+total_time,
+validation_time,
+queue_time,
+prefill_time,
+first_token_time,
+inference_time,
+time_per_token,
+seed,
+)
+)]
+#[allow(clippy::too_many_arguments)]
 async fn completions_generate(
     info: Extension<Info>,
+    compat_config: Extension<CompatConfig>,
+    stream_limiter: Extension<StreamLimiter>,
     infer: Extension<Infer>,
-    req: Json<CompatCompletionRequest>,
-) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
-    let req = req.0;
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    req: CompatJson<CompatCompletionRequest>,
+) -> Result<Response, CompatError> {
+    let stream = compat_response_stream(&headers, req.stream, &compat_config)?;
+    let options = req.options();
+    let model = served_model(&info, &compat_config, req.model())?;
+    validate_adapter(req.adapter_id.as_deref())?;
+    if let Some(metadata) = &req.metadata {
+        validate_compat_metadata(metadata)?;
+    }
+    let mut req: CompatCompletionRequest = req.0;
 
-    if req.stream {
-        Ok(generate_stream_openai(
+    if let Some(prompts) = &req.prompt {
+        compat_config
+            .validate_prompts(prompts.len(), req.n)
+            .map_err(completion_validation_error)?;
+    }
+    if let Some(requests) = req.split_prompts() {
+        if stream {
+            return Err(completion_validation_error(ValidationError::PromptsStream).into());
+        }
+        return Ok(
+            completions_batch_generate(compat_config, infer, req, requests, model, options).await?,
+        );
+    }
+    let metadata = req.metadata.take();
+    let n = compat_config
+        .validate_n(req.n, stream)
+        .map_err(completion_validation_error)?;
+    let (req, options) = completion_generate_request(req, options, &compat_config, &infer).await?;
+    let capped_max_tokens = options
+        .max_tokens_capped
+        .then_some(req.parameters.max_new_tokens);
+    let request_hash = compat_config
+        .request_hash_header
+        .then(|| request_hash(&req));
+
+    let mut response = if stream {
+        validate_compat_stream(&req.parameters, &OpenaiStreamType::CompletionsResponse)?;
+        let stream_guard = acquire_stream(&stream_limiter, &headers, connect_info)?;
+        generate_stream_openai(
             infer,
-            Json(req.into()),
+            Json(req),
             OpenaiStreamType::CompletionsResponse,
-            info.model_id.clone(),
+            model,
+            compat_config.0,
+            options,
+            stream_guard,
         )
-        .await
-        .into_response())
+        .await?
+        .into_response()
     } else {
-        let (headers, generation) = generate(infer, Json(req.into())).await?;
-
-        let generation = generate_to_completions(generation, info).await;
+        let (headers, mut generation) =
+            generate_completion(infer, req, n, model, &compat_config, &options).await?;
+        generation.metadata = metadata;
+        record_completion(
+            &generation.id,
+            generation.usage.as_ref(),
+            generation.choices[0].finish_reason.as_ref(),
+        );
         // wrap generation inside a Vec to match api-inference
-        Ok((headers, Json(generation.0)).into_response())
+        (headers, Json(generation)).into_response()
+    };
+    if let Some(request_hash) = request_hash {
+        response
+            .headers_mut()
+            .insert("x-request-hash", request_hash.parse().unwrap());
     }
+    if let Some(max_tokens) = capped_max_tokens {
+        response
+            .headers_mut()
+            .insert("x-max-tokens", max_tokens.into());
+    }
+    Ok(response)
 }
 
 /// Chat Completion request. Enable stream of token by setting `stream == true`, (in Python use: pip install openai>=0.28.1)
@@ -159,44 +260,604 @@ async fn completions_generate(
     ("application/json" = ChatCompletionsResponse),
     ("text/event-stream" = ChatCompletionsStreamResponse),
     )),
-    (status = 424, description = "Generation Error", body = ErrorResponse,
-    example = json ! ({"error": "Request failed during generation"})),
-    (status = 429, description = "Model is overloaded", body = ErrorResponse,
-    example = json ! ({"error": "Model is overloaded"})),
-    (status = 422, description = "Input validation error", body = ErrorResponse,
-    example = json ! ({"error": "Input validation error"})),
-    (status = 500, description = "Incomplete generation", body = ErrorResponse,
-    example = json ! ({"error": "Incomplete generation"})),
+    (status = 424, description = "Generation Error", body = CompatErrorResponse,
+    example = json ! ({"error": {"message": "Request failed during generation", "type": "server_error", "param": null, "code": "generation"}})),
+    (status = 429, description = "Model is overloaded", body = CompatErrorResponse,
+    example = json ! ({"error": {"message": "Model is overloaded", "type": "server_error", "param": null, "code": "overloaded"}})),
+    (status = 400, description = "Invalid parameter", body = CompatErrorResponse,
+    example = json ! ({"error": {"message": "`top_p` must be > 0.0 and < 1.0", "type": "invalid_request_error", "param": "top_p", "code": "validation"}})),
+    (status = 422, description = "Input validation error", body = CompatErrorResponse,
+    example = json ! ({"error": {"message": "Input validation error", "type": "invalid_request_error", "param": null, "code": "validation"}})),
+    (status = 415, description = "Unsupported content part", body = CompatErrorResponse,
+    example = json ! ({"error": {"message": "`image_url` content parts are not supported by this model", "type": "invalid_request_error", "param": "messages", "code": "unsupported_media_type"}})),
+    (status = 500, description = "Incomplete generation", body = CompatErrorResponse,
+    example = json ! ({"error": {"message": "Incomplete generation", "type": "server_error", "param": null, "code": "incomplete_generation"}})),
     )
     )]
-#[instrument(skip(infer, req))]
+#[instrument(
+skip_all,
+fields(
+id,
+stream = req.stream,
+prompt_tokens,
+completion_tokens,
+finish_reason,
+total_time,
+validation_time,
+queue_time,
+prefill_time,
+first_token_time,
+inference_time,
+time_per_token,
+seed,
+)
+)]
+#[allow(clippy::too_many_arguments)]
 async fn chatcompletions_generate(
     info: Extension<Info>,
+    compat_config: Extension<CompatConfig>,
+    stream_limiter: Extension<StreamLimiter>,
+    infer: Extension<Infer>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    req: CompatJson<CompatChatCompletionRequest>,
+) -> Result<Response, CompatError> {
+    let stream = compat_response_stream(&headers, req.stream, &compat_config)?;
+    let options = req.options();
+    let model = served_model(&info, &compat_config, req.model())?;
+    validate_adapter(req.adapter_id.as_deref())?;
+    if let Some(metadata) = &req.metadata {
+        validate_compat_metadata(metadata)?;
+    }
+    let mut req: CompatChatCompletionRequest = req.0;
+
+    if let Some(batch) = req.batch.take() {
+        return Ok(chatcompletions_batch_generate(
+            compat_config,
+            infer,
+            req,
+            batch,
+            model,
+            options,
+        )
+        .await?);
+    }
+    let metadata = req.metadata.take();
+    let n = compat_config
+        .validate_n(req.n, stream)
+        .map_err(chat_validation_error)?;
+    let (req, options) = chat_generate_request(req, options, &compat_config, &infer).await?;
+    let request_hash = compat_config
+        .request_hash_header
+        .then(|| request_hash(&req));
+    let chat_template_headers = chat_template_headers(&compat_config);
+    let capped_max_tokens = options
+        .max_tokens_capped
+        .then_some(req.parameters.max_new_tokens);
+
+    let mut response = if stream {
+        validate_compat_stream(
+            &req.parameters,
+            &OpenaiStreamType::ChatCompletionsStreamResponse,
+        )?;
+        let stream_guard = acquire_stream(&stream_limiter, &headers, connect_info)?;
+        generate_stream_openai(
+            infer,
+            Json(req),
+            OpenaiStreamType::ChatCompletionsStreamResponse,
+            model,
+            compat_config.0,
+            options,
+            stream_guard,
+        )
+        .await?
+        .into_response()
+    } else {
+        let stop = req.parameters.stop.clone();
+        let (mut headers, generations) = generate_choices(infer, req, n).await.map_err(|err| {
+            compat_infer_error(err, &OpenaiStreamType::ChatCompletionsStreamResponse)
+        })?;
+        record_timings(&generations);
+        if compat_config.server_timing_header {
+            insert_server_timing(&mut headers, &generations);
+        }
+        let mut choices = Vec::with_capacity(generations.len());
+        for mut generation in generations {
+            let finish_details = options
+                .finish_details
+                .then(|| FinishDetails::new(&generation.0, &stop))
+                .flatten();
+            if !options.include_stop_str_in_output {
+                strip_response_stop_sequence(&mut generation.0, &stop);
+            }
+            let mut generation =
+                generate_to_chatcompletions(generation, model.clone(), &compat_config, &options)
+                    .await;
+            generation.0.choices[0].tgi_finish_details = finish_details;
+            choices.push(generation.0);
+        }
+        let mut generation =
+            merge_chatcompletions(choices, &compat_config).expect("n is strictly positive");
+        generation.metadata = metadata;
+        generation.tgi_stop_sequences = options.return_stop_sequences.then_some(stop);
+        record_completion(
+            &generation.id,
+            Some(&generation.usage),
+            generation.choices[0].finish_reason.as_ref(),
+        );
+        // wrap generation inside a Vec to match api-inference
+        (headers, Json(generation)).into_response()
+    };
+    if let Some(request_hash) = request_hash {
+        response
+            .headers_mut()
+            .insert("x-request-hash", request_hash.parse().unwrap());
+    }
+    response.headers_mut().extend(chat_template_headers);
+    if let Some(max_tokens) = capped_max_tokens {
+        response
+            .headers_mut()
+            .insert("x-max-tokens", max_tokens.into());
+    }
+    Ok(response)
+}
+
+/// Generate request of a completions request and its `options`, with the number of prompt
+/// tokens if counted, with the errors of the completions route
+async fn completion_generate_request(
+    req: CompatCompletionRequest,
+    mut options: CompatRequestOptions,
+    compat_config: &CompatConfig,
+    infer: &Infer,
+) -> Result<(GenerateRequest, CompatRequestOptions), (StatusCode, Json<ErrorResponse>)> {
+    let mut req = compat_config
+        .completion_request(req)
+        .map_err(completion_validation_error)?;
+    // A null prompt is not blank, but generates from the special tokens of the tokenizer
+    if !compat_config.allow_blank_prompts
+        && !req.parameters.allow_empty_inputs
+        && req.inputs.trim().is_empty()
+    {
+        return Err(blank_prompt_error("prompt"));
+    }
+    // The prompt tokens of the usage are counted by the validation, and the prompt is only
+    // counted by the router to guard the output ratio
+    if compat_config.max_output_ratio.is_some() {
+        options.prompt_tokens =
+            infer
+                .count_tokens(req.inputs.clone(), true)
+                .await
+                .map(|prompt_tokens| match req.parameters.truncate {
+                    Some(truncate) => prompt_tokens.min(truncate as u32),
+                    None => prompt_tokens,
+                });
+    }
+    compat_config
+        .guard_output_ratio(&mut req.parameters, &mut options)
+        .map_err(completion_validation_error)?;
+    Ok((req, options))
+}
+
+fn completion_validation_error(err: ValidationError) -> (StatusCode, Json<ErrorResponse>) {
+    compat_validation_error(err, &OpenaiStreamType::CompletionsResponse)
+}
+
+/// Completions response with the `n` choices generated for a prompt
+async fn generate_completion(
     infer: Extension<Infer>,
-    req: Json<CompatChatCompletionRequest>,
+    req: GenerateRequest,
+    n: u32,
+    model: String,
+    compat_config: &CompatConfig,
+    options: &CompatRequestOptions,
+) -> Result<(HeaderMap, CompletionsResponse), (StatusCode, Json<ErrorResponse>)> {
+    let stop = req.parameters.stop.clone();
+    let (mut headers, generations) = generate_choices(infer, req, n)
+        .await
+        .map_err(|err| compat_infer_error(err, &OpenaiStreamType::CompletionsResponse))?;
+    record_timings(&generations);
+    if compat_config.server_timing_header {
+        insert_server_timing(&mut headers, &generations);
+    }
+    let mut choices = Vec::with_capacity(generations.len());
+    for mut generation in generations {
+        let finish_details = options
+            .finish_details
+            .then(|| FinishDetails::new(&generation.0, &stop))
+            .flatten();
+        if !options.include_stop_str_in_output {
+            strip_response_stop_sequence(&mut generation.0, &stop);
+        }
+        let mut generation =
+            generate_to_completions(generation, model.clone(), compat_config, options).await;
+        generation.0.choices[0].tgi_finish_details = finish_details;
+        choices.push(generation.0);
+    }
+    let mut generation = merge_completions(choices, compat_config).expect("n is strictly positive");
+    generation.tgi_stop_sequences = options.return_stop_sequences.then_some(stop);
+    Ok((headers, generation))
+}
+
+/// Completions of the prompts of a request with several prompts, generated concurrently so that
+/// they are batched together. Each prompt gets its `n` choices in turn, and the usage is the total
+async fn completions_batch_generate(
+    compat_config: Extension<CompatConfig>,
+    infer: Extension<Infer>,
+    req: CompatCompletionRequest,
+    requests: Vec<CompatCompletionRequest>,
+    model: String,
+    options: CompatRequestOptions,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
-    let stream = req.stream;
-    let req: CompatChatCompletionRequest = req.0;
+    let n = compat_config
+        .validate_n(req.n, false)
+        .map_err(completion_validation_error)?;
+    // Validate every prompt before generating any of them
+    let mut generate_requests = Vec::with_capacity(requests.len());
+    for req in requests {
+        generate_requests
+            .push(completion_generate_request(req, options, &compat_config, &infer).await?);
+    }
+
+    let generations = try_join_all(generate_requests.into_iter().map(|(req, options)| {
+        let infer = infer.clone();
+        let compat_config = &compat_config;
+        let model = model.clone();
+        async move { generate_completion(infer, req, n, model, compat_config, &options).await }
+    }))
+    .await?;
+    let mut headers = None;
+    let generations = generations
+        .into_iter()
+        .map(|(prompt_headers, generation)| {
+            headers.get_or_insert(prompt_headers);
+            generation
+        })
+        .collect();
+
+    let mut generation =
+        batch_completions(generations, &compat_config).expect("batch contains several prompts");
+    generation.metadata = req.metadata;
+    record_completion(
+        &generation.id,
+        generation.usage.as_ref(),
+        generation.choices[0].finish_reason.as_ref(),
+    );
+    Ok((headers.unwrap_or_default(), Json(generation)).into_response())
+}
+
+/// Record the timings of the first generation of a non-streamed request on the current span, as
+/// the streams record theirs
+fn record_timings(generations: &[Json<GenerateResponse>]) {
+    let details = match generations
+        .first()
+        .and_then(|generation| generation.details.as_ref())
+    {
+        Some(details) => details,
+        None => return,
+    };
+    let span = tracing::Span::current();
+    span.record("seed", format!("{:?}", details.seed));
+    if let Some(timings) = &details.timings {
+        let duration = |ms: f64| format!("{:?}", Duration::from_secs_f64(ms / 1000.0));
+        let first_token_ms = timings.validation_ms + timings.queue_ms + timings.prefill_ms;
+        let inference_ms = timings.prefill_ms + timings.decode_ms;
+        span.record("total_time", duration(first_token_ms + timings.decode_ms));
+        span.record("validation_time", duration(timings.validation_ms));
+        span.record("queue_time", duration(timings.queue_ms));
+        span.record("prefill_time", duration(timings.prefill_ms));
+        span.record("first_token_time", duration(first_token_ms));
+        span.record("inference_time", duration(inference_ms));
+        span.record(
+            "time_per_token",
+            duration(inference_ms / details.generated_tokens.max(1) as f64),
+        );
+    }
+}
+
+/// Report the timings of the first generation in the `Server-Timing` header
+fn insert_server_timing(headers: &mut HeaderMap, generations: &[Json<GenerateResponse>]) {
+    let timings = generations
+        .first()
+        .and_then(|generation| generation.details.as_ref())
+        .and_then(|details| details.timings.as_ref());
+    if let Some(timings) = timings {
+        headers.insert("server-timing", timings.server_timing().parse().unwrap());
+    }
+}
+
+/// Generate request of a chat request and its `options`, with the number of prompt tokens, if
+/// counted, and of evicted turns, with the errors of the chat completions route
+async fn chat_generate_request(
+    mut req: CompatChatCompletionRequest,
+    options: CompatRequestOptions,
+    compat_config: &CompatConfig,
+    infer: &Infer,
+) -> Result<(GenerateRequest, CompatRequestOptions), (StatusCode, Json<ErrorResponse>)> {
+    // Multimodal content is recognized but cannot be handled by text-only models
+    if let Some(part) = req
+        .messages
+        .iter()
+        .find_map(|message| message.content.unsupported_part())
+    {
+        metrics::increment_counter!("tgi_request_failure", "err" => "unsupported_media_type");
+        let err = ErrorResponse {
+            error: format!("`{part}` content parts are not supported by this model"),
+            error_type: "unsupported_media_type".to_string(),
+            param: Some("messages".to_string()),
+        };
+        tracing::error!("{}", err.error);
+        return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, Json(err)));
+    }
+    req.validate_logprobs().map_err(chat_validation_error)?;
+    for message in &mut req.messages {
+        message
+            .content
+            .normalize(compat_config.prompt_normalization);
+    }
     // TODO: move this somewhere else
 
-    let chat_formatter = get_chatformatter();
-    let req: GenerateRequest = chat_to_generate_request(req, chat_formatter);
+    // Templates always add delimiters, so that only the contents tell a blank conversation
+    let blank = req
+        .messages
+        .iter()
+        .all(|message| message.content.is_blank());
+    req.add_system_messages(&compat_config.chat_config, options.response_format);
+    compat_config
+        .chat_config
+        .normalize_assistant_role(&mut req.messages);
+
+    let chat_formatter = &compat_config.chat_formatter;
+    // The system messages are never evicted, and the prompts counted by the router share their
+    // cached count
+    let prefix = chat_prompt_prefix(&req.messages, chat_formatter, &compat_config.chat_config);
+    let count_tokens = |prompt| {
+        compat_config.prompt_token_cache.count_tokens(
+            prompt,
+            &prefix,
+            |text, add_special_tokens| infer.count_tokens(text, add_special_tokens),
+        )
+    };
+    let evicted_turns = match compat_config.max_conversation_tokens {
+        Some(max_tokens) => evict_oldest_turns(
+            &mut req,
+            chat_formatter,
+            &compat_config.chat_config,
+            max_tokens,
+            count_tokens,
+        )
+        .await
+        .map_err(chat_validation_error)?,
+        None => 0,
+    };
+    let mut req = chat_to_generate_request(req, chat_formatter, &compat_config.chat_config)
+        .map_err(chat_validation_error)?;
+    compat_config
+        .validate_stop(&mut req.parameters)
+        .map_err(chat_validation_error)?;
+    compat_config
+        .validate_repetition_penalty(&req.parameters)
+        .map_err(chat_validation_error)?;
+    compat_config
+        .validate_top_p(&mut req.parameters)
+        .map_err(chat_validation_error)?;
+    if blank && !compat_config.allow_blank_prompts {
+        return Err(blank_prompt_error("messages"));
+    }
 
-    if stream {
-        Ok(generate_stream_openai(
-            infer,
-            Json(req.into()),
-            OpenaiStreamType::ChatCompletionsStreamResponse,
-            info.model_id.clone(),
+    // The prompt tokens of the usage are counted by the validation, and the prompt is only
+    // counted by the router to guard the output ratio
+    let prompt_tokens = match compat_config.max_output_ratio {
+        Some(_) => count_tokens(req.inputs.clone()).await.map(|prompt_tokens| {
+            match req.parameters.truncate {
+                Some(truncate) => prompt_tokens.min(truncate as u32),
+                None => prompt_tokens,
+            }
+        }),
+        None => None,
+    };
+    let mut options = CompatRequestOptions {
+        prompt_tokens,
+        evicted_turns,
+        ..options
+    };
+    compat_config
+        .guard_output_ratio(&mut req.parameters, &mut options)
+        .map_err(chat_validation_error)?;
+    Ok((req, options))
+}
+
+fn chat_validation_error(err: ValidationError) -> (StatusCode, Json<ErrorResponse>) {
+    compat_validation_error(err, &OpenaiStreamType::ChatCompletionsStreamResponse)
+}
+
+fn compat_validation_error(
+    err: ValidationError,
+    stream_type: &OpenaiStreamType,
+) -> (StatusCode, Json<ErrorResponse>) {
+    metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+    tracing::error!("{err}");
+    compat_infer_error(InferError::from(err), stream_type)
+}
+
+/// Error response of the OpenAI compatible routes. Invalid parameters are client errors, with a
+/// 400 as with OpenAI, and only the prompts that cannot be generated, too long, empty or failing
+/// the tokenizer, are unprocessable
+fn compat_infer_error(
+    err: InferError,
+    stream_type: &OpenaiStreamType,
+) -> (StatusCode, Json<ErrorResponse>) {
+    let input_error = matches!(
+        err,
+        InferError::ValidationError(
+            ValidationError::InputLength(_, _)
+                | ValidationError::MaxTotalTokens(_, _, _)
+                | ValidationError::EmptyInput
+                | ValidationError::Tokenizer(_)
         )
+    );
+    let validation_error = matches!(err, InferError::ValidationError(_));
+    let (status_code, err) = compat_error(err.into(), stream_type);
+    let status_code = match validation_error && !input_error {
+        true => StatusCode::BAD_REQUEST,
+        false => status_code,
+    };
+    (status_code, err)
+}
+
+/// Chat completions of independent conversations, generated concurrently so that they are
+/// batched together. Each conversation gets its own choice, and the usage is the total
+async fn chatcompletions_batch_generate(
+    compat_config: Extension<CompatConfig>,
+    infer: Extension<Infer>,
+    req: CompatChatCompletionRequest,
+    batch: Vec<Vec<ChatMessage>>,
+    model: String,
+    options: CompatRequestOptions,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    if batch.is_empty() {
+        return Err(chat_validation_error(ValidationError::EmptyBatch));
+    }
+    compat_config
+        .validate_batch(batch.len())
+        .map_err(chat_validation_error)?;
+    if !req.messages.is_empty() {
+        return Err(chat_validation_error(ValidationError::BatchMessages));
+    }
+    if req.stream {
+        return Err(chat_validation_error(ValidationError::BatchStream));
+    }
+    if req.n.map_or(false, |n| n != 1) {
+        return Err(chat_validation_error(ValidationError::BatchN));
+    }
+
+    // Validate every conversation before generating any of them
+    let mut requests = Vec::with_capacity(batch.len());
+    for messages in batch {
+        let req = CompatChatCompletionRequest {
+            messages,
+            ..req.clone()
+        };
+        requests.push(chat_generate_request(req, options, &compat_config, &infer).await?);
+    }
+
+    let mut generations =
+        try_join_all(requests.into_iter().enumerate().map(|(i, (req, options))| {
+            let infer = infer.clone();
+            let compat_config = &compat_config;
+            let model = model.clone();
+            async move {
+                let stop = req.parameters.stop.clone();
+                let (_, mut generation) = generate_response(infer, Json(req)).await?;
+                if i == 0 {
+                    record_timings(std::slice::from_ref(&generation));
+                }
+                let finish_details = options
+                    .finish_details
+                    .then(|| FinishDetails::new(&generation.0, &stop))
+                    .flatten();
+                if !options.include_stop_str_in_output {
+                    strip_response_stop_sequence(&mut generation.0, &stop);
+                }
+                let mut generation =
+                    generate_to_chatcompletions(generation, model, compat_config, &options).await;
+                generation.0.choices[0].tgi_finish_details = finish_details;
+                generation.0.tgi_stop_sequences = options.return_stop_sequences.then_some(stop);
+                Ok(generation.0)
+            }
+        }))
         .await
-        .into_response())
-    } else {
-        let (headers, generation) = generate(infer, Json(req.into())).await?;
+        .map_err(|err| compat_infer_error(err, &OpenaiStreamType::ChatCompletionsStreamResponse))?;
+    for generation in generations.iter_mut() {
+        generation.metadata = req.metadata.clone();
+    }
 
-        let generation = generate_to_chatcompletions(generation, info).await;
-        // wrap generation inside a Vec to match api-inference
-        Ok((headers, Json(generation.0)).into_response())
+    let mut response = match compat_config.batch_response_format {
+        BatchResponseFormat::Choices => {
+            let generation = batch_chatcompletions(generations, &compat_config)
+                .expect("batch contains at least one conversation");
+            record_completion(
+                &generation.id,
+                Some(&generation.usage),
+                generation.choices[0].finish_reason.as_ref(),
+            );
+            Json(generation).into_response()
+        }
+        BatchResponseFormat::List => {
+            record_completion(
+                &generations[0].id,
+                Some(&batch_usage(&generations, &compat_config)),
+                generations[0].choices[0].finish_reason.as_ref(),
+            );
+            Json(ChatCompletionsListResponse::new(generations)).into_response()
+        }
+    };
+    response
+        .headers_mut()
+        .extend(chat_template_headers(&compat_config));
+    Ok(response)
+}
+
+/// OpenAI `x-ratelimit-*` headers, for clients to back off before getting 429s.
+/// Requests are limited by the maximum number of concurrent requests, which are released as soon
+/// as requests complete. Tokens are not rate limited
+async fn ratelimit_headers(
+    info: Extension<Info>,
+    infer: Extension<Infer>,
+    mut response: Response,
+) -> Response {
+    let headers = response.headers_mut();
+    headers.insert(
+        "x-ratelimit-limit-requests",
+        info.max_concurrent_requests.into(),
+    );
+    headers.insert(
+        "x-ratelimit-remaining-requests",
+        infer.available_permits().into(),
+    );
+    headers.insert("x-ratelimit-reset-requests", HeaderValue::from_static("0s"));
+    headers.insert(
+        "x-ratelimit-limit-tokens",
+        info.max_batch_total_tokens.into(),
+    );
+    headers.insert(
+        "x-ratelimit-remaining-tokens",
+        info.max_batch_total_tokens.into(),
+    );
+    headers.insert("x-ratelimit-reset-tokens", HeaderValue::from_static("0s"));
+    response
+}
+
+/// Headers identifying the chat templates that rendered the prompt, with `chat_template_header`.
+/// The template name is left out if it is not a valid header value
+fn chat_template_headers(compat_config: &CompatConfig) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if !compat_config.chat_template_header {
+        return headers;
+    }
+    if let Some(hash) = &compat_config.chat_template_hash {
+        headers.insert("x-chat-template-hash", hash.parse().unwrap());
+    }
+    if let Some(name) = compat_config
+        .chat_config
+        .template_name
+        .as_deref()
+        .and_then(|name| HeaderValue::from_str(name).ok())
+    {
+        headers.insert("x-chat-template", name);
+    }
+    headers
+}
+
+/// Record the outcome of an OpenAI compatible request on the current span
+fn record_completion(id: &str, usage: Option<&Usage>, finish_reason: Option<&FinishReason>) {
+    let span = tracing::Span::current();
+    span.record("id", id);
+    if let Some(usage) = usage {
+        span.record("prompt_tokens", usage.prompt_tokens.value);
+        span.record("completion_tokens", usage.completion_tokens.value);
+    }
+    if let Some(finish_reason) = finish_reason {
+        span.record("finish_reason", format!("{finish_reason:?}"));
     }
 }
 
@@ -212,6 +873,58 @@ async fn get_model_info(info: Extension<Info>) -> Json<Info> {
     Json(info.0)
 }
 
+/// Preview the TGI generation parameters that OpenAI compatible parameter sets convert to,
+/// without generating
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/completions/parameters",
+request_body = ParametersPreviewRequest,
+responses(
+(status = 200, description = "Generation parameters of each parameter set", body = ParametersPreviewResponse),
+(status = 400, description = "Parameter set rejected by the validation", body = ErrorResponse,
+example = json ! ({"error": "parameter set 1: `top_p` must be > 0.0 and < 1.0"})),
+(status = 422, description = "Invalid parameter set", body = ErrorResponse,
+example = json ! ({"error": "parameter set 1: invalid type: string \"hot\", expected f32"})),
+)
+)]
+#[instrument(skip_all)]
+async fn completions_parameters(
+    compat_config: Extension<CompatConfig>,
+    req: Json<ParametersPreviewRequest>,
+) -> Result<Json<ParametersPreviewResponse>, (StatusCode, Json<ErrorResponse>)> {
+    preview_parameters(req.0, &compat_config)
+        .map(Json)
+        .map_err(|(i, err)| {
+            let status_code = match err {
+                ParameterSetError::Deserialize(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                ParameterSetError::Validation(_) => StatusCode::BAD_REQUEST,
+            };
+            let err = ErrorResponse {
+                error: format!("parameter set {i}: {err}"),
+                error_type: "validation".to_string(),
+                param: Some("parameter_sets".to_string()),
+            };
+            tracing::error!("{}", err.error);
+            (status_code, Json(err))
+        })
+}
+
+/// OpenAI compatible model list, with the capabilities of this deployment
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/models",
+responses((status = 200, description = "Served model and its capabilities", body = ModelsResponse))
+)]
+#[instrument]
+async fn get_models(
+    info: Extension<Info>,
+    compat_config: Extension<CompatConfig>,
+) -> Json<ModelsResponse> {
+    Json(models_response(&info, &compat_config))
+}
+
 #[utoipa::path(
 get,
 tag = "Text Generation Inference",
@@ -232,6 +945,7 @@ async fn health(mut health: Extension<Health>) -> Result<(), (StatusCode, Json<E
             Json(ErrorResponse {
                 error: "unhealthy".to_string(),
                 error_type: "healthcheck".to_string(),
+                param: None,
             }),
         )),
     }
@@ -255,7 +969,17 @@ example = json ! ({"error": "Input validation error"})),
 example = json ! ({"error": "Incomplete generation"})),
 )
 )]
+async fn generate(
+    infer: Extension<Infer>,
+    req: Json<GenerateRequest>,
+) -> Result<(HeaderMap, Json<GenerateResponse>), (StatusCode, Json<ErrorResponse>)> {
+    Ok(generate_response(infer, req).await?)
+}
+
+/// Generated text of `/generate`, with the inference errors, which the OpenAI compatible routes
+/// report with their own status codes
 #[instrument(
+name = "generate",
 skip_all,
 fields(
 parameters = ? req.0.parameters,
@@ -267,10 +991,10 @@ time_per_token,
 seed,
 )
 )]
-async fn generate(
+async fn generate_response(
     infer: Extension<Infer>,
     req: Json<GenerateRequest>,
-) -> Result<(HeaderMap, Json<GenerateResponse>), (StatusCode, Json<ErrorResponse>)> {
+) -> Result<(HeaderMap, Json<GenerateResponse>), InferError> {
     let span = tracing::Span::current();
     let start_time = Instant::now();
     metrics::increment_counter!("tgi_request_count");
@@ -294,6 +1018,15 @@ async fn generate(
         _ => (infer.generate(req.0).await?, None),
     };
 
+    let timings = Timings::new(
+        start_time,
+        response.queued,
+        response.start,
+        response.first_token,
+        Instant::now(),
+        response.generated_text.generated_tokens,
+    );
+
     // Token details
     let details = match details {
         true => {
@@ -329,6 +1062,9 @@ async fn generate(
                 tokens: response.tokens,
                 seed: response.generated_text.seed,
                 best_of_sequences,
+                prompt_tokens: response.prompt_tokens,
+                truncated_tokens: response.truncated_tokens,
+                timings: Some(timings),
             })
         }
         false => None,
@@ -502,7 +1238,7 @@ async fn generate_stream(
         } else {
             match infer.generate_stream(req.0).instrument(info_span!(parent: &span, "async_stream")).await {
                 // Keep permit as long as generate_stream lives
-                Ok((_permit, mut response_stream)) => {
+                Ok((_permit, _, mut response_stream)) => {
                     // Server-Sent Event stream
                     while let Some(response) = response_stream.next().await {
                         match response {
@@ -529,6 +1265,7 @@ async fn generate_stream(
                                         generated_text,
                                         start,
                                         queued,
+                                        ..
                                     } => {
                                         // Token details
                                         let details = match details {
@@ -620,17 +1357,24 @@ async fn generate_stream_openai(
     req: Json<GenerateRequest>,
     stream_type: OpenaiStreamType,
     model_name: String,
-) -> (
-    HeaderMap,
-    Sse<impl Stream<Item = Result<Event, Infallible>>>,
-) {
+    compat_config: CompatConfig,
+    options: CompatRequestOptions,
+    stream_guard: Option<StreamGuard>,
+) -> Result<
+    (
+        HeaderMap,
+        Sse<impl Stream<Item = Result<Event, Infallible>>>,
+    ),
+    (StatusCode, Json<ErrorResponse>),
+> {
     let span = tracing::Span::current();
     let start_time = Instant::now();
-    let created_time = create_timestamp();
+    let created_time = compat_config.number(create_timestamp());
     metrics::increment_counter!("tgi_request_count");
 
     tracing::debug!("Input: {}", req.0.inputs);
 
+    let low_priority = req.0.parameters.low_priority;
     let compute_characters = req.0.inputs.chars().count();
 
     let mut headers = HeaderMap::new();
@@ -641,130 +1385,318 @@ async fn generate_stream_openai(
     );
     headers.insert("X-Accel-Buffering", "no".parse().unwrap());
 
-    let stream = async_stream::stream! {
-        // Inference
-        let mut end_reached = false;
-        let mut error = false;
-
-        let details = req.0.parameters.details;
-
-        let best_of = req.0.parameters.best_of.unwrap_or(1);
-        if best_of != 1 {
-            let err = InferError::from(ValidationError::BestOfStream);
-            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
-            tracing::error!("{err}");
-            yield Ok(Event::from(err));
-        } else if req.0.parameters.decoder_input_details {
-            let err = InferError::from(ValidationError::PrefillDetailsStream);
-            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
-            tracing::error!("{err}");
-            yield Ok(Event::from(err));
-        } else {
-            match infer.generate_stream(req.0).instrument(info_span!(parent: &span, "async_stream")).await {
-                // Keep permit as long as generate_stream lives
-                Ok((_permit, mut response_stream)) => {
-                    // Server-Sent Event stream
-                    match stream_type {
-                        OpenaiStreamType::ChatCompletionsStreamResponse => {
-                            let start_msg = chat_start_message(created_time, &model_name);
-                            yield Ok(Event::from(Event::default().json_data(start_msg).unwrap()))
-                        },
-                        _ => ()
-                    };
-                    while let Some(response) = response_stream.next().await {
-                        match response {
-                            Ok(response) => {
-                                match response {
-                                    // Prefill is ignored
-                                    InferStreamResponse::Prefill(_) => {}
-                                    // Yield event for every new token
-                                    InferStreamResponse::Token(token) => {
-                                        tracing::debug!(parent: &span, "Token: {:?}", token);
-                                        let stream_event = create_streaming_event(&stream_type, created_time, None, token, &model_name);
-
-                                        yield Ok(stream_event);
-                                    }
-                                    // Yield event for last token and compute timings
-                                    InferStreamResponse::End {
-                                        token,
-                                        generated_text,
-                                        start,
-                                        queued,
-                                    } => {
-                                        // Token details
-                                        let details = match details {
-                                            true => Some(StreamDetails {
-                                                finish_reason: FinishReason::from(generated_text.finish_reason),
-                                                generated_tokens: generated_text.generated_tokens,
-                                                seed: generated_text.seed,
-                                            }),
-                                            false => None,
-                                        };
-
-                                        // Timings
-                                        let total_time = start_time.elapsed();
-                                        let validation_time = queued - start_time;
-                                        let queue_time = start - queued;
-                                        let inference_time = Instant::now() - start;
-                                        let time_per_token = inference_time / generated_text.generated_tokens;
-
-                                        // Tracing metadata
-                                        span.record("total_time", format!("{total_time:?}"));
-                                        span.record("validation_time", format!("{validation_time:?}"));
-                                        span.record("queue_time", format!("{queue_time:?}"));
-                                        span.record("inference_time", format!("{inference_time:?}"));
-                                        span.record("time_per_token", format!("{time_per_token:?}"));
-                                        span.record("seed", format!("{:?}", generated_text.seed));
+    // `echo` streams the prompt before the generated tokens on the completions route.
+    // With `decoder_input_details`, the prompt is streamed token by token with its logprobs
+    let echo = matches!(stream_type, OpenaiStreamType::CompletionsResponse)
+        && req.0.parameters.return_full_text.unwrap_or(false);
+    let echo_prompt_tokens = echo && req.0.parameters.decoder_input_details;
+    let prompt = echo.then(|| req.0.inputs.clone());
+    // Correlate the request span with the response `id`
+    let id = create_id(&stream_type);
+    span.record("id", id.as_str());
+    let details = req.0.parameters.details;
+    let stop = req.0.parameters.stop.clone();
+
+    // Validate the request before the stream opens, so that invalid requests get an error
+    // response rather than an error event
+    let (permit, validated_prompt_tokens, response_stream) = infer
+        .generate_stream(req.0)
+        .instrument(info_span!(parent: &span, "async_stream"))
+        .await
+        .map_err(|err| compat_infer_error(err, &stream_type))?;
 
-                                        // Metrics
-                                        metrics::increment_counter!("tgi_request_success");
-                                        metrics::histogram!("tgi_request_duration", total_time.as_secs_f64());
-                                        metrics::histogram!("tgi_request_validation_duration", validation_time.as_secs_f64());
-                                        metrics::histogram!("tgi_request_queue_duration", queue_time.as_secs_f64());
-                                        metrics::histogram!("tgi_request_inference_duration", inference_time.as_secs_f64());
-                                        metrics::histogram!("tgi_request_mean_time_per_token_duration", time_per_token.as_secs_f64());
-                                        metrics::histogram!("tgi_request_generated_tokens", generated_text.generated_tokens as f64);
+    // Low priority requests are only limited by the maximum stream duration once they got
+    // capacity
+    let stream_start = if low_priority {
+        Instant::now()
+    } else {
+        start_time
+    };
+    // Prompt tokens are counted by the validation, or else by the router, unless the prefill
+    // is streamed back
+    let prompt_tokens = validated_prompt_tokens
+        .or(options.prompt_tokens)
+        .unwrap_or(0);
+    let events = openai_stream_events(
+        response_stream,
+        stream_type,
+        id,
+        created_time,
+        model_name,
+        compat_config,
+        options,
+        stop,
+        details,
+        prompt,
+        echo_prompt_tokens,
+        prompt_tokens,
+        start_time,
+        stream_start,
+        span,
+    );
+    let stream = async_stream::stream! {
+        // Keep the stream of the client active as long as the stream lives
+        let _stream_guard = stream_guard;
+        // Keep permit as long as generate_stream lives
+        let _permit = permit;
+        for await event in events {
+            yield event;
+        }
+    };
 
-                                        // create Openai StreamResponse
-                                        end_reached = true;
+    Ok((headers, Sse::new(stream).keep_alive(KeepAlive::default())))
+}
 
-                                        tracing::debug!(parent: &span, "Output: {}", generated_text.text);
-                                        tracing::info!(parent: &span, "Success");
+/// Server-Sent Events of an OpenAI compatible stream, from the responses of the inference stream
+/// of its request
+#[allow(clippy::too_many_arguments)]
+fn openai_stream_events<S>(
+    mut response_stream: S,
+    stream_type: OpenaiStreamType,
+    id: String,
+    created_time: CompatNumber<u64>,
+    model_name: String,
+    compat_config: CompatConfig,
+    options: CompatRequestOptions,
+    stop: Vec<String>,
+    details: bool,
+    prompt: Option<String>,
+    echo_prompt_tokens: bool,
+    prompt_tokens: u32,
+    start_time: Instant,
+    stream_start: Instant,
+    span: tracing::Span,
+) -> impl Stream<Item = Result<Event, Infallible>>
+where
+    S: Stream<Item = Result<InferStreamResponse, InferError>> + Unpin,
+{
+    let mut stop_buffer = StopSequenceBuffer::new(stop, options.include_stop_str_in_output);
+    let mut sentence_buffer = SentenceBuffer::new(
+        options.stream_granularity,
+        compat_config
+            .sentence_boundaries
+            .as_deref()
+            .unwrap_or(DEFAULT_SENTENCE_BOUNDARIES),
+    );
+    let mut whitespace_buffer = TrailingWhitespaceBuffer::new(compat_config.trailing_whitespace);
+    let mut prefix_buffer = AssistantPrefixBuffer::new(match stream_type {
+        OpenaiStreamType::ChatCompletionsStreamResponse => compat_config.assistant_prefix(),
+        OpenaiStreamType::CompletionsResponse => None,
+    });
 
-                                        let stream_event = create_streaming_event(&stream_type, created_time, details, token, &model_name);
-                                        yield Ok(stream_event);
-                                        yield Ok(Event::default().data("[DONE]"));
-                                        break;
-                                    }
+    async_stream::stream! {
+        let mut end_reached = false;
+        let mut error = false;
+        let mut prompt_tokens = prompt_tokens;
+        // Generated tokens streamed so far, for the usage of the streams closed before the end
+        let mut completion_tokens = 0;
+        let mut first_token_time = None;
+
+        // Server-Sent Event stream
+        if let OpenaiStreamType::ChatCompletionsStreamResponse = stream_type {
+            let start_msg = chat_start_message(
+                created_time,
+                &id,
+                &model_name,
+                compat_config.chat_config.assistant_role(),
+            );
+            yield Ok(compat_config.sse_event(stream_event(start_msg), CHUNK_EVENT))
+        }
+        if let Some(prompt) = prompt {
+            if !echo_prompt_tokens {
+                let echo_chunk = echo_prompt_chunk(created_time, &id, prompt, &model_name);
+                yield Ok(compat_config.sse_event(stream_event(echo_chunk), CHUNK_EVENT))
+            }
+        }
+        // Hard ceiling on the stream wall-time
+        let deadline = compat_config
+            .max_stream_duration
+            .map(|max_stream_duration| stream_start + max_stream_duration);
+        loop {
+            let response = match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, response_stream.next()).await {
+                    Ok(response) => response,
+                    // Dropping `response_stream` at the end of this block cancels the
+                    // request in the batching task
+                    Err(_) => {
+                        metrics::increment_counter!("tgi_request_stream_timeout");
+                        tracing::warn!(parent: &span, "Stream exceeded the maximum stream duration");
+                        end_reached = true;
+
+                        // Release the held back text with the terminal chunk
+                        let text = stop_buffer.push_last(&prefix_buffer.finish(), false);
+                        let mut text = sentence_buffer.push(&text);
+                        text.push_str(&sentence_buffer.finish());
+                        let mut text = whitespace_buffer.push(&text);
+                        text.push_str(&whitespace_buffer.finish());
+                        let text = (!text.is_empty()).then_some(text);
+                        let tgi_finish_reason = compat_config.server_finish_reasons.then_some(ServerFinishReason::Timeout);
+                        yield Ok(compat_config.sse_event(create_stream_end_event(&stream_type, created_time, &id, FinishReason::Length, tgi_finish_reason, text, &model_name), CHUNK_EVENT));
+                        let usage = compat_config.stream_usage(&options).then(|| Usage::new(prompt_tokens, completion_tokens, &compat_config).with_evicted_turns(options.evicted_turns));
+                        for event in stream_done_events(&stream_type, created_time, &id, usage, &model_name, &compat_config) {
+                            yield Ok(event);
+                        }
+                        break;
+                    }
+                },
+                None => response_stream.next().await,
+            };
+            let Some(response) = response else {
+                break;
+            };
+            match response {
+                Ok(response) => {
+                    match response {
+                        // Prefill is only streamed back when echoing prompt tokens
+                        InferStreamResponse::Prefill(prefill) => {
+                            span.record("prompt_tokens", prefill.ids.len());
+                            prompt_tokens = prefill.ids.len() as u32;
+                            if echo_prompt_tokens {
+                                for echo_chunk in echo_prompt_token_chunks(created_time, &id, prefill, &model_name) {
+                                    yield Ok(compat_config.sse_event(stream_event(echo_chunk), CHUNK_EVENT))
                                 }
                             }
-                            // yield error
-                            Err(err) => {
-                                error = true;
-                                yield Ok(Event::from(err));
-                                break;
+                        }
+                        // Yield event for every new token
+                        InferStreamResponse::Token(mut token) => {
+                            tracing::debug!(parent: &span, "Token: {:?}", token);
+                            completion_tokens += 1;
+                            if first_token_time.is_none() {
+                                let now = Instant::now();
+                                span.record("first_token_time", format!("{:?}", now - start_time));
+                                tracing::debug!(parent: &span, "First token");
+                                first_token_time = Some(now);
                             }
+                            // Skip tokens entirely held back as a potential assistant prefix or
+                            // stop sequence, until the end of the sentence or as trailing whitespace
+                            let text = stop_buffer.push(&prefix_buffer.push(&token.text));
+                            let text = whitespace_buffer.push(&sentence_buffer.push(&text));
+                            if text.is_empty() && !token.text.is_empty() {
+                                continue;
+                            }
+                            token.text = text;
+                            let stream_event = create_streaming_event(&stream_type, created_time, &id, None, None, token, &model_name);
+
+                            yield Ok(compat_config.sse_event(stream_event, CHUNK_EVENT));
+                        }
+                        // Yield event for last token and compute timings
+                        InferStreamResponse::End {
+                            mut token,
+                            generated_text,
+                            start,
+                            queued,
+                            truncated_tokens,
+                        } => {
+                            // Release the held back text, without the stop sequence
+                            let stop_sequence_reached = matches!(
+                                FinishReason::from(generated_text.finish_reason),
+                                FinishReason::StopSequence
+                            );
+                            let mut text = prefix_buffer.push(&token.text);
+                            text.push_str(&prefix_buffer.finish());
+                            let text = stop_buffer.push_last(&text, stop_sequence_reached);
+                            let mut text = sentence_buffer.push(&text);
+                            text.push_str(&sentence_buffer.finish());
+                            token.text = whitespace_buffer.push(&text);
+                            token.text.push_str(&whitespace_buffer.finish());
+
+                            let tgi_finish_reason = compat_config.server_finish_reason(&FinishReason::from(generated_text.finish_reason), &options);
+                            let usage = compat_config.stream_usage(&options).then(|| Usage::new(prompt_tokens, generated_text.generated_tokens, &compat_config).with_truncated_tokens(truncated_tokens).with_evicted_turns(options.evicted_turns));
+                            // Token details
+                            let details = match details {
+                                true => Some(StreamDetails {
+                                    finish_reason: FinishReason::from(generated_text.finish_reason),
+                                    generated_tokens: generated_text.generated_tokens,
+                                    seed: generated_text.seed,
+                                }),
+                                false => None,
+                            };
+
+                            // Timings
+                            let total_time = start_time.elapsed();
+                            let validation_time = queued - start_time;
+                            let queue_time = start - queued;
+                            let inference_time = Instant::now() - start;
+                            let time_per_token = inference_time / generated_text.generated_tokens;
+
+                            // Tracing metadata
+                            span.record("total_time", format!("{total_time:?}"));
+                            span.record("validation_time", format!("{validation_time:?}"));
+                            span.record("queue_time", format!("{queue_time:?}"));
+                            span.record("inference_time", format!("{inference_time:?}"));
+                            span.record("time_per_token", format!("{time_per_token:?}"));
+                            span.record("seed", format!("{:?}", generated_text.seed));
+                            // Single token generations only receive the `End` response
+                            let first_token_time = match first_token_time {
+                                Some(first_token_time) => first_token_time,
+                                None => {
+                                    let now = Instant::now();
+                                    span.record("first_token_time", format!("{:?}", now - start_time));
+                                    now
+                                }
+                            };
+                            span.record("prefill_time", format!("{:?}", first_token_time - start));
+                            span.record("completion_tokens", generated_text.generated_tokens);
+                            span.record("finish_reason", format!("{:?}", FinishReason::from(generated_text.finish_reason)));
+
+                            // Metrics
+                            metrics::increment_counter!("tgi_request_success");
+                            metrics::histogram!("tgi_request_duration", total_time.as_secs_f64());
+                            metrics::histogram!("tgi_request_validation_duration", validation_time.as_secs_f64());
+                            metrics::histogram!("tgi_request_queue_duration", queue_time.as_secs_f64());
+                            metrics::histogram!("tgi_request_inference_duration", inference_time.as_secs_f64());
+                            metrics::histogram!("tgi_request_mean_time_per_token_duration", time_per_token.as_secs_f64());
+                            metrics::histogram!("tgi_request_generated_tokens", generated_text.generated_tokens as f64);
+
+                            // create Openai StreamResponse
+                            end_reached = true;
+
+                            tracing::debug!(parent: &span, "Output: {}", generated_text.text);
+                            tracing::info!(parent: &span, "Success");
+
+                            let stream_event = create_streaming_event(&stream_type, created_time, &id, details, tgi_finish_reason, token, &model_name);
+                            yield Ok(compat_config.sse_event(stream_event, CHUNK_EVENT));
+                            for event in stream_done_events(&stream_type, created_time, &id, usage, &model_name, &compat_config) {
+                                yield Ok(event);
+                            }
+                            break;
                         }
                     }
-                },
+                }
                 // yield error
                 Err(err) => {
                     error = true;
-                    yield Ok(Event::from(err));
+                    match &compat_config.stream_error_fallback {
+                        // Close the stream as if the model stopped, after the held back text
+                        Some(fallback) => {
+                            tracing::error!(parent: &span, "Stream closed with the fallback response: {err}");
+                            let text = stop_buffer.push_last(&prefix_buffer.finish(), false);
+                            let mut text = sentence_buffer.push(&text);
+                            text.push_str(&sentence_buffer.finish());
+                            text.push_str(fallback);
+                            let mut text = whitespace_buffer.push(&text);
+                            text.push_str(&whitespace_buffer.finish());
+                            yield Ok(compat_config.sse_event(create_stream_end_event(&stream_type, created_time, &id, FinishReason::EndOfSequenceToken, None, Some(text), &model_name), CHUNK_EVENT));
+                            let usage = compat_config.stream_usage(&options).then(|| Usage::new(prompt_tokens, completion_tokens, &compat_config).with_evicted_turns(options.evicted_turns));
+                            for event in stream_done_events(&stream_type, created_time, &id, usage, &model_name, &compat_config) {
+                                yield Ok(event);
+                            }
+                        }
+                        None => yield Ok(compat_config.sse_event(compat_error_event(err, &stream_type), ERROR_EVENT)),
+                    }
+                    break;
                 }
             }
-            // Check if generation reached the end
-            // Skip if we already sent an error
-            if !end_reached && !error {
-                let err = InferError::IncompleteGeneration;
-                metrics::increment_counter!("tgi_request_failure", "err" => "incomplete");
-                tracing::error!("{err}");
-                yield Ok(Event::from(err));
-            }
         }
-    };
-
-    (headers, Sse::new(stream).keep_alive(KeepAlive::default()))
+        // Check if generation reached the end
+        // Skip if we already sent an error
+        if !end_reached && !error {
+            let err = InferError::IncompleteGeneration;
+            metrics::increment_counter!("tgi_request_failure", "err" => "incomplete");
+            tracing::error!("{err}");
+            yield Ok(compat_config.sse_event(compat_error_event(err, &stream_type), ERROR_EVENT));
+        }
+    }
 }
 
 /// Prometheus metrics scrape endpoint
@@ -796,11 +1728,13 @@ pub async fn run(
     client: ShardedClient,
     tokenizer: Option<Tokenizer>,
     validation_workers: usize,
+    sampler_order: Vec<SamplerFilter>,
     addr: SocketAddr,
     allow_origin: Option<AllowOrigin>,
     ngrok: bool,
     ngrok_authtoken: Option<String>,
     ngrok_edge: Option<String>,
+    mut compat_config: CompatConfig,
 ) -> Result<(), axum::BoxError> {
     // OpenAPI documentation
     #[derive(OpenApi)]
@@ -808,11 +1742,13 @@ pub async fn run(
     paths(
     health,
     get_model_info,
+    get_models,
     compat_generate,
     generate,
     generate_stream,
     completions_generate,
     chatcompletions_generate,
+    completions_parameters,
     metrics,
     ),
     components(
@@ -821,6 +1757,7 @@ pub async fn run(
     CompatGenerateRequest,
     GenerateRequest,
     GenerateParameters,
+    TruncationSide,
     PrefillToken,
     Token,
     GenerateResponse,
@@ -832,16 +1769,41 @@ pub async fn run(
     ErrorResponse,
     // completions messages
     CompatCompletionRequest,
+    ExtraBody,
     CompatChatCompletionRequest,
     ChatMessage,
+    ChatMessageContent,
+    ChatContentPart,
     ChatRole,
     CompletionsResponse,
     Usage,
     CompletionChoices,
+    CompletionLogprobs,
+    ModelsResponse,
+    ModelCard,
+    ModelCapabilities,
     ChatCompletionsResponse,
     ChatCompletionChoices,
+    ChatCompletionLogprobs,
+    ChatCompletionLogprob,
+    ChatCompletionTopLogprob,
     ChatCompletionsStreamResponse,
+    ChatCompletionsListResponse,
     ChatDeltaStreamMessage,    ChatCompletionDeltaStreamChoices,
+    PromptTokensDetails,
+    ResponseFormat,
+    ResponseFormatEnforcement,
+    ServerFinishReason,
+    OpenaiFinishReason,
+    CompatErrorResponse,
+    CompatErrorDetails,
+    FinishDetails,
+    ServiceTier,
+    Timings,
+    ParametersPreviewRequest,
+    ParametersPreviewResponse,
+    ParametersPreview,
+    StreamGranularity,
     )
     ),
     tags(
@@ -857,7 +1819,27 @@ pub async fn run(
     )]
     struct ApiDoc;
 
+    // The templates are built and hashed once rather than for every request
+    compat_config.chat_formatter = Arc::new(compat_config.build_chat_formatter());
+    compat_config.chat_template_hash = compat_config
+        .chat_template_header
+        .then(|| compat_config.chat_formatter.hash());
+    compat_config.prompt_token_cache =
+        Arc::new(PromptTokenCache::new(compat_config.prompt_token_cache_size));
     // Create state
+    let stream_limiter = StreamLimiter::new(
+        compat_config.max_streams_per_client,
+        compat_config.verified_api_keys,
+    );
+    // The prompts are counted by the tokenization workers of the validation
+    if compat_config.max_conversation_tokens.is_some() && tokenizer.is_none() {
+        tracing::warn!(
+            "`--max-conversation-tokens` requires the tokenizer, conversations are not evicted"
+        );
+    }
+    if compat_config.max_output_ratio.is_some() && tokenizer.is_none() {
+        tracing::warn!("`--max-output-ratio` requires the tokenizer, `max_tokens` is not guarded");
+    }
     let validation = Validation::new(
         validation_workers,
         tokenizer,
@@ -865,9 +1847,12 @@ pub async fn run(
         max_stop_sequences,
         max_input_length,
         max_total_tokens,
+        &sampler_order,
     );
     let generation_health = Arc::new(AtomicBool::new(false));
-    let health_ext = Health::new(client.clone(), generation_health.clone());
+    // Without a warmup prompt, the server is ready as soon as it starts
+    let ready = Arc::new(AtomicBool::new(compat_config.warmup_prompt.is_none()));
+    let health_ext = Health::new(client.clone(), generation_health.clone(), ready.clone());
     let infer = Infer::new(
         client,
         validation,
@@ -878,6 +1863,7 @@ pub async fn run(
         max_concurrent_requests,
         shard_info.requires_padding,
         generation_health,
+        compat_config.flex_timeout,
     );
 
     // Duration buckets
@@ -952,8 +1938,23 @@ pub async fn run(
         version: env!("CARGO_PKG_VERSION"),
         sha: option_env!("VERGEN_GIT_SHA"),
         docker_label: option_env!("DOCKER_LABEL"),
+        chat_template: compat_config
+            .chat_template
+            .as_ref()
+            .map(|template| template.source().to_string()),
     };
 
+    if let Some(prompt) = compat_config.warmup_prompt.clone() {
+        tokio::spawn(warmup(
+            Extension(info.clone()),
+            Extension(warmup_config(&compat_config)),
+            Extension(stream_limiter.clone()),
+            Extension(infer.clone()),
+            prompt,
+            ready,
+        ));
+    }
+
     // Create router
     let app = Router::new()
         .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", ApiDoc::openapi()))
@@ -962,8 +1963,16 @@ pub async fn run(
         .route("/info", get(get_model_info))
         .route("/generate", post(generate))
         .route("/generate_stream", post(generate_stream))
-        .route("/completions", post(completions_generate))
-        .route("/chat/completions", post(chatcompletions_generate))
+        .route(
+            "/completions",
+            post(completions_generate).layer(map_response(ratelimit_headers)),
+        )
+        .route(
+            "/chat/completions",
+            post(chatcompletions_generate).layer(map_response(ratelimit_headers)),
+        )
+        .route("/completions/parameters", post(completions_parameters))
+        .route("/models", get(get_models))
         // AWS Sagemaker route
         .route("/invocations", post(compat_generate))
         // Base Health route
@@ -977,6 +1986,8 @@ pub async fn run(
         .layer(Extension(info))
         .layer(Extension(health_ext.clone()))
         .layer(Extension(compat_return_full_text))
+        .layer(Extension(compat_config))
+        .layer(Extension(stream_limiter))
         .layer(Extension(infer))
         .layer(Extension(prom_handle.clone()))
         .layer(opentelemetry_tracing_layer())
@@ -1037,8 +2048,9 @@ pub async fn run(
         }
     } else {
         // Run server
+        // The connection info identifies the clients without API keys
         axum::Server::bind(&addr)
-            .serve(app.into_make_service())
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             // Wait until all requests are finished to shut down
             .with_graceful_shutdown(shutdown_signal())
             .await?;
@@ -1046,6 +2058,62 @@ pub async fn run(
     Ok(())
 }
 
+/// Interval between the attempts of a failed warmup
+const WARMUP_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Configuration of the warmup completion, which is not guarded by the output ratio: the
+/// `max_tokens` of the warmup usually exceeds the ratio of its short prompt, and the rejected
+/// warmup would be retried forever
+fn warmup_config(compat_config: &CompatConfig) -> CompatConfig {
+    CompatConfig {
+        max_output_ratio: None,
+        ..compat_config.clone()
+    }
+}
+
+/// Generate a completion of the warmup prompt through the `/completions` route, so that the first
+/// requests do not pay the cold start, and only mark the server as ready once it succeeded.
+/// Failed warmups are retried
+async fn warmup(
+    info: Extension<Info>,
+    compat_config: Extension<CompatConfig>,
+    stream_limiter: Extension<StreamLimiter>,
+    infer: Extension<Infer>,
+    prompt: String,
+    ready: Arc<AtomicBool>,
+) {
+    let body = serde_json::json!({
+        "prompt": prompt,
+        "max_tokens": compat_config.warmup_max_tokens,
+    });
+    loop {
+        let start_time = Instant::now();
+        let req = serde_json::from_value(body.clone()).expect("invalid warmup request");
+        let response = completions_generate(
+            info.clone(),
+            compat_config.clone(),
+            stream_limiter.clone(),
+            infer.clone(),
+            None,
+            HeaderMap::new(),
+            CompatJson(req),
+        )
+        .await;
+        match response {
+            Ok(response) if response.status().is_success() => {
+                tracing::info!("Warmup completed in {:?}", start_time.elapsed());
+                ready.store(true, Ordering::SeqCst);
+                return;
+            }
+            Ok(response) => tracing::error!("Warmup failed with status {}", response.status()),
+            Err(CompatError(status, err)) => {
+                tracing::error!("Warmup failed with status {status}: {}", err.error.message)
+            }
+        }
+        tokio::time::sleep(WARMUP_RETRY_INTERVAL).await;
+    }
+}
+
 /// Shutdown signal handler
 async fn shutdown_signal() {
     let ctrl_c = async {
@@ -1100,6 +2168,7 @@ impl From<InferError> for (StatusCode, Json<ErrorResponse>) {
             Json(ErrorResponse {
                 error: err.to_string(),
                 error_type: err.error_type().to_string(),
+                param: err.param().map(String::from),
             }),
         )
     }
@@ -1111,7 +2180,500 @@ impl From<InferError> for Event {
             .json_data(ErrorResponse {
                 error: err.to_string(),
                 error_type: err.error_type().to_string(),
+                param: err.param().map(String::from),
             })
             .unwrap()
     }
 }
+
+/// Name of a generation parameter in the OpenAI compatible requests
+fn compat_param(param: &str, stream_type: &OpenaiStreamType) -> String {
+    match (param, stream_type) {
+        ("max_new_tokens", _) => "max_tokens",
+        ("inputs", OpenaiStreamType::CompletionsResponse) => "prompt",
+        ("inputs", OpenaiStreamType::ChatCompletionsStreamResponse) => "messages",
+        (param, _) => param,
+    }
+    .to_string()
+}
+
+/// Name the parameter responsible for an error as in the OpenAI compatible requests
+fn compat_error(
+    (status_code, Json(mut err)): (StatusCode, Json<ErrorResponse>),
+    stream_type: &OpenaiStreamType,
+) -> (StatusCode, Json<ErrorResponse>) {
+    err.param = err.param.map(|param| compat_param(&param, stream_type));
+    (status_code, Json(err))
+}
+
+fn compat_error_event(err: InferError, stream_type: &OpenaiStreamType) -> Event {
+    let CompatError(_, Json(err)) = compat_error(err.into(), stream_type).into();
+    Event::default().json_data(err).unwrap()
+}
+
+/// Model name of an OpenAI compatible response, rejecting unknown models with a 400
+fn served_model(
+    info: &Info,
+    compat_config: &CompatConfig,
+    model: Option<&str>,
+) -> Result<String, (StatusCode, Json<ErrorResponse>)> {
+    compat_config
+        .served_model(&info.model_id, model)
+        .ok_or_else(|| {
+            metrics::increment_counter!("tgi_request_failure", "err" => "model_not_found");
+            let err = ErrorResponse {
+                error: format!("The model `{}` does not exist", model.unwrap_or_default()),
+                error_type: "model_not_found".to_string(),
+                param: Some("model".to_string()),
+            };
+            tracing::error!("{}", err.error);
+            (StatusCode::BAD_REQUEST, Json(err))
+        })
+}
+
+/// Reject the requests selecting a LoRA adapter. The shards only serve the base model, so that
+/// every adapter is unknown, and generating with the base model instead would mislead the client
+fn validate_adapter(adapter_id: Option<&str>) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    match adapter_id {
+        None => Ok(()),
+        Some(adapter_id) => {
+            metrics::increment_counter!("tgi_request_failure", "err" => "adapter_not_found");
+            let err = ErrorResponse {
+                error: format!("The adapter `{adapter_id}` does not exist"),
+                error_type: "adapter_not_found".to_string(),
+                param: Some("adapter_id".to_string()),
+            };
+            tracing::error!("{}", err.error);
+            Err((StatusCode::BAD_REQUEST, Json(err)))
+        }
+    }
+}
+
+/// Rejection of a prompt that is empty or only contains whitespace, from which the model would
+/// generate garbage
+fn blank_prompt_error(param: &str) -> (StatusCode, Json<ErrorResponse>) {
+    metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+    let err = ErrorResponse {
+        error: "prompt cannot be empty".to_string(),
+        error_type: "validation".to_string(),
+        param: Some(param.to_string()),
+    };
+    tracing::error!("{}", err.error);
+    (StatusCode::BAD_REQUEST, Json(err))
+}
+
+/// Reject oversized request `metadata`
+fn validate_compat_metadata(
+    metadata: &HashMap<String, String>,
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    validate_metadata(metadata).map_err(|error| {
+        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        tracing::error!("{error}");
+        let err = ErrorResponse {
+            error,
+            error_type: "validation".to_string(),
+            param: Some("metadata".to_string()),
+        };
+        (StatusCode::BAD_REQUEST, Json(err))
+    })
+}
+
+/// Whether to stream the response of a request with the `stream` body flag.
+/// Streamed requests whose `Accept` header does not allow Server-Sent Events, which the client
+/// would not parse, are rejected, unless `buffer_unaccepted_streams` answers them like
+/// non-streamed requests
+fn compat_response_stream(
+    headers: &HeaderMap,
+    stream: bool,
+    compat_config: &CompatConfig,
+) -> Result<bool, (StatusCode, Json<ErrorResponse>)> {
+    match compat_response_type(headers, stream, compat_config.buffer_unaccepted_streams) {
+        CompatResponseType::Json => Ok(false),
+        CompatResponseType::EventStream => Ok(true),
+        CompatResponseType::Buffered => {
+            tracing::warn!(
+                "`stream` is true but the `Accept` header does not allow `text/event-stream`, returning a JSON response"
+            );
+            Ok(false)
+        }
+        CompatResponseType::NotAcceptable => {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            let err = ErrorResponse {
+                error:
+                    "`stream` is true but the `Accept` header does not allow `text/event-stream`"
+                        .to_string(),
+                error_type: "validation".to_string(),
+                param: Some("stream".to_string()),
+            };
+            tracing::error!("{}", err.error);
+            Err((StatusCode::BAD_REQUEST, Json(err)))
+        }
+    }
+}
+
+/// Register a new stream of the client of a request, identified by its verified API key, or else
+/// by its IP address. Clients with too many active streams are rejected with a 429
+fn acquire_stream(
+    stream_limiter: &StreamLimiter,
+    headers: &HeaderMap,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+) -> Result<Option<StreamGuard>, (StatusCode, Json<ErrorResponse>)> {
+    let api_key = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Bearer "));
+    let ip = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    let client = match stream_limiter.client(api_key, ip) {
+        Some(client) => client,
+        // Clients cannot be told apart, e.g. behind ngrok without verified API keys
+        None => return Ok(None),
+    };
+    stream_limiter.acquire(&client).map_err(|max_streams| {
+        metrics::increment_counter!("tgi_request_failure", "err" => "stream_limit");
+        let err = ErrorResponse {
+            error: format!("too many concurrent streams, the limit is {max_streams} per client"),
+            error_type: "stream_limit".to_string(),
+            param: Some("stream".to_string()),
+        };
+        tracing::error!("{}", err.error);
+        (StatusCode::TOO_MANY_REQUESTS, Json(err))
+    })
+}
+
+/// Generate the `n` choices of a request concurrently, so that they are batched together, each
+/// with its own seed. The headers are the ones of the first choice
+async fn generate_choices(
+    infer: Extension<Infer>,
+    req: GenerateRequest,
+    n: u32,
+) -> Result<(HeaderMap, Vec<Json<GenerateResponse>>), InferError> {
+    let generations = try_join_all(
+        choice_seeds(req.parameters.seed, n)
+            .into_iter()
+            .map(|seed| {
+                let mut req = req.clone();
+                req.parameters.seed = seed;
+                generate_response(infer.clone(), Json(req))
+            }),
+    )
+    .await?;
+    let mut headers = None;
+    let generations = generations
+        .into_iter()
+        .map(|(choice_headers, generation)| {
+            headers.get_or_insert(choice_headers);
+            generation
+        })
+        .collect();
+    Ok((headers.unwrap_or_default(), generations))
+}
+
+/// Reject the parameters that cannot be streamed before starting the stream, so that the
+/// client gets a proper error status
+fn validate_compat_stream(
+    parameters: &GenerateParameters,
+    stream_type: &OpenaiStreamType,
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    // The prefill is only streamed back when echoing the prompt tokens on the completions route
+    let echo_prompt_tokens = matches!(stream_type, OpenaiStreamType::CompletionsResponse)
+        && parameters.return_full_text.unwrap_or(false);
+    let err = if parameters.best_of.map_or(false, |best_of| best_of != 1) {
+        ValidationError::BestOfStream
+    } else if parameters.decoder_input_details && !echo_prompt_tokens {
+        ValidationError::PrefillDetailsStream
+    } else {
+        return Ok(());
+    };
+    Err(compat_validation_error(err, stream_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::Validation;
+    use axum::body::HttpBody;
+    use text_generation_client::{GeneratedText, PrefillTokens};
+
+    fn token(text: &str) -> Token {
+        Token {
+            id: 0,
+            text: text.to_string(),
+            logprob: -0.1,
+            special: false,
+        }
+    }
+
+    fn end(token: Token, generated_tokens: u32) -> InferStreamResponse {
+        let now = Instant::now();
+        InferStreamResponse::End {
+            token,
+            generated_text: GeneratedText {
+                text: String::new(),
+                generated_tokens,
+                finish_reason: text_generation_client::FinishReason::EosToken as i32,
+                seed: None,
+            },
+            start: now,
+            queued: now,
+            truncated_tokens: 0,
+        }
+    }
+
+    /// Data of the frames of the OpenAI stream of the responses, with a single prompt token
+    async fn stream_frames(
+        responses: Vec<InferStreamResponse>,
+        stream_type: OpenaiStreamType,
+        compat_config: CompatConfig,
+        options: CompatRequestOptions,
+        prompt: Option<String>,
+        echo_prompt_tokens: bool,
+    ) -> Vec<String> {
+        let start_time = Instant::now();
+        let events = openai_stream_events(
+            futures::stream::iter(responses.into_iter().map(Ok::<_, InferError>)),
+            stream_type,
+            "cmpl-0".to_string(),
+            compat_config.number(0),
+            "tgi".to_string(),
+            compat_config,
+            options,
+            vec![],
+            true,
+            prompt,
+            echo_prompt_tokens,
+            1,
+            start_time,
+            start_time,
+            tracing::Span::none(),
+        );
+        let mut body = Sse::new(events).into_response().into_body();
+        let mut sse = Vec::new();
+        while let Some(data) = body.data().await {
+            sse.extend_from_slice(&data.unwrap());
+        }
+        String::from_utf8(sse)
+            .unwrap()
+            .trim_end()
+            .split("\n\n")
+            .map(|frame| frame.strip_prefix("data:").unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_stream_done_sentinel() {
+        for stream_type in [
+            OpenaiStreamType::CompletionsResponse,
+            OpenaiStreamType::ChatCompletionsStreamResponse,
+        ] {
+            let responses = vec![InferStreamResponse::Token(token("Hi")), end(token("!"), 2)];
+            let frames = stream_frames(
+                responses,
+                stream_type,
+                Default::default(),
+                Default::default(),
+                None,
+                false,
+            )
+            .await;
+
+            // The final frame is the sentinel, right after the last chunk
+            assert_eq!(frames.last().unwrap(), "[DONE]");
+            let last_chunk: serde_json::Value =
+                serde_json::from_str(&frames[frames.len() - 2]).unwrap();
+            assert_eq!(last_chunk["choices"][0]["finish_reason"], "stop");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streamed_completion_without_prompt() {
+        let prefill = PrefillTokens {
+            ids: vec![1, 2],
+            logprobs: vec![f32::NAN, -0.5],
+            texts: vec!["Say".to_string(), " hi".to_string()],
+        };
+        let stream = |prompt: Option<&str>, echo_prompt_tokens: bool| {
+            let mut responses = vec![];
+            if echo_prompt_tokens {
+                responses.push(InferStreamResponse::Prefill(prefill.clone()));
+            }
+            responses.push(InferStreamResponse::Token(token(":")));
+            responses.push(InferStreamResponse::Token(token(" Hi")));
+            responses.push(end(token(" there"), 3));
+            let prompt = prompt.map(String::from);
+            async move {
+                let frames = stream_frames(
+                    responses,
+                    OpenaiStreamType::CompletionsResponse,
+                    Default::default(),
+                    Default::default(),
+                    prompt,
+                    echo_prompt_tokens,
+                )
+                .await;
+                frames[..frames.len() - 1]
+                    .iter()
+                    .map(|frame| {
+                        let chunk: serde_json::Value = serde_json::from_str(frame).unwrap();
+                        chunk["choices"][0]["text"].as_str().unwrap().to_string()
+                    })
+                    .collect::<String>()
+            }
+        };
+
+        // Each chunk only has the text of a generated token
+        let text = stream(None, false).await;
+        assert_eq!(text, ": Hi there");
+        assert!(!text.contains("Say hi"));
+        // The prompt is only streamed back with `echo`
+        assert_eq!(stream(Some("Say hi"), false).await, "Say hi: Hi there");
+        assert_eq!(stream(Some("Say hi"), true).await, "Say hi: Hi there");
+    }
+
+    #[tokio::test]
+    async fn test_stream_usage_chunk() {
+        let options = CompatRequestOptions {
+            include_usage: true,
+            ..Default::default()
+        };
+        for stream_type in [
+            OpenaiStreamType::CompletionsResponse,
+            OpenaiStreamType::ChatCompletionsStreamResponse,
+        ] {
+            let responses = vec![
+                InferStreamResponse::Token(token(" world")),
+                end(token("!"), 2),
+            ];
+            let frames = stream_frames(
+                responses,
+                stream_type,
+                Default::default(),
+                options,
+                None,
+                false,
+            )
+            .await;
+            assert_eq!(frames.last().unwrap(), "[DONE]");
+
+            // Token chunks carry a null usage
+            for frame in &frames[..frames.len() - 2] {
+                let chunk: serde_json::Value = serde_json::from_str(frame).unwrap();
+                assert!(chunk["usage"].is_null());
+                assert_eq!(chunk["choices"].as_array().unwrap().len(), 1);
+            }
+
+            // The usage is sent in its own chunk, right before `[DONE]`
+            let penultimate: serde_json::Value =
+                serde_json::from_str(&frames[frames.len() - 2]).unwrap();
+            assert_eq!(penultimate["id"], "cmpl-0");
+            assert_eq!(penultimate["choices"], serde_json::json!([]));
+            assert_eq!(penultimate["usage"]["prompt_tokens"], 1);
+            assert_eq!(penultimate["usage"]["completion_tokens"], 2);
+            assert_eq!(penultimate["usage"]["total_tokens"], 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_numbers_as_strings() {
+        let compat_config = CompatConfig {
+            numbers_as_strings: true,
+            ..Default::default()
+        };
+        let options = CompatRequestOptions {
+            include_usage: true,
+            ..Default::default()
+        };
+        for stream_type in [
+            OpenaiStreamType::CompletionsResponse,
+            OpenaiStreamType::ChatCompletionsStreamResponse,
+        ] {
+            let responses = vec![
+                InferStreamResponse::Token(token(" world")),
+                end(token("!"), 2),
+            ];
+            let frames = stream_frames(
+                responses,
+                stream_type,
+                compat_config.clone(),
+                options,
+                None,
+                false,
+            )
+            .await;
+
+            // Every chunk has a string `created`
+            for frame in &frames[..frames.len() - 1] {
+                let chunk: serde_json::Value = serde_json::from_str(frame).unwrap();
+                assert_eq!(chunk["created"], "0");
+            }
+            let usage: serde_json::Value = serde_json::from_str(&frames[frames.len() - 2]).unwrap();
+            assert_eq!(usage["usage"]["prompt_tokens"], "1");
+            assert_eq!(usage["usage"]["completion_tokens"], "2");
+            assert_eq!(usage["usage"]["total_tokens"], "3");
+        }
+    }
+
+    #[test]
+    fn test_warmup_output_ratio() {
+        let compat_config = CompatConfig {
+            max_output_ratio: Some(4),
+            warmup_prompt: Some("Hello".to_string()),
+            warmup_max_tokens: 16,
+            ..Default::default()
+        };
+        let guard = |compat_config: &CompatConfig| {
+            let body = serde_json::json!({
+                "prompt": compat_config.warmup_prompt,
+                "max_tokens": compat_config.warmup_max_tokens,
+            });
+            let req: CompatCompletionRequest = serde_json::from_value(body).unwrap();
+            // The warmup prompt is a single token
+            let mut options = CompatRequestOptions {
+                prompt_tokens: Some(1),
+                ..req.options()
+            };
+            let mut parameters = GenerateRequest::from(req).parameters;
+            compat_config.guard_output_ratio(&mut parameters, &mut options)
+        };
+        assert!(matches!(
+            guard(&compat_config),
+            Err(ValidationError::OutputRatio(4, 1, 16))
+        ));
+        // The warmup is not guarded, so that the server becomes healthy
+        assert!(guard(&warmup_config(&compat_config)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_compat_validation_status() {
+        // Invalid parameters are client errors, as with OpenAI
+        let validation = Validation::new(1, None, 2, 3, 4, 5, &[]);
+        let body = r#"{"prompt": "Hi", "temperature": -1}"#;
+        let req: CompatCompletionRequest = serde_json::from_str(body).unwrap();
+        let mut req: GenerateRequest = req.into();
+        let compat_config = CompatConfig::default();
+        compat_config
+            .chat_config
+            .convert_temperature(&mut req.parameters, None);
+        let err = validation.validate(req).await.unwrap_err();
+        let (status_code, Json(err)) =
+            compat_infer_error(err.into(), &OpenaiStreamType::CompletionsResponse);
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        assert_eq!(err.param.as_deref(), Some("temperature"));
+
+        // Only the prompts that cannot be generated are unprocessable
+        for err in [
+            ValidationError::InputLength(4, 5),
+            ValidationError::MaxTotalTokens(5, 4, 2),
+            ValidationError::EmptyInput,
+            ValidationError::Tokenizer("error".to_string()),
+        ] {
+            let (status_code, _) =
+                compat_validation_error(err, &OpenaiStreamType::ChatCompletionsStreamResponse);
+            assert_eq!(status_code, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        let (status_code, _) = compat_validation_error(
+            ValidationError::TopK,
+            &OpenaiStreamType::CompletionsResponse,
+        );
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+    }
+}
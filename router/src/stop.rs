@@ -0,0 +1,292 @@
+//! Stop sequences handling of the OpenAI compatible routes
+//!
+//! The backend stops generating once the output ends with a stop sequence, and the stop
+//! sequence is part of the generated text.
+use crate::{FinishReason, GenerateResponse};
+
+/// Remove the stop sequence ending `text`, if any, and everything after it.
+/// The output is cut at the earliest match of all the stop sequences: the last token can complete
+/// several of them, e.g. `"##\n\n"` with both `"##"` and `"\n\n"`, and the backend only
+/// reports the one ending the output
+pub(crate) fn strip_stop_sequence<'a>(text: &'a str, stop: &[String]) -> &'a str {
+    match earliest_stop_sequence(text, stop) {
+        Some((offset, _)) => &text[..offset],
+        None => text,
+    }
+}
+
+/// Earliest stop sequence of `text` and its offset, which is where `strip_stop_sequence` cuts.
+/// The longest one when several start there
+pub(crate) fn earliest_stop_sequence<'a>(
+    text: &str,
+    stop: &'a [String],
+) -> Option<(usize, &'a str)> {
+    stop.iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| Some((text.find(stop.as_str())?, stop.as_str())))
+        .min_by_key(|(offset, stop)| (*offset, std::cmp::Reverse(stop.len())))
+}
+
+/// Stop sequence a response stopped by one is cut at, and its offset in the generated text.
+/// The cut is the same as streaming the tokens through a `StopSequenceBuffer`: the text before
+/// the last token has been released, but for its end that could start a stop sequence, so a stop
+/// sequence completed within an earlier token does not cut the output
+pub(crate) fn response_stop_sequence<'a>(
+    response: &GenerateResponse,
+    stop: &'a [String],
+) -> Option<(usize, &'a str)> {
+    let details = response.details.as_ref()?;
+    if !matches!(details.finish_reason, FinishReason::StopSequence) {
+        return None;
+    }
+    let text = &response.generated_text;
+    let last = details
+        .tokens
+        .last()
+        .map_or("", |token| token.text.as_str());
+    let released = text.strip_suffix(last).map_or(0, |streamed| {
+        let mut buffer = StopSequenceBuffer::new(stop.to_vec(), false);
+        buffer.push(streamed).len()
+    });
+    let (offset, stop) = earliest_stop_sequence(&text[released..], stop)?;
+    Some((released + offset, stop))
+}
+
+/// Remove the stop sequence a response stopped by one is cut at, and everything after it
+pub(crate) fn strip_response_stop_sequence(response: &mut GenerateResponse, stop: &[String]) {
+    if let Some((offset, _)) = response_stop_sequence(response, stop) {
+        response.generated_text.truncate(offset);
+    }
+}
+
+/// Holds back the streamed text that could be the start of a stop sequence, so that a stop
+/// sequence spread over several tokens is never sent to the client.
+/// The held back text is never longer than the longest stop sequence.
+#[derive(Debug)]
+pub(crate) struct StopSequenceBuffer {
+    stop: Vec<String>,
+    max_stop_len: usize,
+    include_stop_sequence: bool,
+    buffer: String,
+}
+
+impl StopSequenceBuffer {
+    pub(crate) fn new(stop: Vec<String>, include_stop_sequence: bool) -> Self {
+        let stop: Vec<String> = stop.into_iter().filter(|stop| !stop.is_empty()).collect();
+        let max_stop_len = stop.iter().map(String::len).max().unwrap_or(0);
+        Self {
+            stop,
+            max_stop_len,
+            include_stop_sequence,
+            buffer: String::new(),
+        }
+    }
+
+    /// Add the text of a generated token and return the text that can be sent
+    pub(crate) fn push(&mut self, text: &str) -> String {
+        if self.include_stop_sequence || self.stop.is_empty() {
+            return text.to_string();
+        }
+        self.buffer.push_str(text);
+
+        // Earliest position from which the buffer could still become a stop sequence.
+        // Only the last `max_stop_len` bytes can be part of a stop sequence
+        let lookback = self.buffer.len().saturating_sub(self.max_stop_len);
+        let held_back = self
+            .buffer
+            .char_indices()
+            .map(|(i, _)| i)
+            .skip_while(|&i| i < lookback)
+            .find(|&i| {
+                let tail = &self.buffer[i..];
+                self.stop.iter().any(|stop| stop.starts_with(tail))
+            })
+            .unwrap_or(self.buffer.len());
+
+        let tail = self.buffer.split_off(held_back);
+        std::mem::replace(&mut self.buffer, tail)
+    }
+
+    /// Add the text of the last generated token and return the remaining text. Unlike `push`,
+    /// the text is not released before the stop sequences are stripped, so that a stop sequence
+    /// completed before the one ending the output is never sent either
+    pub(crate) fn push_last(&mut self, text: &str, stop_sequence_reached: bool) -> String {
+        if self.include_stop_sequence || self.stop.is_empty() {
+            return text.to_string();
+        }
+        self.buffer.push_str(text);
+        self.finish(stop_sequence_reached)
+    }
+
+    /// Return the remaining text once the generation is over
+    pub(crate) fn finish(&mut self, stop_sequence_reached: bool) -> String {
+        let buffer = std::mem::take(&mut self.buffer);
+        if stop_sequence_reached && !self.include_stop_sequence {
+            strip_stop_sequence(&buffer, &self.stop).to_string()
+        } else {
+            buffer
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Details, Token};
+
+    fn stream(buffer: &mut StopSequenceBuffer, tokens: &[&str]) -> Vec<String> {
+        tokens.iter().map(|token| buffer.push(token)).collect()
+    }
+
+    #[test]
+    fn test_strip_stop_sequence() {
+        let stop = vec!["###".to_string(), "\nUser:".to_string()];
+        assert_eq!(strip_stop_sequence("Hello\nUser:", &stop), "Hello");
+        assert_eq!(strip_stop_sequence("Hello###", &stop), "Hello");
+        assert_eq!(strip_stop_sequence("Hello", &stop), "Hello");
+        assert_eq!(strip_stop_sequence("Hello", &["".to_string()]), "Hello");
+    }
+
+    #[test]
+    fn test_reported_stop_sequence() {
+        // The reported stop sequence is the one the output is cut at, not the one ending it
+        let stop = vec!["\n\n".to_string(), "##".to_string()];
+        assert_eq!(
+            earliest_stop_sequence("Answer: 42##\n\n", &stop),
+            Some((10, "##"))
+        );
+        assert_eq!(earliest_stop_sequence("Answer: 42", &stop), None);
+        let stop = vec!["b".to_string(), "ab".to_string()];
+        assert_eq!(earliest_stop_sequence("xab", &stop), Some((1, "ab")));
+        let stop = vec!["#".to_string(), "##".to_string()];
+        assert_eq!(earliest_stop_sequence("x##", &stop), Some((1, "##")));
+    }
+
+    #[test]
+    fn test_earliest_stop_sequence() {
+        // The backend stopped on `\n\n`, but `##` matched earlier in the same token
+        let stop = vec!["\n\n".to_string(), "##".to_string()];
+        assert_eq!(strip_stop_sequence("Answer: 42##\n\n", &stop), "Answer: 42");
+        // Overlapping stop sequences ending the output
+        let stop = vec!["b".to_string(), "ab".to_string()];
+        assert_eq!(strip_stop_sequence("xab", &stop), "x");
+
+        let stop = vec!["\n\n".to_string(), "##".to_string()];
+        let mut buffer = StopSequenceBuffer::new(stop, false);
+        assert_eq!(buffer.push("Answer: 42"), "Answer: 42");
+        assert_eq!(buffer.push_last("##\n\n", true), "");
+    }
+
+    #[test]
+    fn test_stream_and_response_cut() {
+        let response = |tokens: &[&str]| GenerateResponse {
+            generated_text: tokens.concat(),
+            details: Some(Details {
+                finish_reason: FinishReason::StopSequence,
+                generated_tokens: tokens.len() as u32,
+                seed: None,
+                prefill: vec![],
+                tokens: tokens
+                    .iter()
+                    .map(|text| Token {
+                        id: 0,
+                        text: text.to_string(),
+                        logprob: -0.5,
+                        special: false,
+                    })
+                    .collect(),
+                best_of_sequences: None,
+                prompt_tokens: None,
+                truncated_tokens: 0,
+                timings: None,
+            }),
+        };
+        let stop = vec!["##".to_string(), "\n\n".to_string()];
+        for tokens in [
+            // `##` is completed within a token that does not end with it, and is streamed
+            &["Answer", "##x", " more", "\n\n"][..],
+            // Both stop sequences are completed by the last token
+            &["Answer: 42", "##\n\n"],
+            // The stop sequence starts in the held back text
+            &["Answer: 4", "2#", "#"],
+        ] {
+            let mut buffer = StopSequenceBuffer::new(stop.clone(), false);
+            let (last, streamed) = tokens.split_last().unwrap();
+            let mut sent = stream(&mut buffer, streamed).concat();
+            sent.push_str(&buffer.push_last(last, true));
+
+            let mut response = response(tokens);
+            strip_response_stop_sequence(&mut response, &stop);
+            assert_eq!(response.generated_text, sent);
+        }
+        let mut response = response(&["Answer", "##x", " more", "\n\n"]);
+        assert_eq!(response_stop_sequence(&response, &stop), Some((14, "\n\n")));
+        strip_response_stop_sequence(&mut response, &stop);
+        assert_eq!(response.generated_text, "Answer##x more");
+    }
+
+    #[test]
+    fn test_multi_token_stop_sequence() {
+        let mut buffer = StopSequenceBuffer::new(vec!["\nUser:".to_string()], false);
+        let sent = stream(&mut buffer, &["Hi", " there", "\n", "User", ":"]);
+        assert_eq!(sent, vec!["Hi", " there", "", "", ""]);
+        assert_eq!(buffer.finish(true), "");
+    }
+
+    #[test]
+    fn test_stop_sequence_prefix_released() {
+        let mut buffer = StopSequenceBuffer::new(vec!["\nUser:".to_string()], false);
+        let sent = stream(&mut buffer, &["Hi", "\n", "Us", "ually"]);
+        assert_eq!(sent, vec!["Hi", "", "", "\nUsually"]);
+        assert_eq!(buffer.finish(false), "");
+    }
+
+    #[test]
+    fn test_stop_sequence_within_token() {
+        let mut buffer = StopSequenceBuffer::new(vec!["###".to_string()], false);
+        let sent = stream(&mut buffer, &["Answer: 42#", "##"]);
+        assert_eq!(sent, vec!["Answer: 42", ""]);
+        assert_eq!(buffer.finish(true), "");
+
+        // Generation stopped for another reason while holding back text
+        let mut buffer = StopSequenceBuffer::new(vec!["###".to_string()], false);
+        assert_eq!(buffer.push("Answer: 42#"), "Answer: 42");
+        assert_eq!(buffer.finish(false), "#");
+    }
+
+    #[test]
+    fn test_stop_sequence_across_final_chunks() {
+        let stop = vec!["</answer>".to_string(), "\n\n".to_string()];
+        let mut buffer = StopSequenceBuffer::new(stop, false);
+        // The stop sequence starts within the second to last chunk
+        let sent = stream(&mut buffer, &["The answer", " is 42</an", "swer>"]);
+        assert_eq!(sent, vec!["The answer", " is 42", ""]);
+        assert_eq!(buffer.finish(true), "");
+        assert!(!sent.concat().contains("</"));
+    }
+
+    #[test]
+    fn test_bounded_lookback() {
+        let mut buffer = StopSequenceBuffer::new(vec!["ab\nEND".to_string()], false);
+        let mut sent = String::new();
+        // Long generation always close to matching the stop sequence
+        for _ in 0..100_000 {
+            for token in ["ab", "\n", "EN"] {
+                sent.push_str(&buffer.push(token));
+                assert!(buffer.buffer.len() <= buffer.max_stop_len);
+            }
+        }
+        // The last repetition completes the stop sequence
+        sent.push_str(&buffer.push("D"));
+        sent.push_str(&buffer.finish(true));
+        assert!(sent == "ab\nEN".repeat(99_999));
+    }
+
+    #[test]
+    fn test_include_stop_sequence() {
+        let mut buffer = StopSequenceBuffer::new(vec!["\nUser:".to_string()], true);
+        let sent = stream(&mut buffer, &["Hi", "\n", "User", ":"]);
+        assert_eq!(sent, vec!["Hi", "\n", "User", ":"]);
+        assert_eq!(buffer.finish(true), "");
+    }
+}
@@ -0,0 +1,128 @@
+//! Limit of the concurrent streams of each client of the OpenAI compatible routes
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Active streams of each client. Streams are long-lived and pin resources as long as they
+/// are open, so that a single client could otherwise take the whole server
+#[derive(Clone, Debug)]
+pub(crate) struct StreamLimiter {
+    max_streams: Option<usize>,
+    /// The API keys are verified in front of the router, so that they identify the clients
+    verified_api_keys: bool,
+    active: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl StreamLimiter {
+    pub(crate) fn new(max_streams: Option<usize>, verified_api_keys: bool) -> Self {
+        Self {
+            max_streams,
+            verified_api_keys,
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Client of a stream: its API key when the keys are verified, hashed so that the keys are
+    /// not kept in memory, or else its IP address. Unverified keys are ignored, as a client
+    /// sending a new key with each request would escape the limit. `None` when clients cannot be
+    /// told apart
+    pub(crate) fn client(&self, api_key: Option<&str>, ip: Option<IpAddr>) -> Option<String> {
+        match (api_key, ip) {
+            (Some(api_key), _) if self.verified_api_keys => {
+                Some(format!("key:{:x}", Sha256::digest(api_key.as_bytes())))
+            }
+            (_, Some(ip)) => Some(format!("ip:{ip}")),
+            _ => None,
+        }
+    }
+
+    /// Register a new stream of `client`. `Err` with the limit when the client already has
+    /// as many active streams, `Ok(None)` when streams are not limited
+    pub(crate) fn acquire(&self, client: &str) -> Result<Option<StreamGuard>, usize> {
+        let max_streams = match self.max_streams {
+            Some(max_streams) => max_streams,
+            None => return Ok(None),
+        };
+        let mut active = self.active.lock().unwrap();
+        let streams = active.entry(client.to_string()).or_default();
+        if *streams >= max_streams {
+            return Err(max_streams);
+        }
+        *streams += 1;
+        Ok(Some(StreamGuard {
+            client: client.to_string(),
+            active: self.active.clone(),
+        }))
+    }
+}
+
+/// Active stream of a client, released when dropped: when the stream completes or when the
+/// client disconnects
+#[derive(Debug)]
+pub(crate) struct StreamGuard {
+    client: String,
+    active: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(streams) = active.get_mut(&self.client) {
+            *streams -= 1;
+            if *streams == 0 {
+                active.remove(&self.client);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_limit() {
+        let limiter = StreamLimiter::new(Some(2), false);
+        let first = limiter.acquire("key-a").unwrap();
+        let _second = limiter.acquire("key-a").unwrap();
+        assert_eq!(limiter.acquire("key-a").unwrap_err(), 2);
+
+        // Clients are limited independently
+        assert!(limiter.acquire("key-b").unwrap().is_some());
+
+        // A completed stream frees a slot
+        drop(first);
+        assert!(limiter.acquire("key-a").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_stream_client() {
+        let ip = Some(IpAddr::from([10, 0, 0, 1]));
+        // Unverified keys do not identify the clients
+        let limiter = StreamLimiter::new(Some(1), false);
+        assert_eq!(limiter.client(Some("sk-1"), ip).unwrap(), "ip:10.0.0.1");
+        assert_eq!(limiter.client(Some("sk-1"), None), None);
+
+        let limiter = StreamLimiter::new(Some(1), true);
+        let client = limiter.client(Some("sk-1"), ip).unwrap();
+        assert!(client.starts_with("key:") && !client.contains("sk-1"));
+        assert_eq!(limiter.client(Some("sk-1"), None).unwrap(), client);
+        assert_ne!(limiter.client(Some("sk-2"), ip).unwrap(), client);
+        assert_eq!(limiter.client(None, ip).unwrap(), "ip:10.0.0.1");
+    }
+
+    #[test]
+    fn test_stream_limit_released() {
+        let limiter = StreamLimiter::new(Some(1), false);
+        drop(limiter.acquire("key-a").unwrap());
+        assert!(limiter.active.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unlimited_streams() {
+        let limiter = StreamLimiter::new(None, false);
+        assert!(limiter.acquire("key-a").unwrap().is_none());
+        assert!(limiter.active.lock().unwrap().is_empty());
+    }
+}
@@ -1,8 +1,8 @@
 /// Payload validation logic
 use crate::validation::ValidationError::{BestOfSampling, BestOfSeed, EmptyInput};
-use crate::{GenerateParameters, GenerateRequest};
+use crate::{GenerateParameters, GenerateRequest, SamplerFilter, TruncationSide};
 use rand::{thread_rng, Rng};
-use text_generation_client::{NextTokenChooserParameters, StoppingCriteriaParameters};
+use text_generation_client::{LogitBias, NextTokenChooserParameters, StoppingCriteriaParameters};
 use thiserror::Error;
 use tokenizers::tokenizer::Tokenizer;
 use tokenizers::TruncationDirection;
@@ -17,6 +17,10 @@ pub struct Validation {
     max_stop_sequences: usize,
     max_input_length: usize,
     max_total_tokens: usize,
+    /// Order of the truncation samplers sent to the shards, which use their default when empty
+    sampler_order: Vec<i32>,
+    /// Vocabulary size of the tokenizer, bounding the token ids of `logit_bias`
+    vocab_size: Option<usize>,
     /// Channel to communicate with the background tokenization task
     sender: Option<flume::Sender<TokenizerRequest>>,
 }
@@ -29,7 +33,11 @@ impl Validation {
         max_stop_sequences: usize,
         max_input_length: usize,
         max_total_tokens: usize,
+        sampler_order: &[SamplerFilter],
     ) -> Self {
+        let vocab_size = tokenizer
+            .as_ref()
+            .map(|tokenizer| tokenizer.get_vocab_size(true));
         // If we have a fast tokenizer
         let sender = if let Some(tokenizer) = tokenizer {
             // Create channel
@@ -50,12 +58,26 @@ impl Validation {
             None
         };
 
+        let sampler_order = match sampler_order.is_empty() {
+            true => Vec::new(),
+            false => SamplerFilter::order(sampler_order)
+                .into_iter()
+                .map(|filter| match filter {
+                    SamplerFilter::TopK => text_generation_client::SamplerFilter::TopK,
+                    SamplerFilter::TopP => text_generation_client::SamplerFilter::TopP,
+                    SamplerFilter::TypicalP => text_generation_client::SamplerFilter::TypicalP,
+                } as i32)
+                .collect(),
+        };
+
         Self {
             max_best_of,
             sender,
             max_stop_sequences,
             max_input_length,
             max_total_tokens,
+            sampler_order,
+            vocab_size,
         }
     }
 
@@ -64,8 +86,9 @@ impl Validation {
         &self,
         inputs: String,
         truncate: Option<usize>,
+        truncation_side: TruncationSide,
         max_new_tokens: u32,
-    ) -> Result<(String, usize), ValidationError> {
+    ) -> Result<(String, usize, usize), ValidationError> {
         // If we have a fast tokenizer
         if let Some(sender) = &self.sender {
             // Create response channel
@@ -73,12 +96,16 @@ impl Validation {
             // Send request to the background validation task
             // Unwrap is safe here
             sender
-                .send(((inputs, truncate), response_sender, Span::current()))
+                .send((
+                    (inputs, truncate, truncation_side, true),
+                    response_sender,
+                    Span::current(),
+                ))
                 .unwrap();
 
             // Await on response channel
             // Unwrap is safe here
-            let (inputs, input_length) = response_receiver.await.unwrap()?;
+            let (inputs, input_length, truncated_tokens) = response_receiver.await.unwrap()?;
 
             // Get total tokens
             let total_tokens = input_length + max_new_tokens as usize;
@@ -101,12 +128,12 @@ impl Validation {
             }
 
             metrics::histogram!("tgi_request_input_length", input_length as f64);
-            Ok((inputs, input_length))
+            Ok((inputs, input_length, truncated_tokens))
         }
         // Return inputs without validation
         else {
             // In this case, we don't know the real length in tokens of the inputs
-            // However, the inputs will be truncated by the python servers
+            // However, the inputs will be truncated by the python servers, always on the left
             // We make sure that truncate + max_new_tokens <= self.max_total_tokens
             let input_length = truncate.unwrap_or(self.max_input_length);
 
@@ -118,20 +145,47 @@ impl Validation {
                 ));
             }
 
-            Ok((inputs, input_length))
+            // The number of tokens dropped by the python servers is unknown
+            Ok((inputs, input_length, 0))
         }
     }
 
+    /// Number of tokens of `inputs`, counted by the tokenization workers without truncation,
+    /// with the special tokens the tokenizer adds to a prompt if `add_special_tokens`.
+    /// `None` without a fast tokenizer or if the inputs cannot be tokenized
+    #[instrument(skip_all)]
+    pub(crate) async fn count_tokens(
+        &self,
+        inputs: String,
+        add_special_tokens: bool,
+    ) -> Option<u32> {
+        let sender = self.sender.as_ref()?;
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Unwrap is safe here
+        sender
+            .send((
+                (inputs, None, TruncationSide::Left, add_special_tokens),
+                response_sender,
+                Span::current(),
+            ))
+            .unwrap();
+        let (_, input_length, _) = response_receiver.await.unwrap().ok()?;
+        Some(input_length as u32)
+    }
+
     /// Validate a payload and get the number of tokens in the input
     #[instrument(skip_all)]
     pub(crate) async fn validate(
         &self,
         request: GenerateRequest,
     ) -> Result<ValidGenerateRequest, ValidationError> {
+        let sampling = request.parameters.sampling();
         let GenerateParameters {
             best_of,
             temperature,
             repetition_penalty,
+            presence_penalty,
+            frequency_penalty,
             top_k,
             top_p,
             typical_p,
@@ -139,20 +193,19 @@ impl Validation {
             max_new_tokens,
             stop: stop_sequences,
             truncate,
+            truncation_side,
             seed,
             watermark,
+            logit_bias,
+            logit_bias_first_token_only,
             decoder_input_details,
+            low_priority,
+            allow_empty_inputs,
             ..
         } = request.parameters;
 
         // sampling must be true when best_of > 1
         let best_of = best_of.unwrap_or(1);
-        let sampling = do_sample
-            || temperature.is_some()
-            || top_k.is_some()
-            || top_p.is_some()
-            || typical_p.is_some();
-
         if best_of > 1 && !sampling {
             return Err(BestOfSampling);
         }
@@ -167,6 +220,16 @@ impl Validation {
             return Err(ValidationError::RepetitionPenalty);
         }
 
+        let presence_penalty = presence_penalty.unwrap_or(0.0);
+        if !(-2.0..=2.0).contains(&presence_penalty) {
+            return Err(ValidationError::PresencePenalty);
+        }
+
+        let frequency_penalty = frequency_penalty.unwrap_or(0.0);
+        if !(-2.0..=2.0).contains(&frequency_penalty) {
+            return Err(ValidationError::FrequencyPenalty);
+        }
+
         // Different because the proto default value is not a valid value
         // for the user
         let top_p = top_p
@@ -200,6 +263,24 @@ impl Validation {
             return Err(ValidationError::NegativeMaxNewTokens);
         }
 
+        let logit_bias = logit_bias
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(token_id, bias)| {
+                if !(-100.0..=100.0).contains(&bias) {
+                    return Err(ValidationError::LogitBias(token_id, bias));
+                }
+                // Out of vocabulary ids would fail the logit bias of the whole batch in the shards
+                match self.vocab_size {
+                    Some(vocab_size) if token_id as usize >= vocab_size => {
+                        return Err(ValidationError::LogitBiasToken(token_id, vocab_size));
+                    }
+                    _ => {}
+                }
+                Ok(LogitBias { token_id, bias })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
         if stop_sequences.len() > self.max_stop_sequences {
             return Err(ValidationError::StopSequence(
                 self.max_stop_sequences,
@@ -218,8 +299,9 @@ impl Validation {
             }
         };
 
-        // Check if inputs is empty
-        if request.inputs.is_empty() {
+        // Check if inputs is empty. Allowed empty inputs still need the tokenizer to add special
+        // tokens to generate from
+        if request.inputs.is_empty() && !(allow_empty_inputs && self.sender.is_some()) {
             return Err(EmptyInput);
         }
 
@@ -234,9 +316,18 @@ impl Validation {
             .unwrap_or(Ok(None))?;
 
         // Validate inputs
-        let (inputs, input_length) = self
-            .validate_input(request.inputs, truncate, max_new_tokens)
+        let (inputs, input_length, truncated_tokens) = self
+            .validate_input(request.inputs, truncate, truncation_side, max_new_tokens)
             .await?;
+        if input_length == 0 {
+            return Err(EmptyInput);
+        }
+        // The shards truncate the inputs on the left. Inputs truncated on the right by the
+        // router must not be truncated again
+        let truncate = match truncation_side {
+            TruncationSide::Right if self.sender.is_some() => None,
+            _ => truncate,
+        };
 
         let parameters = NextTokenChooserParameters {
             temperature,
@@ -247,6 +338,11 @@ impl Validation {
             do_sample,
             seed,
             watermark,
+            logit_bias,
+            logit_bias_first_token_only,
+            sampler_order: self.sampler_order.clone(),
+            presence_penalty,
+            frequency_penalty,
         };
         let stopping_parameters = StoppingCriteriaParameters {
             max_new_tokens,
@@ -260,7 +356,10 @@ impl Validation {
             inputs,
             decoder_input_details,
             input_length: input_length as u32,
+            prompt_tokens: self.sender.is_some().then_some(input_length as u32),
+            truncated_tokens: truncated_tokens as u32,
             truncate: truncate.unwrap_or(self.max_input_length) as u32,
+            low_priority,
             parameters,
             stopping_parameters,
         })
@@ -284,47 +383,65 @@ impl Validation {
 /// Start tokenization workers
 fn tokenizer_worker(tokenizer: Tokenizer, receiver: flume::Receiver<TokenizerRequest>) {
     // Loop over requests
-    while let Ok(((inputs, truncate), response_tx, parent_span)) = receiver.recv() {
+    while let Ok((
+        (inputs, truncate, truncation_side, add_special_tokens),
+        response_tx,
+        parent_span,
+    )) = receiver.recv()
+    {
         parent_span.in_scope(|| {
             response_tx
-                .send(prepare_input(inputs, truncate, &tokenizer))
+                .send(prepare_input(
+                    inputs,
+                    truncate,
+                    truncation_side,
+                    add_special_tokens,
+                    &tokenizer,
+                ))
                 .unwrap_or(())
         })
     }
 }
 
-/// Get input length and optionally truncate it
+/// Get input length and optionally truncate it, along with the number of truncated tokens
 fn prepare_input(
     inputs: String,
     truncate: Option<usize>,
+    truncation_side: TruncationSide,
+    add_special_tokens: bool,
     tokenizer: &Tokenizer,
-) -> Result<(String, usize), ValidationError> {
+) -> Result<(String, usize, usize), ValidationError> {
     // Get the number of tokens in the input
     let mut encoding = tokenizer
-        .encode(inputs.clone(), true)
+        .encode(inputs.clone(), add_special_tokens)
         .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
 
     // Optionally truncate
-    let (inputs, input_length) = match truncate {
+    let (inputs, input_length, truncated_tokens) = match truncate {
         // Truncate is some and < encoding length
         Some(truncate) if truncate < encoding.len() => {
             // truncate encoding and decode new inputs
-            encoding.truncate(truncate, 0, TruncationDirection::Left);
+            let direction = match truncation_side {
+                TruncationSide::Left => TruncationDirection::Left,
+                TruncationSide::Right => TruncationDirection::Right,
+            };
+            let truncated_tokens = encoding.len() - truncate;
+            encoding.truncate(truncate, 0, direction);
             let inputs = tokenizer
                 .decode(From::from(encoding.get_ids()), false)
                 .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
-            (inputs, encoding.len())
+            (inputs, encoding.len(), truncated_tokens)
         }
         // Nothing to do
-        _ => (inputs, encoding.len()),
+        _ => (inputs, encoding.len(), 0),
     };
 
-    Ok((inputs, input_length))
+    Ok((inputs, input_length, truncated_tokens))
 }
 
 type TokenizerRequest = (
-    (String, Option<usize>),
-    oneshot::Sender<Result<(String, usize), ValidationError>>,
+    (String, Option<usize>, TruncationSide, bool),
+    oneshot::Sender<Result<(String, usize, usize), ValidationError>>,
     Span,
 );
 
@@ -332,8 +449,14 @@ type TokenizerRequest = (
 pub(crate) struct ValidGenerateRequest {
     pub inputs: String,
     pub input_length: u32,
+    /// Number of tokens of the inputs counted by the tokenizer. `None` without a fast tokenizer,
+    /// when `input_length` is an upper bound
+    pub prompt_tokens: Option<u32>,
+    /// Number of tokens dropped from the inputs by the router
+    pub truncated_tokens: u32,
     pub truncate: u32,
     pub decoder_input_details: bool,
+    pub low_priority: bool,
     pub parameters: NextTokenChooserParameters,
     pub stopping_parameters: StoppingCriteriaParameters,
 }
@@ -348,7 +471,7 @@ pub enum ValidationError {
     BestOfSampling,
     #[error("`seed` must not be set when `best_of` > 1")]
     BestOfSeed,
-    #[error("`best_of` != 1 is not supported when streaming tokens")]
+    #[error("`best_of` > 1 cannot be streamed")]
     BestOfStream,
     #[error("`decoder_input_details` == true is not supported when streaming tokens")]
     PrefillDetailsStream,
@@ -356,6 +479,14 @@ pub enum ValidationError {
     Temperature,
     #[error("`repetition_penalty` must be strictly positive")]
     RepetitionPenalty,
+    #[error("`repetition_penalty` must be >= {0}. Given: {1}")]
+    RepetitionPenaltyMin(f32, f32),
+    #[error("`repetition_penalty` must be <= {0}. Given: {1}")]
+    RepetitionPenaltyMax(f32, f32),
+    #[error("`presence_penalty` must be >= -2.0 and <= 2.0")]
+    PresencePenalty,
+    #[error("`frequency_penalty` must be >= -2.0 and <= 2.0")]
+    FrequencyPenalty,
     #[error("`top_p` must be > 0.0 and < 1.0")]
     TopP,
     #[error("`top_k` must be strictly positive")]
@@ -366,6 +497,10 @@ pub enum ValidationError {
     TypicalP,
     #[error("`max_new_tokens` must be strictly positive")]
     NegativeMaxNewTokens,
+    #[error("`logit_bias` values must be >= -100 and <= 100. Given: {1} for token {0}")]
+    LogitBias(u32, f32),
+    #[error("`logit_bias` token ids must be < {1}, the vocabulary size. Given: {0}")]
+    LogitBiasToken(u32, usize),
     #[error("`max_new_tokens` must be <= {0}. Given: {1}")]
     MaxNewTokens(usize, u32),
     #[error("`inputs` tokens + `max_new_tokens` must be <= {0}. Given: {1} `inputs` tokens and {2} `max_new_tokens`")]
@@ -376,15 +511,123 @@ pub enum ValidationError {
     EmptyInput,
     #[error("`stop` supports up to {0} stop sequences. Given: {1}")]
     StopSequence(usize, usize),
+    #[error("stop sequence {0} is empty")]
+    EmptyStop(usize),
     #[error("tokenizer error {0}")]
     Tokenizer(String),
+    #[error("`continue_final_message` requires the last message to be from the assistant")]
+    ContinueFinalMessage,
+    #[error("`context` requires a `completion_context_template` in the chat config")]
+    CompletionContext,
+    #[error("chat template error: {0}")]
+    ChatTemplate(String),
+    #[error("`preset` must be one of the configured presets. Given: {0}")]
+    UnknownPreset(String),
+    #[error("user message {0} has an empty `content`")]
+    EmptyContent(usize),
+    #[error("message {0} follows another `{1}` message")]
+    ConsecutiveMessages(usize, String),
+    #[error("message role `{0}` has no configured template")]
+    UnknownRole(String),
+    #[error("`max_tokens` must be at most {0} for a prompt of {1} tokens. Given: {2}")]
+    OutputRatio(u32, u32, u32),
+    #[error("the system messages and the last user turn must have at most {0} tokens. Given: {1}")]
+    ConversationTokens(u32, u32),
+    #[error("`batch` must contain at least one conversation")]
+    EmptyBatch,
+    #[error("`messages` must be empty when `batch` is set")]
+    BatchMessages,
+    #[error("`batch` cannot be streamed")]
+    BatchStream,
+    #[error("`n` must be strictly positive")]
+    ZeroN,
+    #[error("`n` must be <= {0}. Given: {1}")]
+    MaxN(u32, u32),
+    #[error("`n` > 1 cannot be streamed")]
+    NStream,
+    #[error("`n` > 1 is not supported with `batch`")]
+    BatchN,
+    #[error("several prompts cannot be streamed")]
+    PromptsStream,
+    #[error("`prompt` must have at most {0} prompts. Given: {1}")]
+    MaxPrompts(usize, usize),
+    #[error("`prompt` must have at most {0} choices over all its prompts. Given: {1} prompts with `n` = {2}")]
+    PromptChoices(u32, usize, u32),
+    #[error("`batch` must contain at most {0} conversations. Given: {1}")]
+    MaxBatch(usize, usize),
+    #[error(
+        "`logprobs` must be <= {0}, alternatives to the sampled token are not returned. Given: {1}"
+    )]
+    MaxLogprobs(u32, u32),
+    #[error("`top_logprobs` must be <= {0}, alternatives to the sampled token are not returned. Given: {1}")]
+    MaxTopLogprobs(u32, u32),
+    #[error("`top_logprobs` requires `logprobs`")]
+    TopLogprobsWithoutLogprobs,
+}
+
+impl ValidationError {
+    /// Request parameter responsible for the error
+    pub(crate) fn param(&self) -> Option<&'static str> {
+        match self {
+            ValidationError::BestOf(_, _)
+            | ValidationError::BestOfDisabled
+            | ValidationError::BestOfSampling
+            | ValidationError::BestOfSeed
+            | ValidationError::BestOfStream => Some("best_of"),
+            ValidationError::PrefillDetailsStream => Some("decoder_input_details"),
+            ValidationError::Temperature => Some("temperature"),
+            ValidationError::RepetitionPenalty
+            | ValidationError::RepetitionPenaltyMin(_, _)
+            | ValidationError::RepetitionPenaltyMax(_, _) => Some("repetition_penalty"),
+            ValidationError::PresencePenalty => Some("presence_penalty"),
+            ValidationError::FrequencyPenalty => Some("frequency_penalty"),
+            ValidationError::TopP => Some("top_p"),
+            ValidationError::TopK => Some("top_k"),
+            ValidationError::Truncate(_, _) => Some("truncate"),
+            ValidationError::TypicalP => Some("typical_p"),
+            ValidationError::NegativeMaxNewTokens
+            | ValidationError::MaxNewTokens(_, _)
+            | ValidationError::MaxTotalTokens(_, _, _)
+            | ValidationError::OutputRatio(_, _, _) => Some("max_new_tokens"),
+            ValidationError::LogitBias(_, _) | ValidationError::LogitBiasToken(_, _) => {
+                Some("logit_bias")
+            }
+            ValidationError::InputLength(_, _) | ValidationError::EmptyInput => Some("inputs"),
+            ValidationError::StopSequence(_, _) | ValidationError::EmptyStop(_) => Some("stop"),
+            ValidationError::Tokenizer(_) => None,
+            ValidationError::ContinueFinalMessage => Some("continue_final_message"),
+            ValidationError::CompletionContext => Some("context"),
+            ValidationError::PromptsStream
+            | ValidationError::MaxPrompts(_, _)
+            | ValidationError::PromptChoices(_, _, _) => Some("prompt"),
+            ValidationError::UnknownPreset(_) => Some("preset"),
+            ValidationError::EmptyContent(_)
+            | ValidationError::UnknownRole(_)
+            | ValidationError::ChatTemplate(_)
+            | ValidationError::ConsecutiveMessages(_, _)
+            | ValidationError::ConversationTokens(_, _)
+            | ValidationError::BatchMessages => Some("messages"),
+            ValidationError::EmptyBatch
+            | ValidationError::BatchStream
+            | ValidationError::MaxBatch(_, _) => Some("batch"),
+            ValidationError::ZeroN
+            | ValidationError::MaxN(_, _)
+            | ValidationError::NStream
+            | ValidationError::BatchN => Some("n"),
+            ValidationError::MaxLogprobs(_, _) => Some("logprobs"),
+            ValidationError::MaxTopLogprobs(_, _) | ValidationError::TopLogprobsWithoutLogprobs => {
+                Some("top_logprobs")
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::default_parameters;
-    use crate::tests::get_tokenizer;
+    use crate::tests::{get_tokenizer, word_level_tokenizer};
+    use std::collections::HashMap;
 
     #[tokio::test]
     async fn test_validation_max_new_tokens() {
@@ -401,11 +644,17 @@ mod tests {
             max_stop_sequence,
             max_input_length,
             max_total_tokens,
+            &[],
         );
 
         let max_new_tokens = 10;
         match validation
-            .validate_input("Hello".to_string(), None, max_new_tokens)
+            .validate_input(
+                "Hello".to_string(),
+                None,
+                TruncationSide::Left,
+                max_new_tokens,
+            )
             .await
         {
             Err(ValidationError::MaxNewTokens(1, 10)) => (),
@@ -428,11 +677,17 @@ mod tests {
             max_stop_sequence,
             max_input_length,
             max_total_tokens,
+            &[],
         );
 
         let max_new_tokens = 10;
         match validation
-            .validate_input("Hello".to_string(), None, max_new_tokens)
+            .validate_input(
+                "Hello".to_string(),
+                None,
+                TruncationSide::Left,
+                max_new_tokens,
+            )
             .await
         {
             Err(ValidationError::MaxTotalTokens(5, 1, 10)) => (),
@@ -455,6 +710,7 @@ mod tests {
             max_stop_sequence,
             max_input_length,
             max_total_tokens,
+            &[],
         );
         match validation
             .validate(GenerateRequest {
@@ -487,6 +743,7 @@ mod tests {
             max_stop_sequence,
             max_input_length,
             max_total_tokens,
+            &[],
         );
         match validation
             .validate(GenerateRequest {
@@ -531,4 +788,220 @@ mod tests {
         // top_p == 1.0 is invalid for users to ask for but it's the default resolved value.
         assert_eq!(valid_request.parameters.top_p, 1.0);
     }
+
+    #[tokio::test]
+    async fn test_validation_logit_bias() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_input_length,
+            max_total_tokens,
+            &[],
+        );
+        match validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    logit_bias: Some(HashMap::from([(42, 101.0)])),
+                    max_new_tokens: 1,
+                    ..default_parameters()
+                },
+            })
+            .await
+        {
+            Err(ValidationError::LogitBias(42, _)) => (),
+            _ => panic!("Unexpected logit_bias"),
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    logit_bias: Some(HashMap::from([(42, -100.0)])),
+                    logit_bias_first_token_only: true,
+                    max_new_tokens: 1,
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            valid_request.parameters.logit_bias,
+            vec![LogitBias {
+                token_id: 42,
+                bias: -100.0
+            }]
+        );
+        assert!(valid_request.parameters.logit_bias_first_token_only);
+    }
+
+    #[tokio::test]
+    async fn test_validation_logit_bias_token() {
+        let tokenizer = word_level_tokenizer(&["a", "b"]);
+        let validation = Validation::new(1, Some(tokenizer), 2, 3, 4, 5, &[]);
+        let validate = |token_id| {
+            validation.validate(GenerateRequest {
+                inputs: "a b".to_string(),
+                parameters: GenerateParameters {
+                    logit_bias: Some(HashMap::from([(token_id, 1.0)])),
+                    max_new_tokens: 1,
+                    ..default_parameters()
+                },
+            })
+        };
+        assert_eq!(validate(2).await.unwrap().parameters.logit_bias.len(), 1);
+        let err = validate(3).await.unwrap_err();
+        assert!(matches!(err, ValidationError::LogitBiasToken(3, 3)));
+        assert_eq!(err.param(), Some("logit_bias"));
+    }
+
+    #[tokio::test]
+    async fn test_validation_presence_penalty() {
+        let validation = Validation::new(1, None, 2, 3, 4, 5, &[]);
+        let validate = |presence_penalty| {
+            validation.validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    presence_penalty,
+                    max_new_tokens: 1,
+                    ..default_parameters()
+                },
+            })
+        };
+        match validate(Some(2.5)).await {
+            Err(ValidationError::PresencePenalty) => (),
+            _ => panic!("Unexpected presence_penalty"),
+        }
+        let valid_request = validate(Some(-2.0)).await.unwrap();
+        assert_eq!(valid_request.parameters.presence_penalty, -2.0);
+        // No penalty by default
+        let valid_request = validate(None).await.unwrap();
+        assert_eq!(valid_request.parameters.presence_penalty, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_validation_frequency_penalty() {
+        let validation = Validation::new(1, None, 2, 3, 4, 5, &[]);
+        let validate = |presence_penalty, frequency_penalty| {
+            validation.validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    presence_penalty,
+                    frequency_penalty,
+                    max_new_tokens: 1,
+                    ..default_parameters()
+                },
+            })
+        };
+        match validate(None, Some(-2.5)).await {
+            Err(ValidationError::FrequencyPenalty) => (),
+            _ => panic!("Unexpected frequency_penalty"),
+        }
+        // Both penalties are passed to the sampler, which subtracts them from the logits
+        let valid_request = validate(Some(0.5), Some(1.0)).await.unwrap();
+        assert_eq!(valid_request.parameters.presence_penalty, 0.5);
+        assert_eq!(valid_request.parameters.frequency_penalty, 1.0);
+        let valid_request = validate(None, Some(2.0)).await.unwrap();
+        assert_eq!(valid_request.parameters.presence_penalty, 0.0);
+        assert_eq!(valid_request.parameters.frequency_penalty, 2.0);
+    }
+
+    #[tokio::test]
+    async fn test_validation_error_param() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_input_length,
+            max_total_tokens,
+            &[],
+        );
+        let err = validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    temperature: Some(0.0),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.param(), Some("temperature"));
+
+        assert_eq!(ValidationError::BestOfStream.param(), Some("best_of"));
+        assert_eq!(
+            ValidationError::BestOfStream.to_string(),
+            "`best_of` > 1 cannot be streamed"
+        );
+        // The penalties are reported under the names the clients sent
+        assert_eq!(
+            ValidationError::RepetitionPenalty.param(),
+            Some("repetition_penalty")
+        );
+        assert_eq!(
+            ValidationError::PresencePenalty.param(),
+            Some("presence_penalty")
+        );
+        assert_eq!(
+            ValidationError::Tokenizer("error".to_string()).param(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_count_tokens() {
+        let tokenizer = word_level_tokenizer(&["a", "b"]);
+        let validation = Validation::new(1, Some(tokenizer), 2, 3, 4, 5, &[]);
+        // Inputs longer than the maximum input length are counted in full
+        assert_eq!(
+            validation.count_tokens("a b a c".to_string(), true).await,
+            Some(4)
+        );
+
+        let validation = Validation::new(1, None, 2, 3, 4, 5, &[]);
+        assert_eq!(validation.count_tokens("a b".to_string(), true).await, None);
+    }
+
+    #[test]
+    fn test_truncation_side() {
+        let tokenizer = word_level_tokenizer(&["a", "b", "c", "d"]);
+
+        let prepare = |truncation_side| {
+            prepare_input(
+                "a b c d".to_string(),
+                Some(2),
+                truncation_side,
+                true,
+                &tokenizer,
+            )
+            .unwrap()
+        };
+        assert_eq!(prepare(TruncationSide::Left), ("c d".to_string(), 2, 2));
+        assert_eq!(prepare(TruncationSide::Right), ("a b".to_string(), 2, 2));
+
+        // Short enough inputs are left as is
+        let inputs = prepare_input(
+            "a b".to_string(),
+            Some(2),
+            TruncationSide::Right,
+            true,
+            &tokenizer,
+        );
+        assert_eq!(inputs.unwrap(), ("a b".to_string(), 2, 0));
+    }
 }
@@ -0,0 +1,116 @@
+import torch
+
+from text_generation_server.utils.logits_process import (
+    HeterogeneousFrequencyPenaltyLogitsProcessor,
+    HeterogeneousPresencePenaltyLogitsProcessor,
+    LogitBiasProcessor,
+    StaticWarper,
+)
+
+
+def test_logit_bias_processor():
+    processor = LogitBiasProcessor({1: 5.0, 3: -5.0})
+
+    scores = processor(None, torch.zeros(1, 4))
+    assert scores.tolist() == [[0.0, 5.0, 0.0, -5.0]]
+    scores = processor(None, torch.zeros(1, 4))
+    assert scores.tolist() == [[0.0, 5.0, 0.0, -5.0]]
+
+
+def test_logit_bias_processor_first_token_only():
+    processor = LogitBiasProcessor({1: 5.0}, first_token_only=True)
+
+    scores = processor(None, torch.zeros(1, 4))
+    assert scores.tolist() == [[0.0, 5.0, 0.0, 0.0]]
+    scores = processor(None, torch.zeros(1, 4))
+    assert scores.tolist() == [[0.0, 0.0, 0.0, 0.0]]
+
+
+def test_presence_penalty_processor():
+    processor = HeterogeneousPresencePenaltyLogitsProcessor([0.5, -1.0], "cpu")
+    input_ids = torch.tensor([[1, 1, 3], [0, 2, 2]])
+
+    # Tokens are penalized once, however many times they appear
+    scores = processor(input_ids, torch.zeros(2, 4), torch.tensor([3, 3]))
+    assert scores.tolist() == [[0.0, -0.5, 0.0, -0.5], [1.0, 0.0, 1.0, 0.0]]
+
+    processor = processor.filter([1])
+    scores = processor(input_ids[1:], torch.zeros(1, 4), torch.tensor([3]))
+    assert scores.tolist() == [[1.0, 0.0, 1.0, 0.0]]
+    assert HeterogeneousPresencePenaltyLogitsProcessor([0.5], "cpu").filter([]) is None
+
+
+def test_presence_penalty_processor_padding():
+    processor = HeterogeneousPresencePenaltyLogitsProcessor([0.5, -1.0], "cpu")
+    # The shorter sequences are padded with token 0
+    input_ids = torch.tensor([[1, 3, 0], [2, 0, 0]])
+    input_lengths = torch.tensor([3, 1])
+
+    scores = processor(input_ids, torch.zeros(2, 4), input_lengths, input_lengths)
+    assert scores.tolist() == [[-0.5, -0.5, 0.0, -0.5], [0.0, 0.0, 1.0, 0.0]]
+
+
+def test_presence_penalty_processor_prompt():
+    processor = HeterogeneousPresencePenaltyLogitsProcessor([0.5, 0.5], "cpu")
+    # The prompts [1, 3] and [2] are followed by the generated tokens 2 and 1
+    input_ids = torch.tensor([[1, 3, 2, 0], [2, 1, 0, 0]])
+    input_lengths = torch.tensor([3, 2])
+
+    # The tokens of the prompts are not penalized, only the generated ones
+    generated_lengths = torch.tensor([1, 1])
+    scores = processor(input_ids, torch.zeros(2, 4), generated_lengths, input_lengths)
+    assert scores.tolist() == [[0.0, 0.0, -0.5, 0.0], [0.0, -0.5, 0.0, 0.0]]
+
+    # Nothing is penalized before the first generated token
+    generated_lengths = torch.tensor([0, 0])
+    scores = processor(input_ids, torch.zeros(2, 4), generated_lengths, input_lengths)
+    assert scores.tolist() == [[0.0, 0.0, 0.0, 0.0], [0.0, 0.0, 0.0, 0.0]]
+
+
+def test_frequency_penalty_processor():
+    processor = HeterogeneousFrequencyPenaltyLogitsProcessor([0.5, -1.0], "cpu")
+    input_ids = torch.tensor([[1, 1, 3], [0, 2, 2]])
+
+    # Tokens are penalized once for each occurrence
+    scores = processor(input_ids, torch.zeros(2, 4), torch.tensor([3, 3]))
+    assert scores.tolist() == [[0.0, -1.0, 0.0, -0.5], [1.0, 0.0, 2.0, 0.0]]
+
+    processor = processor.filter([1])
+    scores = processor(input_ids[1:], torch.zeros(1, 4), torch.tensor([3]))
+    assert scores.tolist() == [[1.0, 0.0, 2.0, 0.0]]
+    assert HeterogeneousFrequencyPenaltyLogitsProcessor([0.5], "cpu").filter([]) is None
+
+
+def test_frequency_penalty_processor_padding():
+    processor = HeterogeneousFrequencyPenaltyLogitsProcessor([-2.0, -2.0], "cpu")
+    # The shorter sequence is padded with token 0
+    input_ids = torch.tensor([[1, 3, 3, 2], [2, 0, 0, 0]])
+    input_lengths = torch.tensor([4, 1])
+
+    scores = processor(input_ids, torch.zeros(2, 4), input_lengths, input_lengths)
+    assert scores.tolist() == [[0.0, 2.0, 2.0, 4.0], [0.0, 0.0, 2.0, 0.0]]
+
+
+def test_frequency_penalty_processor_prompt():
+    processor = HeterogeneousFrequencyPenaltyLogitsProcessor([0.5, 0.5], "cpu")
+    # The prompts [2, 2, 1] and [3, 3] contain the generated tokens 2 and [3, 3]
+    input_ids = torch.tensor([[2, 2, 1, 2], [3, 3, 3, 3]])
+    generated_lengths = torch.tensor([1, 2])
+
+    # Only the occurrences in the generated tokens are counted
+    scores = processor(input_ids, torch.zeros(2, 4), generated_lengths)
+    assert scores.tolist() == [[0.0, 0.0, -0.5, 0.0], [0.0, 0.0, 0.0, -1.0]]
+
+
+def test_static_warper_sampler_order():
+    scores = torch.log(torch.tensor([[0.5, 0.3, 0.15, 0.05]]))
+
+    # Top-p renormalizes the 2 tokens kept by top-k, and only keeps the first one
+    warper = StaticWarper(top_k=2, top_p=0.6)
+    warped, _ = warper(scores.clone())
+    assert torch.isfinite(warped).tolist() == [[True, False, False, False]]
+
+    # Top-p keeps the 2 first tokens, which top-k keeps too
+    warper = StaticWarper(top_k=2, top_p=0.6, sampler_order=("top_p", "top_k"))
+    warped, _ = warper(scores.clone())
+    assert torch.isfinite(warped).tolist() == [[True, True, False, False]]
@@ -29,6 +29,16 @@ def test_stopping_criteria():
     assert criteria(30, ";") == (True, FinishReason.FINISH_REASON_STOP_SEQUENCE)
 
 
+def test_stopping_criteria_bounded_output():
+    criteria = StoppingCriteria(
+        0, [StopSequenceCriteria("/test;")], max_new_tokens=100_000
+    )
+    for _ in range(10_000):
+        assert criteria(1, "/test") == (False, None)
+        assert len(criteria.current_output) <= len("/test;")
+    assert criteria(1, ";") == (True, FinishReason.FINISH_REASON_STOP_SEQUENCE)
+
+
 def test_stopping_criteria_eos():
     criteria = StoppingCriteria(0, [StopSequenceCriteria("/test;")], max_new_tokens=5)
     assert criteria(1, "") == (False, None)
@@ -518,7 +518,9 @@ class CausalLM(Model):
         ) in enumerate(iterator):
             # Select next token
             next_token_id, logprobs = next_token_chooser(
-                all_input_ids.view(1, -1), logits[-1:, :]
+                all_input_ids.view(1, -1),
+                logits[-1:, :],
+                stopping_criteria.current_tokens,
             )
 
             # Append next token to all tokens
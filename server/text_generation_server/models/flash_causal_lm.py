@@ -641,6 +641,7 @@ class FlashCausalLMBatch(Batch):
             next_token_chooser_parameters,
             dtype=batches[0].next_token_chooser.dtype,
             device=batches[0].next_token_chooser.device,
+            prefilled=True,
         )
 
         # Needed to avoid dropping blocks when the batches will go out of scope
@@ -838,8 +839,17 @@ class FlashCausalLM(Model):
         else:
             next_token_logits = out
 
+        # The generated tokens end the sequences, after their prompt
+        generated_lengths = torch.tensor(
+            [criteria.current_tokens for criteria in batch.stopping_criterias],
+            dtype=batch.input_lengths_tensor.dtype,
+            device=batch.input_lengths_tensor.device,
+        )
         next_input_ids, next_token_logprobs = batch.next_token_chooser(
-            batch.all_input_ids_tensor[:, : batch.max_seqlen], next_token_logits
+            batch.all_input_ids_tensor[:, : batch.max_seqlen],
+            next_token_logits,
+            generated_lengths,
+            batch.input_lengths_tensor,
         )
 
         if prefill:
@@ -644,7 +644,9 @@ class Seq2SeqLM(Model):
         ) in enumerate(iterator):
             # Select next token
             next_token_id, logprobs = next_token_chooser(
-                all_decoder_input_ids.view(1, -1), logits[-1:, :]
+                all_decoder_input_ids.view(1, -1),
+                logits[-1:, :],
+                stopping_criteria.current_tokens,
             )
 
             # Append next token to decoder tokens
@@ -2,7 +2,7 @@ import math
 import torch
 
 from functools import lru_cache
-from typing import Optional, List, Dict, Union
+from typing import Optional, List, Dict, Tuple, Union
 
 from transformers import (
     LogitsWarper,
@@ -15,6 +15,9 @@ from transformers import (
 
 mempool = torch.cuda.graph_pool_handle() if torch.cuda.is_available() else None
 
+# Order the truncation samplers apply in when the request does not set one
+DEFAULT_SAMPLER_ORDER = ("top_k", "top_p", "typical_p")
+
 
 class StaticWarper:
     def __init__(
@@ -23,18 +26,22 @@ class StaticWarper:
         top_k=None,
         top_p=None,
         typical_p=None,
+        sampler_order=DEFAULT_SAMPLER_ORDER,
     ):
         self.warpers = []
 
         if temperature is not None and temperature != 1.0:
             temperature = float(temperature)
             self.warpers.append(TemperatureLogitsWarper(temperature))
+
+        filters = {}
         if top_k is not None and top_k != 0:
-            self.warpers.append(TopKLogitsWarper(top_k=top_k))
+            filters["top_k"] = TopKLogitsWarper(top_k=top_k)
         if top_p is not None and top_p < 1.0:
-            self.warpers.append(TopPLogitsWarper(top_p=top_p))
+            filters["top_p"] = TopPLogitsWarper(top_p=top_p)
         if typical_p is not None and typical_p < 1.0:
-            self.warpers.append(TypicalLogitsWarper(mass=typical_p))
+            filters["typical_p"] = TypicalLogitsWarper(mass=typical_p)
+        self.warpers.extend(filters[name] for name in sampler_order if name in filters)
 
         self.cuda_graph = None
         self.static_scores = None
@@ -75,9 +82,14 @@ def static_warper(
     top_k: Optional[int],
     top_p: Optional[float],
     typical_p: Optional[float],
+    sampler_order: Tuple[str, ...] = DEFAULT_SAMPLER_ORDER,
 ) -> StaticWarper:
     return StaticWarper(
-        temperature=temperature, top_k=top_k, top_p=top_p, typical_p=typical_p
+        temperature=temperature,
+        top_k=top_k,
+        top_p=top_p,
+        typical_p=typical_p,
+        sampler_order=sampler_order,
     )
 
 
@@ -118,6 +130,107 @@ class HeterogeneousRepetitionPenaltyLogitsProcessor(LogitsProcessor):
         return None
 
 
+def token_counts(
+    input_ids: torch.Tensor,
+    scores: torch.Tensor,
+    input_lengths: Optional[torch.Tensor] = None,
+    generated_lengths: Optional[torch.Tensor] = None,
+) -> torch.Tensor:
+    """Occurrences of each token of the vocabulary in each sequence. `input_lengths` leaves out
+    the padding of the sequences shorter than `input_ids`, which would count as token 0, and
+    `generated_lengths` only counts the generated tokens ending each sequence, leaving the
+    prompt out"""
+    positions = torch.arange(input_ids.shape[1], device=input_ids.device).unsqueeze(0)
+    if input_lengths is None:
+        input_lengths = torch.full(
+            (input_ids.shape[0],), input_ids.shape[1], device=input_ids.device
+        )
+    occurrences = positions < input_lengths.unsqueeze(1)
+    if generated_lengths is not None:
+        prompt_lengths = input_lengths - generated_lengths
+        occurrences &= positions >= prompt_lengths.unsqueeze(1)
+    return torch.zeros_like(scores).scatter_add_(
+        1, input_ids, occurrences.to(scores.dtype)
+    )
+
+
+class HeterogeneousPresencePenaltyLogitsProcessor(LogitsProcessor):
+    r"""
+    [`LogitsProcessor`] subtracting a penalty from the logits of the tokens already generated,
+    once however many times they appear, like the OpenAI `presence_penalty`. The tokens of the
+    prompt are not penalized.
+    This version allows for a separate value for each sample.
+    It doesn't validate inputs.
+
+    Args:
+        penalty (`List[float]`):
+            The penalty subtracted from the logits. 0.0 means no penalty, and negative values
+            encourage repetitions.
+    """
+
+    def __init__(self, penalty: List[float], device: torch.device):
+        self.penalty = penalty
+        self.penalty_tensor = torch.tensor(
+            penalty, dtype=torch.float32, device=device
+        ).unsqueeze(1)
+
+    def __call__(
+        self,
+        input_ids: torch.Tensor,
+        scores: torch.Tensor,
+        generated_lengths: torch.Tensor,
+        input_lengths: Optional[torch.Tensor] = None,
+    ) -> torch.Tensor:
+        # Repeated tokens are only penalized once
+        present = token_counts(input_ids, scores, input_lengths, generated_lengths) > 0
+        return scores - present.to(scores.dtype) * self.penalty_tensor.to(scores.dtype)
+
+    def filter(self, indices):
+        self.penalty = [self.penalty[i] for i in indices]
+        if any([x != 0.0 for x in self.penalty]):
+            self.penalty_tensor = self.penalty_tensor[indices]
+            return self
+        return None
+
+
+class HeterogeneousFrequencyPenaltyLogitsProcessor(LogitsProcessor):
+    r"""
+    [`LogitsProcessor`] subtracting a penalty from the logits of the tokens already generated,
+    once for each time they appear, like the OpenAI `frequency_penalty`. The occurrences in the
+    prompt are not counted.
+    This version allows for a separate value for each sample.
+    It doesn't validate inputs.
+
+    Args:
+        penalty (`List[float]`):
+            The penalty subtracted from the logits for each occurrence. 0.0 means no penalty,
+            and negative values encourage repetitions.
+    """
+
+    def __init__(self, penalty: List[float], device: torch.device):
+        self.penalty = penalty
+        self.penalty_tensor = torch.tensor(
+            penalty, dtype=torch.float32, device=device
+        ).unsqueeze(1)
+
+    def __call__(
+        self,
+        input_ids: torch.Tensor,
+        scores: torch.Tensor,
+        generated_lengths: torch.Tensor,
+        input_lengths: Optional[torch.Tensor] = None,
+    ) -> torch.Tensor:
+        counts = token_counts(input_ids, scores, input_lengths, generated_lengths)
+        return scores - counts * self.penalty_tensor.to(scores.dtype)
+
+    def filter(self, indices):
+        self.penalty = [self.penalty[i] for i in indices]
+        if any([x != 0.0 for x in self.penalty]):
+            self.penalty_tensor = self.penalty_tensor[indices]
+            return self
+        return None
+
+
 class HeterogeneousTemperatureLogitsWarper:
     r"""
     [`LogitsWarper`] for temperature (exponential scaling output probability distribution).
@@ -408,3 +521,38 @@ class HeterogeneousProcessorWrapper(LogitsProcessor):
             self.processors = new_processors
             return self
         return None
+
+
+class LogitBiasProcessor(LogitsProcessor):
+    r"""
+    [`LogitsProcessor`] adding a bias to the logits of a set of tokens.
+    Args:
+        logit_bias (`Dict[int, float]`):
+            A mapping of token ids to the bias added to their logits.
+        first_token_only (`bool`):
+            Only apply the bias when choosing the first generated token.
+        device (`torch.device`):
+            The device to allocate the tensors.
+    """
+
+    def __init__(
+        self,
+        logit_bias: Dict[int, float],
+        first_token_only: bool = False,
+        device: Union[str, torch.device] = "cpu",
+    ):
+        self.token_ids = torch.tensor(
+            list(logit_bias.keys()), dtype=torch.int64, device=device
+        )
+        self.bias = torch.tensor(
+            list(logit_bias.values()), dtype=torch.float32, device=device
+        )
+        self.first_token_only = first_token_only
+        self.applied = False
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        if self.first_token_only and self.applied:
+            return scores
+        self.applied = True
+        scores[:, self.token_ids] += self.bias.to(scores.dtype)
+        return scores
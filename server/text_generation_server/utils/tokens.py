@@ -5,22 +5,39 @@ from transformers import (
     RepetitionPenaltyLogitsProcessor,
     PreTrainedTokenizerBase,
 )
-from typing import List, Tuple, Optional
+from typing import Dict, List, Tuple, Optional
 
 from text_generation_server.pb import generate_pb2
 from text_generation_server.pb.generate_pb2 import FinishReason
 from text_generation_server.utils.watermark import WatermarkLogitsProcessor
 from text_generation_server.utils.logits_process import (
+    DEFAULT_SAMPLER_ORDER,
     static_warper,
+    HeterogeneousFrequencyPenaltyLogitsProcessor,
+    HeterogeneousPresencePenaltyLogitsProcessor,
     HeterogeneousRepetitionPenaltyLogitsProcessor,
     HeterogeneousTemperatureLogitsWarper,
     HeterogeneousTopKLogitsWarper,
     HeterogeneousTopPLogitsWarper,
     HeterogeneousTypicalLogitsWarper,
     HeterogeneousProcessorWrapper,
+    LogitBiasProcessor,
 )
 
 
+SAMPLER_FILTERS = {
+    generate_pb2.SAMPLER_FILTER_TOP_K: "top_k",
+    generate_pb2.SAMPLER_FILTER_TOP_P: "top_p",
+    generate_pb2.SAMPLER_FILTER_TYPICAL_P: "typical_p",
+}
+
+
+def sampler_order(pb: generate_pb2.NextTokenChooserParameters) -> Tuple[str, ...]:
+    """Order of the truncation samplers of a request, the default order when unset"""
+    order = tuple(SAMPLER_FILTERS[f] for f in pb.sampler_order)
+    return order or DEFAULT_SAMPLER_ORDER
+
+
 class NextTokenChooser:
     def __init__(
         self,
@@ -33,6 +50,11 @@ class NextTokenChooser:
         do_sample=False,
         seed=0,
         device="cpu",
+        logit_bias=None,
+        logit_bias_first_token_only=False,
+        sampler_order=DEFAULT_SAMPLER_ORDER,
+        presence_penalty=0.0,
+        frequency_penalty=0.0,
     ):
         self.watermark_processor = (
             WatermarkLogitsProcessor(device=device) if watermark else None
@@ -42,6 +64,21 @@ class NextTokenChooser:
             if repetition_penalty
             else None
         )
+        self.presence_processor = (
+            HeterogeneousPresencePenaltyLogitsProcessor([presence_penalty], device)
+            if presence_penalty
+            else None
+        )
+        self.frequency_processor = (
+            HeterogeneousFrequencyPenaltyLogitsProcessor([frequency_penalty], device)
+            if frequency_penalty
+            else None
+        )
+        self.logit_bias_processor = (
+            LogitBiasProcessor(logit_bias, logit_bias_first_token_only, device)
+            if logit_bias
+            else None
+        )
 
         has_warpers = (
             (temperature is not None and temperature != 1.0)
@@ -51,7 +88,11 @@ class NextTokenChooser:
         )
         if has_warpers:
             self.static_warper = static_warper(
-                temperature=temperature, top_k=top_k, top_p=top_p, typical_p=typical_p
+                temperature=temperature,
+                top_k=top_k,
+                top_p=top_p,
+                typical_p=typical_p,
+                sampler_order=sampler_order,
             )
         else:
             self.static_warper = None
@@ -59,11 +100,20 @@ class NextTokenChooser:
         sampling = do_sample or has_warpers
         self.choice = Sampling(seed, device) if sampling else Greedy()
 
-    def __call__(self, input_ids, scores):
+    def __call__(self, input_ids, scores, generated_length: int):
+        """`generated_length` is the number of generated tokens ending `input_ids`, after the
+        prompt"""
         if self.watermark_processor is not None:
             scores = self.watermark_processor(input_ids, scores)
         if self.repetition_processor is not None:
             scores = self.repetition_processor(input_ids, scores)
+        generated_lengths = torch.tensor([generated_length], device=input_ids.device)
+        if self.presence_processor is not None:
+            scores = self.presence_processor(input_ids, scores, generated_lengths)
+        if self.frequency_processor is not None:
+            scores = self.frequency_processor(input_ids, scores, generated_lengths)
+        if self.logit_bias_processor is not None:
+            scores = self.logit_bias_processor(input_ids, scores)
 
         if self.static_warper is None:
             next_logprob = torch.log_softmax(scores, -1)
@@ -90,11 +140,17 @@ class NextTokenChooser:
             do_sample=pb.do_sample,
             seed=pb.seed,
             device=device,
+            logit_bias={b.token_id: b.bias for b in pb.logit_bias},
+            logit_bias_first_token_only=pb.logit_bias_first_token_only,
+            sampler_order=sampler_order(pb),
+            presence_penalty=pb.presence_penalty,
+            frequency_penalty=pb.frequency_penalty,
         )
 
 
 class StopSequenceCriteria:
     def __init__(self, stop_sequence: str):
+        self.length = len(stop_sequence)
         stop_sequence = re.escape(stop_sequence)
         self.regex = re.compile(f".*{stop_sequence}$")
 
@@ -117,6 +173,10 @@ class StoppingCriteria:
         self.max_new_tokens = max_new_tokens
         self.current_tokens = 0
         self.current_output = ""
+        # Only the end of the output can match a stop sequence
+        self.max_stop_length = max(
+            (criteria.length for criteria in stop_sequence_criterias), default=0
+        )
         self.ignore_eos_token = ignore_eos_token
 
     def __call__(self, last_token: int, last_output: str) -> Tuple[bool, Optional[str]]:
@@ -128,6 +188,9 @@ class StoppingCriteria:
             return True, FinishReason.FINISH_REASON_EOS_TOKEN
 
         self.current_output += last_output
+        self.current_output = self.current_output[
+            len(self.current_output) - self.max_stop_length :
+        ]
         for stop_sequence_criteria in self.stop_sequence_criterias:
             if stop_sequence_criteria(self.current_output):
                 return True, FinishReason.FINISH_REASON_STOP_SEQUENCE
@@ -164,6 +227,11 @@ class HeterogeneousNextTokenChooser:
         typical_p: List[float],
         do_sample: List[bool],
         seeds: List[int],
+        logit_bias: Optional[List[Dict[int, float]]] = None,
+        logit_bias_first_token_only: Optional[List[bool]] = None,
+        sampler_order: Tuple[str, ...] = DEFAULT_SAMPLER_ORDER,
+        presence_penalty: Optional[List[float]] = None,
+        frequency_penalty: Optional[List[float]] = None,
     ):
         warpers = []
 
@@ -187,6 +255,38 @@ class HeterogeneousNextTokenChooser:
             else None
         )
 
+        presence_penalty = presence_penalty or [0.0 for _ in seeds]
+        self.presence_processor = (
+            HeterogeneousPresencePenaltyLogitsProcessor(presence_penalty, device)
+            if any([x != 0.0 for x in presence_penalty])
+            else None
+        )
+
+        frequency_penalty = frequency_penalty or [0.0 for _ in seeds]
+        self.frequency_processor = (
+            HeterogeneousFrequencyPenaltyLogitsProcessor(frequency_penalty, device)
+            if any([x != 0.0 for x in frequency_penalty])
+            else None
+        )
+
+        logit_bias = logit_bias or [{} for _ in seeds]
+        logit_bias_first_token_only = logit_bias_first_token_only or [
+            False for _ in seeds
+        ]
+        self.logit_bias_processor = (
+            HeterogeneousProcessorWrapper(
+                {
+                    i: LogitBiasProcessor(bias, first_token_only, device)
+                    for i, (bias, first_token_only) in enumerate(
+                        zip(logit_bias, logit_bias_first_token_only)
+                    )
+                    if bias
+                }
+            )
+            if any(logit_bias)
+            else None
+        )
+
         if any([x != 1.0 for x in temperature]):
             do_sample = [
                 sample or x != 1.0 for x, sample in zip(temperature, do_sample)
@@ -195,17 +295,21 @@ class HeterogeneousNextTokenChooser:
                 HeterogeneousTemperatureLogitsWarper(temperature, dtype, device)
             )
 
+        filters = {}
         if any([x != 0 for x in top_k]):
             do_sample = [sample or x != 0 for x, sample in zip(top_k, do_sample)]
-            warpers.append(HeterogeneousTopKLogitsWarper(top_k, device))
+            filters["top_k"] = HeterogeneousTopKLogitsWarper(top_k, device)
 
         if any([x < 1.0 for x in top_p]):
             do_sample = [sample or x < 1.0 for x, sample in zip(top_p, do_sample)]
-            warpers.append(HeterogeneousTopPLogitsWarper(top_p, dtype, device))
+            filters["top_p"] = HeterogeneousTopPLogitsWarper(top_p, dtype, device)
 
         if any([x < 1.0 for x in typical_p]):
             do_sample = [sample or x < 1.0 for x, sample in zip(typical_p, do_sample)]
-            warpers.append(HeterogeneousTypicalLogitsWarper(typical_p, dtype, device))
+            filters["typical_p"] = HeterogeneousTypicalLogitsWarper(
+                typical_p, dtype, device
+            )
+        warpers.extend(filters[name] for name in sampler_order if name in filters)
 
         self.warpers = warpers
 
@@ -219,11 +323,30 @@ class HeterogeneousNextTokenChooser:
         self.dtype = dtype
         self.device = device
 
-    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor):
+    def __call__(
+        self,
+        input_ids: torch.Tensor,
+        scores: torch.Tensor,
+        generated_lengths: torch.Tensor,
+        input_lengths: Optional[torch.Tensor] = None,
+    ):
+        """`generated_lengths` are the numbers of generated tokens ending the sequences of
+        `input_ids`, after their prompt, and `input_lengths` the lengths of the sequences, when
+        shorter sequences are padded"""
         if self.watermark_processor is not None:
             scores = self.watermark_processor(input_ids, scores)
         if self.repetition_processor is not None:
             scores = self.repetition_processor(input_ids, scores)
+        if self.presence_processor is not None:
+            scores = self.presence_processor(
+                input_ids, scores, generated_lengths, input_lengths
+            )
+        if self.frequency_processor is not None:
+            scores = self.frequency_processor(
+                input_ids, scores, generated_lengths, input_lengths
+            )
+        if self.logit_bias_processor is not None:
+            scores = self.logit_bias_processor(input_ids, scores)
 
         for warper in self.warpers:
             scores = warper(input_ids, scores)
@@ -242,6 +365,15 @@ class HeterogeneousNextTokenChooser:
         if self.repetition_processor is not None:
             self.repetition_processor = self.repetition_processor.filter(indices)
 
+        if self.presence_processor is not None:
+            self.presence_processor = self.presence_processor.filter(indices)
+
+        if self.frequency_processor is not None:
+            self.frequency_processor = self.frequency_processor.filter(indices)
+
+        if self.logit_bias_processor is not None:
+            self.logit_bias_processor = self.logit_bias_processor.filter(indices)
+
         filtered_warpers = []
         for warper in self.warpers:
             filtered_warper = warper.filter(indices)
@@ -265,7 +397,15 @@ class HeterogeneousNextTokenChooser:
         pb: List[generate_pb2.NextTokenChooserParameters],
         dtype: torch.dtype,
         device: torch.device,
+        prefilled: bool = False,
     ) -> "HeterogeneousNextTokenChooser":
+        # Biases restricted to the first token were already applied if the requests were prefilled
+        logit_bias = [
+            {}
+            if prefilled and pb_.logit_bias_first_token_only
+            else {b.token_id: b.bias for b in pb_.logit_bias}
+            for pb_ in pb
+        ]
         return HeterogeneousNextTokenChooser(
             watermark=[pb_.watermark for pb_ in pb],
             temperature=[pb_.temperature for pb_ in pb],
@@ -277,6 +417,14 @@ class HeterogeneousNextTokenChooser:
             seeds=[pb_.seed for pb_ in pb],
             device=device,
             dtype=dtype,
+            logit_bias=logit_bias,
+            logit_bias_first_token_only=[
+                pb_.logit_bias_first_token_only for pb_ in pb
+            ],
+            # The order is set by the router for all the requests
+            sampler_order=sampler_order(pb[0]) if pb else DEFAULT_SAMPLER_ORDER,
+            presence_penalty=[pb_.presence_penalty for pb_ in pb],
+            frequency_penalty=[pb_.frequency_penalty for pb_ in pb],
         )
 
 